@@ -0,0 +1,573 @@
+//! Dumping of the DWARF call frame information (`.eh_frame`)
+//!
+//! Every CIE and FDE is parsed once, and the FDEs are then matched against the
+//! function symbols. For each FDE, both the raw call frame instructions and the
+//! resulting unwind table rows are dumped.
+
+use std::collections::BTreeMap;
+
+use anyhow::Result;
+use gimli::{
+	AArch64,
+	Arm,
+	CallFrameInstruction,
+	CfaRule,
+	CieOrFde,
+	CommonInformationEntry,
+	EhFrame,
+	EndianSlice,
+	LoongArch,
+	MIPS,
+	PowerPc64,
+	Register,
+	RegisterRule,
+	RiscV,
+	RunTimeEndian,
+	UnwindContext,
+	UnwindSection,
+	X86,
+	X86_64
+};
+use object::{
+	Endian,
+	Endianness,
+	Object,
+	ObjectSection,
+	ObjectSymbol,
+	ReadRef,
+	elf::{
+		EM_386,
+		EM_AARCH64,
+		EM_ARM,
+		EM_LOONGARCH,
+		EM_MIPS,
+		EM_PPC,
+		EM_PPC64,
+		EM_RISCV,
+		EM_X86_64,
+		STT_FUNC
+	},
+	read::elf::{ElfFile, FileHeader, Sym}
+};
+use regex::Regex;
+use serde::Serialize;
+
+use crate::{OutputFormat, demangle, troll};
+
+type EhFrameReader<'a> = EndianSlice<'a, RunTimeEndian>;
+
+/// Restrict the dump to some functions
+#[derive(Default)]
+pub struct DwarfFilter
+{
+	/// Only dump the functions whose (mangled or demangled) name matches
+	pub symbol:  Option<Regex>,
+	/// Only dump the function containing this address
+	pub address: Option<u64>
+}
+
+impl DwarfFilter
+{
+	fn matches(&self, mangled: &str, demangled: &str, start: u64, end: u64) -> bool
+	{
+		self.symbol
+			.as_ref()
+			.is_none_or(|regex| regex.is_match(mangled) || regex.is_match(demangled))
+			&& self
+				.address
+				.is_none_or(|address| start <= address && address < end.max(start + 1))
+	}
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct CieDump
+{
+	pub offset:                  usize,
+	pub length:                  usize,
+	pub version:                 u8,
+	pub address_size:            u8,
+	pub code_alignment_factor:   u64,
+	pub data_alignment_factor:   i64,
+	pub return_address_register: String,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub fde_address_encoding:    Option<u8>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub lsda_encoding:           Option<u8>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub personality:             Option<String>,
+	pub signal_trampoline:       bool,
+	pub initial_instructions:    Vec<String>
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct RowDump
+{
+	pub start:     u64,
+	pub end:       u64,
+	pub cfa:       String,
+	/// Rules of the registers, by register name
+	pub registers: Vec<(String, String)>
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct FdeDump
+{
+	pub offset:       usize,
+	pub length:       usize,
+	pub cie_offset:   usize,
+	pub start:        u64,
+	pub end:          u64,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub lsda:         Option<String>,
+	pub instructions: Vec<String>,
+	pub rows:         Vec<RowDump>
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct FunctionDump
+{
+	pub name:    String,
+	pub mangled: String,
+	pub address: u64,
+	pub size:    u64,
+	/// `None` if no FDE covers the function
+	pub fde:     Option<FdeDump>
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct DwarfDump
+{
+	pub eh_frame_size: u64,
+	pub cies:          Vec<CieDump>,
+	pub functions:     Vec<FunctionDump>
+}
+
+/// Name of a DWARF register for the given ELF machine
+pub fn register_name(machine: u16, register: Register) -> String
+{
+	match machine
+	{
+		EM_X86_64 => X86_64::register_name(register),
+		EM_386 => X86::register_name(register),
+		EM_AARCH64 => AArch64::register_name(register),
+		EM_ARM => Arm::register_name(register),
+		EM_RISCV => RiscV::register_name(register),
+		EM_LOONGARCH => LoongArch::register_name(register),
+		EM_MIPS => MIPS::register_name(register),
+		EM_PPC | EM_PPC64 => PowerPc64::register_name(register),
+		_ => None
+	}
+	.map_or_else(|| format!("r{}", register.0), String::from)
+}
+
+struct Describer<'a>
+{
+	machine: u16,
+	cie:     &'a CommonInformationEntry<EhFrameReader<'a>>
+}
+
+impl Describer<'_>
+{
+	fn reg(&self, register: Register) -> String
+	{
+		register_name(self.machine, register)
+	}
+
+	fn cfa(&self, rule: &CfaRule<usize>) -> String
+	{
+		match rule
+		{
+			CfaRule::RegisterAndOffset { register, offset } =>
+			{
+				format!("{}{offset:+}", self.reg(*register))
+			},
+			CfaRule::Expression(_) => String::from("<expression>")
+		}
+	}
+
+	fn rule(&self, rule: &RegisterRule<usize>) -> String
+	{
+		match rule
+		{
+			RegisterRule::Undefined => String::from("undefined"),
+			RegisterRule::SameValue => String::from("same"),
+			RegisterRule::Offset(offset) => format!("[cfa{offset:+}]"),
+			RegisterRule::ValOffset(offset) => format!("cfa{offset:+}"),
+			RegisterRule::Register(register) => self.reg(*register),
+			RegisterRule::Expression(_) => String::from("[<expression>]"),
+			RegisterRule::ValExpression(_) => String::from("<expression>"),
+			RegisterRule::Architectural => String::from("architectural"),
+			RegisterRule::Constant(value) => format!("{value:#x}"),
+			rule => format!("{rule:?}")
+		}
+	}
+
+	fn instruction(&self, instruction: &CallFrameInstruction<usize>) -> String
+	{
+		let code = self.cie.code_alignment_factor();
+		let data = self.cie.data_alignment_factor();
+		match *instruction
+		{
+			CallFrameInstruction::SetLoc { address } => format!("set_loc {address:#x}"),
+			CallFrameInstruction::AdvanceLoc { delta } =>
+			{
+				format!("advance_loc {}", u64::from(delta) * code)
+			},
+			CallFrameInstruction::DefCfa { register, offset } =>
+			{
+				format!("def_cfa {}+{offset}", self.reg(register))
+			},
+			CallFrameInstruction::DefCfaSf {
+				register,
+				factored_offset
+			} =>
+			{
+				format!(
+					"def_cfa_sf {}{:+}",
+					self.reg(register),
+					factored_offset * data
+				)
+			},
+			CallFrameInstruction::DefCfaRegister { register } =>
+			{
+				format!("def_cfa_register {}", self.reg(register))
+			},
+			CallFrameInstruction::DefCfaOffset { offset } => format!("def_cfa_offset {offset}"),
+			CallFrameInstruction::DefCfaOffsetSf { factored_offset } =>
+			{
+				format!("def_cfa_offset_sf {}", factored_offset * data)
+			},
+			CallFrameInstruction::DefCfaExpression { .. } => String::from("def_cfa_expression"),
+			CallFrameInstruction::Undefined { register } =>
+			{
+				format!("undefined {}", self.reg(register))
+			},
+			CallFrameInstruction::SameValue { register } =>
+			{
+				format!("same_value {}", self.reg(register))
+			},
+			CallFrameInstruction::Offset {
+				register,
+				factored_offset
+			} =>
+			{
+				format!(
+					"offset {} at cfa{:+}",
+					self.reg(register),
+					factored_offset as i64 * data
+				)
+			},
+			CallFrameInstruction::OffsetExtendedSf {
+				register,
+				factored_offset
+			} =>
+			{
+				format!(
+					"offset_extended_sf {} at cfa{:+}",
+					self.reg(register),
+					factored_offset * data
+				)
+			},
+			CallFrameInstruction::ValOffset {
+				register,
+				factored_offset
+			} =>
+			{
+				format!(
+					"val_offset {} = cfa{:+}",
+					self.reg(register),
+					factored_offset as i64 * data
+				)
+			},
+			CallFrameInstruction::ValOffsetSf {
+				register,
+				factored_offset
+			} =>
+			{
+				format!(
+					"val_offset_sf {} = cfa{:+}",
+					self.reg(register),
+					factored_offset * data
+				)
+			},
+			CallFrameInstruction::Register {
+				dest_register,
+				src_register
+			} =>
+			{
+				format!(
+					"register {} = {}",
+					self.reg(dest_register),
+					self.reg(src_register)
+				)
+			},
+			CallFrameInstruction::Expression { register, .. } =>
+			{
+				format!("expression {}", self.reg(register))
+			},
+			CallFrameInstruction::ValExpression { register, .. } =>
+			{
+				format!("val_expression {}", self.reg(register))
+			},
+			CallFrameInstruction::Restore { register } =>
+			{
+				format!("restore {}", self.reg(register))
+			},
+			CallFrameInstruction::RememberState => String::from("remember_state"),
+			CallFrameInstruction::RestoreState => String::from("restore_state"),
+			CallFrameInstruction::ArgsSize { size } => format!("args_size {size}"),
+			CallFrameInstruction::NegateRaState => String::from("negate_ra_state"),
+			CallFrameInstruction::Nop => String::from("nop"),
+			_ => format!("{instruction:?}")
+		}
+	}
+}
+
+/// Parse the whole `.eh_frame` section of `file`, and match its FDEs against
+/// the function symbols selected by `filter`
+pub fn dump_elf<'data, Elf, R>(
+	file: &ElfFile<'data, Elf, R>,
+	filter: &DwarfFilter
+) -> Result<DwarfDump>
+where
+	Elf: FileHeader<Endian = Endianness>,
+	R: ReadRef<'data>
+{
+	let endian = file.endian();
+	let machine = file.elf_header().e_machine(endian);
+	let (base_addresses, uncompressed_eh_frame) = troll::eh_frame(file)?;
+	let mut unwind_info = EhFrame::new(
+		&uncompressed_eh_frame,
+		if endian.is_little_endian()
+		{
+			RunTimeEndian::Little
+		}
+		else
+		{
+			RunTimeEndian::Big
+		}
+	);
+	unwind_info.set_address_size(if file.is_64() { 8 } else { 4 });
+
+	let mut cies = BTreeMap::new();
+	let mut fdes = BTreeMap::new();
+	let mut ctx = UnwindContext::new();
+	let mut entries = unwind_info.entries(&base_addresses);
+	while let Some(entry) = entries.next()?
+	{
+		let fde = match entry
+		{
+			CieOrFde::Cie(cie) =>
+			{
+				cies.insert(cie.offset(), cie);
+				continue;
+			},
+			CieOrFde::Fde(partial) => partial.parse(EhFrame::cie_from_offset)?
+		};
+		let cie = fde.cie();
+		let describer = Describer { machine, cie };
+		let mut instructions = Vec::new();
+		let mut iter = fde.instructions(&unwind_info, &base_addresses);
+		while let Some(instruction) = iter.next()?
+		{
+			instructions.push(describer.instruction(&instruction));
+		}
+		let mut rows = Vec::new();
+		let mut table = fde.rows(&unwind_info, &base_addresses, &mut ctx)?;
+		while let Some(row) = table.next_row()?
+		{
+			rows.push(RowDump {
+				start:     row.start_address(),
+				end:       row.end_address(),
+				cfa:       describer.cfa(row.cfa()),
+				registers: row
+					.registers()
+					.map(|(register, rule)| (describer.reg(*register), describer.rule(rule)))
+					.collect()
+			});
+		}
+		fdes.insert(
+			fde.initial_address(),
+			FdeDump {
+				offset: fde.offset(),
+				length: fde.entry_len(),
+				cie_offset: cie.offset(),
+				start: fde.initial_address(),
+				end: fde.end_address(),
+				lsda: fde.lsda().map(|lsda| format!("{lsda:x?}")),
+				instructions,
+				rows
+			}
+		);
+	}
+
+	let mut dump = DwarfDump {
+		eh_frame_size: uncompressed_eh_frame.len() as u64,
+		..Default::default()
+	};
+	let strtable = file.elf_symbol_table().strings();
+	for sym in file.symbols()
+	{
+		if sym.elf_symbol().st_type() != STT_FUNC
+		{
+			continue;
+		}
+		let mangled = str::from_utf8(sym.elf_symbol().name(endian, strtable)?)?;
+		let demangled = demangle(mangled.to_string());
+		// the lowest bit of the address of Thumb functions is set
+		let start = if machine == EM_ARM
+		{
+			sym.address() & !1
+		}
+		else
+		{
+			sym.address()
+		};
+		let end = start + sym.size();
+		if !filter.matches(mangled, &demangled, start, end)
+		{
+			continue;
+		}
+		let fde = fdes
+			.range(..=start)
+			.next_back()
+			.map(|(_, fde)| fde)
+			.filter(|fde| start < fde.end)
+			.cloned();
+		dump.functions.push(FunctionDump {
+			name: demangled,
+			mangled: mangled.to_string(),
+			address: sym.address(),
+			size: sym.size(),
+			fde
+		});
+	}
+	dump.functions
+		.sort_by_key(|function| (function.address, function.name.clone()));
+
+	// only dump the CIEs used by the selected functions
+	for (offset, cie) in cies
+	{
+		if !dump
+			.functions
+			.iter()
+			.filter_map(|function| function.fde.as_ref())
+			.any(|fde| fde.cie_offset == offset)
+		{
+			continue;
+		}
+		let describer = Describer { machine, cie: &cie };
+		let mut initial_instructions = Vec::new();
+		let mut iter = cie.instructions(&unwind_info, &base_addresses);
+		while let Some(instruction) = iter.next()?
+		{
+			initial_instructions.push(describer.instruction(&instruction));
+		}
+		dump.cies.push(CieDump {
+			offset,
+			length: cie.entry_len(),
+			version: cie.version(),
+			address_size: cie.address_size(),
+			code_alignment_factor: cie.code_alignment_factor(),
+			data_alignment_factor: cie.data_alignment_factor(),
+			return_address_register: describer.reg(cie.return_address_register()),
+			fde_address_encoding: cie.fde_address_encoding().map(|encoding| encoding.0),
+			lsda_encoding: cie.lsda_encoding().map(|encoding| encoding.0),
+			personality: cie
+				.personality()
+				.map(|personality| format!("{personality:x?}")),
+			signal_trampoline: cie.is_signal_trampoline(),
+			initial_instructions
+		});
+	}
+	Ok(dump)
+}
+
+impl DwarfDump
+{
+	pub fn print(&self, format: OutputFormat) -> Result<()>
+	{
+		match format
+		{
+			OutputFormat::Text => self.print_text(),
+			OutputFormat::Json => println!("{}", serde_json::to_string_pretty(self)?)
+		}
+		Ok(())
+	}
+
+	fn print_text(&self)
+	{
+		for cie in &self.cies
+		{
+			println!("CIE at {:#x} (length: {}):", cie.offset, cie.length);
+			println!("  version: {}", cie.version);
+			println!("  address size: {}", cie.address_size);
+			println!("  code alignment factor: {}", cie.code_alignment_factor);
+			println!("  data alignment factor: {}", cie.data_alignment_factor);
+			println!("  return address register: {}", cie.return_address_register);
+			if let Some(encoding) = cie.fde_address_encoding
+			{
+				println!("  fde address encoding: {encoding:#04x}");
+			}
+			if let Some(encoding) = cie.lsda_encoding
+			{
+				println!("  lsda encoding: {encoding:#04x}");
+			}
+			if let Some(personality) = &cie.personality
+			{
+				println!("  personality: {personality}");
+			}
+			if cie.signal_trampoline
+			{
+				println!("  signal trampoline");
+			}
+			println!("  initial instructions:");
+			for instruction in &cie.initial_instructions
+			{
+				println!("    {instruction}");
+			}
+		}
+
+		for function in &self.functions
+		{
+			println!("{}:", function.name);
+			println!("  mangled: {}", function.mangled);
+			println!("  address: {:#x}", function.address);
+			println!("  size: {}", function.size);
+			let Some(fde) = &function.fde
+			else
+			{
+				println!("  fde: <none>");
+				continue;
+			};
+			println!(
+				"  fde at {:#x} (length: {}, cie: {:#x}):",
+				fde.offset, fde.length, fde.cie_offset
+			);
+			println!("    range: {:#x}..{:#x}", fde.start, fde.end);
+			if let Some(lsda) = &fde.lsda
+			{
+				println!("    lsda: {lsda}");
+			}
+			println!("    instructions:");
+			for instruction in &fde.instructions
+			{
+				println!("      {instruction}");
+			}
+			println!("    rows:");
+			for row in &fde.rows
+			{
+				let registers = row
+					.registers
+					.iter()
+					.map(|(register, rule)| format!(" {register}={rule}"))
+					.collect::<String>();
+				println!(
+					"      {:#x}..{:#x}: cfa={}{registers}",
+					row.start, row.end, row.cfa
+				);
+			}
+		}
+		println!("total .eh_frame size: {}", self.eh_frame_size);
+	}
+}
@@ -2,22 +2,47 @@ use std::{fs, path::PathBuf};
 
 use anyhow::{Ok, Result, anyhow, bail};
 use clap::{Args, Parser, Subcommand, ValueEnum};
-use gimli::{BaseAddresses, EhFrame, UnwindContext, UnwindSection};
 use object::{
-	Endian,
 	Endianness,
+	Object,
 	ObjectSection,
-	ObjectSegment,
-	ObjectSymbol,
 	ReadRef,
-	elf::STT_FUNC,
-	read::{
-		Object,
-		elf::{ElfFile64, Sym}
-	}
+	elf::{
+		EM_386,
+		EM_AARCH64,
+		EM_ARM,
+		EM_AVR32,
+		EM_LOONGARCH,
+		EM_MIPS,
+		EM_PPC,
+		EM_PPC64,
+		EM_RISCV,
+		EM_S390,
+		EM_SPARC,
+		EM_SPARC32PLUS,
+		EM_SPARCV9,
+		EM_X86_64
+	},
+	read::elf::{ElfFile, FileHeader}
 };
+use regex::Regex;
 
+mod dwarf;
 mod troll;
+mod verify;
+
+/// Evaluate `$body` with `$elf` bound to the (32-bit or 64-bit) ELF file
+/// `$file`
+macro_rules! with_elf {
+	($file:expr, $elf:ident => $body:expr) => {
+		match $file
+		{
+			object::File::Elf32(ref $elf) => $body,
+			object::File::Elf64(ref $elf) => $body,
+			_ => bail!("unsupported format !")
+		}
+	};
+}
 
 #[derive(Parser)]
 #[command(
@@ -45,7 +70,18 @@ enum UnwindToolAction
 	{
 		#[clap(value_name = "FILE")]
 		/// The executable to inspect.
-		executable: PathBuf
+		executable: PathBuf,
+		#[arg(short, long, value_enum, default_value_t)]
+		/// The output format.
+		format:     OutputFormat,
+		#[arg(short, long, value_name = "REGEX")]
+		/// Only inspect the functions whose name matches the given regular
+		/// expression.
+		symbol:     Option<Regex>,
+		#[arg(short = 'A', long, value_name = "ADDRESS", value_parser = parse_address)]
+		/// Only inspect the function (or the TROLL entry) containing the given
+		/// address.
+		address:    Option<u64>
 	},
 
 	/// Generate the needed unwinding informations in the selected format (note
@@ -60,6 +96,22 @@ enum UnwindToolAction
 		arch:   SupportedArch,
 		#[command(flatten)]
 		output: UnwindToolGenerationOutput
+	},
+
+	/// Check that the generated unwinding informations agree with the DWARF
+	/// ones, at every instruction of every function (exits with an error if
+	/// they don't)
+	Verify
+	{
+		#[clap(value_name = "FILE")]
+		/// The executable to verify.
+		executable: PathBuf,
+		#[arg(short, long, value_enum)]
+		/// The architecture of the executable.
+		arch:       SupportedArch,
+		#[arg(short, long, value_enum, default_value_t)]
+		/// The format of the report.
+		format:     OutputFormat
 	}
 }
 
@@ -97,6 +149,18 @@ struct UnwindToolGenerationOutput
 	file: Option<PathBuf>
 }
 
+#[derive(Default, Copy, Clone, Debug, PartialEq, Eq, ValueEnum, strum::AsRefStr)]
+#[strum(serialize_all = "lowercase")]
+#[clap(rename_all = "lower")]
+enum OutputFormat
+{
+	#[default]
+	/// Human-readable output.
+	Text,
+	/// Machine-readable output.
+	Json
+}
+
 #[derive(
 	Default, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Hash, strum::AsRefStr,
 )]
@@ -130,6 +194,62 @@ enum SupportedArch
 	ZArch
 }
 
+impl SupportedArch
+{
+	/// The ELF machine(s) of the architecture
+	fn elf_machines(self) -> &'static [u16]
+	{
+		match self
+		{
+			Self::Amd64 => &[EM_X86_64],
+			Self::X86 => &[EM_386],
+			Self::AArch64 => &[EM_AARCH64],
+			Self::Arm32 => &[EM_ARM],
+			Self::Riscv32 | Self::Riscv64 => &[EM_RISCV],
+			Self::PowerPC32 => &[EM_PPC],
+			Self::PowerPC64 => &[EM_PPC64],
+			Self::Sparc32 => &[EM_SPARC, EM_SPARC32PLUS],
+			Self::Sparc64 => &[EM_SPARCV9],
+			Self::Mips32 | Self::Mips64 => &[EM_MIPS],
+			Self::Avr32 => &[EM_AVR32],
+			Self::LoongArch64 => &[EM_LOONGARCH],
+			Self::ZArch => &[EM_S390]
+		}
+	}
+
+	fn is_64_bit(self) -> bool
+	{
+		!matches!(
+			self,
+			Self::X86
+				| Self::Arm32
+				| Self::Riscv32
+				| Self::PowerPC32
+				| Self::Sparc32
+				| Self::Mips32
+				| Self::Avr32
+		)
+	}
+
+	/// Check that `elf` has been built for this architecture
+	fn check_elf<'data, Elf, R>(self, elf: &ElfFile<'data, Elf, R>) -> Result<()>
+	where
+		Elf: FileHeader<Endian = Endianness>,
+		R: ReadRef<'data>
+	{
+		let machine = elf.elf_header().e_machine(elf.endian());
+		if !self.elf_machines().contains(&machine) || self.is_64_bit() != elf.is_64()
+		{
+			bail!(
+				"the executable ({}-bit, machine {machine}) wasn't built for {}",
+				if elf.is_64() { 64 } else { 32 },
+				self.as_ref()
+			);
+		}
+		Ok(())
+	}
+}
+
 fn main() -> Result<()>
 {
 	let cli = UnwindToolCli::parse();
@@ -137,18 +257,21 @@ fn main() -> Result<()>
 	{
 		UnwindToolFormat::Dwarf { action } =>
 		{
-			if let UnwindToolAction::Inspect { executable } = action
+			if let UnwindToolAction::Inspect {
+				executable,
+				format,
+				symbol,
+				address
+			} = action
 			{
 				let content = fs::read(executable)?;
 				let parsed = object::File::parse(&*content)?;
-				match parsed
-				{
-					object::File::Elf64(elf64) =>
-					{
-						dump_dwarf_elf64(&elf64)?;
-					},
-					_ => bail!("unsupported format !")
-				}
+				let filter = dwarf::DwarfFilter {
+					symbol:  symbol.clone(),
+					address: *address
+				};
+				let dump = with_elf!(parsed, elf => dwarf::dump_elf(elf, &filter)?);
+				dump.print(*format)?;
 			}
 			else
 			{
@@ -158,102 +281,99 @@ fn main() -> Result<()>
 				));
 			}
 		},
-		UnwindToolFormat::Troll { action } => todo!()
+		UnwindToolFormat::Troll { action } =>
+		{
+			match action
+			{
+				UnwindToolAction::Inspect {
+					executable,
+					format,
+					symbol,
+					address
+				} =>
+				{
+					if symbol.is_some()
+					{
+						bail!("filtering by symbol is not supported for TROLL format !");
+					}
+					let content = fs::read(executable)?;
+					let parsed = object::File::parse(&*content)?;
+					let section =
+						parsed
+							.section_by_name(troll::TROLL_SECTION_NAME)
+							.ok_or(anyhow!(
+								"couldn't find \"{}\" section",
+								troll::TROLL_SECTION_NAME
+							))?;
+					let mut table =
+						troll::TrollTable::decode(parsed.endianness(), section.data()?)?;
+					if let Some(address) = address
+					{
+						table.entries = table.lookup(*address).into_iter().copied().collect();
+					}
+					troll::dump_table(&table, *format)?;
+				},
+				UnwindToolAction::Generate {
+					input,
+					arch,
+					output
+				} =>
+				{
+					let content = fs::read(input)?;
+					let parsed = object::File::parse(&*content)?;
+					let table = with_elf!(parsed, elf => troll::Troll::new(*arch, elf)?.table()?);
+					let encoded = table.encode(parsed.endianness());
+					let destination = if output.in_place
+					{
+						input
+					}
+					else
+					{
+						output
+							.file
+							.as_ref()
+							.ok_or(anyhow!("no output file specified"))?
+					};
+					troll::write_table(&content, &encoded, destination)?;
+					println!(
+						"wrote {} TROLL entries ({} bytes) to {}",
+						table.entries.len(),
+						encoded.len(),
+						destination.display()
+					);
+				},
+				UnwindToolAction::Verify {
+					executable,
+					arch,
+					format
+				} =>
+				{
+					let content = fs::read(executable)?;
+					let parsed = object::File::parse(&*content)?;
+					let report = with_elf!(parsed, elf => verify::verify(*arch, elf)?);
+					report.print(*format)?;
+					if !report.is_ok()
+					{
+						bail!("the TROLL table doesn't match the DWARF unwinding informations");
+					}
+				}
+			}
+		},
 	}
 	Ok(())
 }
 
-fn demangle(fname: String) -> String
+fn parse_address(address: &str) -> Result<u64>
 {
-	symbolic_demangle::demangle(&fname).to_string()
+	let parsed = match address.strip_prefix("0x").or(address.strip_prefix("0X"))
+	{
+		Some(hex) => u64::from_str_radix(hex, 16)?,
+		None => address.parse()?
+	};
+	Ok(parsed)
 }
 
-fn dump_dwarf_elf64<'data, R: ReadRef<'data>>(file: &ElfFile64<'data, Endianness, R>)
--> Result<()>
+fn demangle(fname: String) -> String
 {
-	let eh_frame_hdr = file
-		.section_by_name(".eh_frame_hdr")
-		.ok_or(anyhow!("couldn't find \".eh_frame_hdr\" section"))?;
-	let eh_frame = file
-		.section_by_name(".eh_frame")
-		.ok_or(anyhow!("couldn't find \".eh_frame\" section"))?;
-	let text = file
-		.section_by_name(".text")
-		.ok_or(anyhow!("couldn't find \".text\" section"))?;
-	let base_addresses = BaseAddresses::default()
-		.set_eh_frame_hdr(eh_frame_hdr.address())
-		.set_eh_frame(eh_frame.address())
-		.set_text(text.address());
-	let uncompressed_eh_frame = eh_frame.uncompressed_data()?;
-	let unwind_info = EhFrame::new(
-		&uncompressed_eh_frame,
-		if file.endian().is_little_endian()
-		{
-			gimli::RunTimeEndian::Little
-		}
-		else
-		{
-			gimli::RunTimeEndian::Big
-		}
-	);
-	let mut ctx = UnwindContext::new();
-	let strtable = file.elf_symbol_table().strings();
-	for sym in file.symbols()
-	{
-		if sym.elf_symbol().st_type() == STT_FUNC
-		{
-			let mangled = str::from_utf8(sym.elf_symbol().name(Endianness::Little, strtable)?)?;
-			let demangled = demangle(mangled.to_string());
-			let unwind = unwind_info
-				.unwind_info_for_address(
-					&base_addresses,
-					&mut ctx,
-					sym.address(),
-					EhFrame::cie_from_offset
-				)
-				.map_err(|err| {
-					anyhow!(format!(
-						"{} (function: {demangled}, address: {})",
-						err,
-						sym.address()
-					))
-				})?;
-			let fde = unwind_info
-				.fde_for_address(&base_addresses, sym.address(), EhFrame::cie_from_offset)
-				.map_err(|err| {
-					anyhow!(format!(
-						"{} (function: {demangled}, address: {})",
-						err,
-						sym.address()
-					))
-				})?;
-			#[rustfmt::skip]
-			println!(
-				concat!(
-					"  {}:\n",
-					"    mangled: {}\n",
-					"    address: {:#x}\n",
-					"    size: {}\n",
-					"    unwind info:\n",
-					"      start: {:#x}\n",
-					"      size: {}\n",
-					"      fde:\n",
-					"        start: {:#x}\n",
-					"        end: {:#x}\n",
-					"        size: {}\n"
-				),
-				demangled,
-				mangled,
-				sym.address(),
-				sym.size(),
-				unwind.start_address(),
-				unwind.end_address() - unwind.start_address(),
-				fde.initial_address(),
-				fde.end_address(),
-				fde.end_address().wrapping_sub(fde.initial_address())
-			);
-		}
-	}
-	println!("total .eh_frame size: {}", eh_frame.size());
-	Ok(())
+	symbolic_demangle::demangle(&fname).to_string()
 }
@@ -1,161 +1,756 @@
-use std::{borrow::Cow, collections::HashMap};
+//! The « TROLL » unwind table format
+//!
+//! A TROLL table is a header followed by entries sorted by PC. Each entry
+//! describes how to recover the CFA, the frame pointer and the return address
+//! for every PC from its own address up to the address of the next entry.
+//! Entries whose CFA base is [`TrollCfaBase::Undefined`] mark PC ranges
+//! without any unwind information (gaps between FDEs, or the end of the
+//! table).
+//!
+//! All the fields are stored in the endianness of the ELF file they come from.
+//!
+//! ```text
+//! header (16 bytes):
+//!     magic:       [u8; 4] = b"TROL"
+//!     version:     u16
+//!     machine:     u16     (ELF `e_machine`)
+//!     entry_count: u32
+//!     entry_size:  u32
+//!
+//! entry (16 bytes):
+//!     pc:          u64
+//!     cfa_offset:  i16
+//!     fp_offset:   i16
+//!     ra_offset:   i16
+//!     cfa_base:    u8      (see `TrollCfaBase`)
+//!     flags:       u8      (see `TrollFlags`)
+//! ```
 
-use anyhow::{Result, anyhow};
+use std::{borrow::Cow, collections::BTreeMap, fs, path::Path};
+
+use anyhow::{Context, Result, anyhow, bail};
+use bitflags::bitflags;
 use fallible_iterator::FallibleIterator;
-use gimli::{BaseAddresses, CfiEntriesIter, CieOrFde, EhFrame, EhFrameOffset, Register, UnwindContext, UnwindSection, UnwindTableRow};
+use gimli::{
+	AArch64,
+	Arm,
+	BaseAddresses,
+	CfaRule,
+	CieOrFde,
+	EhFrame,
+	EhFrameOffset,
+	LoongArch,
+	MIPS,
+	PowerPc64,
+	Register,
+	RegisterRule,
+	RiscV,
+	UnwindContext,
+	UnwindSection,
+	UnwindTableRow,
+	X86,
+	X86_64
+};
 use object::{
 	Endian,
 	Endianness,
 	Object,
 	ObjectSection,
+	ObjectSymbol,
 	ReadRef,
-	elf::SHF_EXECINSTR,
-	read::elf::{ElfFile64, SectionHeader}
+	SectionKind,
+	build,
+	elf::{SHT_PROGBITS, STT_FUNC},
+	read::elf::{ElfFile, FileHeader, Sym}
 };
+use serde::Serialize;
 
-use crate::SupportedArch;
+use crate::{OutputFormat, SupportedArch, demangle};
 
-pub type Troll64PCRange = (u64, u64);
-pub struct TrollData<'data>
+/// Name of the ELF section holding the TROLL table
+pub const TROLL_SECTION_NAME: &str = ".troll";
+pub const TROLL_MAGIC: [u8; 4] = *b"TROL";
+pub const TROLL_VERSION: u16 = 1;
+pub const TROLL_HEADER_SIZE: usize = 16;
+pub const TROLL_ENTRY_SIZE: usize = 16;
+
+/// Register the CFA is computed from
+#[repr(u8)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, strum::AsRefStr)]
+#[serde(rename_all = "kebab-case")]
+#[strum(serialize_all = "kebab-case")]
+pub enum TrollCfaBase
 {
-	pub regs:            TrollRegisterSet,
-	pub source_location: TrollLocation<'data>
+	/// No unwind information
+	Undefined    = 0,
+	StackPointer = 1,
+	FramePointer = 2
 }
 
-pub struct TrollLocation<'data>
+impl TryFrom<u8> for TrollCfaBase
 {
-	pub file:   Cow<'data, str>,
-	pub line:   usize,
-	pub column: usize
+	type Error = anyhow::Error;
+
+	fn try_from(value: u8) -> Result<Self>
+	{
+		Ok(match value
+		{
+			0 => Self::Undefined,
+			1 => Self::StackPointer,
+			2 => Self::FramePointer,
+			_ => bail!("invalid TROLL CFA base: {value}")
+		})
+	}
 }
 
-pub struct Troll64<'data, 'file, R: ReadRef<'data>>
+bitflags! {
+	#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+	pub struct TrollFlags: u8
+	{
+		/// The caller's frame pointer is saved at `CFA + fp_offset`
+		const FP_SAVED = 1 << 0;
+		/// The caller's frame pointer can't be recovered
+		const FP_UNDEFINED = 1 << 1;
+		/// There is no caller (i.e. this is the outermost frame)
+		const RA_UNDEFINED = 1 << 2;
+		/// The return address is still in the link register (e.g. at the start
+		/// of a function on AArch64 or RISC-V)
+		const RA_IN_REGISTER = 1 << 3;
+	}
+}
+
+/// How to recover the CFA
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum TrollCfaRule
 {
-	elf:    &'file ElfFile64<'data, Endianness, R>,
-	pc_map: HashMap<Troll64PCRange, TrollData<'data>>,
-	arch: SupportedArch,
+	RegisterAndOffset
+	{
+		base: TrollCfaBase, offset: i16
+	}
 }
 
-impl<'data, 'file, R: ReadRef<'data>> Troll64<'data, 'file, R>
+/// How to recover a register of the caller
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum TrollRegisterRule
 {
-	fn map_from_elf(
-		arch: SupportedArch,
-		elf: &'file ElfFile64<'data, Endianness, R>
-	) -> Result<HashMap<Troll64PCRange, TrollData<'data>>>
-	{
-		let eh_frame_hdr = elf
-			.section_by_name(".eh_frame_hdr")
-			.ok_or(anyhow!("couldn't find \".eh_frame_hdr\" section"))?;
-		let eh_frame = elf
-			.section_by_name(".eh_frame")
-			.ok_or(anyhow!("couldn't find \".eh_frame\" section"))?;
-		let base_addresses = BaseAddresses::default()
-			.set_eh_frame_hdr(eh_frame_hdr.address())
-			.set_eh_frame(eh_frame.address());
-		let uncompressed_eh_frame = eh_frame.uncompressed_data()?;
-		let unwind_info = EhFrame::new(
-			&uncompressed_eh_frame,
-			if elf.endian().is_little_endian()
+	SameValue,
+	Undefined,
+	/// The register is saved at `CFA + offset`
+	AtCfa(i16)
+}
+
+/// The unwinding rules of a PC range, as tracked by TROLL
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct TrollRegisterSet
+{
+	pub cfa: TrollCfaRule,
+	/// Rule of the frame pointer
+	pub fp:  TrollRegisterRule,
+	/// Rule of the return address (`$RA` in DWARF parlance)
+	pub ret: TrollRegisterRule
+}
+
+/// The DWARF registers TROLL tracks on a given architecture
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct TrollArchRegisters
+{
+	pub stack_pointer:  Register,
+	pub frame_pointer:  Register,
+	pub return_address: Register,
+	/// Whether the return address is passed in a (link) register rather than
+	/// on the stack
+	pub link_register:  bool
+}
+
+impl TrollArchRegisters
+{
+	pub fn for_arch(arch: SupportedArch) -> Result<Self>
+	{
+		let (stack_pointer, frame_pointer, return_address, link_register) = match arch
+		{
+			SupportedArch::Amd64 => (X86_64::RSP, X86_64::RBP, X86_64::RA, false),
+			SupportedArch::X86 => (X86::ESP, X86::EBP, X86::RA, false),
+			SupportedArch::AArch64 => (AArch64::SP, AArch64::X29, AArch64::X30, true),
+			SupportedArch::Arm32 => (Arm::SP, Arm::R11, Arm::LR, true),
+			SupportedArch::Riscv32 | SupportedArch::Riscv64 =>
 			{
-				gimli::RunTimeEndian::Little
-			}
-			else
+				(RiscV::SP, RiscV::S0, RiscV::RA, true)
+			},
+			SupportedArch::LoongArch64 => (LoongArch::SP, LoongArch::FP, LoongArch::RA, true),
+			SupportedArch::Mips32 | SupportedArch::Mips64 => (MIPS::SP, MIPS::FP, MIPS::RA, true),
+			SupportedArch::PowerPC32 | SupportedArch::PowerPC64 =>
+			{
+				(PowerPc64::R1, PowerPc64::R31, PowerPc64::LR, true)
+			},
+			// %r15 is the stack pointer, %r11 the frame pointer and %r14 holds the
+			// return address
+			SupportedArch::ZArch => (Register(15), Register(11), Register(14), true),
+			SupportedArch::Sparc32 | SupportedArch::Sparc64 | SupportedArch::Avr32 =>
 			{
-				gimli::RunTimeEndian::Big
+				bail!("TROLL generation is not supported for {}", arch.as_ref())
 			}
-		);
-		// let mut executable_sections = vec![];
-		let mut executable_size = 0;
-		for section in elf.sections()
+		};
+		Ok(Self {
+			stack_pointer,
+			frame_pointer,
+			return_address,
+			link_register
+		})
+	}
+}
+
+/// A single, decoded, TROLL table entry
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct TrollEntry
+{
+	pub pc:         u64,
+	pub cfa_base:   TrollCfaBase,
+	pub cfa_offset: i16,
+	pub fp_offset:  i16,
+	pub ra_offset:  i16,
+	pub flags:      TrollFlags
+}
+
+impl TrollEntry
+{
+	/// An entry marking the start of a range without unwind information
+	pub fn terminator(pc: u64) -> Self
+	{
+		Self {
+			pc,
+			cfa_base: TrollCfaBase::Undefined,
+			cfa_offset: 0,
+			fp_offset: 0,
+			ra_offset: 0,
+			flags: TrollFlags::empty()
+		}
+	}
+
+	pub fn from_regs(pc: u64, regs: &TrollRegisterSet) -> Result<Self>
+	{
+		let TrollRegisterSet { cfa, fp, ret } = *regs;
+
+		let mut entry = Self::terminator(pc);
+		let TrollCfaRule::RegisterAndOffset { base, offset } = cfa;
+		entry.cfa_base = base;
+		entry.cfa_offset = offset;
+		match fp
 		{
-			if section.elf_section_header().sh_flags(elf.endian()) & (SHF_EXECINSTR as u64)
-				== (SHF_EXECINSTR as _)
+			TrollRegisterRule::SameValue => (),
+			TrollRegisterRule::Undefined => entry.flags |= TrollFlags::FP_UNDEFINED,
+			TrollRegisterRule::AtCfa(offset) =>
 			{
-				// executable_sections.push(&section);
-				executable_size += section.size() as usize;
+				entry.flags |= TrollFlags::FP_SAVED;
+				entry.fp_offset = offset;
 			}
 		}
-		let mut map = HashMap::with_capacity((executable_size / 10) + 1);
-		let mut entries = unwind_info.entries(&base_addresses);
-		let mut cies: HashMap<EhFrameOffset, _> = HashMap::new();
-        let mut ctx = UnwindContext::new();
-		while let Some(entry) = entries.next()?
+		match ret
+		{
+			TrollRegisterRule::AtCfa(offset) => entry.ra_offset = offset,
+			TrollRegisterRule::Undefined => entry.flags |= TrollFlags::RA_UNDEFINED,
+			TrollRegisterRule::SameValue => entry.flags |= TrollFlags::RA_IN_REGISTER
+		}
+		Ok(entry)
+	}
+
+	/// Human-readable CFA rule, e.g. `stack-pointer+16`
+	pub fn describe_cfa(&self) -> String
+	{
+		match self.cfa_base
+		{
+			TrollCfaBase::Undefined => String::from("undefined"),
+			base => format!("{}{:+}", base.as_ref(), self.cfa_offset)
+		}
+	}
+
+	/// Human-readable frame pointer rule
+	pub fn describe_fp(&self) -> String
+	{
+		if self.flags.contains(TrollFlags::FP_SAVED)
+		{
+			format!("cfa{:+}", self.fp_offset)
+		}
+		else if self.flags.contains(TrollFlags::FP_UNDEFINED)
+		{
+			String::from("undefined")
+		}
+		else
+		{
+			String::from("same")
+		}
+	}
+
+	/// Human-readable return address rule
+	pub fn describe_ra(&self) -> String
+	{
+		if self.flags.contains(TrollFlags::RA_UNDEFINED)
+		{
+			String::from("undefined")
+		}
+		else if self.flags.contains(TrollFlags::RA_IN_REGISTER)
+		{
+			String::from("same")
+		}
+		else
+		{
+			format!("cfa{:+}", self.ra_offset)
+		}
+	}
+
+	/// Whether both entries describe the same unwinding rules
+	pub fn same_rules(&self, other: &Self) -> bool
+	{
+		Self { pc: 0, ..*self } == Self { pc: 0, ..*other }
+	}
+
+	pub fn encode(&self, endian: Endianness, out: &mut Vec<u8>)
+	{
+		out.extend_from_slice(&endian.write_u64_bytes(self.pc));
+		out.extend_from_slice(&endian.write_u16_bytes(self.cfa_offset as u16));
+		out.extend_from_slice(&endian.write_u16_bytes(self.fp_offset as u16));
+		out.extend_from_slice(&endian.write_u16_bytes(self.ra_offset as u16));
+		out.push(self.cfa_base as u8);
+		out.push(self.flags.bits());
+	}
+
+	pub fn decode(endian: Endianness, bytes: &[u8]) -> Result<Self>
+	{
+		let bytes: &[u8; TROLL_ENTRY_SIZE] = bytes
+			.get(..TROLL_ENTRY_SIZE)
+			.and_then(|bytes| bytes.try_into().ok())
+			.ok_or(anyhow!("truncated TROLL entry"))?;
+		let read_i16 = |at: usize| endian.read_u16_bytes([bytes[at], bytes[at + 1]]) as i16;
+		Ok(Self {
+			pc:         endian.read_u64_bytes(bytes[0..8].try_into()?),
+			cfa_offset: read_i16(8),
+			fp_offset:  read_i16(10),
+			ra_offset:  read_i16(12),
+			cfa_base:   bytes[14].try_into()?,
+			flags:      TrollFlags::from_bits(bytes[15])
+				.ok_or(anyhow!("invalid TROLL entry flags: {:#x}", bytes[15]))?
+		})
+	}
+}
+
+/// A complete TROLL table
+#[derive(Serialize)]
+pub struct TrollTable
+{
+	pub machine: u16,
+	pub entries: Vec<TrollEntry>
+}
+
+impl TrollTable
+{
+	pub fn encode(&self, endian: Endianness) -> Vec<u8>
+	{
+		let mut out = Vec::with_capacity(TROLL_HEADER_SIZE + self.entries.len() * TROLL_ENTRY_SIZE);
+		out.extend_from_slice(&TROLL_MAGIC);
+		out.extend_from_slice(&endian.write_u16_bytes(TROLL_VERSION));
+		out.extend_from_slice(&endian.write_u16_bytes(self.machine));
+		out.extend_from_slice(&endian.write_u32_bytes(self.entries.len() as u32));
+		out.extend_from_slice(&endian.write_u32_bytes(TROLL_ENTRY_SIZE as u32));
+		for entry in &self.entries
+		{
+			entry.encode(endian, &mut out);
+		}
+		out
+	}
+
+	pub fn decode(endian: Endianness, bytes: &[u8]) -> Result<Self>
+	{
+		if bytes.len() < TROLL_HEADER_SIZE || bytes[0..4] != TROLL_MAGIC
+		{
+			bail!("not a TROLL table");
+		}
+		let version = endian.read_u16_bytes(bytes[4..6].try_into()?);
+		if version != TROLL_VERSION
+		{
+			bail!("unsupported TROLL version {version} (expected {TROLL_VERSION})");
+		}
+		let machine = endian.read_u16_bytes(bytes[6..8].try_into()?);
+		let count = endian.read_u32_bytes(bytes[8..12].try_into()?) as usize;
+		let entry_size = endian.read_u32_bytes(bytes[12..16].try_into()?) as usize;
+		if entry_size != TROLL_ENTRY_SIZE
 		{
-			// TODO: when we hit a CIE, save it somewhere to somehow re-use it when hitting
-			// an FDE (?)
-			match entry
+			bail!("unexpected TROLL entry size {entry_size} (expected {TROLL_ENTRY_SIZE})");
+		}
+		let entries = bytes[TROLL_HEADER_SIZE..]
+			.chunks(TROLL_ENTRY_SIZE)
+			.take(count)
+			.map(|chunk| TrollEntry::decode(endian, chunk))
+			.collect::<Result<Vec<_>>>()?;
+		if entries.len() != count
+		{
+			bail!(
+				"truncated TROLL table ({} entries out of {count})",
+				entries.len()
+			);
+		}
+		Ok(Self { machine, entries })
+	}
+
+	/// Find the entry covering `pc`, if any (terminators don't cover anything)
+	pub fn lookup(&self, pc: u64) -> Option<&TrollEntry>
+	{
+		let index = self
+			.entries
+			.partition_point(|entry| entry.pc <= pc)
+			.checked_sub(1)?;
+		Some(&self.entries[index]).filter(|entry| entry.cfa_base != TrollCfaBase::Undefined)
+	}
+}
+
+pub type TrollPCRange = (u64, u64);
+
+/// A TROLL table generator, for 32-bit and 64-bit ELF files
+pub struct Troll<'data, 'file, Elf, R>
+where
+	Elf: FileHeader<Endian = Endianness>,
+	R: ReadRef<'data>
+{
+	elf:    &'file ElfFile<'data, Elf, R>,
+	pc_map: BTreeMap<TrollPCRange, TrollRegisterSet>,
+	arch:   SupportedArch
+}
+
+impl<'data, 'file, Elf, R> Troll<'data, 'file, Elf, R>
+where
+	Elf: FileHeader<Endian = Endianness>,
+	R: ReadRef<'data>
+{
+	fn map_from_elf(
+		arch: SupportedArch,
+		elf: &'file ElfFile<'data, Elf, R>
+	) -> Result<BTreeMap<TrollPCRange, TrollRegisterSet>>
+	{
+		let mut map = BTreeMap::new();
+		for_each_row(elf, |row| {
+			let range = (row.start_address(), row.end_address());
+			if range.0 == range.1
 			{
-				CieOrFde::Fde(partial_fde) =>
-				{
-					let fde = partial_fde.parse(|sect, base_addrs, offset| {
-						cies.get(&offset).cloned().map_or_else(
-							|| {
-								sect.cie_from_offset(base_addrs, offset).inspect(|cie| {
-									cies.insert(offset, cie.clone());
-								})
-							},
-							Ok
-						)
-					})?;
-					let mut rows = fde.rows(&unwind_info, &base_addresses, &mut ctx)?;
-                    while let Some(row) = rows.next_row()?
-                    {
-                        let range = (row.start_address(), row.end_address());
-						let regs = get_regs(arch, row);
-                        todo!("do something with row");
-                    }
-				},
-				CieOrFde::Cie(cie) =>
-				{
-					cies.insert(cie.offset().into(), cie);
-				}
+				return Ok(());
 			}
-		}
+			let regs = get_regs(arch, row).with_context(|| {
+				format!(
+					"can't express the unwind rules of {} (pc range: {:#x}..{:#x}) in TROLL",
+					function_name(elf, range.0),
+					range.0,
+					range.1
+				)
+			})?;
+			if let Some(((start, end), _)) = map
+				.range(..(range.1, 0))
+				.next_back()
+				.filter(|((_, end), _)| *end > range.0)
+			{
+				bail!(
+					"overlapping FDEs: {:#x}..{:#x} and {:#x}..{:#x}",
+					start,
+					end,
+					range.0,
+					range.1
+				);
+			}
+			map.insert(range, regs);
+			Ok(())
+		})?;
 		Ok(map)
 	}
 
-	pub fn new(arch: SupportedArch, elf: &'file ElfFile64<'data, Endianness, R>) -> Result<Self>
+	pub fn new(arch: SupportedArch, elf: &'file ElfFile<'data, Elf, R>) -> Result<Self>
 	{
+		arch.check_elf(elf)?;
 		Ok(Self {
 			elf,
 			pc_map: Self::map_from_elf(arch, elf)?,
 			arch
 		})
 	}
+
+	/// Build the final table, merging adjacent ranges with the same rules and
+	/// marking the gaps between them
+	pub fn table(&self) -> Result<TrollTable>
+	{
+		let mut entries: Vec<TrollEntry> = Vec::with_capacity(self.pc_map.len() + 1);
+		let mut previous_end = None;
+		for (&(start, end), regs) in &self.pc_map
+		{
+			if previous_end.is_some_and(|previous_end| previous_end != start)
+			{
+				entries.push(TrollEntry::terminator(previous_end.unwrap()));
+			}
+			let entry = TrollEntry::from_regs(start, regs)?;
+			if !entries.last().is_some_and(|last| last.same_rules(&entry))
+			{
+				entries.push(entry);
+			}
+			previous_end = Some(end);
+		}
+		if let Some(end) = previous_end
+		{
+			entries.push(TrollEntry::terminator(end));
+		}
+		Ok(TrollTable {
+			machine: self.elf.elf_header().e_machine(self.elf.endian()),
+			entries
+		})
+	}
 }
 
-fn get_regs(arch: SupportedArch, row: &UnwindTableRow<usize>) -> TrollRegisterSet
+/// Base addresses and content of the `.eh_frame` section of `elf`
+pub fn eh_frame<'data, Elf, R>(
+	elf: &ElfFile<'data, Elf, R>
+) -> Result<(BaseAddresses, Cow<'data, [u8]>)>
+where
+	Elf: FileHeader<Endian = Endianness>,
+	R: ReadRef<'data>
 {
-	todo!();
-	match arch
+	let eh_frame = elf
+		.section_by_name(".eh_frame")
+		.ok_or(anyhow!("couldn't find \".eh_frame\" section"))?;
+	let mut base_addresses = BaseAddresses::default().set_eh_frame(eh_frame.address());
+	if let Some(eh_frame_hdr) = elf.section_by_name(".eh_frame_hdr")
 	{
-		SupportedArch::Amd64 => {
-			/* let ra = row.register(gimli::X86_64::RA); */
+		base_addresses = base_addresses.set_eh_frame_hdr(eh_frame_hdr.address());
+	}
+	if let Some(text) = elf.section_by_name(".text")
+	{
+		base_addresses = base_addresses.set_text(text.address());
+	}
+	Ok((base_addresses, eh_frame.uncompressed_data()?))
+}
+
+/// Call `f` on every row of the unwind tables of every FDE in `.eh_frame`
+pub fn for_each_row<'data, Elf, R>(
+	elf: &ElfFile<'data, Elf, R>,
+	mut f: impl FnMut(&UnwindTableRow<usize>) -> Result<()>
+) -> Result<()>
+where
+	Elf: FileHeader<Endian = Endianness>,
+	R: ReadRef<'data>
+{
+	let (base_addresses, uncompressed_eh_frame) = eh_frame(elf)?;
+	let mut unwind_info = EhFrame::new(
+		&uncompressed_eh_frame,
+		if elf.endian().is_little_endian()
+		{
+			gimli::RunTimeEndian::Little
+		}
+		else
+		{
+			gimli::RunTimeEndian::Big
+		}
+	);
+	unwind_info.set_address_size(if elf.is_64() { 8 } else { 4 });
+	let mut entries = unwind_info.entries(&base_addresses);
+	let mut cies: BTreeMap<EhFrameOffset, _> = BTreeMap::new();
+	let mut ctx = UnwindContext::new();
+	while let Some(entry) = entries.next()?
+	{
+		match entry
+		{
+			CieOrFde::Fde(partial_fde) =>
+			{
+				let fde = partial_fde.parse(|sect, base_addrs, offset| {
+					cies.get(&offset).cloned().map_or_else(
+						|| {
+							sect.cie_from_offset(base_addrs, offset).inspect(|cie| {
+								cies.insert(offset, cie.clone());
+							})
+						},
+						Ok
+					)
+				})?;
+				let mut rows = fde.rows(&unwind_info, &base_addresses, &mut ctx)?;
+				while let Some(row) = rows.next_row()?
+				{
+					f(row)?;
+				}
+			},
+			CieOrFde::Cie(cie) =>
+			{
+				cies.insert(cie.offset().into(), cie);
+			}
 		}
 	}
+	Ok(())
 }
 
-#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
-pub enum TrollRegisterSet
+fn cfa_base(registers: &TrollArchRegisters, register: Register) -> Result<TrollCfaBase>
 {
-	Amd64
+	if register == registers.stack_pointer
 	{
-		rsp: u64,
-		rbp: u64,
-		ret: u64 // `$RA` in DWARF parlance
-	},
-	X86,
-	AArch64,
-	Arm32,
-	Riscv32,
-	Riscv64,
-	PowerPC32,
-	PowerPC64,
-	Sparc32,
-	Sparc64,
-	Mips32,
-	Mips64,
-	Avr32,
-	LoongArch64,
-	ZArch
+		Ok(TrollCfaBase::StackPointer)
+	}
+	else if register == registers.frame_pointer
+	{
+		Ok(TrollCfaBase::FramePointer)
+	}
+	else
+	{
+		bail!("unsupported CFA base register {}", register.0)
+	}
+}
+
+fn offset(value: i64, what: &str) -> Result<i16>
+{
+	i16::try_from(value).map_err(|_| anyhow!("{what} offset {value} doesn't fit in 16 bits"))
+}
+
+fn register_rule(rule: RegisterRule<usize>, what: &str) -> Result<TrollRegisterRule>
+{
+	Ok(match rule
+	{
+		RegisterRule::Undefined => TrollRegisterRule::Undefined,
+		RegisterRule::SameValue => TrollRegisterRule::SameValue,
+		RegisterRule::Offset(value) => TrollRegisterRule::AtCfa(offset(value, what)?),
+		RegisterRule::ValOffset(_) => bail!("unsupported \"val_offset\" rule for {what}"),
+		RegisterRule::Register(reg) => bail!("unsupported \"register({})\" rule for {what}", reg.0),
+		RegisterRule::Expression(_) => bail!("unsupported \"expression\" rule for {what}"),
+		RegisterRule::ValExpression(_) => bail!("unsupported \"val_expression\" rule for {what}"),
+		RegisterRule::Architectural => bail!("unsupported \"architectural\" rule for {what}"),
+		RegisterRule::Constant(_) => bail!("unsupported \"constant\" rule for {what}"),
+		_ => bail!("unknown rule for {what}")
+	})
+}
+
+pub fn get_regs(arch: SupportedArch, row: &UnwindTableRow<usize>) -> Result<TrollRegisterSet>
+{
+	let registers = TrollArchRegisters::for_arch(arch)?;
+	let cfa = match row.cfa()
+	{
+		CfaRule::RegisterAndOffset {
+			register,
+			offset: value
+		} =>
+		{
+			TrollCfaRule::RegisterAndOffset {
+				base:   cfa_base(&registers, *register)?,
+				offset: offset(*value, "CFA")?
+			}
+		},
+		CfaRule::Expression(_) => bail!("unsupported CFA expression")
+	};
+	// gimli reports registers without any rule as undefined, while only an
+	// explicit `DW_CFA_undefined` makes them so
+	let has_rule = |wanted: Register| row.registers().any(|(register, _)| *register == wanted);
+	let fp = match row.register(registers.frame_pointer)
+	{
+		// the compilers omit the rules for registers that keep their value
+		RegisterRule::Undefined if !has_rule(registers.frame_pointer) =>
+		{
+			TrollRegisterRule::SameValue
+		},
+		rule => register_rule(rule, "frame pointer")?
+	};
+	let ret = match row.register(registers.return_address)
+	{
+		// no rule at all for the return address means that it is still in the link
+		// register, or that there is no caller if it is passed on the stack
+		RegisterRule::Undefined
+			if !has_rule(registers.return_address) && registers.link_register =>
+		{
+			TrollRegisterRule::SameValue
+		},
+		RegisterRule::Undefined => TrollRegisterRule::Undefined,
+		rule => register_rule(rule, "return address")?
+	};
+	Ok(TrollRegisterSet { cfa, fp, ret })
+}
+
+/// Demangled name of the function containing `pc`, for diagnostics
+pub fn function_name<'data, Elf, R>(elf: &ElfFile<'data, Elf, R>, pc: u64) -> String
+where
+	Elf: FileHeader<Endian = Endianness>,
+	R: ReadRef<'data>
+{
+	elf.symbols()
+		.filter(|sym| sym.elf_symbol().st_type() == STT_FUNC)
+		.find(|sym| sym.address() <= pc && pc < sym.address() + sym.size().max(1))
+		.and_then(|sym| sym.name().ok().map(|name| demangle(name.to_string())))
+		.unwrap_or_else(|| String::from("<unknown function>"))
+}
+
+/// Write `table` into the TROLL section of `input`, and save the result to
+/// `output`
+///
+/// If the executable already has a (reserved) TROLL section, the table is
+/// written over its content, which must be big enough. Otherwise, a new
+/// non-allocated section is appended.
+pub fn write_table(input: &[u8], table: &[u8], output: &Path) -> Result<()>
+{
+	let file = object::File::parse(input)?;
+	let out = match file.section_by_name(TROLL_SECTION_NAME)
+	{
+		Some(section) if section.kind() != SectionKind::UninitializedData =>
+		{
+			let (offset, size) = section.file_range().ok_or(anyhow!(
+				"section \"{TROLL_SECTION_NAME}\" has no file content"
+			))?;
+			if (table.len() as u64) > size
+			{
+				bail!(
+					"section \"{TROLL_SECTION_NAME}\" is too small: {size} bytes available, {} \
+					 needed",
+					table.len()
+				);
+			}
+			let mut out = input.to_vec();
+			let range = offset as usize..(offset + size) as usize;
+			out[range.clone()].fill(0);
+			out[range.start..range.start + table.len()].copy_from_slice(table);
+			out
+		},
+		Some(_) => bail!("section \"{TROLL_SECTION_NAME}\" has no file content"),
+		None =>
+		{
+			let mut builder = build::elf::Builder::read(input)?;
+			let section = builder.sections.add();
+			section.name = TROLL_SECTION_NAME.into();
+			section.sh_type = SHT_PROGBITS;
+			section.sh_addralign = 8;
+			section.data = build::elf::SectionData::Data(table.to_vec().into());
+			let mut out = Vec::new();
+			builder.write(&mut out)?;
+			out
+		}
+	};
+	fs::write(output, out).with_context(|| format!("couldn't write {}", output.display()))
+}
+
+/// Print the TROLL table of an executable
+pub fn dump_table(table: &TrollTable, format: OutputFormat) -> Result<()>
+{
+	if format == OutputFormat::Json
+	{
+		println!("{}", serde_json::to_string_pretty(table)?);
+		return Ok(());
+	}
+
+	println!(
+		"TROLL table (machine: {}, {} entries):",
+		table.machine,
+		table.entries.len()
+	);
+	for (entry, next) in table
+		.entries
+		.iter()
+		.zip(table.entries.iter().skip(1).map(Some).chain([None]))
+	{
+		let end = next.map_or_else(|| String::from("..."), |next| format!("{:#x}", next.pc));
+		if entry.cfa_base == TrollCfaBase::Undefined
+		{
+			println!("  {:#x}..{end}: <no unwind info>", entry.pc);
+			continue;
+		}
+		println!(
+			"  {:#x}..{end}: cfa={} fp={} ra={}",
+			entry.pc,
+			entry.describe_cfa(),
+			entry.describe_fp(),
+			entry.describe_ra()
+		);
+	}
+	Ok(())
 }
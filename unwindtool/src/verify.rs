@@ -0,0 +1,626 @@
+//! Cross-checking of a TROLL table against the DWARF call frame information
+//!
+//! Both encodings are evaluated at every instruction boundary of every function
+//! symbol, and any disagreement is reported as an [`Issue`]. Consecutive
+//! instructions with the same issue are merged into a single PC range.
+//!
+//! The DWARF rules are evaluated straight from the rows of the unwind tables,
+//! without going through the code generating the TROLL table, so that the
+//! generator is actually checked.
+
+use std::{collections::BTreeMap, fmt};
+
+use anyhow::{Result, anyhow, bail};
+use gimli::{Register, RegisterRule, UnwindTableRow};
+use iced_x86::{Decoder, DecoderOptions};
+use object::{
+	Endian,
+	Endianness,
+	Object,
+	ObjectSection,
+	ObjectSymbol,
+	ReadRef,
+	elf::STT_FUNC,
+	read::elf::{ElfFile, FileHeader, Sym}
+};
+use serde::Serialize;
+
+use crate::{
+	OutputFormat,
+	SupportedArch,
+	demangle,
+	troll::{self, TrollArchRegisters, TrollCfaBase, TrollEntry, TrollFlags, TrollTable}
+};
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, strum::AsRefStr)]
+#[serde(rename_all = "kebab-case")]
+#[strum(serialize_all = "kebab-case")]
+pub enum IssueKind
+{
+	/// Both formats describe the PC, but their rules differ
+	Mismatch,
+	/// The DWARF rules of the PC can't be expressed in TROLL
+	Inexpressible,
+	/// The DWARF call frame information describes the PC, but the TROLL table
+	/// doesn't
+	Uncovered,
+	/// No FDE describes the PC
+	FdeGap
+}
+
+/// A PC range over which both formats disagree
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct Issue
+{
+	pub kind:     IssueKind,
+	pub function: String,
+	pub start:    u64,
+	pub end:      u64,
+	/// The rule that differs (`cfa`, `fp` or `ra`), for mismatches
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub field:    Option<&'static str>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub dwarf:    Option<String>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub troll:    Option<String>
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct VerifyReport
+{
+	pub functions:    usize,
+	pub instructions: usize,
+	pub summary:      BTreeMap<IssueKind, usize>,
+	pub issues:       Vec<Issue>
+}
+
+impl VerifyReport
+{
+	pub fn is_ok(&self) -> bool
+	{
+		self.issues.is_empty()
+	}
+
+	/// Record `issue`, merging it with the same issue of the previous
+	/// instruction if possible
+	fn push(&mut self, issue: Issue)
+	{
+		// at most one issue per rule is recorded for each instruction
+		const WINDOW: usize = 3;
+
+		if let Some(previous) = self.issues.iter_mut().rev().take(WINDOW).find(|previous| {
+			previous.end == issue.start
+				&& Issue {
+					start: 0,
+					end: 0,
+					..previous.clone()
+				} == Issue {
+					start: 0,
+					end: 0,
+					..issue.clone()
+				}
+		})
+		{
+			previous.end = issue.end;
+			return;
+		}
+		*self.summary.entry(issue.kind).or_default() += 1;
+		self.issues.push(issue);
+	}
+
+	pub fn print(&self, format: OutputFormat) -> Result<()>
+	{
+		match format
+		{
+			OutputFormat::Text =>
+			{
+				for issue in &self.issues
+				{
+					print!(
+						"{:<13} {:#x}..{:#x} in {}",
+						issue.kind.as_ref(),
+						issue.start,
+						issue.end,
+						issue.function
+					);
+					if let Some(field) = issue.field
+					{
+						print!(": {field}");
+					}
+					if let Some(dwarf) = &issue.dwarf
+					{
+						print!(" dwarf={dwarf}");
+					}
+					if let Some(troll) = &issue.troll
+					{
+						print!(" troll={troll}");
+					}
+					println!();
+				}
+				println!(
+					"checked {} instructions in {} functions: {}",
+					self.instructions,
+					self.functions,
+					if self.is_ok()
+					{
+						String::from("no issue found")
+					}
+					else
+					{
+						self.summary
+							.iter()
+							.map(|(kind, count)| format!("{count} {}", kind.as_ref()))
+							.collect::<Vec<_>>()
+							.join(", ")
+					}
+				);
+			},
+			OutputFormat::Json => println!("{}", serde_json::to_string_pretty(self)?)
+		}
+		Ok(())
+	}
+}
+
+/// How to recover the CFA
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum CfaRule
+{
+	Undefined,
+	RegisterAndOffset(Register, i64),
+	Expression
+}
+
+/// How to recover a register of the caller
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Rule
+{
+	SameValue,
+	Undefined,
+	/// The register is saved at `CFA + offset`
+	AtCfa(i64),
+	/// A rule TROLL has no equivalent for
+	Unsupported(String)
+}
+
+impl fmt::Display for Rule
+{
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+	{
+		match self
+		{
+			Self::SameValue => write!(f, "same"),
+			Self::Undefined => write!(f, "undefined"),
+			Self::AtCfa(offset) => write!(f, "cfa{offset:+}"),
+			Self::Unsupported(rule) => write!(f, "{rule}")
+		}
+	}
+}
+
+/// The rules of the registers TROLL tracks, in terms of DWARF registers
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Rules
+{
+	cfa: CfaRule,
+	fp:  Rule,
+	ra:  Rule
+}
+
+impl Rules
+{
+	/// Evaluate the rules of a row of a DWARF unwind table
+	fn from_row(registers: &TrollArchRegisters, row: &UnwindTableRow<usize>) -> Self
+	{
+		let cfa = match row.cfa()
+		{
+			gimli::CfaRule::RegisterAndOffset { register, offset } =>
+			{
+				CfaRule::RegisterAndOffset(*register, *offset)
+			},
+			gimli::CfaRule::Expression(_) => CfaRule::Expression
+		};
+		// a register without any rule gets the default rule of the ABI
+		let rule = |register: Register, default: Rule| {
+			if !row.registers().any(|(other, _)| *other == register)
+			{
+				return default;
+			}
+			match row.register(register)
+			{
+				RegisterRule::Undefined => Rule::Undefined,
+				RegisterRule::SameValue => Rule::SameValue,
+				RegisterRule::Offset(offset) => Rule::AtCfa(offset),
+				RegisterRule::ValOffset(offset) =>
+				{
+					Rule::Unsupported(format!("val_offset({offset})"))
+				},
+				RegisterRule::Register(other) =>
+				{
+					Rule::Unsupported(format!("register({})", other.0))
+				},
+				RegisterRule::Expression(_) => Rule::Unsupported(String::from("expression")),
+				RegisterRule::ValExpression(_) => Rule::Unsupported(String::from("val_expression")),
+				RegisterRule::Architectural => Rule::Unsupported(String::from("architectural")),
+				RegisterRule::Constant(value) => Rule::Unsupported(format!("constant({value})")),
+				_ => Rule::Unsupported(String::from("unknown"))
+			}
+		};
+		Self {
+			cfa,
+			// the frame pointer is callee-saved on every supported architecture
+			fp: rule(registers.frame_pointer, Rule::SameValue),
+			// the return address is either still in the link register, or
+			// there is no caller when it is passed on the stack
+			ra: rule(
+				registers.return_address,
+				if registers.link_register
+				{
+					Rule::SameValue
+				}
+				else
+				{
+					Rule::Undefined
+				}
+			)
+		}
+	}
+
+	/// Decode the rules of a TROLL entry
+	fn from_entry(registers: &TrollArchRegisters, entry: &TrollEntry) -> Self
+	{
+		let cfa = match entry.cfa_base
+		{
+			TrollCfaBase::Undefined => CfaRule::Undefined,
+			TrollCfaBase::StackPointer =>
+			{
+				CfaRule::RegisterAndOffset(registers.stack_pointer, entry.cfa_offset.into())
+			},
+			TrollCfaBase::FramePointer =>
+			{
+				CfaRule::RegisterAndOffset(registers.frame_pointer, entry.cfa_offset.into())
+			},
+		};
+		let fp = if entry.flags.contains(TrollFlags::FP_SAVED)
+		{
+			Rule::AtCfa(entry.fp_offset.into())
+		}
+		else if entry.flags.contains(TrollFlags::FP_UNDEFINED)
+		{
+			Rule::Undefined
+		}
+		else
+		{
+			Rule::SameValue
+		};
+		let ra = if entry.flags.contains(TrollFlags::RA_UNDEFINED)
+		{
+			Rule::Undefined
+		}
+		else if entry.flags.contains(TrollFlags::RA_IN_REGISTER)
+		{
+			Rule::SameValue
+		}
+		else
+		{
+			Rule::AtCfa(entry.ra_offset.into())
+		};
+		Self { cfa, fp, ra }
+	}
+
+	/// Why TROLL can't express these rules, if it can't
+	fn inexpressible(&self, registers: &TrollArchRegisters) -> Option<String>
+	{
+		let fits = |offset: i64| i16::try_from(offset).is_ok();
+		match self.cfa
+		{
+			CfaRule::Expression => return Some(String::from("unsupported CFA expression")),
+			CfaRule::RegisterAndOffset(register, _)
+				if register != registers.stack_pointer && register != registers.frame_pointer =>
+			{
+				return Some(format!("unsupported CFA base register {}", register.0));
+			},
+			CfaRule::RegisterAndOffset(_, offset) if !fits(offset) =>
+			{
+				return Some(format!("CFA offset {offset} doesn't fit in 16 bits"));
+			},
+			_ => ()
+		}
+		for (rule, what) in [(&self.fp, "frame pointer"), (&self.ra, "return address")]
+		{
+			match rule
+			{
+				Rule::Unsupported(rule) =>
+				{
+					return Some(format!("unsupported \"{rule}\" rule for {what}"));
+				},
+				Rule::AtCfa(offset) if !fits(*offset) =>
+				{
+					return Some(format!("{what} offset {offset} doesn't fit in 16 bits"));
+				},
+				_ => ()
+			}
+		}
+		None
+	}
+
+	/// Human-readable CFA rule, as printed by [`TrollEntry::describe_cfa`]
+	fn describe_cfa(&self, registers: &TrollArchRegisters) -> String
+	{
+		match self.cfa
+		{
+			CfaRule::Undefined => String::from("undefined"),
+			CfaRule::Expression => String::from("expression"),
+			CfaRule::RegisterAndOffset(register, offset) if register == registers.stack_pointer =>
+			{
+				format!("{}{offset:+}", TrollCfaBase::StackPointer.as_ref())
+			},
+			CfaRule::RegisterAndOffset(register, offset) if register == registers.frame_pointer =>
+			{
+				format!("{}{offset:+}", TrollCfaBase::FramePointer.as_ref())
+			},
+			CfaRule::RegisterAndOffset(register, offset) => format!("r{}{offset:+}", register.0)
+		}
+	}
+}
+
+/// The DWARF rows, keyed by their start address, along with their end address
+/// and their rules
+type DwarfRows = BTreeMap<u64, (u64, Rules)>;
+
+fn dwarf_rows<'data, Elf, R>(
+	registers: &TrollArchRegisters,
+	elf: &ElfFile<'data, Elf, R>
+) -> Result<DwarfRows>
+where
+	Elf: FileHeader<Endian = Endianness>,
+	R: ReadRef<'data>
+{
+	let mut rows = BTreeMap::new();
+	troll::for_each_row(elf, |row| {
+		if row.start_address() < row.end_address()
+		{
+			rows.insert(
+				row.start_address(),
+				(row.end_address(), Rules::from_row(registers, row))
+			);
+		}
+		Ok(())
+	})?;
+	Ok(rows)
+}
+
+fn dwarf_lookup(rows: &DwarfRows, pc: u64) -> Option<&Rules>
+{
+	rows.range(..=pc)
+		.next_back()
+		.filter(|(_, (end, _))| pc < *end)
+		.map(|(_, (_, rules))| rules)
+}
+
+/// Start address of every instruction in `code`, which is mapped at `address`
+fn instruction_boundaries(
+	arch: SupportedArch,
+	endian: Endianness,
+	code: &[u8],
+	address: u64,
+	thumb: bool
+) -> Result<Vec<u64>>
+{
+	let x86_bitness = match arch
+	{
+		SupportedArch::Amd64 => Some(64),
+		SupportedArch::X86 => Some(32),
+		_ => None
+	};
+	if let Some(bitness) = x86_bitness
+	{
+		let mut decoder = Decoder::with_ip(bitness, code, address, DecoderOptions::NONE);
+		let mut boundaries = Vec::new();
+		while decoder.can_decode()
+		{
+			boundaries.push(decoder.ip());
+			decoder.decode();
+		}
+		return Ok(boundaries);
+	}
+
+	// the other architectures only need the first bytes of an instruction to
+	// know its length
+	let halfword = |offset: usize| {
+		code.get(offset..offset + 2)
+			.map(|bytes| endian.read_u16_bytes([bytes[0], bytes[1]]))
+	};
+	let instruction_length = |offset: usize| -> Result<usize> {
+		Ok(match arch
+		{
+			SupportedArch::Arm32 if thumb =>
+			{
+				// 32-bit Thumb-2 instructions start with 0b11101, 0b11110 or 0b11111
+				let first = halfword(offset).ok_or(anyhow!("truncated instruction"))?;
+				if first >> 11 >= 0b11101 { 4 } else { 2 }
+			},
+			SupportedArch::Riscv32 | SupportedArch::Riscv64 =>
+			{
+				// compressed instructions don't have their 2 lowest bits set
+				let first = halfword(offset).ok_or(anyhow!("truncated instruction"))?;
+				if first & 0b11 == 0b11 { 4 } else { 2 }
+			},
+			SupportedArch::ZArch =>
+			{
+				// the 2 highest bits of the opcode give the instruction length
+				match code[offset] >> 6
+				{
+					0b00 => 2,
+					0b01 | 0b10 => 4,
+					_ => 6
+				}
+			},
+			SupportedArch::AArch64
+			| SupportedArch::Arm32
+			| SupportedArch::LoongArch64
+			| SupportedArch::Mips32
+			| SupportedArch::Mips64
+			| SupportedArch::PowerPC32
+			| SupportedArch::PowerPC64
+			| SupportedArch::Sparc32
+			| SupportedArch::Sparc64 => 4,
+			_ =>
+			{
+				bail!(
+					"instruction decoding is not supported yet for {}",
+					arch.as_ref()
+				)
+			}
+		})
+	};
+
+	let mut boundaries = Vec::new();
+	let mut offset = 0;
+	while offset < code.len()
+	{
+		boundaries.push(address + offset as u64);
+		offset += instruction_length(offset)?;
+	}
+	Ok(boundaries)
+}
+
+/// Cross-check the TROLL table of `elf` against its `.eh_frame`
+pub fn verify<'data, Elf, R>(
+	arch: SupportedArch,
+	elf: &ElfFile<'data, Elf, R>
+) -> Result<VerifyReport>
+where
+	Elf: FileHeader<Endian = Endianness>,
+	R: ReadRef<'data>
+{
+	arch.check_elf(elf)?;
+	let section = elf
+		.section_by_name(troll::TROLL_SECTION_NAME)
+		.ok_or(anyhow!(
+			"couldn't find \"{}\" section",
+			troll::TROLL_SECTION_NAME
+		))?;
+	let table = TrollTable::decode(elf.endianness(), section.data()?)?;
+	let machine = elf.elf_header().e_machine(elf.endian());
+	if table.machine != machine
+	{
+		bail!(
+			"the TROLL table was generated for machine {} (expected {machine})",
+			table.machine
+		);
+	}
+	let registers = TrollArchRegisters::for_arch(arch)?;
+	let rows = dwarf_rows(&registers, elf)?;
+
+	// aliases share the same code, which only needs to be checked once
+	let mut functions = BTreeMap::new();
+	for sym in elf.symbols()
+	{
+		if sym.elf_symbol().st_type() == STT_FUNC && sym.size() != 0
+		{
+			functions.entry(sym.address()).or_insert(sym);
+		}
+	}
+
+	let mut report = VerifyReport::default();
+	for sym in functions.into_values()
+	{
+		let name = demangle(sym.name()?.to_string());
+		let Some(section) = sym
+			.section_index()
+			.map(|index| elf.section_by_index(index))
+			.transpose()?
+		else
+		{
+			continue;
+		};
+		// the lowest bit of the address of Thumb functions is set
+		let thumb = arch == SupportedArch::Arm32 && sym.address() & 1 != 0;
+		let address = sym.address() & !u64::from(thumb);
+		let data = section.data()?;
+		let code = address
+			.checked_sub(section.address())
+			.and_then(|offset| data.get(offset as usize..(offset + sym.size()) as usize))
+			.ok_or(anyhow!("function {name} lies outside of its section"))?;
+		let boundaries = instruction_boundaries(arch, elf.endian(), code, address, thumb)?;
+
+		report.functions += 1;
+		report.instructions += boundaries.len();
+		let ends = boundaries
+			.iter()
+			.skip(1)
+			.copied()
+			.chain([address + sym.size()]);
+		for (pc, end) in boundaries.iter().copied().zip(ends)
+		{
+			let issue = |kind, field, dwarf, troll| {
+				Issue {
+					kind,
+					function: name.clone(),
+					start: pc,
+					end,
+					field,
+					dwarf,
+					troll
+				}
+			};
+			let troll = table.lookup(pc);
+			let Some(dwarf) = dwarf_lookup(&rows, pc)
+			else
+			{
+				report.push(issue(
+					IssueKind::FdeGap,
+					None,
+					None,
+					troll.map(TrollEntry::describe_cfa)
+				));
+				continue;
+			};
+			if let Some(err) = dwarf.inexpressible(&registers)
+			{
+				report.push(issue(
+					IssueKind::Inexpressible,
+					None,
+					Some(err),
+					troll.map(TrollEntry::describe_cfa)
+				));
+				continue;
+			}
+			let Some(troll) = troll
+			else
+			{
+				report.push(issue(
+					IssueKind::Uncovered,
+					None,
+					Some(dwarf.describe_cfa(&registers)),
+					None
+				));
+				continue;
+			};
+
+			let troll = Rules::from_entry(&registers, troll);
+			if dwarf.cfa != troll.cfa
+			{
+				report.push(issue(
+					IssueKind::Mismatch,
+					Some("cfa"),
+					Some(dwarf.describe_cfa(&registers)),
+					Some(troll.describe_cfa(&registers))
+				));
+			}
+			for (field, dwarf, troll) in
+				[("fp", &dwarf.fp, &troll.fp), ("ra", &dwarf.ra, &troll.ra)]
+			{
+				if dwarf != troll
+				{
+					report.push(issue(
+						IssueKind::Mismatch,
+						Some(field),
+						Some(dwarf.to_string()),
+						Some(troll.to_string())
+					));
+				}
+			}
+		}
+	}
+	Ok(report)
+}
@@ -74,9 +74,22 @@ pub(crate) enum ZerosBuildProfile
 {
 	Dev,
 	DevLTO,
+	/// Like `dev`, but panics unwind the stack and can be caught
+	DevUnwind,
 	Release,
 	#[default]
-	ReleaseLTO
+	ReleaseLTO,
+	/// Like `release`, but panics unwind the stack and can be caught
+	ReleaseUnwind
+}
+
+impl ZerosBuildProfile
+{
+	/// Whether the profile builds zerOS with `panic = "unwind"`
+	pub(crate) fn unwinds(self) -> bool
+	{
+		matches!(self, Self::DevUnwind | Self::ReleaseUnwind)
+	}
 }
 
 impl XtaskBuildableSubproj
@@ -153,6 +166,7 @@ impl XtaskBuildableSubproj
 			"./bin",
 			format!("--profile={profile}").as_str()
 		])
+		.args(profile.unwinds().then_some("--features=unwind"))
 		.env(
 			"RUSTFLAGS",
 			format!(
@@ -164,11 +178,30 @@ impl XtaskBuildableSubproj
 		let cmd = CmdIn::new(&subproj_location!("zerOS"), cmd);
 		cmd.finalize().await;
 
+		let zeros_bin = subproj_location!("zerOS").join("bin");
+
+		// fill in the `.troll` section reserved by the linker script
+		if matches!(arch, SupportedArch::Amd64)
+		{
+			let mut cmd = process::Command::new(cfg.get(&Executable::Cargo));
+			cmd.args(&[
+				"run",
+				"--release",
+				"--",
+				"troll",
+				"generate",
+				"--in-place",
+				format!("--arch={}", arch.as_ref()).as_str(),
+				zeros_bin.join("zerOS").as_str()
+			]);
+			let cmd = CmdIn::new(&subproj_location!("unwindtool"), cmd);
+			cmd.finalize().await;
+		}
+
 		let alt_strips = [Executable::Strip, Executable::EuStrip]
 			.map(|e| "`".to_owned() + get_default_executable_short_name(&e) + "`");
 		let alt_str =
 			alt_strips[0..(alt_strips.len() - 1)].join(", ") + " or " + alt_strips.last().unwrap();
-		let zeros_bin = subproj_location!("zerOS").join("bin");
 		let mut objcopy = None;
 		strip::run(
 			check!(
@@ -189,8 +222,11 @@ impl XtaskBuildableSubproj
 			}),
 			zeros_bin.join("zerOS"),
 			zeros_bin.join("zerOS.stripped"),
-			matches!(profile, ZerosBuildProfile::Dev | ZerosBuildProfile::DevLTO)
-				.then(|| zeros_bin.join("zerOS-boot-modules").join("debug-info.zko"))
+			matches!(
+				profile,
+				ZerosBuildProfile::Dev | ZerosBuildProfile::DevLTO | ZerosBuildProfile::DevUnwind
+			)
+			.then(|| zeros_bin.join("zerOS-boot-modules").join("debug-info.zko"))
 		)
 		.await;
 
@@ -0,0 +1,346 @@
+//! Decoding of the crash dumps written by zerOS' `crash-dump` panic action
+//!
+//! The records below mirror the ones of `zerOS/src/panic/dump.rs`, and must be
+//! kept in sync with them: `postcard` is not self-describing, so any change to
+//! their layout requires bumping [`CRASH_DUMP_VERSION`] on both sides. The
+//! header must stay the first variant, with the version as its first field.
+
+use std::{borrow::Cow, fs};
+
+use addr2line::Loader;
+use anyhow::{Context, Result, anyhow, bail};
+use camino::Utf8PathBuf;
+use clap::Args;
+use serde::Deserialize;
+
+use crate::{
+	XtaskGlobalOptions,
+	actions::{
+		Xtask,
+		configure::{get_topdir, subproj_location}
+	},
+	tools::check
+};
+
+/// Version of the crash dump format understood by this decoder
+const CRASH_DUMP_VERSION: u16 = 2;
+
+#[derive(Debug, Clone, Args)]
+#[clap(rename_all = "kebab-case")]
+pub(crate) struct XtaskCrashDumpArgs
+{
+	/// The crash dump to decode (defaults to the file `xtask run` makes QEMU
+	/// write COM2 to)
+	dump: Option<Utf8PathBuf>,
+
+	#[arg(short, long)]
+	/// The unstripped kernel to symbolize the backtraces against (defaults to
+	/// `zerOS/bin/zerOS`)
+	kernel: Option<Utf8PathBuf>,
+
+	#[arg(long, default_value_t = false)]
+	/// Don't symbolize the backtraces
+	no_symbolize: bool
+}
+
+#[derive(Debug, Deserialize)]
+struct AllocatorStats
+{
+	allocations:        u64,
+	deallocations:      u64,
+	failed_allocations: u64,
+	allocated_bytes:    u64,
+	peak_bytes:         u64
+}
+
+#[derive(Debug, Deserialize)]
+enum DumpRecord
+{
+	Header
+	{
+		version:        u16,
+		kernel_version: String,
+		arch:           String,
+		panicking_cpu:  u32
+	},
+	Panic
+	{
+		cpu:       u32,
+		file:      String,
+		line:      u32,
+		column:    u32,
+		message:   String,
+		truncated: bool
+	},
+	Registers
+	{
+		cpu:       u32,
+		pc:        Option<u64>,
+		registers: Vec<Option<u64>>
+	},
+	Backtrace
+	{
+		cpu:    u32,
+		frames: Vec<u64>
+	},
+	MissingRegisters
+	{
+		cpu: u32
+	},
+	MemoryRegion
+	{
+		base:   u64,
+		length: u64,
+		kind:   u64
+	},
+	Allocator(AllocatorStats),
+	Log(Vec<u8>),
+	End
+}
+
+/// Names of the registers of `arch`, indexed by their DWARF register number
+fn register_names(arch: &str) -> &'static [&'static str]
+{
+	match arch
+	{
+		"amd64" =>
+		{
+			&[
+				"rax", "rdx", "rcx", "rbx", "rsi", "rdi", "rbp", "rsp", "r8", "r9", "r10", "r11",
+				"r12", "r13", "r14", "r15", "ra"
+			]
+		},
+		"x86" => &["eax", "ecx", "edx", "ebx", "esp", "ebp", "esi", "edi", "ra"],
+		_ => &[]
+	}
+}
+
+/// Name of a memory map entry type, as defined by the Limine protocol
+fn memory_kind_name(kind: u64) -> Cow<'static, str>
+{
+	match kind
+	{
+		0 => "usable".into(),
+		1 => "reserved".into(),
+		2 => "ACPI reclaimable".into(),
+		3 => "ACPI NVS".into(),
+		4 => "bad memory".into(),
+		5 => "bootloader reclaimable".into(),
+		6 => "executable and modules".into(),
+		7 => "framebuffer".into(),
+		_ => format!("unknown ({kind})").into()
+	}
+}
+
+/// Decode every record of `bytes`
+///
+/// Records that can't be decoded are reported, and skipped.
+fn decode_records(bytes: &mut [u8]) -> Vec<Result<DumpRecord>>
+{
+	bytes
+		.split_mut(|&byte| byte == 0)
+		.filter(|frame| !frame.is_empty())
+		.map(|frame| {
+			postcard::from_bytes_cobs(frame).map_err(|err| anyhow!("corrupted record: {err}"))
+		})
+		.collect()
+}
+
+/// Source locations of `pc` (innermost inlined function first)
+fn symbolize(loader: &Loader, pc: u64) -> Vec<String>
+{
+	let mut lines = Vec::new();
+	if let Ok(mut frames) = loader.find_frames(pc)
+	{
+		while let Ok(Some(frame)) = frames.next()
+		{
+			let function = frame
+				.function
+				.as_ref()
+				.and_then(|function| function.demangle().ok())
+				.map_or_else(|| String::from("<unknown>"), Cow::into_owned);
+			let location = frame.location.map_or_else(String::new, |location| {
+				format!(
+					"\n\t        at {}:{}:{}",
+					location.file.unwrap_or("<unknown-file>"),
+					location.line.unwrap_or(0),
+					location.column.unwrap_or(0)
+				)
+			});
+			lines.push(format!("{function}{location}"));
+		}
+	}
+	if lines.is_empty()
+		&& let Some(symbol) = loader.find_symbol(pc)
+	{
+		lines.push(addr2line::demangle_auto(symbol.into(), None).into_owned());
+	}
+	lines
+}
+
+fn print_dump(records: Vec<Result<DumpRecord>>, loader: Option<&Loader>) -> Result<()>
+{
+	let mut arch = String::new();
+	let mut saw_end = false;
+	for record in records
+	{
+		let record = match record
+		{
+			Ok(record) => record,
+			Err(err) =>
+			{
+				println!("<{err}>");
+				continue;
+			}
+		};
+		match record
+		{
+			DumpRecord::Header {
+				version,
+				kernel_version,
+				arch: dump_arch,
+				panicking_cpu
+			} =>
+			{
+				if version != CRASH_DUMP_VERSION
+				{
+					bail!(
+						"unsupported crash dump format version {version} (this xtask understands \
+						 version {CRASH_DUMP_VERSION})"
+					);
+				}
+				println!(
+					"zerOS {kernel_version} ({dump_arch}) crash dump, written by cpu \
+					 #{panicking_cpu}"
+				);
+				arch = dump_arch;
+			},
+			DumpRecord::Panic {
+				cpu,
+				file,
+				line,
+				column,
+				message,
+				truncated
+			} =>
+			{
+				println!("\npanic at {file}:{line}:{column} (cpu #{cpu}):");
+				println!("\t{}", message.replace('\n', "\n\t"));
+				if truncated
+				{
+					println!("\t(panic message truncated)");
+				}
+			},
+			DumpRecord::Registers { cpu, pc, registers } =>
+			{
+				println!("\nregisters (cpu #{cpu}):");
+				if let Some(pc) = pc
+				{
+					println!("\tpc  = {pc:#018x}");
+				}
+				let names = register_names(&arch);
+				for (index, value) in registers.iter().enumerate()
+				{
+					if let Some(value) = value
+					{
+						let name = names
+							.get(index)
+							.map_or_else(|| format!("r{index}"), |name| name.to_string());
+						println!("\t{name:<3} = {value:#018x}");
+					}
+				}
+			},
+			DumpRecord::Backtrace { cpu, frames } =>
+			{
+				println!("\nbacktrace (cpu #{cpu}):");
+				for (depth, &pc) in frames.iter().enumerate()
+				{
+					print!("\t#{depth:<2} {pc:#018x}");
+					// except for the innermost frame, `pc` is a return address,
+					// which may belong to the next source line
+					let lookup_pc = if depth == 0 { pc } else { pc - 1 };
+					match loader.map(|loader| symbolize(loader, lookup_pc))
+					{
+						Some(lines) if !lines.is_empty() =>
+						{
+							println!(" in {}", lines.join("\n\t    inlined in "))
+						},
+						Some(_) => println!(" in <unknown>"),
+						None => println!()
+					}
+				}
+			},
+			DumpRecord::MissingRegisters { cpu } =>
+			{
+				println!("\nregisters (cpu #{cpu}): <not saved>");
+			},
+			DumpRecord::MemoryRegion { base, length, kind } =>
+			{
+				println!(
+					"memory: {base:#018x}..{:#018x} {} ({} KiB)",
+					base + length,
+					memory_kind_name(kind),
+					length / 1024
+				);
+			},
+			DumpRecord::Allocator(stats) =>
+			{
+				println!(
+					"\nallocator: {} allocations, {} deallocations, {} failed allocations, {} \
+					 bytes allocated (peak: {} bytes)",
+					stats.allocations,
+					stats.deallocations,
+					stats.failed_allocations,
+					stats.allocated_bytes,
+					stats.peak_bytes
+				);
+			},
+			DumpRecord::Log(chunk) => print!("{}", String::from_utf8_lossy(&chunk)),
+			DumpRecord::End =>
+			{
+				saw_end = true;
+			}
+		}
+	}
+	if !saw_end
+	{
+		println!("\n<the crash dump is incomplete>");
+	}
+	Ok(())
+}
+
+fn decode(args: &XtaskCrashDumpArgs) -> Result<()>
+{
+	let path = args
+		.dump
+		.clone()
+		.unwrap_or_else(|| get_topdir().join("crash.dump"));
+	let mut bytes = fs::read(&path).with_context(|| format!("couldn't read {path}"))?;
+	if bytes.is_empty()
+	{
+		bail!("{path} is empty (the kernel didn't write any crash dump)");
+	}
+
+	let loader = if args.no_symbolize
+	{
+		None
+	}
+	else
+	{
+		let kernel = args
+			.kernel
+			.clone()
+			.unwrap_or_else(|| subproj_location!("zerOS").join("bin").join("zerOS"));
+		Some(Loader::new(&kernel).map_err(|err| anyhow!("couldn't load {kernel}: {err}"))?)
+	};
+
+	print_dump(decode_records(&mut bytes), loader.as_ref())
+}
+
+impl Xtask for XtaskCrashDumpArgs
+{
+	async fn execute(&self, _globals: &XtaskGlobalOptions)
+	{
+		check!(decode(self).expect("failed to decode the crash dump"));
+	}
+}
@@ -4,6 +4,7 @@ pub(crate) mod build;
 pub(crate) mod clean;
 pub(crate) mod clippy;
 pub(crate) mod configure;
+pub(crate) mod crashdump;
 pub(crate) mod expand;
 pub(crate) mod format;
 pub(crate) mod run;
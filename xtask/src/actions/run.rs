@@ -1,6 +1,9 @@
+use std::process::ExitStatus;
+
 use chumsky::IterParser;
 use clap::{ArgAction, Subcommand, ValueEnum};
 use itertools::Itertools;
+use log::error;
 use tokio::{process, task};
 
 use crate::{
@@ -48,6 +51,21 @@ pub(crate) enum XtaskRunnableSubproj
 		/// Whether to spawn a new terminal for debugging purposes
 		gdb_window: bool,
 
+		#[arg(long, value_name = "TCP_PORT")]
+		/// Expose COM3 on the given TCP port, for the in-kernel GDB stub (zerOS
+		/// must be booted with `gdb=com3`)
+		gdb_stub: Option<u16>,
+
+		#[arg(long, value_name = "OPTIONS", default_value = "testing=0")]
+		/// Extra kernel command-line options, passed through QEMU's fw_cfg
+		/// (overriding the ones of the ISO)
+		kernel_args: String,
+
+		#[arg(long, default_value_t = false)]
+		/// Fail if QEMU is quit before the kernel exits by itself (e.g. when
+		/// running tests, or a panic action exiting QEMU)
+		expect_exit: bool,
+
 		#[arg(short = 'm', long = "ram", default_value = "1500M")]
 		memory: String,
 
@@ -245,6 +263,8 @@ fn run_zerOS_qemu_cmd(
 	cpu: &String,
 	num_cpus: &usize,
 	gdb_window: &bool,
+	gdb_stub_port: &Option<u16>,
+	kernel_args: &String,
 	memory: &String,
 	initial_wait: &bool,
 	emulator_args: &Vec<String>
@@ -253,6 +273,12 @@ fn run_zerOS_qemu_cmd(
 	let cfg = ZerosConfig::load_or_error();
 	let mut buf = itoa::Buffer::new();
 	let gdb_stub = globals.debug || *gdb_window;
+	let kernel_gdb_stub = gdb_stub_port.map(|port| format!("tcp::{port},server=on,wait=off"));
+	// commas must be doubled in QEMU option values
+	let kernel_fw_cfg = format!(
+		"name=opt/dev.nullware.zerOS,string={}",
+		kernel_args.replace(',', ",,")
+	);
 
 	match emulator
 	{
@@ -263,7 +289,7 @@ fn run_zerOS_qemu_cmd(
 			let mem = parse_qemu_mem(memory.clone());
 			qemu_args.extend_from_slice(&[
 				"-fw_cfg",
-				"name=opt/dev.nullware.zerOS,string=testing=0",
+				kernel_fw_cfg.as_str(),
 				"-m",
 				mem.as_str(),
 				"-smp",
@@ -277,9 +303,18 @@ fn run_zerOS_qemu_cmd(
 				"-debugcon",
 				"file:debugcon.log",
 				"-serial",
-				"stdio"
+				"stdio",
+				// COM2, where the `crash-dump` panic action writes by default
+				"-serial",
+				"file:crash.dump"
 			]);
 
+			if let Some(serial) = &kernel_gdb_stub
+			{
+				// COM3, for the in-kernel GDB stub
+				qemu_args.extend_from_slice(&["-serial", serial.as_str()]);
+			}
+
 			if matches!(accelerator, Accelerator::Kvm)
 			{
 				// qemu_args.extend_from_slice(&["-accel", "kvm"]);
@@ -315,11 +350,36 @@ fn run_zerOS_qemu_cmd(
 	}
 }
 
+/// Code the kernel writes to the `isa-debug-exit` device on success (see
+/// `ExitStatus::SUCCESS_CODE` in the kernel)
+const KERNEL_SUCCESS_CODE: i32 = 0x7f;
+
+/// Exit status of `xtask run`, given the one of QEMU
+///
+/// When the kernel exits through the `isa-debug-exit` device, QEMU exits with
+/// `(code << 1) | 1`: the success code is mapped to `0`, and the failure ones
+/// back to `code`. QEMU exits with `0` when it is quit before the kernel exits,
+/// which is only a failure if `expect_exit` is set. Any other status, e.g. `1`
+/// when QEMU fails to start, is a failure.
+fn kernel_exit_code(status: ExitStatus, expect_exit: bool) -> i32
+{
+	match status.code()
+	{
+		Some(code) if code == (KERNEL_SUCCESS_CODE << 1) | 1 => 0,
+		Some(code) if code & 1 != 0 && code > 1 => code >> 1,
+		Some(0) if expect_exit => 1,
+		Some(code) => code,
+		// killed by a signal
+		None => 1
+	}
+}
+
 impl Xtask for XtaskRunnableSubproj
 {
 	async fn execute(&self, globals: &XtaskGlobalOptions)
 	{
 		let mut spawned = vec![];
+		let mut expect_kernel_exit = false;
 		match self
 		{
 			Self::Zeros {
@@ -329,12 +389,17 @@ impl Xtask for XtaskRunnableSubproj
 				cpu,
 				num_cpus,
 				gdb_window,
+				gdb_stub,
+				kernel_args,
+				expect_exit,
 				memory,
 				initial_wait,
 				emulator_args
 			} =>
 			{
+				expect_kernel_exit = *expect_exit;
 				rm(false, false, &get_topdir().join("debugcon.log")).await;
+				rm(false, false, &get_topdir().join("crash.dump")).await;
 				spawned.push(task::spawn(
 					run_zerOS_qemu_cmd(
 						globals,
@@ -344,11 +409,13 @@ impl Xtask for XtaskRunnableSubproj
 						cpu,
 						num_cpus,
 						gdb_window,
+						gdb_stub,
+						kernel_args,
 						memory,
 						initial_wait,
 						emulator_args
 					)
-					.finalize()
+					.finalize_fallible()
 				));
 				if *gdb_window
 				{ /* todo!() */ }
@@ -358,7 +425,17 @@ impl Xtask for XtaskRunnableSubproj
 
 		for task in spawned
 		{
-			check!(task.await.expect("process exited abnormally"));
+			let status = check!(
+				task.await
+					.expect("process exited abnormally")
+					.expect("failed to spawn process")
+			);
+			let code = kernel_exit_code(status, expect_kernel_exit);
+			if code != 0
+			{
+				error!("zerOS exited with status {code}");
+				std::process::exit(code);
+			}
 		}
 	}
 }
@@ -390,4 +467,26 @@ mod tests
 			"1242580M".to_string()
 		);
 	}
+
+	#[test]
+	fn test_kernel_exit_code()
+	{
+		use std::os::unix::process::ExitStatusExt;
+
+		let qemu = |status: i32| kernel_exit_code(ExitStatus::from_raw(status << 8), true);
+		// `ExitStatus::Success`
+		assert_eq!(qemu((0x7f << 1) | 1), 0);
+		// `ExitStatus::Failure`, `0` being written as `1`
+		assert_eq!(qemu((1 << 1) | 1), 1);
+		assert_eq!(qemu((42 << 1) | 1), 42);
+		assert_eq!(qemu((0x7e << 1) | 1), 0x7e);
+		// QEMU failing to start, or quit before the kernel exits
+		assert_eq!(qemu(1), 1);
+		assert_eq!(qemu(0), 1);
+		// quitting QEMU interactively
+		assert_eq!(kernel_exit_code(ExitStatus::from_raw(0), false), 0);
+		assert_eq!(kernel_exit_code(ExitStatus::from_raw(1 << 8), false), 1);
+		// killed by a signal
+		assert_eq!(kernel_exit_code(ExitStatus::from_raw(9), true), 1);
+	}
 }
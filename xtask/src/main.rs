@@ -45,6 +45,7 @@ use crate::{
 		clean::XtaskCleanableSubproj,
 		clippy::XtaskClippyableSubproj,
 		configure::{XtaskConfigurableSubproj, config_location, init_default_executable_names},
+		crashdump::XtaskCrashDumpArgs,
 		expand::XtaskExpandableSubproj,
 		format::XtaskFormattableSubproj,
 		run::XtaskRunnableSubproj
@@ -130,6 +131,12 @@ enum XtaskSubcmd
 		#[command(subcommand)]
 		subproj: XtaskConfigurableSubproj
 	},
+	/// Decode (and symbolize) a crash dump written by zerOS
+	CrashDump
+	{
+		#[command(flatten)]
+		args: XtaskCrashDumpArgs
+	},
 	/// Expand macros in code from a subproject
 	Expand
 	{
@@ -266,6 +273,7 @@ fn main() -> Result<()>
 			XtaskSubcmd::Clean { subproj } => subproj.execute(&cli.globals).await,
 			XtaskSubcmd::Clippy { subproj } => subproj.execute(&cli.globals).await,
 			XtaskSubcmd::Configure { subproj } => subproj.execute(&cli.globals).await,
+			XtaskSubcmd::CrashDump { args } => args.execute(&cli.globals).await,
 			XtaskSubcmd::Expand { subproj } => subproj.execute(&cli.globals).await,
 			XtaskSubcmd::Format { subproj } => subproj.execute(&cli.globals).await,
 			XtaskSubcmd::Run { subproj } => subproj.execute(&cli.globals).await
@@ -504,6 +504,7 @@ macro_rules! KERNEL_SECTION_LIST {
 			"rodata",
 			"eh_frame_hdr",
 			"eh_frame",
+			"troll",
 			/* "debug_line", */
 			/* "debug_info", */
 			/* "debug_abbrev", */
@@ -0,0 +1,494 @@
+//! Local APIC driver
+//!
+//! The local APIC is driven through MSRs in x2APIC mode, which is used
+//! whenever the CPU supports it (and which the bootloader may already have
+//! enabled), and through its memory-mapped registers otherwise (xAPIC mode).
+//!
+//! Once initialized with [`init`], the local APIC acknowledges the interrupts
+//! dispatched by [`irq`](crate::kernel::irq), reports its own errors, and
+//! provides a timer (see [`start_timer`]) and inter-processor interrupts (see
+//! [`send_ipi`]).
+
+use core::{arch::asm, fmt};
+
+use portable_atomic::{AtomicBool, AtomicU8, AtomicU64, Ordering};
+
+use super::{
+	features::{self, Features},
+	misc::read_tsc,
+	msr::{self, ApicBase},
+	paging,
+	pit
+};
+use crate::{
+	info,
+	kernel::irq::{
+		self,
+		Irq,
+		IrqClass,
+		IrqContext,
+		IrqError,
+		IrqFlags,
+		IrqReturn,
+		SPURIOUS_VECTOR
+	},
+	warn
+};
+
+/// Offsets of the registers, in the xAPIC MMIO page
+mod reg
+{
+	pub const ID: u32 = 0x20;
+	pub const VERSION: u32 = 0x30;
+	pub const TPR: u32 = 0x80;
+	pub const EOI: u32 = 0xb0;
+	pub const SPURIOUS: u32 = 0xf0;
+	pub const ESR: u32 = 0x280;
+	pub const ICR_LOW: u32 = 0x300;
+	pub const ICR_HIGH: u32 = 0x310;
+	pub const LVT_TIMER: u32 = 0x320;
+	pub const LVT_ERROR: u32 = 0x370;
+	pub const TIMER_INITIAL: u32 = 0x380;
+	pub const TIMER_CURRENT: u32 = 0x390;
+	pub const TIMER_DIVIDE: u32 = 0x3e0;
+}
+
+const SOFTWARE_ENABLE: u32 = 1 << 8;
+const LVT_MASKED: u32 = 1 << 16;
+const DELIVERY_PENDING: u32 = 1 << 12;
+/// Divide the timer input clock by 16
+const TIMER_DIVIDE_BY_16: u32 = 0b0011;
+
+/// How long the timer is calibrated for, in microseconds
+const CALIBRATION_US: u64 = 10_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ApicMode
+{
+	/// Not initialized yet
+	Disabled,
+	/// Memory-mapped registers
+	XApic,
+	/// Registers mapped to MSRs
+	X2Apic
+}
+
+static MODE: AtomicU8 = AtomicU8::new(ApicMode::Disabled as u8);
+/// Virtual address of the xAPIC registers
+static XAPIC_BASE: AtomicU64 = AtomicU64::new(0);
+
+static INITIALIZED: AtomicBool = AtomicBool::new(false);
+static ERROR_VECTOR: AtomicU8 = AtomicU8::new(0);
+static TIMER_VECTOR: AtomicU8 = AtomicU8::new(0);
+
+/// Frequency of the timer (once divided), in Hz, or 0 if not calibrated
+static TIMER_FREQUENCY: AtomicU64 = AtomicU64::new(0);
+/// Frequency of the TSC, in Hz, or 0 if not calibrated
+static TSC_FREQUENCY: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ApicError
+{
+	/// The CPU has no local APIC
+	Unsupported,
+	/// The local APIC of the calling CPU hasn't been enabled with [`init`]
+	NotInitialized,
+	/// The xAPIC registers couldn't be mapped
+	Unmapped,
+	/// The TSC-deadline mode isn't supported
+	NoTscDeadline,
+	/// The timer hasn't been calibrated
+	NotCalibrated,
+	Irq(IrqError)
+}
+
+impl fmt::Display for ApicError
+{
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+	{
+		match self
+		{
+			Self::Unsupported => write!(f, "the CPU has no local APIC"),
+			Self::NotInitialized => write!(f, "the local APIC isn't initialized"),
+			Self::Unmapped => write!(f, "the xAPIC registers aren't mapped"),
+			Self::NoTscDeadline => write!(f, "the TSC-deadline timer mode isn't supported"),
+			Self::NotCalibrated => write!(f, "the local APIC timer isn't calibrated"),
+			Self::Irq(err) => write!(f, "{err}")
+		}
+	}
+}
+
+impl From<IrqError> for ApicError
+{
+	fn from(err: IrqError) -> Self
+	{
+		Self::Irq(err)
+	}
+}
+
+/// Mode the local APIC is driven in
+pub fn mode() -> ApicMode
+{
+	match MODE.load(Ordering::Acquire)
+	{
+		x if x == ApicMode::XApic as u8 => ApicMode::XApic,
+		x if x == ApicMode::X2Apic as u8 => ApicMode::X2Apic,
+		_ => ApicMode::Disabled
+	}
+}
+
+/// Address of the xAPIC register at `offset`
+fn xapic_register(offset: u32) -> *mut u32
+{
+	(XAPIC_BASE.load(Ordering::Relaxed) + u64::from(offset)) as *mut u32
+}
+
+/// # Panics
+///
+/// If [`init`] hasn't enabled the local APIC, as are all the functions
+/// accessing its registers, unless they can report an [`ApicError`].
+fn read(offset: u32) -> u32
+{
+	match mode()
+	{
+		ApicMode::X2Apic => msr::x2apic(offset / 16).read() as u32,
+		// SAFETY: the register page has been mapped by `init`
+		ApicMode::XApic =>
+		unsafe { xapic_register(offset).read_volatile() },
+		ApicMode::Disabled => panic!("the local APIC is used before `apic::init`")
+	}
+}
+
+/// # Panics
+///
+/// See [`read`].
+fn write(offset: u32, value: u32)
+{
+	match mode()
+	{
+		ApicMode::X2Apic => msr::x2apic(offset / 16).write(u64::from(value)),
+		// SAFETY: see `read`
+		ApicMode::XApic =>
+		unsafe { xapic_register(offset).write_volatile(value) },
+		ApicMode::Disabled => panic!("the local APIC is used before `apic::init`")
+	}
+}
+
+/// Write the interrupt command register, sending an IPI
+fn write_icr(destination: u32, command: u32)
+{
+	match mode()
+	{
+		ApicMode::X2Apic =>
+		{
+			msr::x2apic(reg::ICR_LOW / 16)
+				.write((u64::from(destination) << 32) | u64::from(command));
+		},
+		_ =>
+		{
+			write(reg::ICR_HIGH, destination << 24);
+			write(reg::ICR_LOW, command);
+			while read(reg::ICR_LOW) & DELIVERY_PENDING != 0
+			{
+				core::hint::spin_loop();
+			}
+		}
+	}
+}
+
+/// ID of the local APIC of the calling CPU
+pub fn id() -> u32
+{
+	match mode()
+	{
+		ApicMode::X2Apic => read(reg::ID),
+		_ => read(reg::ID) >> 24
+	}
+}
+
+/// Acknowledge the interrupt being handled
+pub fn end_of_interrupt()
+{
+	write(reg::EOI, 0);
+}
+
+fn handle_error(_: &IrqContext) -> IrqReturn
+{
+	const ERRORS: [&str; 8] = [
+		"send checksum error",
+		"receive checksum error",
+		"send accept error",
+		"receive accept error",
+		"redirectable IPI",
+		"send illegal vector",
+		"received illegal vector",
+		"illegal register address"
+	];
+
+	// the error status register is only updated when written to
+	write(reg::ESR, 0);
+	let status = read(reg::ESR);
+	for (bit, error) in ERRORS.iter().enumerate()
+	{
+		if status & (1 << bit) != 0
+		{
+			warn!("local APIC #{}: {error}", id());
+		}
+	}
+	IrqReturn::Handled
+}
+
+/// Allocate the vectors of the local APIC, and register its handlers
+fn init_vectors() -> Result<(), ApicError>
+{
+	let error = irq::allocate_vector(IrqClass::System)?;
+	let timer = irq::allocate_vector(IrqClass::System)?;
+	irq::request_irq(
+		Irq::Vector(error),
+		handle_error,
+		IrqFlags::empty(),
+		"APIC error"
+	)?;
+	ERROR_VECTOR.store(error, Ordering::Release);
+	TIMER_VECTOR.store(timer, Ordering::Release);
+	irq::set_end_of_interrupt(|_| end_of_interrupt());
+	Ok(())
+}
+
+/// Enable the local APIC of the calling CPU, in x2APIC mode if supported
+pub fn init() -> Result<ApicMode, ApicError>
+{
+	if !features::has(Features::APIC)
+	{
+		return Err(ApicError::Unsupported);
+	}
+
+	let base = msr::APIC_BASE.read();
+	if features::has(Features::X2APIC)
+	{
+		// x2APIC mode can only be entered from xAPIC mode
+		msr::APIC_BASE.write(base | ApicBase::ENABLE);
+		msr::APIC_BASE.write(base | ApicBase::ENABLE | ApicBase::X2APIC);
+		MODE.store(ApicMode::X2Apic as u8, Ordering::Release);
+	}
+	else
+	{
+		// SAFETY: the register page isn't RAM
+		let registers = unsafe { paging::map_physical(base.address(), paging::PAGE_SIZE, true) }
+			.map_err(|_| ApicError::Unmapped)?;
+		msr::APIC_BASE.write(base | ApicBase::ENABLE);
+		XAPIC_BASE.store(registers, Ordering::Relaxed);
+		MODE.store(ApicMode::XApic as u8, Ordering::Release);
+	}
+
+	if !INITIALIZED.swap(true, Ordering::AcqRel)
+	{
+		init_vectors()?;
+	}
+
+	// accept all interrupts
+	write(reg::TPR, 0);
+	write(
+		reg::LVT_ERROR,
+		u32::from(ERROR_VECTOR.load(Ordering::Acquire))
+	);
+	write(
+		reg::LVT_TIMER,
+		LVT_MASKED | u32::from(TIMER_VECTOR.load(Ordering::Acquire))
+	);
+	// clear the errors which may have been recorded so far
+	write(reg::ESR, 0);
+	write(reg::ESR, 0);
+	write(reg::SPURIOUS, SOFTWARE_ENABLE | u32::from(SPURIOUS_VECTOR));
+
+	let mode = mode();
+	info!(
+		"local APIC #{} enabled in {mode:?} mode (version {:#x})",
+		id(),
+		read(reg::VERSION) & 0xff
+	);
+	Ok(mode)
+}
+
+/// Vector the timer interrupts are delivered to
+///
+/// Handlers of timer interrupts are registered on it with
+/// [`request_irq`](irq::request_irq).
+pub fn timer_vector() -> u8
+{
+	TIMER_VECTOR.load(Ordering::Acquire)
+}
+
+/// Measure the frequencies of the timer and of the TSC against the PIT
+pub fn calibrate_timer()
+{
+	write(reg::TIMER_DIVIDE, TIMER_DIVIDE_BY_16);
+	write(
+		reg::LVT_TIMER,
+		LVT_MASKED | u32::from(TIMER_VECTOR.load(Ordering::Acquire))
+	);
+	write(reg::TIMER_INITIAL, u32::MAX);
+	let tsc = read_tsc();
+	pit::busy_wait(CALIBRATION_US);
+	let elapsed = u32::MAX - read(reg::TIMER_CURRENT);
+	let tsc = read_tsc() - tsc;
+	write(reg::TIMER_INITIAL, 0);
+
+	let timer_frequency = u64::from(elapsed) * 1_000_000 / CALIBRATION_US;
+	let tsc_frequency = tsc * 1_000_000 / CALIBRATION_US;
+	TIMER_FREQUENCY.store(timer_frequency, Ordering::Release);
+	TSC_FREQUENCY.store(tsc_frequency, Ordering::Release);
+	info!(
+		"local APIC timer: {} kHz, TSC: {} kHz",
+		timer_frequency / 1000,
+		tsc_frequency / 1000
+	);
+}
+
+/// Frequency of the TSC, in Hz, as measured by [`calibrate_timer`]
+pub fn tsc_frequency() -> Option<u64>
+{
+	Some(TSC_FREQUENCY.load(Ordering::Acquire)).filter(|&frequency| frequency != 0)
+}
+
+/// Whether the timer supports [`TimerMode::TscDeadline`]
+pub fn has_tsc_deadline() -> bool
+{
+	features::has(Features::TSC_DEADLINE)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimerMode
+{
+	/// Fire once, after the given delay
+	OneShot,
+	/// Fire repeatedly, with the given period
+	Periodic,
+	/// Fire once, when the TSC reaches the deadline computed from the given
+	/// delay
+	TscDeadline
+}
+
+/// Start the timer of the calling CPU, to fire after `ns` nanoseconds
+pub fn start_timer(mode: TimerMode, ns: u64) -> Result<(), ApicError>
+{
+	if self::mode() == ApicMode::Disabled
+	{
+		return Err(ApicError::NotInitialized);
+	}
+	let vector = u32::from(TIMER_VECTOR.load(Ordering::Acquire));
+	match mode
+	{
+		TimerMode::OneShot | TimerMode::Periodic =>
+		{
+			let frequency = TIMER_FREQUENCY.load(Ordering::Acquire);
+			if frequency == 0
+			{
+				return Err(ApicError::NotCalibrated);
+			}
+			let ticks = (u128::from(ns) * u128::from(frequency) / 1_000_000_000)
+				.clamp(1, u128::from(u32::MAX)) as u32;
+			let periodic = if mode == TimerMode::Periodic
+			{
+				0b01 << 17
+			}
+			else
+			{
+				0
+			};
+			write(reg::TIMER_DIVIDE, TIMER_DIVIDE_BY_16);
+			write(reg::LVT_TIMER, periodic | vector);
+			write(reg::TIMER_INITIAL, ticks);
+		},
+		TimerMode::TscDeadline =>
+		{
+			if !has_tsc_deadline()
+			{
+				return Err(ApicError::NoTscDeadline);
+			}
+			let frequency = tsc_frequency().ok_or(ApicError::NotCalibrated)?;
+			let ticks = (u128::from(ns) * u128::from(frequency) / 1_000_000_000) as u64;
+			write(reg::LVT_TIMER, (0b10 << 17) | vector);
+			// the MMIO write of the mode must be done before the deadline is
+			// written, which `WRMSR` doesn't wait for (see the SDM, 11.5.4.1)
+			if self::mode() == ApicMode::XApic
+			{
+				// SAFETY: just a fence
+				unsafe { asm!("mfence", options(att_syntax, nostack, preserves_flags)) };
+			}
+			msr::TSC_DEADLINE.write(read_tsc() + ticks.max(1));
+		}
+	}
+	Ok(())
+}
+
+/// Stop the timer of the calling CPU
+pub fn stop_timer()
+{
+	write(
+		reg::LVT_TIMER,
+		LVT_MASKED | u32::from(TIMER_VECTOR.load(Ordering::Acquire))
+	);
+	write(reg::TIMER_INITIAL, 0);
+	if has_tsc_deadline()
+	{
+		msr::TSC_DEADLINE.write(0);
+	}
+}
+
+/// Which CPUs an IPI is sent to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IpiDestination
+{
+	/// The CPU with the given APIC ID
+	Apic(u32),
+	/// The calling CPU (not allowed for INIT and SIPIs)
+	Current,
+	/// All the CPUs, including the calling one
+	All,
+	/// All the CPUs, except the calling one
+	Others
+}
+
+/// What an IPI asks its destination to do
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IpiKind
+{
+	/// Raise the interrupt vector
+	Fixed(u8),
+	/// Raise a non-maskable interrupt
+	Nmi,
+	/// Reset the CPU into its wait-for-SIPI state
+	Init,
+	/// Start the CPU in real mode, at the physical page with the given number
+	Startup(u8)
+}
+
+/// Send an inter-processor interrupt
+pub fn send_ipi(destination: IpiDestination, kind: IpiKind)
+{
+	const LEVEL_ASSERT: u32 = 1 << 14;
+
+	let command = match kind
+	{
+		IpiKind::Fixed(vector) => u32::from(vector),
+		IpiKind::Nmi => 0b100 << 8,
+		IpiKind::Init => (0b101 << 8) | LEVEL_ASSERT,
+		IpiKind::Startup(page) => (0b110 << 8) | u32::from(page)
+	};
+	let (apic, shorthand) = match destination
+	{
+		IpiDestination::Apic(apic) => (apic, 0b00),
+		IpiDestination::Current => (0, 0b01),
+		IpiDestination::All => (0, 0b10),
+		IpiDestination::Others => (0, 0b11)
+	};
+	write_icr(apic, command | (shorthand << 18));
+}
+
+/// Send the IPI to all the CPUs but the calling one
+pub fn broadcast_ipi(kind: IpiKind)
+{
+	send_ipi(IpiDestination::Others, kind);
+}
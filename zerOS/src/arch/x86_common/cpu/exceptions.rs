@@ -0,0 +1,680 @@
+//! CPU exception handlers
+//!
+//! Each of the 32 architectural exceptions has a small entry stub, pushing a
+//! dummy error code when the CPU doesn't push one, and the vector number. They
+//! all continue in a common stub which saves the general purpose registers
+//! (and `CR2`/`CR3`), and calls [`dispatch`] with the resulting
+//! [`InterruptFrame`]. Any change made to the frame is applied when returning
+//! from the exception. The FPU and SIMD registers are saved by [`dispatch`],
+//! within a [`fpu::kernel_fpu_begin`] guard.
+//!
+//! Breakpoints and debug traps are first forwarded to the GDB stub (see
+//! [`gdb`]). The other exceptions are reported with a register dump and a
+//! backtrace, then handed to the GDB stub if it is enabled, and end up in a
+//! panic otherwise.
+//!
+//! The other vectors (i.e. interrupts) share the same common stub, and are
+//! handed over to [`irq::dispatch`].
+//!
+//! Exceptions raised in user mode aren't handled here, but handed back to the
+//! kernel code which entered user mode (see [`user`]).
+
+use core::{arch::naked_asm, fmt};
+
+use super::{
+	dbgregs::{self, WatchKind},
+	extable,
+	fpu,
+	idt::VECTOR_COUNT,
+	user
+};
+use crate::{
+	error,
+	kernel::{
+		gdb::{self, GdbRegisters},
+		irq,
+		memory::stack
+	},
+	unwinding::{self, RegisterSet},
+	warn
+};
+
+/// Vector of debug exceptions (`#DB`)
+pub const DEBUG: usize = 1;
+/// Vector of non-maskable interrupts
+pub const NMI: usize = 2;
+/// Vector of breakpoints (`#BP`)
+pub const BREAKPOINT: usize = 3;
+/// Vector of device not available exceptions (`#NM`)
+pub const DEVICE_NOT_AVAILABLE: usize = 7;
+/// Vector of double faults (`#DF`)
+pub const DOUBLE_FAULT: usize = 8;
+/// Vector of general protection faults (`#GP`)
+pub const GENERAL_PROTECTION: usize = 13;
+/// Vector of page faults (`#PF`)
+pub const PAGE_FAULT: usize = 14;
+/// Vector of machine checks (`#MC`)
+pub const MACHINE_CHECK: usize = 18;
+
+/// Number of architectural exception vectors
+pub const EXCEPTION_COUNT: usize = 32;
+
+/// Trap flag of `RFLAGS`
+const TF: u64 = 1 << 8;
+/// Resume flag of `RFLAGS`, suppressing instruction breakpoints for one
+/// instruction
+const RF: u64 = 1 << 16;
+
+/// Mnemonic, name, and whether the CPU pushes an error code, of each exception
+const EXCEPTIONS: [(&str, &str, bool); EXCEPTION_COUNT] = [
+	("#DE", "divide error", false),
+	("#DB", "debug exception", false),
+	("NMI", "non-maskable interrupt", false),
+	("#BP", "breakpoint", false),
+	("#OF", "overflow", false),
+	("#BR", "bound range exceeded", false),
+	("#UD", "invalid opcode", false),
+	("#NM", "device not available", false),
+	("#DF", "double fault", true),
+	("#MP", "coprocessor segment overrun", false),
+	("#TS", "invalid TSS", true),
+	("#NP", "segment not present", true),
+	("#SS", "stack-segment fault", true),
+	("#GP", "general protection fault", true),
+	("#PF", "page fault", true),
+	("#15", "reserved", false),
+	("#MF", "x87 floating-point error", false),
+	("#AC", "alignment check", true),
+	("#MC", "machine check", false),
+	("#XM", "SIMD floating-point exception", false),
+	("#VE", "virtualization exception", false),
+	("#CP", "control protection exception", true),
+	("#22", "reserved", false),
+	("#23", "reserved", false),
+	("#24", "reserved", false),
+	("#25", "reserved", false),
+	("#26", "reserved", false),
+	("#27", "reserved", false),
+	("#HV", "hypervisor injection exception", false),
+	("#VC", "VMM communication exception", true),
+	("#SX", "security exception", true),
+	("#31", "reserved", false)
+];
+
+/// The frame pushed by the CPU when an interrupt or an exception happens
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct InterruptStackFrame
+{
+	pub rip:    u64,
+	pub cs:     u64,
+	pub rflags: u64,
+	pub rsp:    u64,
+	pub ss:     u64
+}
+
+/// The full state of the CPU when an exception happened, as saved by the
+/// entry stubs
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct InterruptFrame
+{
+	pub cr3:        u64,
+	/// Faulting address of the last page fault
+	pub cr2:        u64,
+	pub r15:        u64,
+	pub r14:        u64,
+	pub r13:        u64,
+	pub r12:        u64,
+	pub r11:        u64,
+	pub r10:        u64,
+	pub r9:         u64,
+	pub r8:         u64,
+	pub rbp:        u64,
+	pub rdi:        u64,
+	pub rsi:        u64,
+	pub rdx:        u64,
+	pub rcx:        u64,
+	pub rbx:        u64,
+	pub rax:        u64,
+	pub vector:     u64,
+	/// Error code pushed by the CPU, or 0 if the exception has none
+	pub error_code: u64,
+	pub cpu:        InterruptStackFrame
+}
+
+impl InterruptFrame
+{
+	/// Mnemonic of the exception (e.g. `#PF`)
+	pub fn mnemonic(&self) -> &'static str
+	{
+		EXCEPTIONS[self.vector as usize % EXCEPTION_COUNT].0
+	}
+
+	/// Name of the exception (e.g. `page fault`)
+	pub fn name(&self) -> &'static str
+	{
+		EXCEPTIONS[self.vector as usize % EXCEPTION_COUNT].1
+	}
+
+	/// Whether the CPU pushed an error code for this exception
+	pub fn has_error_code(&self) -> bool
+	{
+		EXCEPTIONS[self.vector as usize % EXCEPTION_COUNT].2
+	}
+
+	/// Registers of the interrupted code, to unwind its stack
+	pub fn register_set(&self) -> RegisterSet
+	{
+		RegisterSet::from_snapshot([
+			self.rax,
+			self.rdx,
+			self.rcx,
+			self.rbx,
+			self.rsi,
+			self.rdi,
+			self.rbp,
+			self.cpu.rsp,
+			self.r8,
+			self.r9,
+			self.r10,
+			self.r11,
+			self.r12,
+			self.r13,
+			self.r14,
+			self.r15,
+			self.cpu.rip
+		])
+	}
+
+	/// Registers of the interrupted code, as GDB expects them
+	pub fn gdb_registers(&self) -> GdbRegisters
+	{
+		let mut regs = GdbRegisters::from_register_set(&self.register_set());
+		regs.regs[GdbRegisters::FLAGS] = self.cpu.rflags;
+		regs
+	}
+
+	/// Apply registers modified by GDB
+	pub fn set_gdb_registers(&mut self, regs: &GdbRegisters)
+	{
+		let [
+			rax,
+			rbx,
+			rcx,
+			rdx,
+			rsi,
+			rdi,
+			rbp,
+			rsp,
+			r8,
+			r9,
+			r10,
+			r11,
+			r12,
+			r13,
+			r14,
+			r15,
+			..
+		] = regs.regs;
+		*self = Self {
+			r15,
+			r14,
+			r13,
+			r12,
+			r11,
+			r10,
+			r9,
+			r8,
+			rbp,
+			rdi,
+			rsi,
+			rdx,
+			rcx,
+			rbx,
+			rax,
+			cpu: InterruptStackFrame {
+				rip: regs.pc(),
+				rflags: regs.regs[GdbRegisters::FLAGS],
+				rsp,
+				..self.cpu
+			},
+			..*self
+		};
+	}
+}
+
+impl fmt::Display for InterruptFrame
+{
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+	{
+		writeln!(
+			f,
+			"rax = {:#018x}  rbx = {:#018x}  rcx = {:#018x}  rdx = {:#018x}",
+			self.rax, self.rbx, self.rcx, self.rdx
+		)?;
+		writeln!(
+			f,
+			"rsi = {:#018x}  rdi = {:#018x}  rbp = {:#018x}  rsp = {:#018x}",
+			self.rsi, self.rdi, self.rbp, self.cpu.rsp
+		)?;
+		writeln!(
+			f,
+			"r8  = {:#018x}  r9  = {:#018x}  r10 = {:#018x}  r11 = {:#018x}",
+			self.r8, self.r9, self.r10, self.r11
+		)?;
+		writeln!(
+			f,
+			"r12 = {:#018x}  r13 = {:#018x}  r14 = {:#018x}  r15 = {:#018x}",
+			self.r12, self.r13, self.r14, self.r15
+		)?;
+		writeln!(
+			f,
+			"rip = {:#018x}  rflags = {:#010x}  cs = {:#06x}  ss = {:#06x}",
+			self.cpu.rip, self.cpu.rflags, self.cpu.cs, self.cpu.ss
+		)?;
+		write!(f, "cr2 = {:#018x}  cr3 = {:#018x}", self.cr2, self.cr3)
+	}
+}
+
+/// Human-readable decoding of the error code of an exception
+pub struct ErrorCode
+{
+	pub vector: usize,
+	pub code:   u64
+}
+
+impl fmt::Display for ErrorCode
+{
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+	{
+		let code = self.code;
+		match self.vector
+		{
+			PAGE_FAULT =>
+			{
+				const FLAGS: [(u64, &str, &str); 5] = [
+					(1 << 0, "protection violation", "page not present"),
+					(1 << 1, "write", "read"),
+					(1 << 2, "user mode", "kernel mode"),
+					(1 << 3, "reserved bit set", ""),
+					(1 << 4, "instruction fetch", "")
+				];
+
+				let mut first = true;
+				let mut flag = |f: &mut fmt::Formatter<'_>, name: &str| {
+					let separator = if first { "" } else { ", " };
+					first = false;
+					write!(f, "{separator}{name}")
+				};
+				for (mask, set, unset) in FLAGS
+				{
+					let name = if code & mask != 0 { set } else { unset };
+					if !name.is_empty()
+					{
+						flag(f, name)?;
+					}
+				}
+				if code & (1 << 5) != 0
+				{
+					flag(f, "protection key")?;
+				}
+				if code & (1 << 6) != 0
+				{
+					flag(f, "shadow stack")?;
+				}
+				if code & (1 << 15) != 0
+				{
+					flag(f, "SGX")?;
+				}
+				Ok(())
+			},
+			// selector error codes
+			10..=13 if code != 0 =>
+			{
+				let table = match (code >> 1) & 0b11
+				{
+					0b00 => "GDT",
+					0b10 => "LDT",
+					_ => "IDT"
+				};
+				write!(f, "{table} entry #{}", (code >> 3) & 0x1fff)?;
+				if code & 1 != 0
+				{
+					write!(f, ", external event")?;
+				}
+				Ok(())
+			},
+			_ => write!(f, "{code:#x}")
+		}
+	}
+}
+
+/// Report the exception described by `frame`, with a register dump and a
+/// backtrace
+fn report(frame: &InterruptFrame)
+{
+	let error_code = ErrorCode {
+		vector: frame.vector as usize,
+		code:   frame.error_code
+	};
+	if frame.has_error_code()
+	{
+		error!(
+			"CPU exception {} ({}) on cpu #{}, error code {:#x} ({error_code}):\n{frame}",
+			frame.mnemonic(),
+			frame.name(),
+			super::current_cpu_index(),
+			frame.error_code
+		);
+	}
+	else
+	{
+		error!(
+			"CPU exception {} ({}) on cpu #{}:\n{frame}",
+			frame.mnemonic(),
+			frame.name(),
+			super::current_cpu_index()
+		);
+	}
+	unwinding::print_backtrace(frame.register_set(), log::Level::Error);
+}
+
+/// Let the GDB stub handle the exception, returning whether it did
+fn forward_to_gdb(frame: &mut InterruptFrame) -> bool
+{
+	let mut regs = frame.gdb_registers();
+	if !gdb::handle_exception(frame.vector as u8, &mut regs)
+	{
+		return false;
+	}
+	frame.set_gdb_registers(&regs);
+	true
+}
+
+/// Log the hardware breakpoints which triggered a debug exception, returning
+/// whether any did
+fn report_watchpoints(frame: &mut InterruptFrame) -> bool
+{
+	let triggered = dbgregs::take_triggered();
+	for slot in (0..dbgregs::BREAKPOINT_COUNT).filter(|&slot| triggered & (1 << slot) != 0)
+	{
+		let Some(watchpoint) = dbgregs::watchpoint(slot)
+		else
+		{
+			continue;
+		};
+		warn!(
+			"hardware breakpoint #{slot} hit on cpu #{} (rip = {:#x}): {:?} of {} bytes at {:#x}",
+			super::current_cpu_index(),
+			frame.cpu.rip,
+			watchpoint.kind,
+			watchpoint.len.bytes(),
+			watchpoint.addr
+		);
+		// instruction breakpoints are faults, which would trigger again
+		if watchpoint.kind == WatchKind::Execute
+		{
+			frame.cpu.rflags |= RF;
+		}
+	}
+	triggered != 0
+}
+
+/// Common handler of all the exceptions and interrupts
+extern "C" fn dispatch(frame: &mut InterruptFrame)
+{
+	handle(frame);
+	// unless it left user mode, the handler returns to it or to the kernel
+	// code about to enter it
+	fpu::return_to_context();
+}
+
+fn handle(frame: &mut InterruptFrame)
+{
+	let vector = frame.vector as usize;
+	// the first use of the FPU since entering user mode with the lazy policy
+	if vector == DEVICE_NOT_AVAILABLE && fpu::handle_device_not_available(frame.cpu.cs & 3 == 3)
+	{
+		return;
+	}
+	// exceptions and system calls from user mode are handed back to the
+	// kernel code which entered it, except for the ones running on an IST
+	// stack, which must return with `iretq` (to unblock NMIs, and free the
+	// stack): they aren't caused by the user code anyway
+	if frame.cpu.cs & 3 == 3
+		&& ((vector < EXCEPTION_COUNT && !matches!(vector, NMI | DOUBLE_FAULT | MACHINE_CHECK))
+			|| frame.vector == user::SYSCALL_VECTOR)
+	{
+		user::leave(frame);
+	}
+	// kernel code expecting the fault (e.g. probing an MSR)
+	if vector == GENERAL_PROTECTION
+		&& let Some(fixup) = extable::fixup(frame.cpu.rip)
+	{
+		frame.cpu.rip = fixup;
+		return;
+	}
+	if vector >= EXCEPTION_COUNT
+	{
+		irq::dispatch(vector as u8, frame);
+		return;
+	}
+	// the stubs only save the general purpose registers, while the handlers
+	// may use SIMD instructions
+	let _fpu = fpu::try_kernel_fpu_begin();
+	if matches!(vector, DEBUG | BREAKPOINT) && forward_to_gdb(frame)
+	{
+		return;
+	}
+	if vector == DEBUG && report_watchpoints(frame)
+	{
+		return;
+	}
+
+	report(frame);
+	match vector
+	{
+		// traps, resuming after the instruction which triggered them
+		DEBUG | BREAKPOINT =>
+		{
+			if vector == DEBUG && frame.cpu.rflags & TF != 0
+			{
+				warn!("stray single-step trap, clearing the trap flag");
+				frame.cpu.rflags &= !TF;
+			}
+		},
+		DOUBLE_FAULT =>
+		{
+			// the stack can't take an exception frame anymore, which is a
+			// double fault when the original fault was caused by pushing on it
+			if let Some(stack) = stack::overflowed_stack(frame.cpu.rsp)
+			{
+				panic!(
+					"kernel stack overflow in task {stack} (rsp = {:#x}, rip = {:#x}, stack = \
+					 {:#x}..{:#x})",
+					frame.cpu.rsp, frame.cpu.rip, stack.bottom, stack.top
+				);
+			}
+			panic!(
+				"double fault (rsp = {:#x}, rip = {:#x})",
+				frame.cpu.rsp, frame.cpu.rip
+			);
+		},
+		// the CPU state can't be trusted enough to hand it over to GDB
+		NMI | MACHINE_CHECK =>
+		{
+			panic!(
+				"unhandled CPU exception {} ({}) at {:#x}",
+				frame.mnemonic(),
+				frame.name(),
+				frame.cpu.rip
+			)
+		},
+		_ =>
+		{
+			if !forward_to_gdb(frame)
+			{
+				panic!(
+					"unhandled CPU exception {} ({}) at {:#x}",
+					frame.mnemonic(),
+					frame.name(),
+					frame.cpu.rip
+				);
+			}
+		}
+	}
+}
+
+/// Switch to the kernel `GS` base when coming from user mode, and continue in
+/// [`save_and_dispatch`]
+///
+/// Whether to swap is decided from the privilege level of the interrupted
+/// code, which is wrong for an NMI or a machine check hitting the few
+/// instructions between a `swapgs` and the return to user mode: these end up
+/// in a panic anyway.
+#[unsafe(naked)]
+extern "C" fn common_entry() -> !
+{
+	naked_asm! {
+		// the CS pushed by the CPU, above the vector and the error code
+		"testb $3, 24(%rsp)",
+		"jz 1f",
+		"swapgs",
+		"1:",
+		"jmp {save_and_dispatch}",
+		save_and_dispatch = sym save_and_dispatch,
+		options(att_syntax)
+	}
+}
+
+/// Save the registers, call [`dispatch`], and return from the exception or
+/// interrupt
+///
+/// Expects the `GS` base of the kernel, and a hardware frame topped with an
+/// error code and a vector on the stack.
+#[unsafe(naked)]
+pub(super) extern "C" fn save_and_dispatch() -> !
+{
+	naked_asm! {
+		"pushq %rax",
+		"pushq %rbx",
+		"pushq %rcx",
+		"pushq %rdx",
+		"pushq %rsi",
+		"pushq %rdi",
+		"pushq %rbp",
+		"pushq %r8",
+		"pushq %r9",
+		"pushq %r10",
+		"pushq %r11",
+		"pushq %r12",
+		"pushq %r13",
+		"pushq %r14",
+		"pushq %r15",
+		"movq %cr2, %rax",
+		"pushq %rax",
+		"movq %cr3, %rax",
+		"pushq %rax",
+		// the stack is 16-byte aligned: the CPU aligns it before pushing its
+		// frame, and 24 quadwords were pushed since
+		"movq %rsp, %rdi",
+		"cld",
+		"call {dispatch}",
+		"jmp {restore_and_return}",
+		dispatch = sym dispatch,
+		restore_and_return = sym restore_and_return,
+		options(att_syntax)
+	}
+}
+
+/// Restore the registers from the [`InterruptFrame`] on top of the stack, and
+/// return to the code it describes, switching back to the user `GS` base if
+/// it runs in user mode
+#[unsafe(naked)]
+pub(super) extern "C" fn restore_and_return() -> !
+{
+	naked_asm! {
+		"addq $16, %rsp",
+		"popq %r15",
+		"popq %r14",
+		"popq %r13",
+		"popq %r12",
+		"popq %r11",
+		"popq %r10",
+		"popq %r9",
+		"popq %r8",
+		"popq %rbp",
+		"popq %rdi",
+		"popq %rsi",
+		"popq %rdx",
+		"popq %rcx",
+		"popq %rbx",
+		"popq %rax",
+		// vector and error code
+		"addq $16, %rsp",
+		"testb $3, 8(%rsp)",
+		"jz 1f",
+		"swapgs",
+		"1:",
+		"iretq",
+		options(att_syntax)
+	}
+}
+
+macro_rules! entry_stubs {
+	($($vector:literal),* $(,)?) => {
+		/// Entry stub of each exception, to put in the IDT
+		pub(super) const ENTRY_STUBS: [extern "C" fn() -> !; EXCEPTION_COUNT] = [$({
+			#[unsafe(naked)]
+			extern "C" fn stub() -> !
+			{
+				naked_asm! {
+					".if {has_error_code} == 0",
+					"pushq $0",
+					".endif",
+					"pushq ${vector}",
+					"jmp {common}",
+					has_error_code = const EXCEPTIONS[$vector].2 as u8,
+					vector = const $vector,
+					common = sym common_entry,
+					options(att_syntax)
+				}
+			}
+			stub
+		}),*];
+	};
+}
+
+entry_stubs!(
+	0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23, 24, 25,
+	26, 27, 28, 29, 30, 31
+);
+
+/// Size of each entry stub of [`irq_entries`]
+pub(super) const IRQ_ENTRY_SIZE: usize = 16;
+
+/// Entry stubs of the interrupt vectors, from [`EXCEPTION_COUNT`] onwards,
+/// each of them being [`IRQ_ENTRY_SIZE`] bytes long
+#[unsafe(naked)]
+pub(super) extern "C" fn irq_entries() -> !
+{
+	naked_asm! {
+		".set zeros_irq_vector, {first}",
+		".rept {count}",
+		"1:",
+		// interrupts have no error code
+		"pushq $0",
+		"pushq $zeros_irq_vector",
+		"jmp {common}",
+		// pad each entry to the same size, with `int3`s
+		".fill {size} - (. - 1b), 1, 0xcc",
+		".set zeros_irq_vector, zeros_irq_vector + 1",
+		".endr",
+		first = const EXCEPTION_COUNT,
+		count = const VECTOR_COUNT - EXCEPTION_COUNT,
+		size = const IRQ_ENTRY_SIZE,
+		common = sym common_entry,
+		options(att_syntax)
+	}
+}
@@ -0,0 +1,66 @@
+//! Exception table
+//!
+//! Instructions which may fault in a way the kernel can recover from (e.g.
+//! `rdmsr` on an MSR the CPU doesn't implement) are recorded in the `.extable`
+//! section with [`extable_entry`], along with the address execution resumes at
+//! when they do. Before treating a general protection fault in the kernel as
+//! fatal, the exception dispatcher looks the faulting instruction up there.
+
+use crate::kernel::linker::LinkerSym;
+
+#[unsafe(link_section = ".extable")]
+#[used(linker)]
+static _SECTION_PLACE_HOLDER: [Entry; 0] = [];
+
+unsafe extern "C" {
+	unsafe static __extable_start: LinkerSym;
+	unsafe static __extable_end: LinkerSym;
+}
+
+#[repr(C)]
+struct Entry
+{
+	/// Address of the instruction which may fault
+	fault: u64,
+	/// Address to resume at if it does
+	fixup: u64
+}
+
+/// Record, in an `asm!` template, that the instruction at label `$fault`
+/// resumes at label `$fixup` when it faults
+///
+/// The labels are given as local label references (e.g. `"2b"`).
+macro_rules! extable_entry {
+	($fault:literal, $fixup:literal) => {
+		concat!(
+			".pushsection .extable, \"a\"\n",
+			".balign 8\n",
+			".quad ",
+			$fault,
+			", ",
+			$fixup,
+			"\n",
+			".popsection"
+		)
+	};
+}
+pub(crate) use extable_entry;
+
+fn entries() -> &'static [Entry]
+{
+	let start = (&raw const __extable_start).cast::<Entry>();
+	let end = (&raw const __extable_end).cast::<Entry>();
+	// SAFETY: the linker script puts the section between these symbols, and
+	//         it only contains entries
+	unsafe { core::slice::from_raw_parts(start, end.offset_from_unsigned(start)) }
+}
+
+/// The address execution should resume at after a fault at `rip`, if the
+/// faulting instruction is in the exception table
+pub fn fixup(rip: u64) -> Option<u64>
+{
+	entries()
+		.iter()
+		.find(|entry| entry.fault == rip)
+		.map(|entry| entry.fixup)
+}
@@ -0,0 +1,436 @@
+//! CPU feature detection and enabling
+//!
+//! Each CPU detects its own features with CPUID, then enables them in two
+//! steps:
+//! - [`init`], before anything else runs, enables the x87, SSE, AVX and AVX-512
+//!   state (with `FXSAVE` or `XSAVE`), as the Rust code is compiled for the
+//!   target CPU (with `-Ctarget-cpu`) and may use them, and [`check_required`]
+//!   then refuses to boot if a feature the kernel has been compiled for is
+//!   missing;
+//! - [`configure`], once the command line has been parsed, enables the
+//!   protections (UMIP, SMEP, PKU) and `FSGSBASE`, unless disabled with
+//!   `nocpufeatures=...`. SMAP is only enabled when asked for with
+//!   `cpufeatures=smap`, as the kernel doesn't bracket its accesses to user
+//!   memory with `STAC`/`CLAC` yet.
+
+use core::fmt;
+
+use bitflags::bitflags;
+use portable_atomic::{AtomicU64, AtomicUsize, Ordering};
+
+use super::{
+	MAX_CPU_COUNT,
+	cpuid,
+	ctlregs::{Cr0, Cr4, cr0, cr4},
+	current_cpu_index,
+	xctlregs::{Xcr0, xcr0}
+};
+use crate::info;
+
+bitflags! {
+	/// Features a CPU may support
+	#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+	pub struct Features: u64
+	{
+		const FPU = 1 << 0;
+		const TSC = 1 << 1;
+		const PAE = 1 << 2;
+		const APIC = 1 << 3;
+		const X2APIC = 1 << 4;
+		const TSC_DEADLINE = 1 << 5;
+		const FXSR = 1 << 6;
+		const SSE = 1 << 7;
+		const SSE2 = 1 << 8;
+		const SSE3 = 1 << 9;
+		const SSSE3 = 1 << 10;
+		const SSE4_1 = 1 << 11;
+		const SSE4_2 = 1 << 12;
+		const POPCNT = 1 << 13;
+		const PCLMULQDQ = 1 << 14;
+		const AES = 1 << 15;
+		const CMPXCHG16B = 1 << 16;
+		const MOVBE = 1 << 17;
+		const RDRAND = 1 << 18;
+		const RDSEED = 1 << 19;
+		const XSAVE = 1 << 20;
+		const XSAVEOPT = 1 << 21;
+		const XSAVEC = 1 << 22;
+		const XSAVES = 1 << 23;
+		const AVX = 1 << 24;
+		const AVX2 = 1 << 25;
+		const FMA = 1 << 26;
+		const F16C = 1 << 27;
+		const BMI1 = 1 << 28;
+		const BMI2 = 1 << 29;
+		const LZCNT = 1 << 30;
+		const ADX = 1 << 31;
+		const AVX512F = 1 << 32;
+		const AVX512DQ = 1 << 33;
+		const AVX512CD = 1 << 34;
+		const AVX512BW = 1 << 35;
+		const AVX512VL = 1 << 36;
+		const FSGSBASE = 1 << 37;
+		const UMIP = 1 << 38;
+		const SMEP = 1 << 39;
+		const SMAP = 1 << 40;
+		const PKU = 1 << 41;
+		const NX = 1 << 42;
+		const PAGE_1GB = 1 << 43;
+		const RDTSCP = 1 << 44;
+		const INVARIANT_TSC = 1 << 45;
+		const HYPERVISOR = 1 << 46;
+		const MSR = 1 << 47;
+		const PAT = 1 << 48;
+		const SYSCALL = 1 << 49;
+	}
+}
+
+/// Features which can be enabled or disabled on the command line
+pub const CONFIGURABLE: Features = Features::FSGSBASE
+	.union(Features::UMIP)
+	.union(Features::SMEP)
+	.union(Features::SMAP)
+	.union(Features::PKU);
+/// Features among [`CONFIGURABLE`] which are only enabled on demand
+pub const OPT_IN: Features = Features::SMAP;
+
+/// Features depending on the SSE state being enabled
+const SSE_FEATURES: Features = Features::SSE
+	.union(Features::SSE2)
+	.union(Features::SSE3)
+	.union(Features::SSSE3)
+	.union(Features::SSE4_1)
+	.union(Features::SSE4_2);
+/// Features depending on the AVX state being enabled
+const AVX_FEATURES: Features = Features::AVX
+	.union(Features::AVX2)
+	.union(Features::FMA)
+	.union(Features::F16C);
+/// Features depending on the AVX-512 state being enabled
+const AVX512_FEATURES: Features = Features::AVX512F
+	.union(Features::AVX512DQ)
+	.union(Features::AVX512CD)
+	.union(Features::AVX512BW)
+	.union(Features::AVX512VL);
+
+/// Features the kernel has been compiled to use, and can't run without
+pub const REQUIRED: Features = {
+	let mut required = Features::empty();
+	macro_rules! require {
+		($($feature:literal => $flag:ident),* $(,)?) => {
+			$(
+				if cfg!(target_feature = $feature)
+				{
+					required = required.union(Features::$flag);
+				}
+			)*
+		};
+	}
+	require! {
+		"fxsr" => FXSR,
+		"sse" => SSE,
+		"sse2" => SSE2,
+		"sse3" => SSE3,
+		"ssse3" => SSSE3,
+		"sse4.1" => SSE4_1,
+		"sse4.2" => SSE4_2,
+		"popcnt" => POPCNT,
+		"pclmulqdq" => PCLMULQDQ,
+		"aes" => AES,
+		"cmpxchg16b" => CMPXCHG16B,
+		"movbe" => MOVBE,
+		"rdrand" => RDRAND,
+		"rdseed" => RDSEED,
+		"xsave" => XSAVE,
+		"xsaveopt" => XSAVEOPT,
+		"xsavec" => XSAVEC,
+		"xsaves" => XSAVES,
+		"avx" => AVX,
+		"avx2" => AVX2,
+		"fma" => FMA,
+		"f16c" => F16C,
+		"bmi1" => BMI1,
+		"bmi2" => BMI2,
+		"lzcnt" => LZCNT,
+		"adx" => ADX,
+		"avx512f" => AVX512F,
+		"avx512dq" => AVX512DQ,
+		"avx512cd" => AVX512CD,
+		"avx512bw" => AVX512BW,
+		"avx512vl" => AVX512VL
+	}
+	required
+};
+
+/// Size of the `FXSAVE` area
+const FXSAVE_AREA_SIZE: usize = 512;
+
+static DETECTED: [AtomicU64; MAX_CPU_COUNT] = [const { AtomicU64::new(0) }; MAX_CPU_COUNT];
+static ENABLED: [AtomicU64; MAX_CPU_COUNT] = [const { AtomicU64::new(0) }; MAX_CPU_COUNT];
+static XSAVE_AREA_SIZE: AtomicUsize = AtomicUsize::new(FXSAVE_AREA_SIZE);
+static XSTATE: AtomicU64 = AtomicU64::new(0);
+
+/// The features of the calling CPU, as reported by CPUID
+fn detect() -> Features
+{
+	let bit = |register: u32, bit: u32, feature: Features| {
+		if register & (1 << bit) != 0
+		{
+			feature
+		}
+		else
+		{
+			Features::empty()
+		}
+	};
+	let mut features = Features::empty();
+
+	let max_leaf = cpuid(0_u32).eax;
+	let leaf = cpuid(1_u32);
+	features |= bit(leaf.edx, 0, Features::FPU)
+		| bit(leaf.edx, 4, Features::TSC)
+		| bit(leaf.edx, 5, Features::MSR)
+		| bit(leaf.edx, 6, Features::PAE)
+		| bit(leaf.edx, 9, Features::APIC)
+		| bit(leaf.edx, 16, Features::PAT)
+		| bit(leaf.edx, 24, Features::FXSR)
+		| bit(leaf.edx, 25, Features::SSE)
+		| bit(leaf.edx, 26, Features::SSE2)
+		| bit(leaf.ecx, 0, Features::SSE3)
+		| bit(leaf.ecx, 1, Features::PCLMULQDQ)
+		| bit(leaf.ecx, 9, Features::SSSE3)
+		| bit(leaf.ecx, 12, Features::FMA)
+		| bit(leaf.ecx, 13, Features::CMPXCHG16B)
+		| bit(leaf.ecx, 19, Features::SSE4_1)
+		| bit(leaf.ecx, 20, Features::SSE4_2)
+		| bit(leaf.ecx, 21, Features::X2APIC)
+		| bit(leaf.ecx, 22, Features::MOVBE)
+		| bit(leaf.ecx, 23, Features::POPCNT)
+		| bit(leaf.ecx, 24, Features::TSC_DEADLINE)
+		| bit(leaf.ecx, 25, Features::AES)
+		| bit(leaf.ecx, 26, Features::XSAVE)
+		| bit(leaf.ecx, 28, Features::AVX)
+		| bit(leaf.ecx, 29, Features::F16C)
+		| bit(leaf.ecx, 30, Features::RDRAND)
+		| bit(leaf.ecx, 31, Features::HYPERVISOR);
+
+	if max_leaf >= 7
+	{
+		let leaf = cpuid(7_u32, 0_u32);
+		features |= bit(leaf.ebx, 0, Features::FSGSBASE)
+			| bit(leaf.ebx, 3, Features::BMI1)
+			| bit(leaf.ebx, 5, Features::AVX2)
+			| bit(leaf.ebx, 7, Features::SMEP)
+			| bit(leaf.ebx, 8, Features::BMI2)
+			| bit(leaf.ebx, 16, Features::AVX512F)
+			| bit(leaf.ebx, 17, Features::AVX512DQ)
+			| bit(leaf.ebx, 18, Features::RDSEED)
+			| bit(leaf.ebx, 19, Features::ADX)
+			| bit(leaf.ebx, 20, Features::SMAP)
+			| bit(leaf.ebx, 28, Features::AVX512CD)
+			| bit(leaf.ebx, 30, Features::AVX512BW)
+			| bit(leaf.ebx, 31, Features::AVX512VL)
+			| bit(leaf.ecx, 2, Features::UMIP)
+			| bit(leaf.ecx, 3, Features::PKU);
+	}
+	if max_leaf >= 0xd && features.contains(Features::XSAVE)
+	{
+		let leaf = cpuid(0xd_u32, 1_u32);
+		features |= bit(leaf.eax, 0, Features::XSAVEOPT)
+			| bit(leaf.eax, 1, Features::XSAVEC)
+			| bit(leaf.eax, 3, Features::XSAVES);
+	}
+
+	let max_extended_leaf = cpuid(0x8000_0000_u32).eax;
+	if max_extended_leaf >= 0x8000_0001
+	{
+		let leaf = cpuid(0x8000_0001_u32);
+		features |= bit(leaf.ecx, 5, Features::LZCNT)
+			| bit(leaf.edx, 11, Features::SYSCALL)
+			| bit(leaf.edx, 20, Features::NX)
+			| bit(leaf.edx, 26, Features::PAGE_1GB)
+			| bit(leaf.edx, 27, Features::RDTSCP);
+	}
+	if max_extended_leaf >= 0x8000_0007
+	{
+		features |= bit(cpuid(0x8000_0007_u32).edx, 8, Features::INVARIANT_TSC);
+	}
+	features
+}
+
+/// Detect the features of the calling CPU, and enable its x87, SSE, AVX and
+/// AVX-512 state
+///
+/// Must be called on each CPU before any SIMD instruction is executed, so it
+/// doesn't log anything.
+pub fn init()
+{
+	let cpu = current_cpu_index();
+	let features = detect();
+	DETECTED[cpu].store(features.bits(), Ordering::Release);
+	// the other features don't need to be enabled
+	let mut enabled = features
+		- CONFIGURABLE
+		- Features::FXSR
+		- Features::XSAVE
+		- SSE_FEATURES
+		- AVX_FEATURES
+		- AVX512_FEATURES;
+
+	if !features.contains(Features::FXSR | Features::SSE)
+	{
+		ENABLED[cpu].store(enabled.bits(), Ordering::Release);
+		return;
+	}
+	cr0::update(|control| (control - Cr0::EM) | Cr0::MP);
+	let mut control = cr4::read() | Cr4::OSFXSR | Cr4::OSXMMEXCPT;
+	enabled |= features & (Features::FXSR | SSE_FEATURES);
+
+	if features.contains(Features::XSAVE)
+	{
+		control |= Cr4::OSXSAVE;
+		cr4::write(control);
+		enabled |= Features::XSAVE;
+
+		let supported = {
+			let leaf = cpuid(0xd_u32, 0_u32);
+			Xcr0::from_bits_retain((u64::from(leaf.edx) << 32) | u64::from(leaf.eax))
+		};
+		let mut state = Xcr0::X87 | Xcr0::SSE;
+		if features.contains(Features::AVX) && supported.contains(Xcr0::AVX)
+		{
+			state |= Xcr0::AVX;
+			enabled |= features & AVX_FEATURES;
+			if features.contains(Features::AVX512F) && supported.contains(Xcr0::AVX512)
+			{
+				state |= Xcr0::AVX512;
+				enabled |= features & AVX512_FEATURES;
+			}
+		}
+		xcr0::write(state);
+		XSTATE.store(state.bits(), Ordering::Release);
+		// the size of the area for the components enabled in XCR0
+		XSAVE_AREA_SIZE.store(cpuid(0xd_u32, 0_u32).ebx as usize, Ordering::Release);
+	}
+	else
+	{
+		cr4::write(control);
+	}
+	ENABLED[cpu].store(enabled.bits(), Ordering::Release);
+}
+
+/// Refuse to boot if the calling CPU lacks a feature the kernel has been
+/// compiled for
+///
+/// Called as soon as a message can be printed, right after [`init`].
+///
+/// # Panics
+///
+/// If the CPU lacks any of the [`REQUIRED`] features.
+pub fn check_required()
+{
+	let missing = REQUIRED - enabled();
+	assert!(
+		missing.is_empty(),
+		"the kernel has been compiled for CPU features this CPU lacks: {}",
+		Summary(missing)
+	);
+}
+
+/// Enable the protections and `FSGSBASE` on the calling CPU, except for the
+/// `disabled` ones and the [`OPT_IN`] ones not `requested`, and print the
+/// features of the CPU
+pub fn configure(requested: Features, disabled: Features)
+{
+	let cpu = current_cpu_index();
+	let features = detected();
+	let wanted = features & (((CONFIGURABLE - OPT_IN) | (requested & OPT_IN)) - disabled);
+
+	let mut control = cr4::read();
+	for (feature, bit) in [
+		(Features::UMIP, Cr4::UMIP),
+		(Features::FSGSBASE, Cr4::FSGSBASE),
+		(Features::SMEP, Cr4::SMEP),
+		(Features::SMAP, Cr4::SMAP),
+		(Features::PKU, Cr4::PKE)
+	]
+	{
+		control.set(bit, wanted.contains(feature));
+	}
+	cr4::write(control);
+	let enabled = (enabled() - CONFIGURABLE) | wanted;
+	ENABLED[cpu].store(enabled.bits(), Ordering::Release);
+
+	info!("cpu #{cpu} features: {}", Summary(features));
+	info!("cpu #{cpu} enabled features: {}", Summary(enabled));
+	info!(
+		"cpu #{cpu} XSAVE area: {} bytes (XCR0 = {:#x})",
+		xsave_area_size(),
+		XSTATE.load(Ordering::Acquire)
+	);
+}
+
+/// Features of the calling CPU, as reported by CPUID
+pub fn detected() -> Features
+{
+	Features::from_bits_retain(DETECTED[current_cpu_index()].load(Ordering::Acquire))
+}
+
+/// Features enabled on the calling CPU
+pub fn enabled() -> Features
+{
+	Features::from_bits_retain(ENABLED[current_cpu_index()].load(Ordering::Acquire))
+}
+
+/// Whether the calling CPU has `feature`
+pub fn has(feature: Features) -> bool
+{
+	detected().contains(feature)
+}
+
+/// Size of the area `XSAVE` (or `FXSAVE`) saves the enabled state components
+/// to
+pub fn xsave_area_size() -> usize
+{
+	XSAVE_AREA_SIZE.load(Ordering::Acquire)
+}
+
+/// The state components enabled in `XCR0`, or none if `XSAVE` isn't used
+pub fn xsave_state() -> Xcr0
+{
+	Xcr0::from_bits_retain(XSTATE.load(Ordering::Acquire))
+}
+
+/// Space-separated lowercase names of a set of features
+struct Summary(Features);
+
+impl fmt::Display for Summary
+{
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+	{
+		for (index, (name, _)) in self.0.iter_names().enumerate()
+		{
+			if index != 0
+			{
+				write!(f, " ")?;
+			}
+			for c in name.chars()
+			{
+				write!(f, "{}", c.to_ascii_lowercase())?;
+			}
+		}
+		Ok(())
+	}
+}
+
+impl Features
+{
+	/// The feature named `name` (e.g. `smap`), case-insensitively
+	pub fn from_name(name: &str) -> Option<Self>
+	{
+		Self::all()
+			.iter_names()
+			.find(|(flag, _)| flag.eq_ignore_ascii_case(name))
+			.map(|(_, feature)| feature)
+	}
+}
@@ -0,0 +1,526 @@
+//! FPU and SIMD state switching
+//!
+//! Each execution context owns an [`FpuState`], sized for the state
+//! components enabled in `XCR0` (see [`features`]), which [`switch`] saves the
+//! registers to and restores them from, with the best instruction available
+//! (`XSAVES`, `XSAVEOPT`, `XSAVE`, or `FXSAVE` without XSAVE support).
+//!
+//! Kernel contexts are always switched eagerly. The state of the user code
+//! (see [`enter_context`]) follows the [`FpuPolicy`]: with the
+//! [`FpuPolicy::Lazy`] policy, it is only restored on the first use of the FPU
+//! in user mode, and only saved when leaving user mode if it was restored.
+//! `CR0.TS` is set meanwhile, so that the resulting device not available
+//! exception (`#NM`) restores it. The kernel may use SIMD instructions
+//! anywhere, so an `#NM` raised by the kernel itself just clears `CR0.TS`: the
+//! registers then only hold a stale state, which the user code never sees. The
+//! default [`FpuPolicy::Eager`] policy restores the state right away, which is
+//! cheaper when the user code uses SIMD instructions, and doesn't leak the
+//! registers of the kernel to it speculatively.
+//!
+//! Kernel code using SIMD registers outside of any context (e.g. in an
+//! interrupt handler) must do so within a [`kernel_fpu_begin`] guard.
+
+use alloc::alloc::{Layout, alloc_zeroed, dealloc, handle_alloc_error};
+use core::{arch::asm, ptr::NonNull};
+
+use portable_atomic::{AtomicBool, AtomicPtr, AtomicU8, AtomicUsize, Ordering};
+
+use super::{
+	MAX_CPU_COUNT,
+	cpuid,
+	ctlregs::{Cr0, cr0},
+	current_cpu_index,
+	features::{self, Features}
+};
+
+/// Alignment of the `XSAVE` areas
+const AREA_ALIGN: usize = 64;
+/// Offset of `MXCSR` in the legacy area
+const MXCSR_OFFSET: usize = 24;
+/// Offset of `XCOMP_BV` in the `XSAVE` header
+const XCOMP_BV_OFFSET: usize = 512 + 8;
+/// Compacted format bit of `XCOMP_BV`
+const XCOMP_BV_COMPACTED: u64 = 1 << 63;
+/// Default `x87` control word
+const DEFAULT_FCW: u16 = 0x037f;
+/// Default `MXCSR`, with all the exceptions masked
+const DEFAULT_MXCSR: u32 = 0x1f80;
+
+/// Maximum nesting of [`kernel_fpu_begin`] guards on a CPU
+pub const MAX_KERNEL_FPU_DEPTH: usize = 8;
+
+/// When the FPU state of the user code is restored
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum FpuPolicy
+{
+	/// On every entry to user mode
+	Eager,
+	/// On the first use of the FPU in user mode
+	Lazy
+}
+
+impl core::str::FromStr for FpuPolicy
+{
+	type Err = ();
+
+	fn from_str(s: &str) -> Result<Self, Self::Err>
+	{
+		if s.eq_ignore_ascii_case("eager")
+		{
+			Ok(Self::Eager)
+		}
+		else if s.eq_ignore_ascii_case("lazy")
+		{
+			Ok(Self::Lazy)
+		}
+		else
+		{
+			Err(())
+		}
+	}
+}
+
+/// Instructions saving and restoring the state
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+enum Mechanism
+{
+	Fxsave,
+	Xsave,
+	Xsaveopt,
+	/// `XSAVES`, saving in the compacted format
+	Xsaves
+}
+
+static POLICY: AtomicU8 = AtomicU8::new(FpuPolicy::Eager as u8);
+static MECHANISM: AtomicU8 = AtomicU8::new(Mechanism::Fxsave as u8);
+static AREA_SIZE: AtomicUsize = AtomicUsize::new(512);
+
+/// State of the user code running on each CPU, between [`enter_context`] and
+/// [`leave_context`]
+static CURRENT: [AtomicPtr<FpuState>; MAX_CPU_COUNT] =
+	[const { AtomicPtr::new(core::ptr::null_mut()) }; MAX_CPU_COUNT];
+/// Whether the registers of each CPU hold its [`CURRENT`] state
+static LOADED: [AtomicBool; MAX_CPU_COUNT] = [const { AtomicBool::new(false) }; MAX_CPU_COUNT];
+
+/// Areas [`kernel_fpu_begin`] saves the interrupted state to, on each CPU
+static KERNEL_AREAS: [[AtomicPtr<FpuState>; MAX_KERNEL_FPU_DEPTH]; MAX_CPU_COUNT] =
+	[const { [const { AtomicPtr::new(core::ptr::null_mut()) }; MAX_KERNEL_FPU_DEPTH] };
+		MAX_CPU_COUNT];
+static KERNEL_DEPTH: [AtomicUsize; MAX_CPU_COUNT] = [const { AtomicUsize::new(0) }; MAX_CPU_COUNT];
+
+fn mechanism() -> Mechanism
+{
+	match MECHANISM.load(Ordering::Relaxed)
+	{
+		1 => Mechanism::Xsave,
+		2 => Mechanism::Xsaveopt,
+		3 => Mechanism::Xsaves,
+		_ => Mechanism::Fxsave
+	}
+}
+
+/// The policy in use
+pub fn policy() -> FpuPolicy
+{
+	if POLICY.load(Ordering::Relaxed) == FpuPolicy::Lazy as u8
+	{
+		FpuPolicy::Lazy
+	}
+	else
+	{
+		FpuPolicy::Eager
+	}
+}
+
+/// Size of each [`FpuState`]
+pub fn area_size() -> usize
+{
+	AREA_SIZE.load(Ordering::Relaxed)
+}
+
+/// Saved FPU and SIMD registers of an execution context
+#[derive(Debug)]
+pub struct FpuState
+{
+	area: NonNull<u8>,
+	size: usize
+}
+
+// SAFETY: the area is owned
+unsafe impl Send for FpuState {}
+unsafe impl Sync for FpuState {}
+
+impl FpuState
+{
+	/// A state with all the registers in their initial state
+	pub fn new() -> Self
+	{
+		let size = area_size();
+		let layout = Self::layout(size);
+		// SAFETY: the layout isn't zero-sized
+		let Some(area) = NonNull::new(unsafe { alloc_zeroed(layout) })
+		else
+		{
+			handle_alloc_error(layout);
+		};
+		// SAFETY: the area is large enough, and suitably aligned
+		unsafe {
+			area.cast::<u16>().write(DEFAULT_FCW);
+			area.add(MXCSR_OFFSET).cast::<u32>().write(DEFAULT_MXCSR);
+			if mechanism() == Mechanism::Xsaves
+			{
+				// components absent from `XSTATE_BV` are restored in their
+				// initial state
+				area.add(XCOMP_BV_OFFSET)
+					.cast::<u64>()
+					.write(XCOMP_BV_COMPACTED | features::xsave_state().bits());
+			}
+		}
+		Self { area, size }
+	}
+
+	fn layout(size: usize) -> Layout
+	{
+		Layout::from_size_align(size, AREA_ALIGN).expect("invalid XSAVE area size")
+	}
+
+	/// Save the registers of the calling CPU
+	pub fn save(&mut self)
+	{
+		let area = self.area.as_ptr();
+		let mask = features::xsave_state().bits();
+		let (low, high) = (mask as u32, (mask >> 32) as u32);
+		// SAFETY: the area is large enough for the enabled components, and
+		//         the instructions are supported
+		unsafe {
+			match mechanism()
+			{
+				Mechanism::Fxsave =>
+				{
+					asm!("fxsave64 ({})", in(reg) area, options(att_syntax, nostack, preserves_flags))
+				},
+				Mechanism::Xsave =>
+				{
+					asm!(
+						"xsave64 ({})",
+						in(reg) area,
+						in("eax") low,
+						in("edx") high,
+						options(att_syntax, nostack, preserves_flags)
+					)
+				},
+				Mechanism::Xsaveopt =>
+				{
+					asm!(
+						"xsaveopt64 ({})",
+						in(reg) area,
+						in("eax") low,
+						in("edx") high,
+						options(att_syntax, nostack, preserves_flags)
+					)
+				},
+				Mechanism::Xsaves =>
+				{
+					asm!(
+						"xsaves64 ({})",
+						in(reg) area,
+						in("eax") low,
+						in("edx") high,
+						options(att_syntax, nostack, preserves_flags)
+					)
+				}
+			}
+		}
+	}
+
+	/// Load the registers of the calling CPU from this state
+	pub fn restore(&self)
+	{
+		let area = self.area.as_ptr();
+		let mask = features::xsave_state().bits();
+		let (low, high) = (mask as u32, (mask >> 32) as u32);
+		// SAFETY: the area holds a state saved by `save`, or initialized by
+		//         `new`
+		unsafe {
+			match mechanism()
+			{
+				Mechanism::Fxsave =>
+				{
+					asm!("fxrstor64 ({})", in(reg) area, options(att_syntax, nostack, preserves_flags))
+				},
+				Mechanism::Xsave | Mechanism::Xsaveopt =>
+				{
+					asm!(
+						"xrstor64 ({})",
+						in(reg) area,
+						in("eax") low,
+						in("edx") high,
+						options(att_syntax, nostack, preserves_flags)
+					)
+				},
+				Mechanism::Xsaves =>
+				{
+					asm!(
+						"xrstors64 ({})",
+						in(reg) area,
+						in("eax") low,
+						in("edx") high,
+						options(att_syntax, nostack, preserves_flags)
+					)
+				}
+			}
+		}
+	}
+}
+
+impl Default for FpuState
+{
+	fn default() -> Self
+	{
+		Self::new()
+	}
+}
+
+impl Drop for FpuState
+{
+	fn drop(&mut self)
+	{
+		// SAFETY: allocated by `new` with this layout
+		unsafe { dealloc(self.area.as_ptr(), Self::layout(self.size)) };
+	}
+}
+
+fn set_task_switched(set: bool)
+{
+	let value = cr0::read();
+	let mut wanted = value;
+	wanted.set(Cr0::TS, set);
+	if wanted != value
+	{
+		cr0::write(wanted);
+	}
+}
+
+/// Choose the instructions used to save the state, and the policy
+///
+/// Must be called after [`features::init`], and before any [`FpuState`] is
+/// created.
+pub fn init(policy: FpuPolicy)
+{
+	let enabled = features::enabled();
+	let (mechanism, size) = if !enabled.contains(Features::XSAVE)
+	{
+		(Mechanism::Fxsave, 512)
+	}
+	else if features::has(Features::XSAVES)
+	{
+		// the size of the compacted format, for the components of XCR0 and
+		// IA32_XSS (which is left empty)
+		(Mechanism::Xsaves, cpuid(0xd_u32, 1_u32).ebx as usize)
+	}
+	else if features::has(Features::XSAVEOPT)
+	{
+		(Mechanism::Xsaveopt, features::xsave_area_size())
+	}
+	else
+	{
+		(Mechanism::Xsave, features::xsave_area_size())
+	};
+	MECHANISM.store(mechanism as u8, Ordering::Relaxed);
+	AREA_SIZE.store(size.max(512), Ordering::Relaxed);
+	POLICY.store(policy as u8, Ordering::Relaxed);
+	init_cpu();
+	crate::info!("FPU state: {mechanism:?}, {size} bytes, {policy:?} switching of the user state");
+}
+
+/// Allocate the areas of the [`kernel_fpu_begin`] guards of the calling CPU
+pub fn init_cpu()
+{
+	for slot in &KERNEL_AREAS[current_cpu_index()]
+	{
+		let area = alloc::boxed::Box::into_raw(alloc::boxed::Box::new(FpuState::new()));
+		slot.store(area, Ordering::Release);
+	}
+}
+
+/// Switch the FPU state of the calling CPU from the context of `prev` to the
+/// one of `next`
+///
+/// The registers of `prev` are always saved, and the ones of `next` restored,
+/// whatever the [`FpuPolicy`]: it only applies to the user code.
+pub fn switch(prev: &mut FpuState, next: &FpuState)
+{
+	prev.save();
+	next.restore();
+}
+
+/// Make `state` the state of the user code about to run on the calling CPU
+///
+/// With the [`FpuPolicy::Eager`] policy, it is restored right away. Must be
+/// called with interrupts disabled, right before entering user mode: nothing
+/// may use the SIMD registers until [`leave_context`].
+///
+/// # Safety
+///
+/// `state` must outlive its use by this CPU (i.e. until [`leave_context`]).
+pub unsafe fn enter_context(state: &mut FpuState)
+{
+	let cpu = current_cpu_index();
+	CURRENT[cpu].store(state, Ordering::Release);
+	match policy()
+	{
+		FpuPolicy::Eager =>
+		{
+			state.restore();
+			LOADED[cpu].store(true, Ordering::Release);
+		},
+		FpuPolicy::Lazy =>
+		{
+			LOADED[cpu].store(false, Ordering::Release);
+			set_task_switched(true);
+		}
+	}
+}
+
+/// Save the state of the user code which just left user mode on the calling
+/// CPU, if its registers were restored, and put the registers back in the
+/// state the kernel expects
+pub fn leave_context()
+{
+	let cpu = current_cpu_index();
+	set_task_switched(false);
+	let current = CURRENT[cpu].swap(core::ptr::null_mut(), Ordering::AcqRel);
+	// SAFETY: `enter_context` callers keep the state alive until then
+	if LOADED[cpu].swap(false, Ordering::AcqRel)
+		&& let Some(current) = unsafe { current.as_mut() }
+	{
+		current.save();
+	}
+	reset();
+}
+
+/// Handle a device not available exception (`#NM`), raised by the first use of
+/// the FPU since [`enter_context`] with the lazy policy
+///
+/// Returns whether the exception was caused by the lazy policy.
+pub fn handle_device_not_available(from_user: bool) -> bool
+{
+	if !cr0::read().contains(Cr0::TS)
+	{
+		return false;
+	}
+	set_task_switched(false);
+	if !from_user
+	{
+		// the kernel itself uses the registers, which don't hold the user
+		// state
+		return true;
+	}
+
+	let cpu = current_cpu_index();
+	// SAFETY: `enter_context` callers keep the state alive while it is current
+	if let Some(current) = unsafe { CURRENT[cpu].load(Ordering::Acquire).as_ref() }
+	{
+		current.restore();
+		LOADED[cpu].store(true, Ordering::Release);
+	}
+	true
+}
+
+/// Set `CR0.TS` again before returning from an exception or interrupt, if the
+/// user state still isn't loaded
+///
+/// The handler may have cleared it by using the FPU, even when it interrupted
+/// the kernel code about to enter user mode.
+pub fn return_to_context()
+{
+	let cpu = current_cpu_index();
+	if policy() == FpuPolicy::Lazy
+		&& !CURRENT[cpu].load(Ordering::Acquire).is_null()
+		&& !LOADED[cpu].load(Ordering::Acquire)
+	{
+		set_task_switched(true);
+	}
+}
+
+/// Put the x87 and SSE control registers of the calling CPU back in their
+/// initial state, which the kernel code expects
+pub fn reset()
+{
+	// SAFETY: just resets the x87 and SSE control registers
+	unsafe {
+		asm!(
+			"fninit",
+			"ldmxcsr ({})",
+			in(reg) &DEFAULT_MXCSR,
+			options(att_syntax, nostack, preserves_flags)
+		);
+	}
+}
+
+/// Allows the kernel to use the FPU and SIMD registers until it is dropped
+///
+/// The registers of the interrupted code are saved, and restored when the
+/// guard is dropped.
+#[must_use]
+pub struct KernelFpuGuard
+{
+	area:      *mut FpuState,
+	_not_send: core::marker::PhantomData<*const ()>
+}
+
+/// Start using the FPU in the kernel, which can be done in interrupt context
+///
+/// # Panics
+///
+/// If more than [`MAX_KERNEL_FPU_DEPTH`] guards are nested, or if
+/// [`init_cpu`] hasn't been called on this CPU.
+pub fn kernel_fpu_begin() -> KernelFpuGuard
+{
+	try_kernel_fpu_begin().expect("kernel FPU guards are nested too deeply")
+}
+
+/// Start using the FPU in the kernel if possible, like [`kernel_fpu_begin`]
+///
+/// Returns `None` instead of panicking, e.g. for exceptions raised before
+/// [`init_cpu`] has been called on this CPU (and so before anything has an
+/// FPU state to lose).
+pub fn try_kernel_fpu_begin() -> Option<KernelFpuGuard>
+{
+	let cpu = current_cpu_index();
+	// an NMI taken in between uses the same area, but is done with it before
+	// returning
+	let depth = KERNEL_DEPTH[cpu].load(Ordering::Acquire);
+	let area = KERNEL_AREAS[cpu]
+		.get(depth)
+		.map(|slot| slot.load(Ordering::Acquire))
+		.filter(|area| !area.is_null())?;
+	KERNEL_DEPTH[cpu].store(depth + 1, Ordering::Release);
+
+	// SAFETY: the area of this nesting level is only used by this guard
+	unsafe { (*area).save() };
+	// start from a clean state
+	reset();
+	Some(KernelFpuGuard {
+		area,
+		_not_send: core::marker::PhantomData
+	})
+}
+
+/// End the use of the FPU started by [`kernel_fpu_begin`]
+pub fn kernel_fpu_end(guard: KernelFpuGuard)
+{
+	drop(guard);
+}
+
+impl Drop for KernelFpuGuard
+{
+	fn drop(&mut self)
+	{
+		// SAFETY: saved by `kernel_fpu_begin`
+		unsafe { (*self.area).restore() };
+		KERNEL_DEPTH[current_cpu_index()].fetch_sub(1, Ordering::AcqRel);
+	}
+}
@@ -0,0 +1,132 @@
+use core::{arch::asm, cell::SyncUnsafeCell, mem::size_of};
+
+use super::{MAX_CPU_COUNT, exceptions};
+use crate::kernel::memory::{
+	gdt::entry_index,
+	tss::{DOUBLE_FAULT_IST, MACHINE_CHECK_IST, NMI_IST}
+};
+
+/// Number of interrupt vectors
+pub const VECTOR_COUNT: usize = 256;
+
+#[repr(C, packed)]
+#[derive(Debug, Clone, Copy)]
+struct IDTDescriptor
+{
+	size:   u16,
+	offset: u64
+}
+static_assert!(size_of::<IDTDescriptor>() == 10);
+
+/// A 64-bit interrupt gate descriptor
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct GateDescriptor
+{
+	offset_low:  u16,
+	selector:    u16,
+	ist:         u8,
+	attributes:  u8,
+	offset_mid:  u16,
+	offset_high: u32,
+	_reserved:   u32
+}
+static_assert!(size_of::<GateDescriptor>() == 16);
+
+impl GateDescriptor
+{
+	/// A non-present gate (raising `#NP`, or `#DF` when used by an exception)
+	pub const MISSING: Self = Self {
+		offset_low:  0,
+		selector:    0,
+		ist:         0,
+		attributes:  0,
+		offset_mid:  0,
+		offset_high: 0,
+		_reserved:   0
+	};
+
+	/// An interrupt gate (i.e. with interrupts disabled on entry) to the
+	/// kernel code at `handler`, optionally switching to the IST stack `ist`
+	pub fn interrupt(handler: usize, ist: Option<u8>) -> Self
+	{
+		const PRESENT: u8 = 1 << 7;
+		const INTERRUPT_GATE: u8 = 0xe;
+
+		Self {
+			offset_low:  handler as u16,
+			selector:    (entry_index!(KERNEL64_CS) as u16) << 3,
+			ist:         ist.unwrap_or(0),
+			attributes:  PRESENT | INTERRUPT_GATE,
+			offset_mid:  (handler >> 16) as u16,
+			offset_high: ((handler as u64) >> 32) as u32,
+			_reserved:   0
+		}
+	}
+}
+
+#[repr(C, align(16))]
+pub struct InterruptDescriptorTable
+{
+	pub entries: [GateDescriptor; VECTOR_COUNT]
+}
+
+impl InterruptDescriptorTable
+{
+	pub const fn new() -> Self
+	{
+		Self {
+			entries: [GateDescriptor::MISSING; VECTOR_COUNT]
+		}
+	}
+
+	/// Load the table into the calling CPU
+	pub fn load(&'static self)
+	{
+		let idt_desc = IDTDescriptor {
+			size:   (size_of::<Self>() - 1) as u16,
+			offset: self.entries.as_ptr() as u64
+		};
+		unsafe {
+			asm! {
+				"lidt ({})",
+				in(reg) &raw const idt_desc,
+				options(att_syntax, readonly, nostack, preserves_flags)
+			}
+		}
+	}
+}
+
+static IDTS: [SyncUnsafeCell<InterruptDescriptorTable>; MAX_CPU_COUNT] =
+	[const { SyncUnsafeCell::new(InterruptDescriptorTable::new()) }; MAX_CPU_COUNT];
+
+/// Set up and load the IDT of the calling CPU, with handlers for all the CPU
+/// exceptions and interrupt vectors (see [`irq`](crate::kernel::irq))
+///
+/// The IST stacks the handlers use must have been set up beforehand (see
+/// [`tss::init`](crate::kernel::memory::tss::init)).
+pub fn init()
+{
+	let cpu = super::current_cpu_index();
+	// SAFETY: each CPU only ever touches its own IDT, before loading it
+	let idt = unsafe { &mut *IDTS[cpu].get() };
+	for (vector, stub) in exceptions::ENTRY_STUBS.into_iter().enumerate()
+	{
+		let ist = match vector
+		{
+			exceptions::NMI => Some(NMI_IST),
+			exceptions::DOUBLE_FAULT => Some(DOUBLE_FAULT_IST),
+			exceptions::MACHINE_CHECK => Some(MACHINE_CHECK_IST),
+			_ => None
+		};
+		idt.entries[vector] = GateDescriptor::interrupt(stub as usize, ist);
+	}
+	let irq_entries = exceptions::irq_entries as usize;
+	for vector in exceptions::EXCEPTION_COUNT..VECTOR_COUNT
+	{
+		let stub =
+			irq_entries + (vector - exceptions::EXCEPTION_COUNT) * exceptions::IRQ_ENTRY_SIZE;
+		idt.entries[vector] = GateDescriptor::interrupt(stub, None);
+	}
+	idt.load();
+}
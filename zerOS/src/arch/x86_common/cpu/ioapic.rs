@@ -0,0 +1,253 @@
+//! I/O APIC driver
+//!
+//! The I/O APICs are found in the ACPI MADT, along with the interrupt source
+//! overrides describing how the ISA IRQs are wired to them. Once initialized
+//! with [`init`], they route the GSIs requested with
+//! [`request_irq`](irq::request_irq) (see [`GsiController`]).
+
+use portable_atomic::{AtomicU16, AtomicU32, Ordering};
+
+use super::paging;
+use crate::{
+	info,
+	kernel::{
+		acpi::{self, AcpiError, MadtEntry, inti},
+		irq::{self, GsiController, IrqError, IrqFlags},
+		sync::BasicMutex
+	},
+	warn
+};
+
+/// Maximum number of I/O APICs
+pub const MAX_IO_APICS: usize = 8;
+
+/// Number of ISA IRQs
+pub const ISA_IRQ_COUNT: usize = 16;
+
+const IOREGSEL: u64 = 0x00;
+const IOWIN: u64 = 0x10;
+
+const REG_ID: u32 = 0x00;
+const REG_VERSION: u32 = 0x01;
+const REG_REDIRECTION: u32 = 0x10;
+
+const ACTIVE_LOW: u64 = 1 << 13;
+const LEVEL_TRIGGERED: u64 = 1 << 15;
+const MASKED: u64 = 1 << 16;
+
+#[derive(Debug, Clone, Copy)]
+struct IoApic
+{
+	id:       u8,
+	/// Virtual address of the register window
+	base:     u64,
+	gsi_base: u32,
+	/// Number of redirection entries
+	count:    u32
+}
+
+impl IoApic
+{
+	fn read(&self, reg: u32) -> u32
+	{
+		// SAFETY: the registers have been mapped by `init`, and are only
+		//         accessed with the lock held
+		unsafe {
+			((self.base + IOREGSEL) as *mut u32).write_volatile(reg);
+			((self.base + IOWIN) as *const u32).read_volatile()
+		}
+	}
+
+	fn write(&self, reg: u32, value: u32)
+	{
+		// SAFETY: see `read`
+		unsafe {
+			((self.base + IOREGSEL) as *mut u32).write_volatile(reg);
+			((self.base + IOWIN) as *mut u32).write_volatile(value);
+		}
+	}
+
+	fn read_entry(&self, index: u32) -> u64
+	{
+		let low = self.read(REG_REDIRECTION + 2 * index);
+		let high = self.read(REG_REDIRECTION + 2 * index + 1);
+		(u64::from(high) << 32) | u64::from(low)
+	}
+
+	fn write_entry(&self, index: u32, entry: u64)
+	{
+		// mask the entry while it is being changed
+		self.write(REG_REDIRECTION + 2 * index, MASKED as u32);
+		self.write(REG_REDIRECTION + 2 * index + 1, (entry >> 32) as u32);
+		self.write(REG_REDIRECTION + 2 * index, entry as u32);
+	}
+
+	fn handles(&self, gsi: u32) -> bool
+	{
+		(self.gsi_base..self.gsi_base + self.count).contains(&gsi)
+	}
+}
+
+static IO_APICS: BasicMutex<heapless::Vec<IoApic, MAX_IO_APICS>> =
+	BasicMutex::new(heapless::Vec::new());
+
+/// Flags of each ISA IRQ, as given by the MPS INTI flags of the interrupt
+/// source overrides
+static ISA_FLAGS: [AtomicU16; ISA_IRQ_COUNT] = [const { AtomicU16::new(0) }; ISA_IRQ_COUNT];
+/// GSI of each ISA IRQ (or `u32::MAX` if it is identity-mapped)
+static ISA_GSIS: [AtomicU32; ISA_IRQ_COUNT] = [const { AtomicU32::new(u32::MAX) }; ISA_IRQ_COUNT];
+
+/// The GSI an ISA IRQ is connected to, and the flags to request it with
+pub fn isa_irq(irq: u8) -> (u32, IrqFlags)
+{
+	let Some(index) = Some(usize::from(irq)).filter(|&index| index < ISA_IRQ_COUNT)
+	else
+	{
+		return (u32::from(irq), IrqFlags::empty());
+	};
+	let gsi = match ISA_GSIS[index].load(Ordering::Acquire)
+	{
+		u32::MAX => u32::from(irq),
+		gsi => gsi
+	};
+
+	// ISA interrupts are edge-triggered and active high, unless overridden
+	let overridden = ISA_FLAGS[index].load(Ordering::Acquire);
+	let mut flags = IrqFlags::empty();
+	if overridden & inti::POLARITY_MASK == inti::ACTIVE_LOW
+	{
+		flags |= IrqFlags::ACTIVE_LOW;
+	}
+	if overridden & inti::TRIGGER_MASK == inti::LEVEL
+	{
+		flags |= IrqFlags::LEVEL_TRIGGERED;
+	}
+	(gsi, flags)
+}
+
+struct Controller;
+
+impl Controller
+{
+	/// Run `f` on the I/O APIC handling `gsi`, with the index of its entry
+	fn with_entry<R>(gsi: u32, f: impl FnOnce(&IoApic, u32) -> R) -> Option<R>
+	{
+		let io_apics = IO_APICS.lock();
+		let io_apic = io_apics.iter().find(|io_apic| io_apic.handles(gsi))?;
+		Some(f(io_apic, gsi - io_apic.gsi_base))
+	}
+}
+
+impl GsiController for Controller
+{
+	fn name(&self) -> &'static str
+	{
+		"I/O APIC"
+	}
+
+	fn route(&self, gsi: u32, vector: u8, flags: IrqFlags, cpu: usize) -> Result<(), IrqError>
+	{
+		let mut entry = MASKED | u64::from(vector);
+		if flags.contains(IrqFlags::ACTIVE_LOW)
+		{
+			entry |= ACTIVE_LOW;
+		}
+		if flags.contains(IrqFlags::LEVEL_TRIGGERED)
+		{
+			entry |= LEVEL_TRIGGERED;
+		}
+		// fixed delivery, in physical destination mode, which can only reach
+		// 8-bit APIC IDs
+		let apic_id = super::cpu_apic_id(cpu)
+			.filter(|&apic_id| apic_id <= 0xff)
+			.ok_or(IrqError::UnreachableCpu)?;
+		entry |= u64::from(apic_id) << 56;
+		Self::with_entry(gsi, |io_apic, index| io_apic.write_entry(index, entry))
+			.ok_or(IrqError::InvalidGsi)
+	}
+
+	fn mask(&self, gsi: u32)
+	{
+		Self::with_entry(gsi, |io_apic, index| {
+			io_apic.write_entry(index, io_apic.read_entry(index) | MASKED);
+		});
+	}
+
+	fn unmask(&self, gsi: u32)
+	{
+		Self::with_entry(gsi, |io_apic, index| {
+			io_apic.write_entry(index, io_apic.read_entry(index) & !MASKED);
+		});
+	}
+}
+
+/// Find the I/O APICs and the ISA IRQ overrides in the MADT, mask all the
+/// GSIs, and start routing them
+pub fn init() -> Result<(), AcpiError>
+{
+	let mut io_apics = IO_APICS.lock();
+	for entry in acpi::madt_entries()?
+	{
+		match entry
+		{
+			MadtEntry::IoApic {
+				id,
+				address,
+				gsi_base
+			} =>
+			{
+				// SAFETY: the register window isn't RAM
+				let mapped =
+					unsafe { paging::map_physical(u64::from(address), paging::PAGE_SIZE, true) };
+				let base = match mapped
+				{
+					Ok(base) => base,
+					Err(err) =>
+					{
+						warn!("couldn't map the registers of I/O APIC #{id}: {err}");
+						continue;
+					}
+				};
+				let mut io_apic = IoApic {
+					id,
+					base,
+					gsi_base,
+					count: 0
+				};
+				io_apic.count = ((io_apic.read(REG_VERSION) >> 16) & 0xff) + 1;
+				for index in 0..io_apic.count
+				{
+					io_apic.write_entry(index, MASKED);
+				}
+				info!(
+					"I/O APIC #{} (ID register {:#x}): GSIs {}..{}",
+					io_apic.id,
+					io_apic.read(REG_ID) >> 24,
+					io_apic.gsi_base,
+					io_apic.gsi_base + io_apic.count
+				);
+				if io_apics.push(io_apic).is_err()
+				{
+					warn!("too many I/O APICs, ignoring I/O APIC #{id}");
+				}
+			},
+			MadtEntry::InterruptSourceOverride {
+				bus: 0,
+				source,
+				gsi,
+				flags
+			} if usize::from(source) < ISA_IRQ_COUNT =>
+			{
+				info!("ISA IRQ {source} is connected to GSI {gsi} (flags {flags:#x})");
+				ISA_GSIS[usize::from(source)].store(gsi, Ordering::Release);
+				ISA_FLAGS[usize::from(source)].store(flags, Ordering::Release);
+			},
+			_ =>
+			{}
+		}
+	}
+	drop(io_apics);
+
+	irq::set_gsi_controller(&Controller);
+	Ok(())
+}
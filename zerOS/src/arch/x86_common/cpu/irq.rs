@@ -21,3 +21,36 @@ pub fn disable()
 		}
 	}
 }
+
+/// Whether maskable interrupts are currently enabled (i.e. `RFLAGS.IF` is set)
+#[inline]
+pub fn enabled() -> bool
+{
+	const IF: usize = 1 << 9;
+
+	let flags: usize;
+	unsafe {
+		asm! {
+			"pushf",
+			"pop {}",
+			out(reg) flags,
+			options(att_syntax, nomem, preserves_flags)
+		}
+	}
+	flags & IF != 0
+}
+
+/// Run `f` with maskable interrupts disabled, restoring them afterwards if
+/// they were enabled
+#[inline]
+pub fn without_interrupts<R>(f: impl FnOnce() -> R) -> R
+{
+	let irqs = enabled();
+	disable();
+	let ret = f();
+	if irqs
+	{
+		enable();
+	}
+	ret
+}
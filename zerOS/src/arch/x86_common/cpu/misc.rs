@@ -1,6 +1,10 @@
 use core::arch::asm;
 
-use super::{io::outw, irq};
+use super::{
+	io::{outl, outw},
+	irq
+};
+use crate::arch::core_target;
 
 pub fn halt()
 {
@@ -15,8 +19,39 @@ pub fn halt()
 pub fn reboot() -> !
 {
 	outw(0x64, 0xfe | (1 << 8));
-	halt();
-	unreachable!();
+	hcf()
+}
+
+/// I/O port of QEMU's `isa-debug-exit` device, as configured by `xtask run`
+pub const QEMU_DEBUG_EXIT_PORT: u16 = 0xf4;
+
+/// Make QEMU exit with status `(code << 1) | 1`
+///
+/// This only does something if QEMU has been started with an `isa-debug-exit`
+/// device at [`QEMU_DEBUG_EXIT_PORT`]. Otherwise, it simply returns.
+pub fn qemu_exit(code: u32)
+{
+	outl(QEMU_DEBUG_EXIT_PORT, code);
+}
+
+/// Read the time-stamp counter
+#[inline]
+pub fn read_tsc() -> u64
+{
+	// SAFETY: `rdtsc` is available on every x86 CPU the kernel supports
+	unsafe { core_target::_rdtsc() }
+}
+
+/// Read the time-stamp counter, and the `IA32_TSC_AUX` MSR
+///
+/// The CPU must support `RDTSCP`.
+#[inline]
+pub fn read_tscp() -> (u64, u32)
+{
+	let mut aux = 0;
+	// SAFETY: the caller checked that `rdtscp` is available
+	let tsc = unsafe { core_target::__rdtscp(&mut aux) };
+	(tsc, aux)
 }
 
 /// Halt and catch fire
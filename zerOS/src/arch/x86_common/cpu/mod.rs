@@ -2,7 +2,7 @@ use cfg_if::cfg_if;
 use macro_utils::{CaseKind, MultiCaseStaticString};
 use num::traits::AsPrimitive;
 use overloadable::overloadable;
-use portable_atomic::{AtomicBool, Ordering};
+use portable_atomic::{AtomicBool, AtomicU32, Ordering};
 use raw_cpuid::{CpuId, CpuIdReaderNative, ProcessorCapacityAndFeatureInfo};
 
 use crate::kernel::sync::BasicRwLock;
@@ -20,9 +20,20 @@ cfg_if! {
 }
 
 pub mod addr;
+pub mod apic;
+pub mod exceptions;
+pub mod extable;
+pub mod features;
+pub mod fpu;
+pub mod idt;
 pub mod io;
+pub mod ioapic;
 pub mod irq;
 pub mod misc;
+pub mod paging;
+pub mod pic;
+pub mod pit;
+pub mod user;
 
 mod registers;
 
@@ -213,11 +224,111 @@ overloadable! {
 	}
 }
 
+/// Upper bound on the number of CPUs for which the kernel keeps per-CPU state
+pub const MAX_CPU_COUNT: usize = 64;
+
+/// Initial (x2)APIC ID of the calling CPU, as reported by CPUID
+pub fn current_apic_id() -> u32
+{
+	if cpuid(0_u32).eax >= 0xb
+	{
+		let topology = cpuid(0xb_u32, 0_u32);
+		if topology.ebx != 0
+		{
+			return topology.edx;
+		}
+	}
+	cpuid(1_u32).ebx >> 24
+}
+
+/// Marks a free slot of [`CPU_APIC_IDS`]
+const NO_CPU: u32 = u32::MAX;
+
+/// APIC IDs of the CPUs, indexed by their [`current_cpu_index`]
+static CPU_APIC_IDS: [AtomicU32; MAX_CPU_COUNT] = [const { AtomicU32::new(NO_CPU) }; MAX_CPU_COUNT];
+
+/// Bit of `IA32_TSC_AUX` telling it holds the index of its CPU
+const TSC_AUX_CPU_INDEX: u32 = 1 << 31;
+/// Whether some CPU put its index in `IA32_TSC_AUX` (see [`cache_cpu_index`])
+static CPU_INDEX_IN_TSC_AUX: AtomicBool = AtomicBool::new(false);
+
+/// Index of the calling CPU into per-CPU arrays of [`MAX_CPU_COUNT`] elements
+///
+/// The first call on a CPU assigns it the next free index, so the boot CPU
+/// gets `0` and APIC IDs, which may be sparse, never collide. Once
+/// [`cache_cpu_index`] has run on the CPU, the index is read back with
+/// `RDTSCP`, rather than looked up from the APIC ID CPUID reports (which
+/// traps to the hypervisor in a VM). Neither depends on any per-CPU area or on
+/// `GS`, so this can be used very early, or when things have gone badly wrong
+/// (e.g. while panicking, or in an NMI taken right after `SYSCALL`).
+pub fn current_cpu_index() -> usize
+{
+	if CPU_INDEX_IN_TSC_AUX.load(Ordering::Relaxed)
+	{
+		let (_, aux) = misc::read_tscp();
+		if aux & TSC_AUX_CPU_INDEX != 0
+		{
+			return (aux & !TSC_AUX_CPU_INDEX) as usize;
+		}
+	}
+	let apic_id = current_apic_id();
+	cpu_index(apic_id).unwrap_or_else(|| register_cpu(apic_id))
+}
+
+/// Keep the index of the calling CPU in its `IA32_TSC_AUX` MSR, for
+/// [`current_cpu_index`], if the CPU supports `RDTSCP`
+///
+/// Called once per CPU, from [`user::init`] (the index is then visible to
+/// user code through `RDTSCP` as well).
+pub fn cache_cpu_index()
+{
+	if !features::has(features::Features::RDTSCP)
+	{
+		return;
+	}
+	msr::TSC_AUX.write(u64::from(current_cpu_index() as u32 | TSC_AUX_CPU_INDEX));
+	CPU_INDEX_IN_TSC_AUX.store(true, Ordering::Release);
+}
+
+/// Index of the CPU whose APIC ID is `apic_id`, if it got one
+pub fn cpu_index(apic_id: u32) -> Option<usize>
+{
+	CPU_APIC_IDS
+		.iter()
+		.position(|id| id.load(Ordering::Acquire) == apic_id)
+}
+
+/// APIC ID of the CPU at `index`, if some CPU got it
+pub fn cpu_apic_id(index: usize) -> Option<u32>
+{
+	CPU_APIC_IDS
+		.get(index)
+		.map(|id| id.load(Ordering::Acquire))
+		.filter(|&id| id != NO_CPU)
+}
+
+/// Assign the calling CPU, whose APIC ID is `apic_id`, the first free index
+///
+/// A CPU only ever registers itself, so `apic_id` can't be registered
+/// concurrently. The CPUs which don't fit in the per-CPU arrays are parked.
+fn register_cpu(apic_id: u32) -> usize
+{
+	CPU_APIC_IDS
+		.iter()
+		.position(|id| {
+			id.compare_exchange(NO_CPU, apic_id, Ordering::AcqRel, Ordering::Acquire)
+				.is_ok()
+		})
+		.unwrap_or_else(|| misc::hcf())
+}
+
+/// CPUID data of the boot CPU
+///
+/// The features of each CPU are tracked by [`features`] instead.
 pub struct CpuFeatures
 {
 	pub cpuid:                  BasicRwLock<Option<CpuId<CpuIdReaderNative>>>,
-	pub proc_cap_and_feat_info: BasicRwLock<Option<ProcessorCapacityAndFeatureInfo>>,
-	pub have_pae:               AtomicBool
+	pub proc_cap_and_feat_info: BasicRwLock<Option<ProcessorCapacityAndFeatureInfo>>
 }
 
 impl CpuFeatures
@@ -226,8 +337,7 @@ impl CpuFeatures
 	{
 		Self {
 			cpuid:                  BasicRwLock::new(None),
-			proc_cap_and_feat_info: BasicRwLock::new(None),
-			have_pae:               AtomicBool::new(false)
+			proc_cap_and_feat_info: BasicRwLock::new(None)
 		}
 	}
 }
@@ -263,18 +373,6 @@ ctor! {
 				.unwrap_unchecked()
 		}.get_processor_capacity_feature_info();
 
-	ZEROS_BOOT_CPU_FEATURES.have_pae.store(
-		unsafe {
-			ZEROS_BOOT_CPU_FEATURES
-				.cpuid
-				.read()
-				.unwrap_unchecked()
-		}
-		.get_feature_info()
-		.map_or(false, |featinf| featinf.has_pae()),
-		Ordering::Release
-	);
-
 	if let Some(inf) = &*ZEROS_BOOT_CPU_FEATURES
 		.proc_cap_and_feat_info
 		.read()
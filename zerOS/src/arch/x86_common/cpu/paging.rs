@@ -0,0 +1,206 @@
+//! Minimal manipulation of the page tables set up by the bootloader
+//!
+//! The page tables are reached through the higher-half direct map, and are
+//! assumed to use 4-level (or 5-level, if `CR4.LA57` is set) paging.
+//!
+//! The bootloader's direct map only covers RAM, so physical memory outside of
+//! it (e.g. MMIO registers) has to be mapped with [`map_physical`] before being
+//! accessed through it. The page tables this needs come from a small static
+//! pool.
+
+use core::{arch::asm, cell::SyncUnsafeCell, fmt};
+
+use portable_atomic::{AtomicUsize, Ordering};
+
+use super::ctlregs::{Cr4, cr3, cr4};
+use crate::kernel::memory::hhdm_offset;
+
+pub const PAGE_SIZE: usize = 4096;
+
+/// Number of page tables [`map_physical`] may allocate
+pub const PAGE_TABLE_POOL_SIZE: usize = 32;
+
+const PRESENT: u64 = 1 << 0;
+const WRITABLE: u64 = 1 << 1;
+/// Page-level write-through and cache disable
+const UNCACHED: u64 = (1 << 3) | (1 << 4);
+const HUGE: u64 = 1 << 7;
+const ADDRESS_MASK: u64 = 0x000f_ffff_ffff_f000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PagingError
+{
+	/// The higher-half direct map isn't known (yet)
+	NoDirectMap,
+	/// The address isn't mapped
+	NotMapped,
+	/// The address is mapped by a 2 MiB or 1 GiB page
+	HugePage,
+	/// The page table pool is exhausted
+	OutOfPageTables
+}
+
+impl fmt::Display for PagingError
+{
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+	{
+		match self
+		{
+			Self::NoDirectMap => write!(f, "the higher-half direct map is unknown"),
+			Self::NotMapped => write!(f, "the address isn't mapped"),
+			Self::HugePage => write!(f, "the address is mapped by a huge page"),
+			Self::OutOfPageTables => write!(f, "no page table is left to map the address")
+		}
+	}
+}
+
+#[repr(C, align(4096))]
+struct PageTable([u64; 512]);
+
+static PAGE_TABLE_POOL: [SyncUnsafeCell<PageTable>; PAGE_TABLE_POOL_SIZE] =
+	[const { SyncUnsafeCell::new(PageTable([0; 512])) }; PAGE_TABLE_POOL_SIZE];
+static PAGE_TABLES_USED: AtomicUsize = AtomicUsize::new(0);
+
+/// Physical address of a new, empty page table
+fn allocate_page_table() -> Result<u64, PagingError>
+{
+	let table = PAGE_TABLE_POOL
+		.get(PAGE_TABLES_USED.fetch_add(1, Ordering::AcqRel))
+		.ok_or(PagingError::OutOfPageTables)?
+		.get();
+	// the pool lives in the kernel image, which is mapped with 4 KiB pages
+	physical_address(table.addr() as u64)
+}
+
+/// Page table entry mapping the 4 KiB page containing `addr`
+fn leaf_entry(addr: u64) -> Result<*mut u64, PagingError>
+{
+	walk(addr, false)
+}
+
+/// Page table entry mapping the 4 KiB page containing `addr`, allocating the
+/// missing page tables on the way if `allocate` is set
+fn walk(addr: u64, allocate: bool) -> Result<*mut u64, PagingError>
+{
+	let hhdm = hhdm_offset().ok_or(PagingError::NoDirectMap)?;
+	let levels = if cr4::read().contains(Cr4::LA57)
+	{
+		5
+	}
+	else
+	{
+		4
+	};
+	let mut table = cr3::read().frame();
+	for level in (1..=levels).rev()
+	{
+		let index = (addr >> (12 + 9 * (level - 1))) & 0x1ff;
+		let entry = ((hhdm + table) as *mut u64).wrapping_add(index as usize);
+		if level == 1
+		{
+			return Ok(entry);
+		}
+		// SAFETY: page tables are mapped by the higher-half direct map
+		let mut value = unsafe { entry.read_volatile() };
+		if value & PRESENT == 0 && allocate
+		{
+			value = allocate_page_table()? | WRITABLE | PRESENT;
+			// SAFETY: the entry wasn't used
+			unsafe { entry.write_volatile(value) };
+		}
+		if value & PRESENT == 0
+		{
+			return Err(PagingError::NotMapped);
+		}
+		if value & HUGE != 0
+		{
+			return Err(PagingError::HugePage);
+		}
+		table = value & ADDRESS_MASK;
+	}
+	unreachable!()
+}
+
+/// Whether the page containing `addr` is mapped in the current address space
+///
+/// The page tables are walked through the higher-half direct map, so this
+/// always answers `false` until it is known.
+pub fn is_mapped(addr: u64) -> bool
+{
+	match leaf_entry(addr)
+	{
+		// SAFETY: see `leaf_entry`
+		Ok(entry) =>
+		unsafe { entry.read_volatile() & PRESENT != 0 },
+		Err(PagingError::HugePage) => true,
+		Err(_) => false
+	}
+}
+
+/// Physical address `addr` is mapped to, if it is mapped by a 4 KiB page (as
+/// the kernel image is)
+pub fn physical_address(addr: u64) -> Result<u64, PagingError>
+{
+	let entry = leaf_entry(addr)?;
+	// SAFETY: see `leaf_entry`
+	let value = unsafe { entry.read_volatile() };
+	if value & PRESENT == 0
+	{
+		return Err(PagingError::NotMapped);
+	}
+	Ok((value & ADDRESS_MASK) | (addr & (PAGE_SIZE as u64 - 1)))
+}
+
+/// Unmap the 4 KiB page containing `addr` from the current address space
+///
+/// The physical page itself is left untouched (and still reachable through
+/// the higher-half direct map).
+///
+/// # Safety
+///
+/// Nothing may use the page anymore.
+pub unsafe fn unmap_page(addr: u64) -> Result<(), PagingError>
+{
+	let entry = leaf_entry(addr)?;
+	// SAFETY: see `leaf_entry`
+	unsafe {
+		if entry.read_volatile() & PRESENT == 0
+		{
+			return Err(PagingError::NotMapped);
+		}
+		entry.write_volatile(entry.read_volatile() & !PRESENT);
+		asm! {
+			"invlpg ({addr})",
+			addr = in(reg) addr as usize,
+			options(att_syntax, nostack, preserves_flags)
+		}
+	}
+	Ok(())
+}
+
+/// Make the physical range `phys..phys + size` accessible through the
+/// higher-half direct map, returning its virtual address
+///
+/// Pages which are already mapped are left as they are. The others are mapped
+/// writable, and uncached if `uncached` is set (as MMIO registers must be).
+///
+/// # Safety
+///
+/// The range must not overlap RAM used by anything else.
+pub unsafe fn map_physical(phys: u64, size: usize, uncached: bool) -> Result<u64, PagingError>
+{
+	let hhdm = hhdm_offset().ok_or(PagingError::NoDirectMap)?;
+	let caching = if uncached { UNCACHED } else { 0 };
+	let first = phys & ADDRESS_MASK;
+	for page in (first..phys + size as u64).step_by(PAGE_SIZE)
+	{
+		if is_mapped(hhdm + page)
+		{
+			continue;
+		}
+		let entry = walk(hhdm + page, true)?;
+		// SAFETY: see `leaf_entry`, the page wasn't mapped
+		unsafe { entry.write_volatile(page | caching | WRITABLE | PRESENT) };
+	}
+	Ok(hhdm + phys)
+}
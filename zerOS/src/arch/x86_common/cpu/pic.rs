@@ -0,0 +1,113 @@
+//! Legacy 8259 programmable interrupt controllers
+//!
+//! They are never used to deliver interrupts, the I/O APIC taking over, but
+//! they still have to be taken care of: they are remapped above the CPU
+//! exceptions (to the [`IrqClass::Legacy`] vectors) and fully masked. The
+//! spurious IRQ 7 and 15 they may still raise are silently dropped, and any
+//! other IRQ acknowledged on the PICs themselves, never on the local APIC.
+
+use super::io::{inb, outb};
+use crate::{
+	kernel::irq::{self, Irq, IrqClass, IrqContext, IrqFlags, IrqReturn},
+	warn
+};
+
+const MASTER_COMMAND: u16 = 0x20;
+const MASTER_DATA: u16 = 0x21;
+const SLAVE_COMMAND: u16 = 0xa0;
+const SLAVE_DATA: u16 = 0xa1;
+
+const ICW1_INIT: u8 = 0x10;
+const ICW1_ICW4: u8 = 0x01;
+const ICW4_8086: u8 = 0x01;
+const OCW3_READ_ISR: u8 = 0x0b;
+const END_OF_INTERRUPT: u8 = 0x20;
+
+/// Give the PICs some time to process a command, by writing to an unused
+/// port
+fn io_wait()
+{
+	outb(0x80, 0);
+}
+
+/// Whether IRQ 7 of the PIC (`slave` or master) is really in service
+fn in_service(slave: bool) -> bool
+{
+	let command = if slave { SLAVE_COMMAND } else { MASTER_COMMAND };
+	outb(command, OCW3_READ_ISR);
+	inb(command) & (1 << 7) != 0
+}
+
+/// Acknowledge an IRQ of the slave PIC (IRQs 8 to 15) or of the master one
+fn end_of_interrupt(slave: bool)
+{
+	if slave
+	{
+		outb(SLAVE_COMMAND, END_OF_INTERRUPT);
+	}
+	outb(MASTER_COMMAND, END_OF_INTERRUPT);
+}
+
+fn handle_irq(context: &IrqContext) -> IrqReturn
+{
+	let irq = context.vector - IrqClass::Legacy.vectors().start;
+	let slave = irq >= 8;
+	if irq % 8 == 7 && !in_service(slave)
+	{
+		// spurious, which the PIC doesn't expect to be acknowledged, but the
+		// master doesn't know the slave's interrupt was
+		if slave
+		{
+			outb(MASTER_COMMAND, END_OF_INTERRUPT);
+		}
+		return IrqReturn::Handled;
+	}
+	// a real interrupt, which shouldn't have been raised as they're all masked
+	end_of_interrupt(slave);
+	IrqReturn::NotMine
+}
+
+/// Remap the PICs to the [`IrqClass::Legacy`] vectors, and mask all their
+/// IRQs
+pub fn disable()
+{
+	let base = IrqClass::Legacy.vectors().start;
+
+	outb(MASTER_COMMAND, ICW1_INIT | ICW1_ICW4);
+	io_wait();
+	outb(SLAVE_COMMAND, ICW1_INIT | ICW1_ICW4);
+	io_wait();
+	outb(MASTER_DATA, base);
+	io_wait();
+	outb(SLAVE_DATA, base + 8);
+	io_wait();
+	// the slave is cascaded on IRQ 2
+	outb(MASTER_DATA, 1 << 2);
+	io_wait();
+	outb(SLAVE_DATA, 2);
+	io_wait();
+	outb(MASTER_DATA, ICW4_8086);
+	io_wait();
+	outb(SLAVE_DATA, ICW4_8086);
+	io_wait();
+
+	outb(MASTER_DATA, 0xff);
+	outb(SLAVE_DATA, 0xff);
+
+	for vector in IrqClass::Legacy.vectors()
+	{
+		irq::claim_vector(vector);
+		if let Err(err) = irq::request_irq(
+			Irq::Vector(vector),
+			handle_irq,
+			IrqFlags::empty(),
+			"8259 PIC"
+		)
+		{
+			warn!(
+				"couldn't register the handler of legacy IRQ {}: {err}",
+				vector - base
+			);
+		}
+	}
+}
@@ -0,0 +1,63 @@
+//! Minimal driver of the legacy programmable interval timer (8253/8254 PIT)
+//!
+//! Channel 2 is used by polling its output through port `0x61`, as a
+//! reference clock to calibrate the other timers against, and channel 0 as a
+//! free-running counter. Neither needs any interrupt to be routed.
+
+use super::io::{inb, outb};
+
+/// Frequency of the PIT input clock, in Hz
+pub const FREQUENCY: u64 = 1_193_182;
+
+const CHANNEL0_DATA: u16 = 0x40;
+const CHANNEL2_DATA: u16 = 0x42;
+const COMMAND: u16 = 0x43;
+const CHANNEL2_GATE: u16 = 0x61;
+
+const GATE: u8 = 1 << 0;
+const SPEAKER: u8 = 1 << 1;
+const OUTPUT: u8 = 1 << 5;
+
+/// Longest delay [`busy_wait`] can measure at once, in microseconds
+pub const MAX_WAIT_US: u64 = 0xffff * 1_000_000 / FREQUENCY;
+
+/// Wait for `us` microseconds (at most [`MAX_WAIT_US`])
+pub fn busy_wait(us: u64)
+{
+	let ticks = (us.min(MAX_WAIT_US) * FREQUENCY / 1_000_000).max(1) as u16;
+
+	// gate channel 2 off, with the speaker disconnected
+	let gate = inb(CHANNEL2_GATE) & !(GATE | SPEAKER);
+	outb(CHANNEL2_GATE, gate);
+	// channel 2, lobyte/hibyte, mode 0 (interrupt on terminal count)
+	outb(COMMAND, 0xb0);
+	outb(CHANNEL2_DATA, ticks as u8);
+	outb(CHANNEL2_DATA, (ticks >> 8) as u8);
+	// start counting down
+	outb(CHANNEL2_GATE, gate | GATE);
+
+	while inb(CHANNEL2_GATE) & OUTPUT == 0
+	{
+		core::hint::spin_loop();
+	}
+	outb(CHANNEL2_GATE, gate);
+}
+
+/// Make channel 0 count down continuously, over its whole 16-bit range
+pub fn start_counter()
+{
+	// channel 0, lobyte/hibyte, mode 2 (rate generator)
+	outb(COMMAND, 0x34);
+	outb(CHANNEL0_DATA, 0);
+	outb(CHANNEL0_DATA, 0);
+}
+
+/// Current value of the counter of channel 0, which counts down
+pub fn read_counter() -> u16
+{
+	// latch the count of channel 0
+	outb(COMMAND, 0x00);
+	let low = inb(CHANNEL0_DATA);
+	let high = inb(CHANNEL0_DATA);
+	u16::from_le_bytes([low, high])
+}
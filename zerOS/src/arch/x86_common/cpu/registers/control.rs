@@ -1,80 +1,224 @@
+use bitflags::bitflags;
+
+use crate::arch::ureg;
+
 pub(super) mod extended
 {
+	use bitflags::bitflags;
+
+	bitflags! {
+		/// State components enabled for `XSAVE`
+		#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+		pub struct Xcr0: u64
+		{
+			const X87 = 1 << 0;
+			const SSE = 1 << 1;
+			const AVX = 1 << 2;
+			const BNDREGS = 1 << 3;
+			const BNDCSR = 1 << 4;
+			const OPMASK = 1 << 5;
+			const ZMM_HI256 = 1 << 6;
+			const HI16_ZMM = 1 << 7;
+			const PKRU = 1 << 9;
+			/// The components of the AVX-512 state, which are enabled together
+			const AVX512 = Self::OPMASK.bits() | Self::ZMM_HI256.bits() | Self::HI16_ZMM.bits();
+		}
+	}
+
 	pub mod xcr0
 	{
+		use super::Xcr0;
 		use crate::arch::core_target::{_xgetbv, _xsetbv};
 
-		pub fn read() -> u64
+		pub fn read() -> Xcr0
 		{
-			unsafe { _xgetbv(0) }
+			Xcr0::from_bits_retain(unsafe { _xgetbv(0) })
 		}
 
-		pub fn write(value: u64)
+		pub fn write(value: Xcr0)
 		{
 			unsafe {
-				_xsetbv(0, value);
+				_xsetbv(0, value.bits());
 			}
 		}
 	}
 }
 
+bitflags! {
+	#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+	pub struct Cr0: ureg
+	{
+		/// Protected mode enable
+		const PE = 1 << 0;
+		/// Monitor coprocessor (`WAIT` honors `TS`)
+		const MP = 1 << 1;
+		/// x87 emulation
+		const EM = 1 << 2;
+		/// Task switched, making the next FPU/SIMD instruction raise `#NM`
+		const TS = 1 << 3;
+		const ET = 1 << 4;
+		/// Native x87 error reporting
+		const NE = 1 << 5;
+		/// Write protection of read-only pages in ring 0
+		const WP = 1 << 16;
+		/// Alignment checks in ring 3
+		const AM = 1 << 18;
+		/// Not write-through
+		const NW = 1 << 29;
+		/// Cache disable
+		const CD = 1 << 30;
+		/// Paging enable
+		const PG = 1 << 31;
+	}
+
+	#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+	pub struct Cr4: ureg
+	{
+		const VME = 1 << 0;
+		const PVI = 1 << 1;
+		/// `RDTSC` restricted to ring 0
+		const TSD = 1 << 2;
+		/// Debugging extensions (I/O breakpoints)
+		const DE = 1 << 3;
+		const PSE = 1 << 4;
+		const PAE = 1 << 5;
+		const MCE = 1 << 6;
+		/// Global pages
+		const PGE = 1 << 7;
+		const PCE = 1 << 8;
+		/// `FXSAVE`/`FXRSTOR` and SSE support
+		const OSFXSR = 1 << 9;
+		/// Unmasked SIMD floating-point exceptions raise `#XM`
+		const OSXMMEXCPT = 1 << 10;
+		/// User-mode instruction prevention
+		const UMIP = 1 << 11;
+		/// 5-level paging
+		const LA57 = 1 << 12;
+		const VMXE = 1 << 13;
+		const SMXE = 1 << 14;
+		const FSGSBASE = 1 << 16;
+		/// Process-context identifiers, in `CR3`
+		const PCIDE = 1 << 17;
+		const OSXSAVE = 1 << 18;
+		const SMEP = 1 << 20;
+		const SMAP = 1 << 21;
+		/// Protection keys for user pages
+		const PKE = 1 << 22;
+		/// Control-flow enforcement technology
+		const CET = 1 << 23;
+		/// Protection keys for supervisor pages
+		const PKS = 1 << 24;
+	}
+}
+
+/// Root of the page tables, and the process-context identifier (with
+/// [`Cr4::PCIDE`])
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cr3(ureg);
+
+impl Cr3
+{
+	const FRAME_MASK: ureg = 0x000f_ffff_ffff_f000_u64 as ureg;
+	const PCID_MASK: ureg = 0xfff;
+
+	/// The page table root at physical address `frame`, with the process-
+	/// context identifier `pcid` (only 12 bits of it being used)
+	pub const fn new(frame: u64, pcid: u16) -> Self
+	{
+		Self((frame as ureg & Self::FRAME_MASK) | (pcid as ureg & Self::PCID_MASK))
+	}
+
+	pub const fn from_bits(bits: ureg) -> Self
+	{
+		Self(bits)
+	}
+
+	pub const fn bits(self) -> ureg
+	{
+		self.0
+	}
+
+	/// Physical address of the root page table
+	pub const fn frame(self) -> u64
+	{
+		(self.0 & Self::FRAME_MASK) as u64
+	}
+
+	/// Process-context identifier (the cache control bits without
+	/// [`Cr4::PCIDE`])
+	pub const fn pcid(self) -> u16
+	{
+		(self.0 & Self::PCID_MASK) as u16
+	}
+}
+
 pub mod cr0
 {
 	use core::arch::asm;
 
+	use super::Cr0;
 	use crate::arch::ureg;
 
-	pub fn read() -> ureg
+	pub fn read() -> Cr0
 	{
-		let mut result: ureg;
+		let result: ureg;
 		unsafe {
 			asm! {
-				"mov %cr0 {res}",
+				"mov %cr0, {res}",
 				res = out(reg) result,
-				options(att_syntax, nomem, nostack)
+				options(att_syntax, nomem, nostack, preserves_flags)
 			}
 		}
-        result
+		Cr0::from_bits_retain(result)
 	}
 
-    pub fn write(value: ureg)
+	pub fn write(value: Cr0)
 	{
 		unsafe {
 			asm! {
-				"mov {val} %cr0",
-				val = in(reg) value,
-				options(att_syntax, nomem, nostack)
+				"mov {val}, %cr0",
+				val = in(reg) value.bits(),
+				options(att_syntax, nostack, preserves_flags)
 			}
 		}
 	}
+
+	/// Write back the value `f` returns from the current one
+	pub fn update(f: impl FnOnce(Cr0) -> Cr0)
+	{
+		write(f(read()));
+	}
 }
 
 pub mod cr3
 {
 	use core::arch::asm;
 
+	use super::Cr3;
 	use crate::arch::ureg;
 
-	pub fn read() -> ureg
+	pub fn read() -> Cr3
 	{
-		let mut result: ureg;
+		let result: ureg;
 		unsafe {
 			asm! {
-				"mov %cr3 {res}",
+				"mov %cr3, {res}",
 				res = out(reg) result,
-				options(att_syntax, nomem, nostack)
+				options(att_syntax, nomem, nostack, preserves_flags)
 			}
 		}
-        result
+		Cr3(result)
 	}
 
-    pub fn write(value: ureg)
+	/// Switch to the page tables of `value`, flushing the non-global TLB
+	/// entries (of its PCID)
+	pub fn write(value: Cr3)
 	{
 		unsafe {
 			asm! {
-				"mov {val} %cr3",
-				val = in(reg) value,
-				options(att_syntax, nomem, nostack)
+				"mov {val}, %cr3",
+				val = in(reg) value.bits(),
+				options(att_syntax, nostack, preserves_flags)
 			}
 		}
 	}
@@ -84,29 +228,36 @@ pub mod cr4
 {
 	use core::arch::asm;
 
+	use super::Cr4;
 	use crate::arch::ureg;
 
-	pub fn read() -> ureg
+	pub fn read() -> Cr4
 	{
-		let mut result: ureg;
+		let result: ureg;
 		unsafe {
 			asm! {
-				"mov %cr4 {res}",
+				"mov %cr4, {res}",
 				res = out(reg) result,
-				options(att_syntax, nomem, nostack)
+				options(att_syntax, nomem, nostack, preserves_flags)
 			}
 		}
-        result
+		Cr4::from_bits_retain(result)
 	}
 
-    pub fn write(value: ureg)
+	pub fn write(value: Cr4)
 	{
 		unsafe {
 			asm! {
-				"mov {val} %cr4",
-				val = in(reg) value,
-				options(att_syntax, nomem, nostack)
+				"mov {val}, %cr4",
+				val = in(reg) value.bits(),
+				options(att_syntax, nostack, preserves_flags)
 			}
 		}
 	}
-}
\ No newline at end of file
+
+	/// Write back the value `f` returns from the current one
+	pub fn update(f: impl FnOnce(Cr4) -> Cr4)
+	{
+		write(f(read()));
+	}
+}
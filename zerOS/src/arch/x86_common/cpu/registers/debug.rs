@@ -1,5 +1,343 @@
+//! Debug registers, and the hardware breakpoints they hold
+//!
+//! `DR0`–`DR3` hold the addresses of up to 4 breakpoints, which `DR7` enables
+//! and configures; `DR6` tells which of them triggered the last debug
+//! exception (`#DB`). They are per-CPU: a watchpoint only fires on the CPU
+//! which set it.
 
-pub fn dummy()
+use core::fmt;
+
+use bitflags::bitflags;
+
+use crate::arch::ureg;
+
+/// Number of hardware breakpoints
+pub const BREAKPOINT_COUNT: usize = 4;
+
+bitflags! {
+	/// Debug status
+	#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+	pub struct Dr6: ureg
+	{
+		/// Breakpoint 0 triggered
+		const B0 = 1 << 0;
+		const B1 = 1 << 1;
+		const B2 = 1 << 2;
+		const B3 = 1 << 3;
+		/// An access to a debug register was detected (with [`Dr7::GD`])
+		const BD = 1 << 13;
+		/// Single step
+		const BS = 1 << 14;
+		/// Task switch
+		const BT = 1 << 15;
+	}
+
+	/// Debug control
+	#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+	pub struct Dr7: ureg
+	{
+		/// Breakpoint 0 enabled locally
+		const L0 = 1 << 0;
+		/// Breakpoint 0 enabled globally
+		const G0 = 1 << 1;
+		const L1 = 1 << 2;
+		const G1 = 1 << 3;
+		const L2 = 1 << 4;
+		const G2 = 1 << 5;
+		const L3 = 1 << 6;
+		const G3 = 1 << 7;
+		const LE = 1 << 8;
+		const GE = 1 << 9;
+		/// Raise `#DB` on accesses to the debug registers
+		const GD = 1 << 13;
+	}
+}
+
+/// Bits of `DR6` which are always set
+const DR6_RESERVED: ureg = 0xffff_0ff0_u64 as ureg;
+
+/// What accesses trigger a hardware breakpoint
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum WatchKind
+{
+	/// Executing the instruction at the address
+	Execute = 0b00,
+	Write   = 0b01,
+	/// Reading or writing (x86 has no read-only watchpoints)
+	Access  = 0b11
+}
+
+/// Size of the watched range, to which its address must be aligned
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum WatchLength
+{
+	Byte  = 0b00,
+	Word  = 0b01,
+	Dword = 0b11,
+	Qword = 0b10
+}
+
+impl WatchLength
+{
+	pub const fn bytes(self) -> u64
+	{
+		match self
+		{
+			Self::Byte => 1,
+			Self::Word => 2,
+			Self::Dword => 4,
+			Self::Qword => 8
+		}
+	}
+
+	/// The length of `bytes` bytes, if a breakpoint can watch that many
+	pub const fn from_bytes(bytes: u64) -> Option<Self>
+	{
+		match bytes
+		{
+			1 => Some(Self::Byte),
+			2 => Some(Self::Word),
+			4 => Some(Self::Dword),
+			8 => Some(Self::Qword),
+			_ => None
+		}
+	}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchpointError
+{
+	/// All the hardware breakpoints are in use
+	NoFreeSlot,
+	/// The address isn't aligned to the length
+	Misaligned,
+	/// Instruction breakpoints must have a length of one byte
+	InvalidLength,
+	/// No hardware breakpoint has this index
+	InvalidSlot
+}
+
+impl fmt::Display for WatchpointError
+{
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+	{
+		match self
+		{
+			Self::NoFreeSlot => write!(f, "all the hardware breakpoints are in use"),
+			Self::Misaligned => write!(f, "the address isn't aligned to the watched length"),
+			Self::InvalidLength => write!(f, "instruction breakpoints must watch a single byte"),
+			Self::InvalidSlot => write!(f, "no such hardware breakpoint")
+		}
+	}
+}
+
+/// A hardware breakpoint, as configured in the debug registers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Watchpoint
+{
+	pub addr: u64,
+	pub len:  WatchLength,
+	pub kind: WatchKind
+}
+
+macro_rules! debug_register {
+	($vis:vis $name:ident, $register:literal) => {
+		$vis mod $name
+		{
+			use core::arch::asm;
+
+			use crate::arch::ureg;
+
+			pub fn read() -> ureg
+			{
+				let result: ureg;
+				unsafe {
+					asm! {
+						concat!("mov %", $register, ", {res}"),
+						res = out(reg) result,
+						options(att_syntax, nomem, nostack, preserves_flags)
+					}
+				}
+				result
+			}
+
+			pub fn write(value: ureg)
+			{
+				unsafe {
+					asm! {
+						concat!("mov {val}, %", $register),
+						val = in(reg) value,
+						options(att_syntax, nostack, preserves_flags)
+					}
+				}
+			}
+		}
+	};
+}
+
+debug_register!(pub dr0, "dr0");
+debug_register!(pub dr1, "dr1");
+debug_register!(pub dr2, "dr2");
+debug_register!(pub dr3, "dr3");
+debug_register!(dr6_raw, "dr6");
+debug_register!(dr7_raw, "dr7");
+
+pub mod dr6
+{
+	use super::{DR6_RESERVED, Dr6, dr6_raw};
+
+	pub fn read() -> Dr6
+	{
+		Dr6::from_bits_truncate(dr6_raw::read())
+	}
+
+	pub fn write(value: Dr6)
+	{
+		dr6_raw::write(value.bits() | DR6_RESERVED);
+	}
+}
+
+pub mod dr7
+{
+	use super::{Dr7, dr7_raw};
+
+	pub fn read() -> Dr7
+	{
+		Dr7::from_bits_retain(dr7_raw::read())
+	}
+
+	pub fn write(value: Dr7)
+	{
+		dr7_raw::write(value.bits());
+	}
+}
+
+/// Address of the hardware breakpoint `slot`
+fn address(slot: usize) -> ureg
+{
+	match slot
+	{
+		0 => dr0::read(),
+		1 => dr1::read(),
+		2 => dr2::read(),
+		_ => dr3::read()
+	}
+}
+
+fn set_address(slot: usize, addr: ureg)
+{
+	match slot
+	{
+		0 => dr0::write(addr),
+		1 => dr1::write(addr),
+		2 => dr2::write(addr),
+		_ => dr3::write(addr)
+	}
+}
+
+/// Enable bits of the hardware breakpoint `slot` in `DR7`
+const fn enable_bits(slot: usize) -> Dr7
+{
+	Dr7::from_bits_retain(0b11 << (2 * slot))
+}
+
+/// Shift of the kind and length fields of the hardware breakpoint `slot` in
+/// `DR7`
+const fn control_shift(slot: usize) -> usize
+{
+	16 + 4 * slot
+}
+
+/// Set a hardware breakpoint on the calling CPU, returning its slot
+pub fn set_watchpoint(
+	addr: u64,
+	len: WatchLength,
+	kind: WatchKind
+) -> Result<usize, WatchpointError>
+{
+	if addr % len.bytes() != 0
+	{
+		return Err(WatchpointError::Misaligned);
+	}
+	if kind == WatchKind::Execute && len != WatchLength::Byte
+	{
+		return Err(WatchpointError::InvalidLength);
+	}
+
+	let control = dr7::read();
+	let slot = (0..BREAKPOINT_COUNT)
+		.find(|&slot| !control.intersects(enable_bits(slot)))
+		.ok_or(WatchpointError::NoFreeSlot)?;
+
+	set_address(slot, addr as ureg);
+	let shift = control_shift(slot);
+	let fields = (((len as ureg) << 2) | kind as ureg) << shift;
+	let control = Dr7::from_bits_retain((control.bits() & !(0b1111 << shift)) | fields);
+	// exact breakpoints, which both bits are recommended for
+	dr7::write(control | enable_bits(slot) | Dr7::LE | Dr7::GE);
+	Ok(slot)
+}
+
+/// Disable the hardware breakpoint `slot` of the calling CPU
+pub fn clear_watchpoint(slot: usize) -> Result<(), WatchpointError>
+{
+	if slot >= BREAKPOINT_COUNT
+	{
+		return Err(WatchpointError::InvalidSlot);
+	}
+	dr7::write(dr7::read() - enable_bits(slot));
+	set_address(slot, 0);
+	Ok(())
+}
+
+/// The hardware breakpoint `slot` of the calling CPU, if it is enabled
+pub fn watchpoint(slot: usize) -> Option<Watchpoint>
+{
+	let control = dr7::read();
+	if slot >= BREAKPOINT_COUNT || !control.intersects(enable_bits(slot))
+	{
+		return None;
+	}
+	let fields = (control.bits() >> control_shift(slot)) & 0b1111;
+	let len = match fields >> 2
+	{
+		0b00 => WatchLength::Byte,
+		0b01 => WatchLength::Word,
+		0b11 => WatchLength::Dword,
+		_ => WatchLength::Qword
+	};
+	let kind = match fields & 0b11
+	{
+		0b00 => WatchKind::Execute,
+		0b01 => WatchKind::Write,
+		_ => WatchKind::Access
+	};
+	Some(Watchpoint {
+		addr: address(slot) as u64,
+		len,
+		kind
+	})
+}
+
+/// Find the hardware breakpoint `addr` is watched by on the calling CPU
+pub fn find_watchpoint(addr: u64, kind: WatchKind) -> Option<usize>
+{
+	(0..BREAKPOINT_COUNT).find(|&slot| {
+		watchpoint(slot)
+			.is_some_and(|watchpoint| watchpoint.addr == addr && watchpoint.kind == kind)
+	})
+}
+
+/// The hardware breakpoints which triggered the current debug exception,
+/// as a mask of slots, clearing them in `DR6`
+///
+/// To be called by the `#DB` handler, as the CPU never clears `DR6`.
+pub fn take_triggered() -> u8
 {
-    todo!()
-}
\ No newline at end of file
+	let status = dr6::read();
+	let triggered = (status & (Dr6::B0 | Dr6::B1 | Dr6::B2 | Dr6::B3)).bits() as u8;
+	dr6::write(Dr6::empty());
+	triggered
+}
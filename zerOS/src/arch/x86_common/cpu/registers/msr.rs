@@ -1,6 +1,242 @@
-use core::arch::asm;
+//! Model-specific registers
+//!
+//! Each MSR the kernel uses is described by an [`Msr`] constant, typed with
+//! the bitflags of its value where it has some (e.g. [`EFER`]), and gated by
+//! the CPU features it requires: [`Msr::is_available`] should be checked
+//! before accessing one which isn't architectural. The `_safe` variants catch
+//! the general protection fault raised by accessing an unimplemented MSR,
+//! through the exception table.
 
-pub fn read(msr: u32) -> u64
+use core::{arch::asm, fmt, marker::PhantomData};
+
+use bitflags::bitflags;
+
+use crate::arch::target::cpu::{
+	extable::extable_entry,
+	features::{self, Features}
+};
+
+/// The CPU faulted when accessing an MSR
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MsrFault
+{
+	pub msr: u32
+}
+
+impl fmt::Display for MsrFault
+{
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+	{
+		write!(f, "accessing MSR {:#x} faulted", self.msr)
+	}
+}
+
+/// Value of an MSR, converted from and to its raw bits
+pub trait MsrValue: Copy
+{
+	fn from_raw(raw: u64) -> Self;
+
+	fn to_raw(self) -> u64;
+}
+
+impl MsrValue for u64
+{
+	fn from_raw(raw: u64) -> Self
+	{
+		raw
+	}
+
+	fn to_raw(self) -> u64
+	{
+		self
+	}
+}
+
+macro_rules! msr_flags {
+	($($flags:ty),* $(,)?) => {
+		$(
+			impl MsrValue for $flags
+			{
+				fn from_raw(raw: u64) -> Self
+				{
+					Self::from_bits_retain(raw)
+				}
+
+				fn to_raw(self) -> u64
+				{
+					self.bits()
+				}
+			}
+		)*
+	};
+}
+
+bitflags! {
+	/// Extended feature enable register
+	#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+	pub struct Efer: u64
+	{
+		/// `SYSCALL`/`SYSRET` enable
+		const SCE = 1 << 0;
+		/// Long mode enable
+		const LME = 1 << 8;
+		/// Long mode active
+		const LMA = 1 << 10;
+		/// No-execute enable
+		const NXE = 1 << 11;
+		/// Secure virtual machine enable
+		const SVME = 1 << 12;
+		/// Long mode segment limit enable
+		const LMSLE = 1 << 13;
+		/// Fast `FXSAVE`/`FXRSTOR`
+		const FFXSR = 1 << 14;
+		/// Translation cache extension
+		const TCE = 1 << 15;
+	}
+
+	/// Local APIC base address and mode
+	#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+	pub struct ApicBase: u64
+	{
+		/// The CPU is the bootstrap processor
+		const BSP = 1 << 8;
+		/// x2APIC mode enable
+		const X2APIC = 1 << 10;
+		/// Global enable of the local APIC
+		const ENABLE = 1 << 11;
+	}
+
+	/// Miscellaneous feature control (Intel only)
+	#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+	pub struct MiscEnable: u64
+	{
+		const FAST_STRINGS = 1 << 0;
+		const AUTOMATIC_THERMAL_CONTROL = 1 << 3;
+		const PERFORMANCE_MONITORING = 1 << 7;
+		const BTS_UNAVAILABLE = 1 << 11;
+		const PEBS_UNAVAILABLE = 1 << 12;
+		const ENHANCED_SPEEDSTEP = 1 << 16;
+		const MONITOR = 1 << 18;
+		const LIMIT_CPUID_MAXVAL = 1 << 22;
+		const XTPR_MESSAGE_DISABLE = 1 << 23;
+		const XD_BIT_DISABLE = 1 << 34;
+	}
+}
+
+msr_flags!(Efer, ApicBase, MiscEnable);
+
+impl ApicBase
+{
+	const ADDRESS_MASK: u64 = 0x000f_ffff_ffff_f000;
+
+	/// Physical address of the xAPIC register page
+	pub fn address(self) -> u64
+	{
+		self.bits() & Self::ADDRESS_MASK
+	}
+}
+
+/// A model-specific register, holding a `T`
+pub struct Msr<T>
+{
+	address:  u32,
+	requires: Features,
+	_value:   PhantomData<fn() -> T>
+}
+
+impl<T> Clone for Msr<T>
+{
+	fn clone(&self) -> Self
+	{
+		*self
+	}
+}
+
+impl<T> Copy for Msr<T> {}
+
+impl<T: MsrValue> Msr<T>
+{
+	/// The MSR at `address`, implemented by the CPUs supporting `requires`
+	pub const fn new(address: u32, requires: Features) -> Self
+	{
+		Self {
+			address,
+			requires,
+			_value: PhantomData
+		}
+	}
+
+	pub const fn address(self) -> u32
+	{
+		self.address
+	}
+
+	/// Whether the calling CPU implements the MSR, according to CPUID
+	pub fn is_available(self) -> bool
+	{
+		features::detected().contains(self.requires | Features::MSR)
+	}
+
+	pub fn read(self) -> T
+	{
+		T::from_raw(rdmsr(self.address))
+	}
+
+	pub fn write(self, value: T)
+	{
+		wrmsr(self.address, value.to_raw());
+	}
+
+	/// Read the MSR, and write back the value `f` returns from it
+	pub fn update(self, f: impl FnOnce(T) -> T)
+	{
+		self.write(f(self.read()));
+	}
+
+	pub fn read_safe(self) -> Result<T, MsrFault>
+	{
+		rdmsr_safe(self.address).map(T::from_raw)
+	}
+
+	pub fn write_safe(self, value: T) -> Result<(), MsrFault>
+	{
+		wrmsr_safe(self.address, value.to_raw())
+	}
+}
+
+pub const APIC_BASE: Msr<ApicBase> = Msr::new(0x1b, Features::APIC);
+/// Only implemented by Intel CPUs, which CPUID doesn't tell
+pub const MISC_ENABLE: Msr<MiscEnable> = Msr::new(0x1a0, Features::empty());
+/// Page attribute table, holding 8 memory types
+pub const PAT: Msr<u64> = Msr::new(0x277, Features::PAT);
+pub const TSC_DEADLINE: Msr<u64> = Msr::new(0x6e0, Features::TSC_DEADLINE);
+pub const EFER: Msr<Efer> = Msr::new(0xc000_0080, Features::empty());
+/// Segments loaded by `SYSCALL` and `SYSRET`
+pub const STAR: Msr<u64> = Msr::new(0xc000_0081, Features::SYSCALL);
+/// Target of `SYSCALL` in 64-bit mode
+pub const LSTAR: Msr<u64> = Msr::new(0xc000_0082, Features::SYSCALL);
+/// Target of `SYSCALL` in compatibility mode
+pub const CSTAR: Msr<u64> = Msr::new(0xc000_0083, Features::SYSCALL);
+/// `RFLAGS` bits `SYSCALL` clears
+pub const SFMASK: Msr<u64> = Msr::new(0xc000_0084, Features::SYSCALL);
+pub const FS_BASE: Msr<u64> = Msr::new(0xc000_0100, Features::empty());
+pub const GS_BASE: Msr<u64> = Msr::new(0xc000_0101, Features::empty());
+/// `GS` base `swapgs` exchanges with [`GS_BASE`]
+pub const KERNEL_GS_BASE: Msr<u64> = Msr::new(0xc000_0102, Features::empty());
+/// Value `RDTSCP` returns in `ECX`
+pub const TSC_AUX: Msr<u64> = Msr::new(0xc000_0103, Features::RDTSCP);
+
+/// MSR of the first x2APIC register
+const X2APIC_BASE: u32 = 0x800;
+
+/// The x2APIC register `index` (i.e. at offset `index * 16` of the xAPIC
+/// register page)
+pub const fn x2apic(index: u32) -> Msr<u64>
+{
+	Msr::new(X2APIC_BASE + index, Features::X2APIC)
+}
+
+fn rdmsr(msr: u32) -> u64
 {
 	let (high, low): (u32, u32);
 	unsafe {
@@ -15,17 +251,81 @@ pub fn read(msr: u32) -> u64
 	((high as u64) << 32) | (low as u64)
 }
 
-pub fn write(msr: u32, value: u64)
+fn wrmsr(msr: u32, value: u64)
+{
+	let low = value as u32;
+	let high = (value >> 32) as u32;
+	unsafe {
+		asm! {
+			"wrmsr",
+			in("ecx") msr,
+			in("eax") low,
+			in("edx") high,
+			options(att_syntax, nomem, nostack)
+		};
+	}
+}
+
+/// Read the MSR at `msr`, which may not be implemented
+pub fn rdmsr_safe(msr: u32) -> Result<u64, MsrFault>
+{
+	let (high, low): (u32, u32);
+	let faulted: u32;
+	unsafe {
+		asm! {
+			"xorl {faulted:e}, {faulted:e}",
+			"2:",
+			"rdmsr",
+			"3:",
+			".pushsection .text.fixup, \"ax\"",
+			"4:",
+			"movl $1, {faulted:e}",
+			"jmp 3b",
+			".popsection",
+			extable_entry!("2b", "4b"),
+			faulted = out(reg) faulted,
+			out("eax") low,
+			out("edx") high,
+			in("ecx") msr,
+			options(att_syntax, nomem, nostack)
+		};
+	}
+	if faulted != 0
+	{
+		return Err(MsrFault { msr });
+	}
+	Ok(((high as u64) << 32) | (low as u64))
+}
+
+/// Write `value` to the MSR at `msr`, which may not be implemented, or
+/// reject the value
+pub fn wrmsr_safe(msr: u32, value: u64) -> Result<(), MsrFault>
 {
 	let low = value as u32;
 	let high = (value >> 32) as u32;
+	let faulted: u32;
 	unsafe {
 		asm! {
+			"xorl {faulted:e}, {faulted:e}",
+			"2:",
 			"wrmsr",
+			"3:",
+			".pushsection .text.fixup, \"ax\"",
+			"4:",
+			"movl $1, {faulted:e}",
+			"jmp 3b",
+			".popsection",
+			extable_entry!("2b", "4b"),
+			faulted = out(reg) faulted,
 			in("ecx") msr,
 			in("eax") low,
 			in("edx") high,
 			options(att_syntax, nomem, nostack)
 		};
 	}
+	if faulted != 0
+	{
+		return Err(MsrFault { msr });
+	}
+	Ok(())
 }
@@ -0,0 +1,330 @@
+//! User mode (ring 3) execution and system calls
+//!
+//! User code runs through [`enter`], which behaves like a function call: it
+//! returns once the user code makes a system call (with `SYSCALL`) or raises
+//! an exception, with the register state of the user code at that point. The
+//! kernel may then handle it, and call [`enter`] again to resume.
+//!
+//! While in user mode, interrupts and system calls run on the stack of the
+//! kernel thread which called [`enter`] (it is put in the TSS, as `RSP0`).
+//! The `GS` base of the kernel points to a per-CPU [`CpuLocal`] area, and is
+//! swapped with the one of the user code on every transition with `swapgs`.
+//!
+//! The FPU and SIMD registers of the user code are kept in an [`FpuState`]
+//! while the kernel runs: they are restored before returning to user mode
+//! (right away, or on their first use with the lazy [`fpu::FpuPolicy`]), and
+//! saved as soon as the user code leaves it, before the kernel (which may use
+//! SIMD instructions anywhere) gets to clobber them.
+
+use core::{arch::naked_asm, cell::SyncUnsafeCell, mem::offset_of, ptr};
+
+use super::{
+	MAX_CPU_COUNT,
+	current_cpu_index,
+	exceptions::{InterruptFrame, InterruptStackFrame, restore_and_return, save_and_dispatch},
+	fpu::{self, FpuState},
+	irq,
+	msr::{self, Efer}
+};
+use crate::kernel::memory::{gdt::entry_index, tss};
+
+/// Bits of `RFLAGS`
+mod rflags
+{
+	pub const TF: u64 = 1 << 8;
+	pub const IF: u64 = 1 << 9;
+	pub const DF: u64 = 1 << 10;
+	pub const IOPL: u64 = 0b11 << 12;
+	pub const NT: u64 = 1 << 14;
+	pub const RF: u64 = 1 << 16;
+	pub const VM: u64 = 1 << 17;
+	pub const AC: u64 = 1 << 18;
+	/// Always set
+	pub const RESERVED: u64 = 1 << 1;
+}
+
+const KERNEL_CS: u64 = (entry_index!(KERNEL64_CS) as u64) << 3;
+/// Code segment of 64-bit user code
+pub const USER_CS: u64 = ((entry_index!(USER64_CS) as u64) << 3) | 3;
+/// Stack segment of user code
+pub const USER_SS: u64 = ((entry_index!(USER_DS) as u64) << 3) | 3;
+
+// `SYSCALL` loads CS from STAR[47:32], and SS from the next entry, while
+// `SYSRET` loads SS from STAR[63:48] + 8, and CS from STAR[63:48] + 16
+static_assert!(entry_index!(KERNEL_DS) == entry_index!(KERNEL64_CS) + 1);
+static_assert!(entry_index!(USER_DS) == entry_index!(USER32_CS) + 1);
+static_assert!(entry_index!(USER64_CS) == entry_index!(USER32_CS) + 2);
+const STAR: u64 = (KERNEL_CS << 32) | ((((entry_index!(USER32_CS) as u64) << 3) | 3) << 48);
+
+/// Pseudo-vector of the [`InterruptFrame`]s of system calls
+pub const SYSCALL_VECTOR: u64 = 0x100;
+
+/// Per-CPU data of the kernel, reachable through `GS` in ring 0
+#[repr(C)]
+struct CpuLocal
+{
+	/// Stack pointer of the kernel code which entered user mode, which system
+	/// calls also run on
+	kernel_rsp: u64,
+	/// Stack pointer of the user code, while a system call saves it
+	user_rsp:   u64,
+	/// Address of the `RSP0` slot of the TSS of the CPU
+	tss_rsp0:   u64
+}
+
+static CPU_LOCALS: [SyncUnsafeCell<CpuLocal>; MAX_CPU_COUNT] = [const {
+	SyncUnsafeCell::new(CpuLocal {
+		kernel_rsp: 0,
+		user_rsp:   0,
+		tss_rsp0:   0
+	})
+}; MAX_CPU_COUNT];
+
+/// Why [`enter`] returned
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UserExit
+{
+	/// The user code executed `SYSCALL` (its number being in `RAX`)
+	Syscall,
+	/// The user code raised the exception `vector`
+	Exception
+	{
+		vector: u8, error_code: u64
+	}
+}
+
+/// Enable `SYSCALL`/`SYSRET` on the calling CPU, and set up its kernel `GS`
+/// base
+///
+/// Must be called once per CPU, after its TSS has been loaded.
+pub fn init()
+{
+	super::cache_cpu_index();
+	let cpu = current_cpu_index();
+	// SAFETY: the area of the calling CPU isn't in use yet
+	let local = unsafe { &mut *CPU_LOCALS[cpu].get() };
+	local.tss_rsp0 = tss::kernel_stack_slot(cpu).addr() as u64;
+
+	msr::GS_BASE.write(ptr::from_mut(local).addr() as u64);
+	msr::KERNEL_GS_BASE.write(0);
+	msr::STAR.write(STAR);
+	msr::LSTAR.write(syscall_entry as usize as u64);
+	// run the entry stub with interrupts disabled, and a sane RFLAGS
+	msr::SFMASK
+		.write(rflags::TF | rflags::IF | rflags::DF | rflags::IOPL | rflags::NT | rflags::AC);
+	msr::EFER.update(|efer| efer | Efer::SCE);
+}
+
+/// A frame starting user code at `entry`, with the stack pointer `stack`
+///
+/// Interrupts are enabled, and all the other registers are zeroed.
+pub fn frame(entry: u64, stack: u64) -> InterruptFrame
+{
+	InterruptFrame {
+		cr3:        0,
+		cr2:        0,
+		r15:        0,
+		r14:        0,
+		r13:        0,
+		r12:        0,
+		r11:        0,
+		r10:        0,
+		r9:         0,
+		r8:         0,
+		rbp:        0,
+		rdi:        0,
+		rsi:        0,
+		rdx:        0,
+		rcx:        0,
+		rbx:        0,
+		rax:        0,
+		vector:     0,
+		error_code: 0,
+		cpu:        InterruptStackFrame {
+			rip:    entry,
+			cs:     USER_CS,
+			rflags: rflags::IF | rflags::RESERVED,
+			rsp:    stack,
+			ss:     USER_SS
+		}
+	}
+}
+
+/// Run user code from the state in `frame`, until it makes a system call or
+/// raises an exception
+///
+/// `frame` and `fpu` are then updated with the state of the user code: to
+/// resume it after a system call, set its result in `RAX` and call `enter`
+/// again. The stack of the calling thread is used as its kernel stack
+/// meanwhile.
+///
+/// # Safety
+///
+/// The code and the stack of `frame` must be mapped as user-accessible in the
+/// current address space, and [`init`] must have been called on this CPU.
+pub unsafe fn enter(frame: &mut InterruptFrame, fpu: &mut FpuState) -> UserExit
+{
+	let irqs = irq::enabled();
+
+	// whatever the user code asks for, it stays in ring 3 with interrupts
+	// enabled
+	frame.cpu.cs = USER_CS;
+	frame.cpu.ss = USER_SS;
+	frame.cpu.rflags = (frame.cpu.rflags & !(rflags::IOPL | rflags::NT | rflags::RF | rflags::VM))
+		| rflags::IF
+		| rflags::RESERVED;
+
+	// after a system call, `RCX` and `RFLAGS` are clobbered anyway: `SYSRET` is
+	// used, unless it would fault in ring 0 with a non-canonical `RIP`
+	let canonical = ((frame.cpu.rip as i64) << 16 >> 16) as u64 == frame.cpu.rip;
+	let sysret = frame.vector == SYSCALL_VECTOR && canonical;
+
+	irq::disable();
+	// nothing may use the SIMD registers from here
+	// SAFETY: `leave` is done with the state before this returns
+	unsafe { fpu::enter_context(fpu) };
+	// SAFETY: the stub returns here once `leave` is called, with the callee-
+	//         saved registers restored
+	unsafe { enter_user(frame, sysret) };
+	if irqs
+	{
+		irq::enable();
+	}
+
+	match frame.vector
+	{
+		SYSCALL_VECTOR => UserExit::Syscall,
+		vector =>
+		{
+			UserExit::Exception {
+				vector:     vector as u8,
+				error_code: frame.error_code
+			}
+		},
+	}
+}
+
+/// Save the callee-saved registers and the stack pointer of the kernel, and
+/// return to user mode with the state in `frame`
+#[unsafe(naked)]
+unsafe extern "C" fn enter_user(frame: *mut InterruptFrame, sysret: bool)
+{
+	naked_asm! {
+		"pushq %rbx",
+		"pushq %rbp",
+		"pushq %r12",
+		"pushq %r13",
+		"pushq %r14",
+		"pushq %r15",
+		"pushq %rdi",
+		// 16-byte aligned, which interrupts and system calls then start from
+		"movq %rsp, %gs:{kernel_rsp}",
+		"movq %gs:{tss_rsp0}, %rax",
+		"movq %rsp, (%rax)",
+		// copy the frame on the stack
+		"subq ${frame_size}, %rsp",
+		"movl %esi, %edx",
+		"movq %rdi, %rsi",
+		"movq %rsp, %rdi",
+		"movl ${frame_qwords}, %ecx",
+		"cld",
+		"rep movsq",
+		"testb %dl, %dl",
+		"jnz 1f",
+		"jmp {restore_and_return}",
+		"1:",
+		// skip CR3 and CR2
+		"addq $16, %rsp",
+		"popq %r15",
+		"popq %r14",
+		"popq %r13",
+		"popq %r12",
+		"popq %r11",
+		"popq %r10",
+		"popq %r9",
+		"popq %r8",
+		"popq %rbp",
+		"popq %rdi",
+		"popq %rsi",
+		"popq %rdx",
+		"popq %rcx",
+		"popq %rbx",
+		"popq %rax",
+		// above the vector and the error code
+		"movq 16(%rsp), %rcx",
+		"movq 32(%rsp), %r11",
+		"movq 40(%rsp), %rsp",
+		"swapgs",
+		"sysretq",
+		kernel_rsp = const offset_of!(CpuLocal, kernel_rsp),
+		tss_rsp0 = const offset_of!(CpuLocal, tss_rsp0),
+		frame_size = const size_of::<InterruptFrame>(),
+		frame_qwords = const size_of::<InterruptFrame>() / 8,
+		restore_and_return = sym restore_and_return,
+		options(att_syntax)
+	}
+}
+
+/// Return from [`enter`], with the state of the user code in `frame`
+///
+/// Called by the exception dispatcher, on the kernel stack of the thread
+/// which entered user mode (so below the frame of [`enter_user`]).
+pub(super) fn leave(frame: &InterruptFrame) -> !
+{
+	fpu::leave_context();
+	// SAFETY: `enter_user` saved the kernel state this returns to
+	unsafe { leave_to_kernel(frame) }
+}
+
+#[unsafe(naked)]
+unsafe extern "C" fn leave_to_kernel(frame: *const InterruptFrame) -> !
+{
+	naked_asm! {
+		"movq %rdi, %rsi",
+		"movq %gs:{kernel_rsp}, %rsp",
+		// the frame `enter_user` was called with
+		"popq %rdi",
+		"movl ${frame_qwords}, %ecx",
+		"cld",
+		"rep movsq",
+		"popq %r15",
+		"popq %r14",
+		"popq %r13",
+		"popq %r12",
+		"popq %rbp",
+		"popq %rbx",
+		"ret",
+		kernel_rsp = const offset_of!(CpuLocal, kernel_rsp),
+		frame_qwords = const size_of::<InterruptFrame>() / 8,
+		options(att_syntax)
+	}
+}
+
+/// Target of `SYSCALL`: build an [`InterruptFrame`] for the system call on
+/// the kernel stack, and dispatch it like an exception
+#[unsafe(naked)]
+extern "C" fn syscall_entry() -> !
+{
+	naked_asm! {
+		"swapgs",
+		"movq %rsp, %gs:{user_rsp}",
+		"movq %gs:{kernel_rsp}, %rsp",
+		"pushq ${user_ss}",
+		"pushq %gs:{user_rsp}",
+		// RFLAGS and RIP, saved by `SYSCALL`
+		"pushq %r11",
+		"pushq ${user_cs}",
+		"pushq %rcx",
+		// error code and vector
+		"pushq $0",
+		"pushq ${syscall_vector}",
+		"jmp {save_and_dispatch}",
+		user_rsp = const offset_of!(CpuLocal, user_rsp),
+		kernel_rsp = const offset_of!(CpuLocal, kernel_rsp),
+		user_ss = const USER_SS,
+		user_cs = const USER_CS,
+		syscall_vector = const SYSCALL_VECTOR,
+		save_and_dispatch = sym save_and_dispatch,
+		options(att_syntax)
+	}
+}
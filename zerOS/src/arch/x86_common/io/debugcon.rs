@@ -1,7 +1,9 @@
+use portable_atomic::{AtomicBool, Ordering};
+
 use crate::{
 	arch::target,
 	kernel::{
-		hypervisor,
+		hypervisor::{self, Hypervisor},
 		io::{KernelIO, KernelOutput},
 		logging,
 		sync::BasicMutex
@@ -37,15 +39,33 @@ impl KernelOutput for DebugCon
 }
 
 static ZEROS_DEBUGCON_LOGGER: BasicMutex<DebugCon> = BasicMutex::new(DebugCon);
+static REGISTERED: AtomicBool = AtomicBool::new(false);
+
+/// Log to the `0xe9` port if the hypervisor is known to emulate it
+///
+/// Called again once [`hypervisor::init`] has completed the detection with the
+/// DMI strings, as some emulators (e.g. Bochs, or QEMU with KVM) can't be told
+/// apart through CPUID alone.
+pub fn init()
+{
+	if !(hypervisor::under_qemu() || hypervisor::hypervisor() == Hypervisor::Bochs)
+		|| REGISTERED.swap(true, Ordering::AcqRel)
+	{
+		return;
+	}
+	logging::ZEROS_GLOBAL_LOGGER
+		.add_logger(
+			&ZEROS_DEBUGCON_LOGGER,
+			None,
+			logging::LoggingBackend::DebugCon
+		)
+		.unwrap();
+	logging::set_global_backend_state(logging::LoggingBackend::DebugCon, true);
+}
 
 ctor! {
 	@priority(100);
 	@name(zerOS_init_debugcon_logger);
-	if hypervisor::under_qemu().unwrap()
-	{
-		logging::ZEROS_GLOBAL_LOGGER.add_logger(
-			&ZEROS_DEBUGCON_LOGGER, None, logging::LoggingBackend::DebugCon
-		).unwrap();
-		logging::set_global_backend_state(logging::LoggingBackend::DebugCon, true);
-	}
+	// the `0xe9` port hack
+	init();
 }
@@ -0,0 +1,300 @@
+//! QEMU firmware configuration (fw_cfg) device
+//!
+//! The host exposes named blobs ("files") through the fw_cfg device, listed in
+//! its file directory (e.g. the ones passed with `-fw_cfg name=...`). An item
+//! is selected by writing its key to the selector port, then read sequentially,
+//! either byte by byte from the data port or, when the device supports it,
+//! through DMA.
+
+use alloc::{string::String, vec, vec::Vec};
+use core::{
+	fmt,
+	hint,
+	mem,
+	sync::atomic::{Ordering, fence}
+};
+
+use lazy_static::lazy_static;
+
+use crate::{
+	arch::x86_common::cpu::{
+		io::{inb, outl, outw},
+		paging::{self, PAGE_SIZE}
+	},
+	info,
+	kernel::{hypervisor, sync::BasicMutex}
+};
+
+/// File the host passes extra command-line options in (see `xtask run`)
+pub const ZEROS_CONFIG_FILE: &str = "opt/dev.nullware.zerOS";
+
+/// Maximum length of a file name
+pub const MAX_FILE_NAME_LENGTH: usize = 56;
+
+const SELECTOR_PORT: u16 = 0x510;
+const DATA_PORT: u16 = 0x511;
+/// Big-endian physical address of a [`DmaAccess`], the transfer starting when
+/// its low half is written
+const DMA_PORT: u16 = 0x514;
+
+/// Well-known items
+const SIGNATURE: u16 = 0x0000;
+const ID: u16 = 0x0001;
+const FILE_DIR: u16 = 0x0019;
+
+/// Revision bit of the `ID` item telling the DMA interface is available
+const ID_DMA: u32 = 1 << 1;
+
+/// Control bits of a [`DmaAccess`]
+const DMA_ERROR: u32 = 1 << 0;
+const DMA_READ: u32 = 1 << 1;
+
+/// Size of an entry of the file directory
+const FILE_ENTRY_SIZE: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FwCfgError
+{
+	/// The kernel doesn't run under QEMU, or the device is absent
+	Unavailable,
+	/// No file has this name
+	NotFound,
+	/// The device reported an error during a DMA transfer
+	Dma
+}
+
+impl fmt::Display for FwCfgError
+{
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+	{
+		match self
+		{
+			Self::Unavailable => write!(f, "the fw_cfg device isn't available"),
+			Self::NotFound => write!(f, "no such fw_cfg file"),
+			Self::Dma => write!(f, "a fw_cfg DMA transfer failed")
+		}
+	}
+}
+
+/// An entry of the file directory
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FwCfgFile
+{
+	pub name:     heapless::String<MAX_FILE_NAME_LENGTH>,
+	pub size:     u32,
+	/// Key of the item holding the file
+	pub selector: u16
+}
+
+impl FwCfgFile
+{
+	fn parse(entry: &[u8; FILE_ENTRY_SIZE]) -> Self
+	{
+		let name = &entry[8..];
+		let name = &name[..name
+			.iter()
+			.position(|&byte| byte == 0)
+			.unwrap_or(name.len())];
+		Self {
+			name:     core::str::from_utf8(name)
+				.ok()
+				.and_then(|name| heapless::String::try_from(name).ok())
+				.unwrap_or_default(),
+			size:     u32::from_be_bytes(entry[0..4].try_into().unwrap()),
+			selector: u16::from_be_bytes(entry[4..6].try_into().unwrap())
+		}
+	}
+}
+
+/// Descriptor of a DMA transfer, whose fields are big-endian
+#[repr(C, align(16))]
+struct DmaAccess
+{
+	control: u32,
+	length:  u32,
+	address: u64
+}
+
+struct FwCfg
+{
+	dma:    bool,
+	/// Kept in the kernel image, so that its physical address is known
+	access: DmaAccess
+}
+
+impl FwCfg
+{
+	fn probe() -> Option<Self>
+	{
+		if !hypervisor::under_qemu() || !has_signature()
+		{
+			return None;
+		}
+
+		let mut this = Self {
+			dma:    false,
+			access: DmaAccess {
+				control: 0,
+				length:  0,
+				address: 0
+			}
+		};
+		let mut id = [0; 4];
+		this.select(ID);
+		this.read(&mut id).ok()?;
+		this.dma = u32::from_le_bytes(id) & ID_DMA != 0;
+
+		info!("found the QEMU fw_cfg device (DMA: {})", this.dma);
+		Some(this)
+	}
+
+	fn select(&mut self, key: u16)
+	{
+		outw(SELECTOR_PORT, key);
+	}
+
+	/// Physical address of the DMA descriptor, if DMA can be used
+	fn dma_access(&mut self) -> Option<u64>
+	{
+		self.dma
+			.then(|| paging::physical_address(&raw mut self.access as u64).ok())
+			.flatten()
+	}
+
+	/// Read `buf.len()` bytes of the selected item, from where the last read
+	/// stopped
+	fn read(&mut self, buf: &mut [u8]) -> Result<(), FwCfgError>
+	{
+		let access = self.dma_access();
+		let mut rest = buf;
+		while !rest.is_empty()
+		{
+			let addr = rest.as_ptr() as u64;
+			// the pages of the buffer may not be contiguous in physical memory
+			let chunk = rest.len().min(PAGE_SIZE - (addr as usize % PAGE_SIZE));
+			let (head, tail) = mem::take(&mut rest).split_at_mut(chunk);
+			match access.zip(paging::physical_address(addr).ok())
+			{
+				Some((access, phys)) => self.transfer(access, DMA_READ, phys, chunk)?,
+				None => head.iter_mut().for_each(|byte| *byte = inb(DATA_PORT))
+			}
+			rest = tail;
+		}
+		Ok(())
+	}
+
+	fn transfer(
+		&mut self,
+		access: u64,
+		control: u32,
+		phys: u64,
+		length: usize
+	) -> Result<(), FwCfgError>
+	{
+		let descriptor = &raw mut self.access;
+		// SAFETY: the device accesses the descriptor behind the compiler's back
+		unsafe {
+			descriptor.write_volatile(DmaAccess {
+				control: control.to_be(),
+				length:  (length as u32).to_be(),
+				address: phys.to_be()
+			});
+		}
+		fence(Ordering::SeqCst);
+		outl(DMA_PORT, ((access >> 32) as u32).to_be());
+		outl(DMA_PORT + 4, (access as u32).to_be());
+
+		// QEMU completes the transfer before the write returns, but the
+		// device is allowed not to
+		loop
+		{
+			// SAFETY: see above
+			let control =
+				u32::from_be(unsafe { (&raw const (*descriptor).control).read_volatile() });
+			if control & DMA_ERROR != 0
+			{
+				return Err(FwCfgError::Dma);
+			}
+			if control == 0
+			{
+				break;
+			}
+			hint::spin_loop();
+		}
+		fence(Ordering::SeqCst);
+		Ok(())
+	}
+}
+
+lazy_static! {
+	static ref FW_CFG: BasicMutex<Option<FwCfg>> = BasicMutex::new(FwCfg::probe());
+}
+
+/// Whether the ports of the fw_cfg device answer with QEMU's signature
+///
+/// Only port I/O is used, so this can be called at any time (e.g. to identify
+/// the hypervisor), but only under a hypervisor: the ports may belong to
+/// another device otherwise.
+pub fn has_signature() -> bool
+{
+	outw(SELECTOR_PORT, SIGNATURE);
+	let signature = [(); 4].map(|()| inb(DATA_PORT));
+	&signature == b"QEMU"
+}
+
+/// Whether the fw_cfg device is present
+pub fn is_available() -> bool
+{
+	FW_CFG.lock().is_some()
+}
+
+/// List the files of the fw_cfg directory
+pub fn files() -> Result<Vec<FwCfgFile>, FwCfgError>
+{
+	let mut guard = FW_CFG.lock();
+	let fw_cfg = guard.as_mut().ok_or(FwCfgError::Unavailable)?;
+
+	let mut count = [0; 4];
+	fw_cfg.select(FILE_DIR);
+	fw_cfg.read(&mut count)?;
+	(0..u32::from_be_bytes(count))
+		.map(|_| {
+			let mut entry = [0; FILE_ENTRY_SIZE];
+			fw_cfg.read(&mut entry)?;
+			Ok(FwCfgFile::parse(&entry))
+		})
+		.collect()
+}
+
+/// Find the file `name` in the fw_cfg directory
+pub fn find_file(name: &str) -> Result<FwCfgFile, FwCfgError>
+{
+	files()?
+		.into_iter()
+		.find(|file| file.name == name)
+		.ok_or(FwCfgError::NotFound)
+}
+
+/// Read the beginning of the item `selector` into `buf`
+pub fn read_item(selector: u16, buf: &mut [u8]) -> Result<(), FwCfgError>
+{
+	let mut guard = FW_CFG.lock();
+	let fw_cfg = guard.as_mut().ok_or(FwCfgError::Unavailable)?;
+	fw_cfg.select(selector);
+	fw_cfg.read(buf)
+}
+
+/// Read the whole file `name`
+pub fn read_file(name: &str) -> Result<Vec<u8>, FwCfgError>
+{
+	let file = find_file(name)?;
+	let mut contents = vec![0; file.size as usize];
+	read_item(file.selector, &mut contents)?;
+	Ok(contents)
+}
+
+/// Read the whole file `name` as text, invalid UTF-8 being replaced
+pub fn read_file_to_string(name: &str) -> Result<String, FwCfgError>
+{
+	read_file(name).map(|contents| String::from_utf8_lossy(&contents).into_owned())
+}
@@ -1,2 +1,3 @@
 pub mod serial;
-pub mod debugcon;
\ No newline at end of file
+pub mod debugcon;
+pub mod fw_cfg;
@@ -6,10 +6,7 @@ use core::{
 use lazy_static::lazy_static;
 
 use crate::{
-	arch::x86_common::cpu::{
-		io::{inb, outb},
-		misc::hcf
-	},
+	arch::x86_common::cpu::io::{inb, outb},
 	kernel::{
 		hypervisor,
 		logging::{self, ZEROS_GLOBAL_LOGGER},
@@ -39,6 +36,34 @@ impl SerialPortId
 	/// QEMU monitor
 	pub const DEBUG: Self = Self::COM1;
 
+	/// The port named `COM<number>`
+	pub fn from_com_number(number: u8) -> Option<Self>
+	{
+		[
+			Self::COM1,
+			Self::COM2,
+			Self::COM3,
+			Self::COM4,
+			Self::COM5,
+			Self::COM6,
+			Self::COM7,
+			Self::COM8
+		]
+		.get(usize::from(number).checked_sub(1)?)
+		.copied()
+	}
+
+	/// ISA IRQ the port raises, for the ports with a standard one
+	pub fn isa_irq(&self) -> Option<u8>
+	{
+		match *self
+		{
+			Self::COM1 | Self::COM3 => Some(4),
+			Self::COM2 | Self::COM4 => Some(3),
+			_ => None
+		}
+	}
+
 	pub fn unique_index(&self) -> Option<usize>
 	{
 		match *self
@@ -128,13 +153,19 @@ impl SerialPort
 	{
 		inb((self.id as u16) + 5) & 1 != 0
 	}
+
+	/// Raise the IRQ of the port whenever a byte is received
+	pub fn enable_receive_interrupt(&self)
+	{
+		outb((self.id as u16) + 1, 0x01);
+	}
 }
 
 impl SerialOutput for SerialPort
 {
 	fn supports_ansi_escape_codes(&self) -> bool
 	{
-		hypervisor::under_qemu().unwrap_or_else(|_| hcf()) && self.id == SerialPortId::DEBUG
+		hypervisor::under_qemu() && self.id == SerialPortId::DEBUG
 	}
 
 	fn serial_write_byte(&self, byte: u8)
@@ -170,7 +201,7 @@ lazy_static! {
 ctor! {
 	@name(zerOS_init_serial_loggers);
 	@priority(100);
-	if hypervisor::under_qemu().unwrap_or_else(|_| hcf())
+	if hypervisor::under_qemu()
 	{
 		ZEROS_GLOBAL_LOGGER.add_logger(
 			&*ZEROS_COM1_SERIAL_LOGGER,
@@ -1,4 +1,5 @@
 pub mod cpu;
 pub mod io;
+pub mod time;
 
 pub const PAGE_SIZE: usize = 4096;
\ No newline at end of file
@@ -0,0 +1,136 @@
+//! The HPET main counter as a clock source
+//!
+//! The HPET is found through its ACPI table. Its comparators aren't used: the
+//! local APIC timer is a better clock event device.
+
+use portable_atomic::{AtomicU64, Ordering};
+
+use crate::{
+	arch::target::cpu::paging,
+	info,
+	kernel::{acpi, time::ClockSource},
+	warn
+};
+
+const GENERAL_CAPABILITIES: u64 = 0x00;
+const GENERAL_CONFIGURATION: u64 = 0x10;
+const MAIN_COUNTER: u64 = 0xf0;
+
+const COUNTER_64BIT: u64 = 1 << 13;
+const ENABLE: u64 = 1 << 0;
+const LEGACY_REPLACEMENT: u64 = 1 << 1;
+
+/// Femtoseconds per second, the counter period being given in femtoseconds
+const FS_PER_SEC: u64 = 1_000_000_000_000_000;
+
+struct Hpet
+{
+	/// Virtual address of the registers
+	base:      AtomicU64,
+	frequency: AtomicU64,
+	mask:      AtomicU64
+}
+
+impl Hpet
+{
+	fn register(&self, offset: u64) -> *mut u64
+	{
+		(self.base.load(Ordering::Relaxed) + offset) as *mut u64
+	}
+}
+
+impl ClockSource for Hpet
+{
+	fn name(&self) -> &'static str
+	{
+		"hpet"
+	}
+
+	fn rating(&self) -> u32
+	{
+		250
+	}
+
+	fn frequency(&self) -> u64
+	{
+		self.frequency.load(Ordering::Relaxed)
+	}
+
+	fn mask(&self) -> u64
+	{
+		self.mask.load(Ordering::Relaxed)
+	}
+
+	fn read(&self) -> u64
+	{
+		// SAFETY: mapped by `probe`
+		unsafe { self.register(MAIN_COUNTER).read_volatile() }
+	}
+}
+
+static HPET: Hpet = Hpet {
+	base:      AtomicU64::new(0),
+	frequency: AtomicU64::new(0),
+	mask:      AtomicU64::new(0)
+};
+
+pub(super) fn probe() -> Option<&'static dyn ClockSource>
+{
+	let table = acpi::find_table(b"HPET").ok()?;
+	// the base address is a generic address structure, at offset 40
+	let Some(&[space, ..]) = table.get(40..44)
+	else
+	{
+		warn!("the HPET table is truncated");
+		return None;
+	};
+	let address = u64::from_le_bytes(table.get(44..52)?.try_into().ok()?);
+	if space != 0
+	{
+		warn!("the HPET isn't memory-mapped");
+		return None;
+	}
+
+	// SAFETY: the registers aren't RAM
+	let base = match unsafe { paging::map_physical(address, paging::PAGE_SIZE, true) }
+	{
+		Ok(base) => base,
+		Err(err) =>
+		{
+			warn!("couldn't map the HPET registers: {err}");
+			return None;
+		}
+	};
+	HPET.base.store(base, Ordering::Relaxed);
+
+	// SAFETY: just mapped
+	let capabilities = unsafe { HPET.register(GENERAL_CAPABILITIES).read_volatile() };
+	let period = capabilities >> 32;
+	if period == 0 || period > 100_000_000
+	{
+		warn!("the HPET has an invalid period ({period} fs)");
+		return None;
+	}
+	HPET.frequency.store(FS_PER_SEC / period, Ordering::Relaxed);
+	let mask = if capabilities & COUNTER_64BIT != 0
+	{
+		u64::MAX
+	}
+	else
+	{
+		u64::from(u32::MAX)
+	};
+	HPET.mask.store(mask, Ordering::Relaxed);
+
+	// SAFETY: just mapped
+	unsafe {
+		let configuration = HPET.register(GENERAL_CONFIGURATION);
+		configuration
+			.write_volatile((configuration.read_volatile() | ENABLE) & !LEGACY_REPLACEMENT);
+	}
+	info!(
+		"HPET at {address:#x}: {} timers",
+		((capabilities >> 8) & 0x1f) + 1
+	);
+	Some(&HPET)
+}
@@ -0,0 +1,200 @@
+//! KVM's paravirtual clock as a clock source
+//!
+//! The hypervisor keeps, for each CPU which registered one, a structure from
+//! which the time since boot (in nanoseconds) can be computed with the TSC,
+//! without exiting to the hypervisor. Each CPU registers its own structure
+//! the first time it reads the clock.
+
+use core::{
+	cell::SyncUnsafeCell,
+	mem::size_of,
+	sync::atomic::{Ordering, fence}
+};
+
+use portable_atomic::{AtomicBool, AtomicU64};
+
+use crate::{
+	arch::target::cpu::{
+		self,
+		MAX_CPU_COUNT,
+		features::Features,
+		misc::read_tsc,
+		msr::Msr,
+		paging
+	},
+	kernel::{
+		hypervisor::{self, ParavirtFeatures},
+		time::ClockSource
+	},
+	warn
+};
+
+const MSR_KVM_SYSTEM_TIME: Msr<u64> = Msr::new(0x12, Features::HYPERVISOR);
+const MSR_KVM_SYSTEM_TIME_NEW: Msr<u64> = Msr::new(0x4b56_4d01, Features::HYPERVISOR);
+
+/// Enable bit of the system time MSRs
+const ENABLE: u64 = 1;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct PvclockTimeInfo
+{
+	/// Odd while the hypervisor updates the structure
+	version:           u32,
+	_pad0:             u32,
+	tsc_timestamp:     u64,
+	system_time:       u64,
+	tsc_to_system_mul: u32,
+	tsc_shift:         i8,
+	flags:             u8,
+	_pad1:             [u8; 2]
+}
+static_assert!(size_of::<PvclockTimeInfo>() == 32);
+
+/// The structures of all the CPUs, which mustn't cross a page boundary
+#[repr(C, align(4096))]
+struct PvclockPage([PvclockTimeInfo; MAX_CPU_COUNT]);
+static_assert!(size_of::<PvclockPage>() <= paging::PAGE_SIZE);
+
+static PVCLOCK: SyncUnsafeCell<PvclockPage> = SyncUnsafeCell::new(PvclockPage(
+	[PvclockTimeInfo {
+		version:           0,
+		_pad0:             0,
+		tsc_timestamp:     0,
+		system_time:       0,
+		tsc_to_system_mul: 0,
+		tsc_shift:         0,
+		flags:             0,
+		_pad1:             [0; 2]
+	}; MAX_CPU_COUNT]
+));
+
+/// Physical address of [`PVCLOCK`]
+static PVCLOCK_PHYS: AtomicU64 = AtomicU64::new(0);
+/// Whether the newer system time MSR is used
+static NEW_MSR: AtomicBool = AtomicBool::new(false);
+/// Whether each CPU registered its structure
+static REGISTERED: [AtomicBool; MAX_CPU_COUNT] = [const { AtomicBool::new(false) }; MAX_CPU_COUNT];
+
+fn time_info(cpu: usize) -> *const PvclockTimeInfo
+{
+	// SAFETY: only a pointer is formed, the structure being written by the
+	//         hypervisor behind our back
+	unsafe { &raw const (*PVCLOCK.get()).0[cpu] }
+}
+
+struct KvmClock;
+
+impl ClockSource for KvmClock
+{
+	fn name(&self) -> &'static str
+	{
+		"kvm-clock"
+	}
+
+	fn rating(&self) -> u32
+	{
+		400
+	}
+
+	fn frequency(&self) -> u64
+	{
+		// the counter is in nanoseconds
+		1_000_000_000
+	}
+
+	fn mask(&self) -> u64
+	{
+		u64::MAX
+	}
+
+	fn read(&self) -> u64
+	{
+		let cpu = cpu::current_cpu_index();
+		if !REGISTERED[cpu].load(Ordering::Acquire)
+		{
+			register(cpu);
+		}
+		let info = time_info(cpu);
+		loop
+		{
+			// SAFETY: registered by `probe`, and only read with volatile
+			//         accesses, which are retried if the hypervisor updated the
+			//         structure meanwhile
+			let (version, time) = unsafe {
+				let version = (&raw const (*info).version).read_volatile();
+				fence(Ordering::Acquire);
+				let snapshot = info.read_volatile();
+				let mut delta = read_tsc().wrapping_sub(snapshot.tsc_timestamp);
+				if snapshot.tsc_shift >= 0
+				{
+					delta <<= snapshot.tsc_shift;
+				}
+				else
+				{
+					delta >>= -snapshot.tsc_shift;
+				}
+				let scaled = (u128::from(delta) * u128::from(snapshot.tsc_to_system_mul)) >> 32;
+				fence(Ordering::Acquire);
+				let time = snapshot.system_time.wrapping_add(scaled as u64);
+				if (&raw const (*info).version).read_volatile() != version
+				{
+					continue;
+				}
+				(version, time)
+			};
+			if version & 1 == 0
+			{
+				return time;
+			}
+		}
+	}
+}
+
+static KVM_CLOCK: KvmClock = KvmClock;
+
+/// Have the hypervisor update the structure of the calling CPU, `cpu`
+fn register(cpu: usize)
+{
+	let msr = if NEW_MSR.load(Ordering::Acquire)
+	{
+		MSR_KVM_SYSTEM_TIME_NEW
+	}
+	else
+	{
+		MSR_KVM_SYSTEM_TIME
+	};
+	let phys = PVCLOCK_PHYS.load(Ordering::Acquire) + (cpu * size_of::<PvclockTimeInfo>()) as u64;
+	msr.write(phys | ENABLE);
+	REGISTERED[cpu].store(true, Ordering::Release);
+}
+
+pub(super) fn probe() -> Option<&'static dyn ClockSource>
+{
+	if !hypervisor::under_kvm()
+	{
+		return None;
+	}
+	let features = hypervisor::hypervisor_info().features;
+	if features.contains(ParavirtFeatures::KVM_CLOCK2)
+	{
+		NEW_MSR.store(true, Ordering::Release);
+	}
+	else if !features.contains(ParavirtFeatures::KVM_CLOCK)
+	{
+		return None;
+	}
+
+	let phys = match paging::physical_address(PVCLOCK.get() as u64)
+	{
+		Ok(phys) => phys,
+		Err(err) =>
+		{
+			warn!("couldn't find the physical address of the kvmclock structures: {err}");
+			return None;
+		}
+	};
+	PVCLOCK_PHYS.store(phys, Ordering::Release);
+	register(cpu::current_cpu_index());
+	Some(&KVM_CLOCK)
+}
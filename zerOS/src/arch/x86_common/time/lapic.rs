@@ -0,0 +1,77 @@
+//! The local APIC timer as a clock event device
+//!
+//! It is programmed in TSC-deadline mode when the TSC is invariant, and in
+//! one-shot mode otherwise.
+
+use crate::{
+	arch::target::cpu::apic::{self, TimerMode},
+	kernel::{
+		irq::{self, Irq, IrqContext, IrqFlags, IrqReturn},
+		time::{self, ClockEvent, TimeError}
+	},
+	warn
+};
+
+struct LapicTimer;
+
+impl ClockEvent for LapicTimer
+{
+	fn name(&self) -> &'static str
+	{
+		"lapic"
+	}
+
+	fn rating(&self) -> u32
+	{
+		if apic::has_tsc_deadline() { 150 } else { 100 }
+	}
+
+	fn program(&self, ns: u64) -> Result<(), TimeError>
+	{
+		let mode = if apic::has_tsc_deadline() && super::tsc_is_invariant()
+		{
+			TimerMode::TscDeadline
+		}
+		else
+		{
+			TimerMode::OneShot
+		};
+		apic::start_timer(mode, ns)
+			.map_err(|_| TimeError::Device("the local APIC timer couldn't be programmed"))
+	}
+
+	fn cancel(&self)
+	{
+		apic::stop_timer();
+	}
+}
+
+static LAPIC_TIMER: LapicTimer = LapicTimer;
+
+fn handle_timer(_: &IrqContext) -> IrqReturn
+{
+	time::handle_clock_event();
+	IrqReturn::Handled
+}
+
+pub(super) fn init()
+{
+	if apic::mode() == apic::ApicMode::Disabled
+	{
+		return;
+	}
+	if let Err(err) = irq::request_irq(
+		Irq::Vector(apic::timer_vector()),
+		handle_timer,
+		IrqFlags::empty(),
+		"local APIC timer"
+	)
+	{
+		warn!("couldn't register the local APIC timer handler: {err}");
+		return;
+	}
+	if let Err(err) = time::register_clock_event(&LAPIC_TIMER)
+	{
+		warn!("couldn't register the local APIC timer: {err}");
+	}
+}
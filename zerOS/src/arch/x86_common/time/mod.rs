@@ -0,0 +1,42 @@
+//! Clock sources and clock event devices of x86 machines
+//!
+//! See [`kernel::time`](crate::kernel::time) for the framework they're
+//! registered to.
+
+mod hpet;
+mod kvmclock;
+mod lapic;
+mod pit;
+mod pm_timer;
+mod tsc;
+
+pub use tsc::is_invariant as tsc_is_invariant;
+
+use crate::{
+	kernel::time::{self, ClockSource},
+	warn
+};
+
+/// Probe and register all the clock sources and clock event devices, then
+/// select the clock source (`preferred`, if it is available)
+///
+/// The local APIC must have been initialized and its timer calibrated.
+pub fn init(preferred: Option<&str>)
+{
+	let probes: [fn() -> Option<&'static dyn ClockSource>; 5] = [
+		pit::probe,
+		pm_timer::probe,
+		hpet::probe,
+		tsc::probe,
+		kvmclock::probe
+	];
+	for source in probes.into_iter().filter_map(|probe| probe())
+	{
+		if let Err(err) = time::register_clock_source(source)
+		{
+			warn!("couldn't register clock source {}: {err}", source.name());
+		}
+	}
+	lapic::init();
+	time::select_clock_source(preferred);
+}
@@ -0,0 +1,55 @@
+//! The PIT as a clock source
+//!
+//! Its 16-bit counter wraps around every 55 ms, and reading it takes several
+//! slow port accesses: it is only used when nothing better is available.
+
+use crate::{
+	arch::target::cpu::pit,
+	kernel::{sync::BasicMutex, time::ClockSource}
+};
+
+struct Pit
+{
+	/// Serializes the latch and read sequences
+	lock: BasicMutex<()>
+}
+
+impl ClockSource for Pit
+{
+	fn name(&self) -> &'static str
+	{
+		"pit"
+	}
+
+	fn rating(&self) -> u32
+	{
+		110
+	}
+
+	fn frequency(&self) -> u64
+	{
+		pit::FREQUENCY
+	}
+
+	fn mask(&self) -> u64
+	{
+		0xffff
+	}
+
+	fn read(&self) -> u64
+	{
+		let _guard = self.lock.lock();
+		// the counter counts down
+		u64::from(0_u16.wrapping_sub(pit::read_counter()))
+	}
+}
+
+static PIT: Pit = Pit {
+	lock: BasicMutex::new(())
+};
+
+pub(super) fn probe() -> Option<&'static dyn ClockSource>
+{
+	pit::start_counter();
+	Some(&PIT)
+}
@@ -0,0 +1,97 @@
+//! The ACPI power management timer as a clock source
+//!
+//! Its port is found in the FADT. The counter runs at 3.579545 MHz, and is
+//! either 24 or 32 bits wide.
+
+use portable_atomic::{AtomicU16, AtomicU64, Ordering};
+
+use crate::{
+	arch::target::cpu::io::inl,
+	info,
+	kernel::{acpi, time::ClockSource}
+};
+
+const FREQUENCY: u64 = 3_579_545;
+
+/// Offsets of the fields of the FADT
+const PM_TMR_BLK: usize = 76;
+const FLAGS: usize = 112;
+const X_PM_TMR_BLK: usize = 208;
+
+/// The counter is 32 bits wide, instead of 24
+const TMR_VAL_EXT: u32 = 1 << 8;
+/// Address space of the system I/O ports, in a generic address structure
+const SYSTEM_IO: u8 = 1;
+
+struct PmTimer
+{
+	port: AtomicU16,
+	mask: AtomicU64
+}
+
+impl ClockSource for PmTimer
+{
+	fn name(&self) -> &'static str
+	{
+		"acpi_pm"
+	}
+
+	fn rating(&self) -> u32
+	{
+		200
+	}
+
+	fn frequency(&self) -> u64
+	{
+		FREQUENCY
+	}
+
+	fn mask(&self) -> u64
+	{
+		self.mask.load(Ordering::Relaxed)
+	}
+
+	fn read(&self) -> u64
+	{
+		u64::from(inl(self.port.load(Ordering::Relaxed))) & self.mask()
+	}
+}
+
+static PM_TIMER: PmTimer = PmTimer {
+	port: AtomicU16::new(0),
+	mask: AtomicU64::new(0)
+};
+
+pub(super) fn probe() -> Option<&'static dyn ClockSource>
+{
+	let fadt = acpi::find_table(b"FACP").ok()?;
+	let u32_at = |offset: usize| {
+		fadt.get(offset..offset + 4)
+			.map(|bytes| u32::from_le_bytes(bytes.try_into().unwrap()))
+	};
+
+	// prefer the extended block, when it is present and in I/O space
+	let extended = fadt
+		.get(X_PM_TMR_BLK..X_PM_TMR_BLK + 12)
+		.filter(|gas| gas[0] == SYSTEM_IO)
+		.map(|gas| u64::from_le_bytes(gas[4..].try_into().unwrap()))
+		.filter(|&address| address != 0);
+	let port = extended.or_else(|| u32_at(PM_TMR_BLK).map(u64::from))?;
+	let port = u16::try_from(port).ok().filter(|&port| port != 0)?;
+
+	let mask = if u32_at(FLAGS).unwrap_or(0) & TMR_VAL_EXT != 0
+	{
+		u64::from(u32::MAX)
+	}
+	else
+	{
+		0x00ff_ffff
+	};
+	PM_TIMER.port.store(port, Ordering::Relaxed);
+	PM_TIMER.mask.store(mask, Ordering::Relaxed);
+	info!(
+		"ACPI PM timer at port {port:#x}: {} bits",
+		mask.count_ones()
+	);
+	Some(&PM_TIMER)
+}
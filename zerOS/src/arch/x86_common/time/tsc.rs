@@ -0,0 +1,99 @@
+//! The TSC as a clock source
+//!
+//! It is the cheapest counter to read, but it is only reliable when invariant
+//! (i.e. running at a constant rate in every power state). Its frequency is
+//! given by CPUID when possible, and measured against the PIT otherwise.
+
+use portable_atomic::{AtomicU64, Ordering};
+
+use crate::{
+	arch::target::cpu::{
+		apic,
+		cpuid,
+		features::{self, Features},
+		misc::read_tsc,
+		pit
+	},
+	kernel::time::ClockSource
+};
+
+/// Whether the TSC runs at a constant rate, in every power state
+pub fn is_invariant() -> bool
+{
+	features::has(Features::INVARIANT_TSC)
+}
+
+/// Frequency of the TSC, as enumerated by CPUID
+fn enumerated_frequency() -> Option<u64>
+{
+	if cpuid(0_u32).eax < 0x15
+	{
+		return None;
+	}
+	let leaf = cpuid(0x15_u32);
+	if leaf.eax == 0 || leaf.ebx == 0 || leaf.ecx == 0
+	{
+		return None;
+	}
+	Some(u64::from(leaf.ecx) * u64::from(leaf.ebx) / u64::from(leaf.eax))
+}
+
+/// Frequency of the TSC, measured against the PIT
+fn measured_frequency() -> u64
+{
+	const CALIBRATION_US: u64 = 10_000;
+
+	let start = read_tsc();
+	pit::busy_wait(CALIBRATION_US);
+	(read_tsc() - start) * 1_000_000 / CALIBRATION_US
+}
+
+struct Tsc
+{
+	frequency: AtomicU64
+}
+
+impl ClockSource for Tsc
+{
+	fn name(&self) -> &'static str
+	{
+		"tsc"
+	}
+
+	fn rating(&self) -> u32
+	{
+		if is_invariant() { 300 } else { 50 }
+	}
+
+	fn frequency(&self) -> u64
+	{
+		self.frequency.load(Ordering::Relaxed)
+	}
+
+	fn mask(&self) -> u64
+	{
+		u64::MAX
+	}
+
+	fn read(&self) -> u64
+	{
+		read_tsc()
+	}
+}
+
+static TSC: Tsc = Tsc {
+	frequency: AtomicU64::new(0)
+};
+
+pub(super) fn probe() -> Option<&'static dyn ClockSource>
+{
+	let frequency = enumerated_frequency()
+		.or_else(apic::tsc_frequency)
+		.unwrap_or_else(measured_frequency);
+	if frequency == 0
+	{
+		return None;
+	}
+	TSC.frequency.store(frequency, Ordering::Relaxed);
+	Some(&TSC)
+}
@@ -14,6 +14,7 @@ use limine::{
 		EfiSystemTableRequest,
 		ExecutableAddressRequest,
 		ExecutableCmdlineRequest,
+		ExecutableFileRequest,
 		FirmwareTypeRequest,
 		FramebufferRequest,
 		HhdmRequest,
@@ -71,6 +72,8 @@ requests! {
 		= ExecutableAddressRequest::new();
 	pub static KERNEL_CMDLINE_REQUEST: ExecutableCmdlineRequest
 		= ExecutableCmdlineRequest::new();
+	pub static KERNEL_FILE_REQUEST: ExecutableFileRequest
+		= ExecutableFileRequest::new();
 	pub static MEMMAP_REQUEST: MemoryMapRequest
 		= MemoryMapRequest::new();
 	pub static MODULES_REQUEST: ModuleRequest
@@ -364,6 +367,13 @@ fn verify_requests() -> bool
 			cmdline;
 		}
 	);
+	verify!(
+		(required: false)
+		"kernel file": KERNEL_FILE_REQUEST;
+		{
+			/* file; */
+		}
+	);
 	verify!(
 		"Limine memory map": MEMMAP_REQUEST;
 		{
@@ -416,16 +426,34 @@ mod entry
 {
 	use super::*;
 	use crate::{
+		arch::target::{
+			cpu,
+			io::{
+				self,
+				fw_cfg::{self, FwCfgError}
+			},
+			time
+		},
 		info,
 		init::{self, ctors::CtorIter},
-		kernel::linker::map::zerOS_kernel_start,
+		kernel::{
+			gdb,
+			hypervisor,
+			linker::map::zerOS_kernel_start,
+			memory::stack::KERNEL_STACK
+		},
 		kmain,
-		trace
+		panic,
+		trace,
+		unwinding
 	};
 
 	#[unsafe(no_mangle)]
 	extern "sysv64" fn zerOS_boot_setup() -> !
 	{
+		// before anything may use SIMD instructions
+		cpu::features::init();
+
 		// All limine requests must also be referenced in a called function, otherwise
 		// they may be removed by the linker.
 		assert!(BASE_REVISION.is_supported());
@@ -436,6 +464,9 @@ mod entry
 
 		CtorIter::new().for_each(|ctor| unsafe { ctor() });
 
+		// as soon as the logging backends are ready
+		cpu::features::check_required();
+
 		log::set_max_level(log::LevelFilter::Warn);
 
 		{
@@ -448,14 +479,67 @@ mod entry
 				.to_str()
 				.unwrap()
 				.into();
+			// the options the host passed override the bootloader's ones
+			match fw_cfg::read_file_to_string(fw_cfg::ZEROS_CONFIG_FILE)
+			{
+				Ok(config) =>
+				{
+					// leaked, as the command line may borrow from it
+					let config = config
+						.leak()
+						.trim_matches(|c: char| c.is_whitespace() || c == '\0');
+					if !config.is_empty()
+						&& let Err(err) = cmdline.extend(config)
+					{
+						error!("ignoring the host configuration: {err}");
+					}
+				},
+				Err(FwCfgError::Unavailable | FwCfgError::NotFound) =>
+				{},
+				Err(err) => warn!("couldn't read the host configuration: {err}")
+			}
+		}
+
+		{
+			let cmdline = init::cmdline::ZEROS_COMMAND_LINE.read();
+			if !cmdline.panic_actions.is_empty()
+			{
+				panic::set_actions(&cmdline.panic_actions);
+			}
+			if let Some(kind) = cmdline.unwinder
+			{
+				unwinding::set_unwinder(kind);
+			}
+			if let Some(port) = cmdline.gdb_port
+			{
+				gdb::init(port);
+			}
 		}
 
+		#[cfg(feature = "unwind")]
+		panic::init_unwinding();
+
 		let loglvl_wanted = init::cmdline::ZEROS_COMMAND_LINE.read().log_level;
 		log::set_max_level(loglvl_wanted);
 		info!("log level set to {loglvl_wanted}");
 
+		{
+			let cmdline = init::cmdline::ZEROS_COMMAND_LINE.read();
+			cpu::features::configure(cmdline.cpu_requested, cmdline.cpu_disabled);
+		}
+		cpu::fpu::init(init::cmdline::ZEROS_COMMAND_LINE.read().fpu_policy);
+
 		assert!(verify_requests());
 
+		if let Some(file) = KERNEL_FILE_REQUEST
+			.get_response()
+			.map(|response| response.file())
+		{
+			unwinding::symbols::set_kernel_image(unsafe {
+				core::slice::from_raw_parts(file.addr(), file.size() as usize)
+			});
+		}
+
 		if let Some(f) = MODULES_REQUEST
 			.get_response()
 			.unwrap()
@@ -464,7 +548,15 @@ mod entry
 			.find(|file| file.path().to_str().unwrap().ends_with("debug-info.zko"))
 		{
 			trace!("feeding debug info to kernel unwinder");
-			todo!("do something with f ({})", f.path().to_string_lossy());
+			let module = unsafe { core::slice::from_raw_parts(f.addr(), f.size() as usize) };
+			if let Err(err) = unwinding::debuginfo::load(module)
+			{
+				warn!(
+					"couldn't load debug info from {}: {}",
+					f.path().to_string_lossy(),
+					err.as_ref()
+				);
+			}
 		}
 		else
 		{
@@ -475,6 +567,32 @@ mod entry
 		init::memory::gdt::init();
 		info!("GDT initialized");
 
-		kmain()
+		info!("initializing IDT...");
+		cpu::idt::init();
+		info!("IDT initialized");
+		cpu::user::init();
+
+		cpu::pic::disable();
+		match cpu::apic::init()
+		{
+			Ok(_) => cpu::apic::calibrate_timer(),
+			Err(err) => warn!("couldn't initialize the local APIC: {err}")
+		}
+		if let Err(err) = cpu::ioapic::init()
+		{
+			warn!("couldn't initialize the I/O APICs: {err}");
+		}
+		gdb::route_interrupt();
+		hypervisor::init();
+		// now that the emulators only the DMI strings reveal are known
+		io::debugcon::init();
+		let clocksource = init::cmdline::ZEROS_COMMAND_LINE.read().clocksource.clone();
+		time::init(clocksource.as_deref());
+
+		// SAFETY: the Limine stack is left for good
+		unsafe {
+			KERNEL_STACK.arm("kmain", None);
+			KERNEL_STACK.enter(kmain)
+		}
 	}
 }
@@ -1,7 +1,7 @@
 use alloc::{borrow::Cow, string::String};
 use core::marker::{self, PhantomCovariantLifetime};
 
-use anyhow::{Ok, Result, bail};
+use anyhow::{Ok, Result, anyhow, bail};
 
 mod lex;
 mod parse;
@@ -11,12 +11,40 @@ use parse::ParsedCmdlineOption;
 use phf::phf_map;
 use unicase::UniCase;
 
-use crate::{error, init::cmdline::parse::ParsedCmdlineValue, kernel::sync::BasicRwLock};
+use crate::{
+	arch::target::{
+		cpu::{
+			features::{self, Features},
+			fpu::FpuPolicy
+		},
+		io::serial::SerialPortId
+	},
+	error,
+	init::cmdline::parse::ParsedCmdlineValue,
+	kernel::{power::ExitFallback, sync::BasicRwLock},
+	panic::{MAX_PANIC_ACTIONS, PanicAction},
+	unwinding::UnwinderKind
+};
 
 pub struct KernelCmdline<'source>
 {
-	pub log_level: log::LevelFilter,
-	_marker:       marker::PhantomCovariantLifetime<'source>
+	pub log_level:     log::LevelFilter,
+	pub panic_actions: heapless::Vec<PanicAction, MAX_PANIC_ACTIONS>,
+	pub unwinder:      Option<UnwinderKind>,
+	/// Number of the COM port of the GDB stub, if enabled
+	pub gdb_port:      Option<u8>,
+	/// Name of the clock source to use instead of the best rated one
+	pub clocksource:   Option<heapless::String<16>>,
+	/// CPU features to enable, among [`features::OPT_IN`]
+	pub cpu_requested: Features,
+	/// CPU features not to enable, among [`features::CONFIGURABLE`]
+	pub cpu_disabled:  Features,
+	/// When the FPU state of the user code is restored
+	pub fpu_policy:    FpuPolicy,
+	/// What to do on [`exit`](crate::kernel::power::exit) when not running
+	/// under QEMU
+	pub exit_fallback: ExitFallback,
+	_marker:           marker::PhantomCovariantLifetime<'source>
 }
 
 impl<'source> Default for KernelCmdline<'source>
@@ -108,11 +136,43 @@ impl<'source> KernelCmdline<'source>
 			}
 		};
 		Self {
-			log_level: DEFAULT_LOG_LEVEL,
-			_marker: PhantomCovariantLifetime::new()
+			log_level:     DEFAULT_LOG_LEVEL,
+			panic_actions: heapless::Vec::new(),
+			unwinder:      None,
+			gdb_port:      None,
+			clocksource:   None,
+			cpu_requested: Features::empty(),
+			cpu_disabled:  Features::empty(),
+			fpu_policy:    FpuPolicy::Eager,
+			exit_fallback: ExitFallback::Halt,
+			_marker:       PhantomCovariantLifetime::new()
 		}
 	}
 
+	/// Apply the options of `source` on top of the current ones (e.g. the ones
+	/// the host passes through QEMU's fw_cfg)
+	///
+	/// If `source` can't be parsed, none of its options are applied.
+	pub fn extend(&mut self, source: &'source str) -> Result<()>
+	{
+		let lexer = SpannedLexer::new(source);
+		let parser = parse::CmdlineParser::new();
+		let parsed = parser.parse(source, lexer).map_err(|err| {
+			anyhow!(
+				"couldn't parse command-line arguments: {}",
+				err.map_error(|inner| inner)
+			)
+		})?;
+		for opt in parsed.iter()
+		{
+			if let Err(err) = self.maybe_update(opt)
+			{
+				error!(event: "command-line", "command-line option ignored: {err}");
+			}
+		}
+		Ok(())
+	}
+
 	fn maybe_update(&mut self, parsed: &ParsedCmdlineOption<'source>) -> Result<()>
 	{
 		const FN_MAP: phf::Map<
@@ -128,6 +188,28 @@ impl<'source> KernelCmdline<'source>
 			UniCase::ascii("Log_Level") => &maybe_loglvl,
 			UniCase::ascii("Log-Lvl") => &maybe_loglvl,
 			UniCase::ascii("Log-Level") => &maybe_loglvl,
+			UniCase::ascii("Panic") => &maybe_panic_actions,
+			UniCase::ascii("OnPanic") => &maybe_panic_actions,
+			UniCase::ascii("On_Panic") => &maybe_panic_actions,
+			UniCase::ascii("On-Panic") => &maybe_panic_actions,
+			UniCase::ascii("Unwinder") => &maybe_unwinder,
+			UniCase::ascii("Gdb") => &maybe_gdb_port,
+			UniCase::ascii("GdbStub") => &maybe_gdb_port,
+			UniCase::ascii("Gdb_Stub") => &maybe_gdb_port,
+			UniCase::ascii("Gdb-Stub") => &maybe_gdb_port,
+			UniCase::ascii("ClockSource") => &maybe_clocksource,
+			UniCase::ascii("Clock_Source") => &maybe_clocksource,
+			UniCase::ascii("Clock-Source") => &maybe_clocksource,
+			UniCase::ascii("CpuFeatures") => &maybe_cpu_requested,
+			UniCase::ascii("Cpu_Features") => &maybe_cpu_requested,
+			UniCase::ascii("Cpu-Features") => &maybe_cpu_requested,
+			UniCase::ascii("NoCpuFeatures") => &maybe_cpu_disabled,
+			UniCase::ascii("No_Cpu_Features") => &maybe_cpu_disabled,
+			UniCase::ascii("No-Cpu-Features") => &maybe_cpu_disabled,
+			UniCase::ascii("Fpu") => &maybe_fpu_policy,
+			UniCase::ascii("OnExit") => &maybe_exit_fallback,
+			UniCase::ascii("On_Exit") => &maybe_exit_fallback,
+			UniCase::ascii("On-Exit") => &maybe_exit_fallback,
 		};
 
 		if let Some(&func) = FN_MAP.get(&UniCase::new(parsed.name.as_ref()))
@@ -166,21 +248,10 @@ impl<'source> From<&'source str> for KernelCmdline<'source>
 {
 	fn from(value: &'source str) -> Self
 	{
-		let lexer = SpannedLexer::new(value);
-		let parser = parse::CmdlineParser::new();
-		let parsed = parser.parse(value, lexer).unwrap_or_else(|err| {
-			panic!(
-				"couldn't parse command-line arguments: {}",
-				err.map_error(|inner| inner)
-			)
-		});
 		let mut kcmdline = KernelCmdline::new();
-		for opt in parsed.iter()
+		if let Err(err) = kcmdline.extend(value)
 		{
-			if let Err(err) = kcmdline.maybe_update(opt)
-			{
-				error!(event: "command-line", "command-line option ignored: {err}");
-			}
+			panic!("{err}");
 		}
 		kcmdline
 	}
@@ -221,4 +292,208 @@ fn maybe_loglvl<'source>(
 	}
 }
 
+/// Parse a comma-separated list of panic actions (e.g. `"qemu-exit:3,halt"`)
+fn maybe_panic_actions<'source>(
+	this: &mut KernelCmdline<'source>,
+	parsed: Option<&ParsedCmdlineValue<'source>>
+) -> bool
+{
+	let list: &str = match parsed
+	{
+		Some(ParsedCmdlineValue::Ident(s)) => s,
+		Some(ParsedCmdlineValue::String(s)) => s,
+		_ => return false
+	};
+
+	let mut actions = heapless::Vec::new();
+	for action in list.split(',')
+	{
+		let Some(action) = PanicAction::parse(action)
+		else
+		{
+			return false;
+		};
+		if actions.push(action).is_err()
+		{
+			error!(
+				event: "command-line",
+				"too many panic actions specified (max: {MAX_PANIC_ACTIONS}), ignoring \"{action}\""
+			);
+		}
+	}
+	this.panic_actions = actions;
+	true
+}
+
+/// Select the unwinder used for backtraces (`dwarf` or `troll`)
+fn maybe_unwinder<'source>(
+	this: &mut KernelCmdline<'source>,
+	parsed: Option<&ParsedCmdlineValue<'source>>
+) -> bool
+{
+	let kind: &str = match parsed
+	{
+		Some(ParsedCmdlineValue::Ident(s)) => s,
+		Some(ParsedCmdlineValue::String(s)) => s,
+		_ => return false
+	};
+
+	let Some(kind) = kind.parse().ok()
+	else
+	{
+		return false;
+	};
+	this.unwinder = Some(kind);
+	true
+}
+
+/// Enable the GDB stub on a serial port (e.g. `com3`)
+fn maybe_gdb_port<'source>(
+	this: &mut KernelCmdline<'source>,
+	parsed: Option<&ParsedCmdlineValue<'source>>
+) -> bool
+{
+	let port: &str = match parsed
+	{
+		Some(ParsedCmdlineValue::Ident(s)) => s,
+		Some(ParsedCmdlineValue::String(s)) => s,
+		_ => return false
+	};
+
+	let Some(number) = port
+		.get(..3)
+		.filter(|prefix| prefix.eq_ignore_ascii_case("com"))
+		.and_then(|_| port[3..].parse().ok())
+		.filter(|&number| SerialPortId::from_com_number(number).is_some())
+	else
+	{
+		return false;
+	};
+	this.gdb_port = Some(number);
+	true
+}
+
+/// Select the clock source by name (e.g. `hpet`)
+fn maybe_clocksource<'source>(
+	this: &mut KernelCmdline<'source>,
+	parsed: Option<&ParsedCmdlineValue<'source>>
+) -> bool
+{
+	let name: &str = match parsed
+	{
+		Some(ParsedCmdlineValue::Ident(s)) => s,
+		Some(ParsedCmdlineValue::String(s)) => s,
+		_ => return false
+	};
+
+	let Some(name) = heapless::String::try_from(name).ok()
+	else
+	{
+		return false;
+	};
+	this.clocksource = Some(name);
+	true
+}
+
+/// Parse a comma-separated list of CPU features among `allowed` (e.g.
+/// `"smap,pku"`)
+fn parse_features(parsed: Option<&ParsedCmdlineValue<'_>>, allowed: Features) -> Option<Features>
+{
+	let list: &str = match parsed
+	{
+		Some(ParsedCmdlineValue::Ident(s)) => s,
+		Some(ParsedCmdlineValue::String(s)) => s,
+		_ => return None
+	};
+
+	let mut features = Features::empty();
+	for name in list.split(',')
+	{
+		match Features::from_name(name)
+		{
+			Some(feature) if allowed.contains(feature) => features |= feature,
+			_ => return None
+		}
+	}
+	Some(features)
+}
+
+/// Parse a comma-separated list of CPU features to enable, which aren't by
+/// default (e.g. `"smap"`)
+fn maybe_cpu_requested<'source>(
+	this: &mut KernelCmdline<'source>,
+	parsed: Option<&ParsedCmdlineValue<'source>>
+) -> bool
+{
+	let Some(requested) = parse_features(parsed, features::OPT_IN)
+	else
+	{
+		return false;
+	};
+	this.cpu_requested = requested;
+	true
+}
+
+/// Parse a comma-separated list of CPU features not to enable (e.g.
+/// `"smap,pku"`)
+fn maybe_cpu_disabled<'source>(
+	this: &mut KernelCmdline<'source>,
+	parsed: Option<&ParsedCmdlineValue<'source>>
+) -> bool
+{
+	let Some(disabled) = parse_features(parsed, features::CONFIGURABLE)
+	else
+	{
+		return false;
+	};
+	this.cpu_disabled = disabled;
+	true
+}
+
+/// Select when the FPU state of the user code is restored (`eager` or
+/// `lazy`)
+fn maybe_fpu_policy<'source>(
+	this: &mut KernelCmdline<'source>,
+	parsed: Option<&ParsedCmdlineValue<'source>>
+) -> bool
+{
+	let policy: &str = match parsed
+	{
+		Some(ParsedCmdlineValue::Ident(s)) => s,
+		Some(ParsedCmdlineValue::String(s)) => s,
+		_ => return false
+	};
+
+	let Some(policy) = policy.parse().ok()
+	else
+	{
+		return false;
+	};
+	this.fpu_policy = policy;
+	true
+}
+
+/// Select what to do when the kernel exits outside of QEMU (`halt` or
+/// `reboot`)
+fn maybe_exit_fallback<'source>(
+	this: &mut KernelCmdline<'source>,
+	parsed: Option<&ParsedCmdlineValue<'source>>
+) -> bool
+{
+	let fallback: &str = match parsed
+	{
+		Some(ParsedCmdlineValue::Ident(s)) => s,
+		Some(ParsedCmdlineValue::String(s)) => s,
+		_ => return false
+	};
+
+	let Some(fallback) = fallback.parse().ok()
+	else
+	{
+		return false;
+	};
+	this.exit_fallback = fallback;
+	true
+}
+
 pub static ZEROS_COMMAND_LINE: BasicRwLock<KernelCmdline> = BasicRwLock::new(KernelCmdline::new());
@@ -1,17 +1,26 @@
 pub mod gdt
 {
-	use lazy_static::lazy_static;
+	use core::{cell::SyncUnsafeCell, mem::MaybeUninit};
 
-	use crate::kernel;
+	use crate::{
+		arch::target::cpu::{self, MAX_CPU_COUNT},
+		kernel::memory::{gdt::GDT, tss}
+	};
 
-	lazy_static! {
-		static ref GDT: kernel::memory::gdt::GDT = kernel::memory::gdt::GDT::default();
-	}
+	/// GDT of each CPU, which only differ by their TSS descriptor
+	static GDTS: [SyncUnsafeCell<MaybeUninit<GDT>>; MAX_CPU_COUNT] =
+		[const { SyncUnsafeCell::new(MaybeUninit::uninit()) }; MAX_CPU_COUNT];
 
+	/// Set up and load the GDT and the TSS of the calling CPU
+	///
+	/// Must only be called once per CPU.
 	pub fn init()
 	{
+		let cpu = cpu::current_cpu_index();
 		unsafe {
-			GDT.set();
+			let gdt = (*GDTS[cpu].get()).write(GDT::with_tss(tss::init(cpu)));
+			gdt.set();
+			gdt.load_tss();
 		}
 	}
 }
@@ -0,0 +1,299 @@
+//! Minimal ACPI table lookup
+//!
+//! Only what's needed to configure the interrupt controllers is implemented:
+//! finding tables through the RSDT/XSDT, and reading the entries of the MADT.
+//! Tables are mapped (cached) through the higher-half direct map on demand.
+
+use core::{fmt, mem::size_of, slice};
+
+use crate::arch::target::cpu::paging;
+
+/// Header shared by all the system description tables
+#[repr(C, packed)]
+#[derive(Debug, Clone, Copy)]
+pub struct SdtHeader
+{
+	pub signature:        [u8; 4],
+	pub length:           u32,
+	pub revision:         u8,
+	pub checksum:         u8,
+	pub oem_id:           [u8; 6],
+	pub oem_table_id:     [u8; 8],
+	pub oem_revision:     u32,
+	pub creator_id:       u32,
+	pub creator_revision: u32
+}
+static_assert!(size_of::<SdtHeader>() == 36);
+
+#[repr(C, packed)]
+#[derive(Debug, Clone, Copy)]
+#[allow(dead_code)]
+struct Rsdp
+{
+	signature:         [u8; 8],
+	checksum:          u8,
+	oem_id:            [u8; 6],
+	revision:          u8,
+	rsdt_address:      u32,
+	// ACPI 2.0+
+	length:            u32,
+	xsdt_address:      u64,
+	extended_checksum: u8,
+	_reserved:         [u8; 3]
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AcpiError
+{
+	/// The bootloader didn't find the RSDP
+	NoRsdp,
+	/// A table couldn't be mapped
+	Unmapped(paging::PagingError),
+	/// A table has an invalid signature or checksum
+	Invalid(&'static str),
+	/// No table has the requested signature
+	NotFound
+}
+
+impl fmt::Display for AcpiError
+{
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+	{
+		match self
+		{
+			Self::NoRsdp => write!(f, "the RSDP wasn't found"),
+			Self::Unmapped(err) => write!(f, "an ACPI table couldn't be mapped: {err}"),
+			Self::Invalid(table) => write!(f, "the {table} is invalid"),
+			Self::NotFound => write!(f, "the ACPI table wasn't found")
+		}
+	}
+}
+
+impl From<paging::PagingError> for AcpiError
+{
+	fn from(err: paging::PagingError) -> Self
+	{
+		Self::Unmapped(err)
+	}
+}
+
+/// Physical address of the RSDP
+#[cfg(bootloader = "limine")]
+fn rsdp_address() -> Option<u64>
+{
+	crate::init::bootloaders::limine::RSDP_REQUEST
+		.get_response()
+		.map(|response| response.address() as u64)
+}
+
+/// Physical address of the RSDP
+#[cfg(not(bootloader = "limine"))]
+fn rsdp_address() -> Option<u64>
+{
+	None
+}
+
+/// Map `size` bytes of firmware memory at `phys`
+fn map(phys: u64, size: usize) -> Result<&'static [u8], AcpiError>
+{
+	// SAFETY: ACPI tables are reserved by the firmware
+	let virt = unsafe { paging::map_physical(phys, size, false) }?;
+	// SAFETY: just mapped
+	Ok(unsafe { slice::from_raw_parts(virt as *const u8, size) })
+}
+
+fn checksum_ok(bytes: &[u8]) -> bool
+{
+	bytes.iter().fold(0_u8, |sum, &byte| sum.wrapping_add(byte)) == 0
+}
+
+/// Map the whole table whose header is at `phys`
+fn map_table(phys: u64) -> Result<&'static [u8], AcpiError>
+{
+	let header = map(phys, size_of::<SdtHeader>())?;
+	// SAFETY: the header is large enough
+	let length = unsafe { header.as_ptr().cast::<SdtHeader>().read_unaligned() }.length;
+	map(phys, (length as usize).max(size_of::<SdtHeader>()))
+}
+
+/// Physical addresses of all the tables listed by the RSDT or XSDT
+fn table_addresses() -> Result<impl Iterator<Item = u64>, AcpiError>
+{
+	let rsdp = map(rsdp_address().ok_or(AcpiError::NoRsdp)?, size_of::<Rsdp>())?;
+	// SAFETY: large enough
+	let rsdp_fields = unsafe { rsdp.as_ptr().cast::<Rsdp>().read_unaligned() };
+	if &rsdp_fields.signature != b"RSD PTR " || !checksum_ok(&rsdp[..20])
+	{
+		return Err(AcpiError::Invalid("RSDP"));
+	}
+
+	let (root, entry_size) = if rsdp_fields.revision >= 2 && rsdp_fields.xsdt_address != 0
+	{
+		(rsdp_fields.xsdt_address, 8)
+	}
+	else
+	{
+		(u64::from(rsdp_fields.rsdt_address), 4)
+	};
+	let root = map_table(root)?;
+	if !checksum_ok(root)
+	{
+		return Err(AcpiError::Invalid("RSDT"));
+	}
+
+	Ok(root[size_of::<SdtHeader>()..]
+		.chunks_exact(entry_size)
+		.map(|entry| {
+			let mut address = [0; 8];
+			address[..entry.len()].copy_from_slice(entry);
+			u64::from_le_bytes(address)
+		}))
+}
+
+/// The (whole) table with the given signature
+pub fn find_table(signature: &[u8; 4]) -> Result<&'static [u8], AcpiError>
+{
+	for address in table_addresses()?
+	{
+		let table = map_table(address)?;
+		if &table[..4] == signature
+		{
+			return if checksum_ok(table)
+			{
+				Ok(table)
+			}
+			else
+			{
+				Err(AcpiError::Invalid("ACPI table"))
+			};
+		}
+	}
+	Err(AcpiError::NotFound)
+}
+
+/// An entry of the MADT (multiple APIC description table)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MadtEntry
+{
+	LocalApic
+	{
+		processor: u8,
+		apic_id:   u8,
+		flags:     u32
+	},
+	IoApic
+	{
+		id:       u8,
+		address:  u32,
+		gsi_base: u32
+	},
+	/// An ISA IRQ connected to another GSI than its number, or with another
+	/// polarity or trigger mode than the ISA ones
+	InterruptSourceOverride
+	{
+		bus:    u8,
+		source: u8,
+		gsi:    u32,
+		flags:  u16
+	},
+	LocalApicNmi
+	{
+		processor: u8,
+		flags:     u16,
+		lint:      u8
+	},
+	LocalX2Apic
+	{
+		apic_id:   u32,
+		flags:     u32,
+		processor: u32
+	},
+	Other
+	{
+		kind: u8
+	}
+}
+
+/// Polarity and trigger mode flags of MADT entries
+pub mod inti
+{
+	pub const POLARITY_MASK: u16 = 0b11;
+	pub const ACTIVE_HIGH: u16 = 0b01;
+	pub const ACTIVE_LOW: u16 = 0b11;
+	pub const TRIGGER_MASK: u16 = 0b11 << 2;
+	pub const EDGE: u16 = 0b01 << 2;
+	pub const LEVEL: u16 = 0b11 << 2;
+}
+
+/// The entries of the MADT
+pub fn madt_entries() -> Result<impl Iterator<Item = MadtEntry>, AcpiError>
+{
+	// the header is followed by the local APIC address and flags
+	let madt = find_table(b"APIC")?;
+	let mut rest = madt.get(size_of::<SdtHeader>() + 8..).unwrap_or_default();
+
+	Ok(core::iter::from_fn(move || {
+		let [kind, length, ..] = *rest
+		else
+		{
+			return None;
+		};
+		let (entry, next) = rest.split_at_checked(usize::from(length).max(2))?;
+		rest = next;
+
+		let u16_at = |offset: usize| u16::from_le_bytes([entry[offset], entry[offset + 1]]);
+		let u32_at = |offset: usize| {
+			u32::from_le_bytes([
+				entry[offset],
+				entry[offset + 1],
+				entry[offset + 2],
+				entry[offset + 3]
+			])
+		};
+		Some(match (kind, entry.len())
+		{
+			(0, 8..) =>
+			{
+				MadtEntry::LocalApic {
+					processor: entry[2],
+					apic_id:   entry[3],
+					flags:     u32_at(4)
+				}
+			},
+			(1, 12..) =>
+			{
+				MadtEntry::IoApic {
+					id:       entry[2],
+					address:  u32_at(4),
+					gsi_base: u32_at(8)
+				}
+			},
+			(2, 10..) =>
+			{
+				MadtEntry::InterruptSourceOverride {
+					bus:    entry[2],
+					source: entry[3],
+					gsi:    u32_at(4),
+					flags:  u16_at(8)
+				}
+			},
+			(4, 6..) =>
+			{
+				MadtEntry::LocalApicNmi {
+					processor: entry[2],
+					flags:     u16_at(3),
+					lint:      entry[5]
+				}
+			},
+			(9, 16..) =>
+			{
+				MadtEntry::LocalX2Apic {
+					apic_id:   u32_at(4),
+					flags:     u32_at(8),
+					processor: u32_at(12)
+				}
+			},
+			_ => MadtEntry::Other { kind }
+		})
+	}))
+}
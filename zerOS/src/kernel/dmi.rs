@@ -0,0 +1,216 @@
+//! Minimal SMBIOS (DMI) table reader
+//!
+//! Only the identification strings of the firmware and the system are read,
+//! which is enough to tell which platform (or emulator) the kernel runs on.
+
+use core::{fmt, slice};
+
+use crate::arch::target::cpu::paging;
+
+/// Maximum length of the identification strings which are kept
+pub const MAX_STRING_LENGTH: usize = 48;
+
+pub type DmiString = heapless::String<MAX_STRING_LENGTH>;
+
+/// Structure types
+const BIOS_INFORMATION: u8 = 0;
+const SYSTEM_INFORMATION: u8 = 1;
+const END_OF_TABLE: u8 = 127;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DmiError
+{
+	/// The bootloader didn't find the SMBIOS entry point
+	NoEntryPoint,
+	/// The entry point or the table couldn't be mapped
+	Unmapped(paging::PagingError),
+	/// The entry point has an invalid anchor or checksum
+	InvalidEntryPoint
+}
+
+impl fmt::Display for DmiError
+{
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+	{
+		match self
+		{
+			Self::NoEntryPoint => write!(f, "the SMBIOS entry point wasn't found"),
+			Self::Unmapped(err) => write!(f, "the SMBIOS table couldn't be mapped: {err}"),
+			Self::InvalidEntryPoint => write!(f, "the SMBIOS entry point is invalid")
+		}
+	}
+}
+
+impl From<paging::PagingError> for DmiError
+{
+	fn from(err: paging::PagingError) -> Self
+	{
+		Self::Unmapped(err)
+	}
+}
+
+/// Identification strings of the platform, empty when not provided
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DmiInfo
+{
+	pub bios_vendor:         DmiString,
+	pub bios_version:        DmiString,
+	pub system_manufacturer: DmiString,
+	pub product_name:        DmiString
+}
+
+/// Physical addresses of the 64-bit (SMBIOS 3) and 32-bit entry points
+#[cfg(bootloader = "limine")]
+fn entry_points() -> (Option<u64>, Option<u64>)
+{
+	crate::init::bootloaders::limine::SMBIOS_REQUEST
+		.get_response()
+		.map_or((None, None), |response| {
+			let nonzero = |addr: u64| (addr != 0).then_some(addr);
+			(
+				nonzero(response.entry_64() as u64),
+				nonzero(response.entry_32() as u64)
+			)
+		})
+}
+
+/// Physical addresses of the 64-bit (SMBIOS 3) and 32-bit entry points
+#[cfg(not(bootloader = "limine"))]
+fn entry_points() -> (Option<u64>, Option<u64>)
+{
+	(None, None)
+}
+
+/// Map `size` bytes of firmware memory at `phys`
+fn map(phys: u64, size: usize) -> Result<&'static [u8], DmiError>
+{
+	// SAFETY: the SMBIOS tables are reserved by the firmware
+	let virt = unsafe { paging::map_physical(phys, size, false) }?;
+	// SAFETY: just mapped
+	Ok(unsafe { slice::from_raw_parts(virt as *const u8, size) })
+}
+
+fn checksum_ok(bytes: &[u8]) -> bool
+{
+	bytes.iter().fold(0_u8, |sum, &byte| sum.wrapping_add(byte)) == 0
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> u16
+{
+	u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32
+{
+	u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+/// Physical address and maximum size of the structure table
+fn table() -> Result<(u64, usize), DmiError>
+{
+	match entry_points()
+	{
+		(Some(entry), _) =>
+		{
+			let header = map(entry, 0x18)?;
+			let length = usize::from(header[0x06]).clamp(0x18, 0xff);
+			let header = map(entry, length)?;
+			if &header[..5] != b"_SM3_" || !checksum_ok(header)
+			{
+				return Err(DmiError::InvalidEntryPoint);
+			}
+			let address =
+				u64::from(read_u32(header, 0x10)) | (u64::from(read_u32(header, 0x14)) << 32);
+			Ok((address, read_u32(header, 0x0c) as usize))
+		},
+		(None, Some(entry)) =>
+		{
+			let header = map(entry, 0x1f)?;
+			let length = usize::from(header[0x05]).clamp(0x1f, 0xff);
+			let header = map(entry, length)?;
+			if &header[..4] != b"_SM_" || !checksum_ok(header) || &header[0x10..0x15] != b"_DMI_"
+			{
+				return Err(DmiError::InvalidEntryPoint);
+			}
+			Ok((
+				u64::from(read_u32(header, 0x18)),
+				usize::from(read_u16(header, 0x16))
+			))
+		},
+		(None, None) => Err(DmiError::NoEntryPoint)
+	}
+}
+
+/// The string `index` (1-based) of the string set starting at `strings`
+fn string(strings: &[u8], index: u8) -> DmiString
+{
+	let mut result = DmiString::new();
+	if index == 0
+	{
+		return result;
+	}
+	let Some(bytes) = strings.split(|&byte| byte == 0).nth(usize::from(index) - 1)
+	else
+	{
+		return result;
+	};
+	for &byte in bytes.iter().take(MAX_STRING_LENGTH)
+	{
+		let _ = result.push(
+			if byte.is_ascii_graphic() || byte == b' '
+			{
+				char::from(byte)
+			}
+			else
+			{
+				'?'
+			}
+		);
+	}
+	result
+}
+
+/// Read the identification strings from the SMBIOS tables
+pub fn read() -> Result<DmiInfo, DmiError>
+{
+	let (address, size) = table()?;
+	let table = map(address, size)?;
+
+	let mut info = DmiInfo::default();
+	let mut offset = 0;
+	while offset + 4 <= table.len()
+	{
+		let kind = table[offset];
+		let length = usize::from(table[offset + 1]);
+		if kind == END_OF_TABLE || length < 4 || offset + length > table.len()
+		{
+			break;
+		}
+		let formatted = &table[offset..offset + length];
+		let strings = &table[offset + length..];
+		// the string set ends with two null bytes
+		let strings_length = strings
+			.windows(2)
+			.position(|pair| pair == [0, 0])
+			.map_or(strings.len(), |end| end + 2);
+		let strings = &strings[..strings_length];
+
+		match kind
+		{
+			BIOS_INFORMATION if length >= 0x06 =>
+			{
+				info.bios_vendor = string(strings, formatted[0x04]);
+				info.bios_version = string(strings, formatted[0x05]);
+			},
+			SYSTEM_INFORMATION if length >= 0x06 =>
+			{
+				info.system_manufacturer = string(strings, formatted[0x04]);
+				info.product_name = string(strings, formatted[0x05]);
+			},
+			_ =>
+			{}
+		}
+		offset += length + strings_length;
+	}
+	Ok(info)
+}
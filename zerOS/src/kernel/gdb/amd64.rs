@@ -0,0 +1,76 @@
+use core::arch::asm;
+
+use crate::{arch::target::cpu::paging, unwinding::RegisterSet};
+
+/// Number of registers in a `g` packet: the general purpose registers, `rip`,
+/// `eflags` and the segment registers (GDB treats the missing FPU and SSE
+/// registers as unavailable)
+pub(super) const REGISTER_COUNT: usize = 24;
+
+/// Size of each register in a `g` packet, indexed by GDB register number
+pub(super) const REGISTER_SIZES: [usize; REGISTER_COUNT] = [
+	8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, // rax, rbx, ..., r15
+	8, // rip
+	4, // eflags
+	4, 4, 4, 4, 4, 4 // cs, ss, ds, es, fs, gs
+];
+
+/// GDB number of the instruction pointer
+pub(super) const PC: usize = 16;
+/// GDB number of the flags register
+pub(super) const FLAGS: usize = 17;
+
+/// GDB number of each register of a [`RegisterSet`], indexed by DWARF register
+/// number (the two only differ for `rbx` and `rdx`)
+const DWARF_TO_GDB: [usize; 16] = [0, 3, 2, 1, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15];
+
+const SEGMENTS: usize = 18;
+
+/// Fill `regs` from an unwinder snapshot
+///
+/// Registers the snapshot doesn't have (i.e. the flags and segment registers)
+/// are read from the calling CPU.
+pub(super) fn from_register_set(snapshot: &RegisterSet, regs: &mut [u64; REGISTER_COUNT])
+{
+	for (dwarf, &value) in snapshot.dwarf_registers().iter().take(16).enumerate()
+	{
+		regs[DWARF_TO_GDB[dwarf]] = value.unwrap_or(0);
+	}
+	regs[PC] = snapshot.get_pc().unwrap_or(0);
+
+	let flags: u64;
+	let segments: [u16; 6];
+	unsafe {
+		let (cs, ss, ds, es, fs, gs): (u16, u16, u16, u16, u16, u16);
+		asm! {
+			"pushfq",
+			"popq {flags}",
+			"movw %cs, {cs:x}",
+			"movw %ss, {ss:x}",
+			"movw %ds, {ds:x}",
+			"movw %es, {es:x}",
+			"movw %fs, {fs:x}",
+			"movw %gs, {gs:x}",
+			flags = out(reg) flags,
+			cs = out(reg) cs,
+			ss = out(reg) ss,
+			ds = out(reg) ds,
+			es = out(reg) es,
+			fs = out(reg) fs,
+			gs = out(reg) gs,
+			options(att_syntax, nomem, preserves_flags)
+		}
+		segments = [cs, ss, ds, es, fs, gs];
+	}
+	regs[FLAGS] = flags;
+	for (slot, segment) in regs[SEGMENTS..].iter_mut().zip(segments)
+	{
+		*slot = u64::from(segment);
+	}
+}
+
+/// Whether the page containing `addr` is mapped in the current address space
+pub(super) fn is_mapped(addr: u64) -> bool
+{
+	paging::is_mapped(addr)
+}
@@ -0,0 +1,1078 @@
+//! An in-kernel GDB stub, speaking the Remote Serial Protocol over a COM port
+//!
+//! The stub is enabled with the `gdb=com<n>` command-line option, and takes
+//! over a CPU when:
+//! - the `gdb` panic action runs (the panicking context can be inspected, but
+//!   not resumed),
+//! - a breakpoint or single-step trap is forwarded to [`handle_exception`] by
+//!   the exception handlers,
+//! - GDB interrupts the kernel (i.e. sends a Ctrl-C), which the interrupt of
+//!   the serial port notices once [`route_interrupt`] has routed it (the
+//!   interrupted context can then be modified and single-stepped), or [`poll`]
+//!   otherwise.
+//!
+//! Each CPU is exposed as a thread, thread `n + 1` being CPU #n. A CPU entering
+//! the stub while another one is talking to GDB waits for its turn, and shows
+//! up in the thread list in the meantime.
+//!
+//! Under QEMU, the port can be forwarded to a TCP socket (see `xtask run
+//! zerOS --gdb-stub`), and GDB attached to it with `target remote :<port>`.
+
+use core::{cell::SyncUnsafeCell, fmt::Write, hint, ptr};
+
+use cfg_if::cfg_if;
+use portable_atomic::{AtomicBool, AtomicPtr, AtomicU8, AtomicUsize, Ordering};
+
+use crate::{
+	arch::target::{
+		cpu::{
+			self,
+			MAX_CPU_COUNT,
+			ctlregs::{Cr0, cr0},
+			dbgregs::{self, WatchKind, WatchLength, Watchpoint},
+			exceptions::InterruptFrame,
+			ioapic,
+			irq
+		},
+		io::serial::{SerialPort, SerialPortId}
+	},
+	error,
+	info,
+	kernel::irq::{Irq, IrqContext, IrqReturn, request_irq},
+	unwinding::{self, RegisterSet},
+	warn
+};
+
+mod packet;
+
+use packet::{INTERRUPT, PACKET_SIZE, Reply, decode_hex, parse_hex};
+
+cfg_if! {
+	if #[cfg(target_arch = "x86_64")]
+	{
+		mod amd64;
+		use self::amd64 as target;
+	}
+	else if #[cfg(target_arch = "x86")]
+	{
+		mod x86;
+		use self::x86 as target;
+	}
+	else
+	{
+		compile_error!("TODO: implement the GDB stub for this target !");
+	}
+}
+
+/// Maximum number of software breakpoints set at the same time
+pub const MAX_BREAKPOINTS: usize = 32;
+
+const NO_CPU: usize = usize::MAX;
+
+/// The `int3` instruction
+const INT3: u8 = 0xcc;
+/// Trap flag of the flags register, for single-stepping
+const TF: u64 = 1 << 8;
+/// Resume flag of the flags register, to resume from an instruction breakpoint
+const RF: u64 = 1 << 16;
+
+/// Number of the COM port of the stub, or 0 if it is disabled
+static PORT: AtomicU8 = AtomicU8::new(0);
+/// CPU currently talking to GDB
+static OWNER: AtomicUsize = AtomicUsize::new(NO_CPU);
+/// Whether GDB resumed the kernel, and is waiting for a stop reply
+static RESUMED: AtomicBool = AtomicBool::new(false);
+/// Context of each CPU currently in the stub
+static STOPPED: [AtomicPtr<Stop>; MAX_CPU_COUNT] =
+	[const { AtomicPtr::new(ptr::null_mut()) }; MAX_CPU_COUNT];
+/// Whether each CPU is being single-stepped
+static STEPPING: [AtomicBool; MAX_CPU_COUNT] = [const { AtomicBool::new(false) }; MAX_CPU_COUNT];
+
+// only ever accessed by the CPU owning the stub
+static BREAKPOINTS: SyncUnsafeCell<[Option<Breakpoint>; MAX_BREAKPOINTS]> =
+	SyncUnsafeCell::new([None; MAX_BREAKPOINTS]);
+/// Hardware breakpoints GDB set, by CPU and slot, as the debug registers are
+/// per-CPU: each one is set on the CPU owning the stub at the time
+// only ever accessed by the CPU owning the stub
+static HARDWARE_BREAKPOINTS: SyncUnsafeCell<
+	[[Option<Watchpoint>; dbgregs::BREAKPOINT_COUNT]; MAX_CPU_COUNT]
+> = SyncUnsafeCell::new([[None; dbgregs::BREAKPOINT_COUNT]; MAX_CPU_COUNT]);
+/// Slots of the debug registers of each CPU which GDB removed a hardware
+/// breakpoint from while another CPU owned the stub, and which the CPU still
+/// has to clear
+static STALE_HARDWARE_BREAKPOINTS: [AtomicU8; MAX_CPU_COUNT] =
+	[const { AtomicU8::new(0) }; MAX_CPU_COUNT];
+static BUFFERS: SyncUnsafeCell<[[u8; PACKET_SIZE]; 2]> = SyncUnsafeCell::new([[0; PACKET_SIZE]; 2]);
+
+/// The registers of a stopped CPU, in the order GDB expects them in a `g`
+/// packet
+#[derive(Debug, Clone)]
+pub struct GdbRegisters
+{
+	/// Registers, indexed by GDB register number
+	pub regs: [u64; target::REGISTER_COUNT]
+}
+
+impl GdbRegisters
+{
+	/// GDB number of the flags register
+	pub const FLAGS: usize = target::FLAGS;
+	/// GDB number of the instruction pointer
+	pub const PC: usize = target::PC;
+
+	/// Build the registers from an unwinder snapshot (e.g. taken with
+	/// [`unwinding::read_registers`])
+	pub fn from_register_set(snapshot: &RegisterSet) -> Self
+	{
+		let mut regs = [0; target::REGISTER_COUNT];
+		target::from_register_set(snapshot, &mut regs);
+		Self { regs }
+	}
+
+	pub fn pc(&self) -> u64
+	{
+		self.regs[Self::PC]
+	}
+
+	pub fn set_pc(&mut self, pc: u64)
+	{
+		self.regs[Self::PC] = pc;
+	}
+
+	/// Hex-encode register `n` into `reply`
+	fn push(&self, n: usize, reply: &mut Reply)
+	{
+		reply.push_hex(&self.regs[n].to_le_bytes()[..target::REGISTER_SIZES[n]]);
+	}
+
+	/// Set register `n` from its hex encoding, returning the number of digits
+	/// used
+	fn set(&mut self, n: usize, digits: &[u8]) -> Option<usize>
+	{
+		let size = *target::REGISTER_SIZES.get(n)?;
+		let mut bytes = [0; 8];
+		decode_hex(digits.get(..2 * size)?, &mut bytes[..size])?;
+		self.regs[n] = u64::from_le_bytes(bytes);
+		Some(2 * size)
+	}
+}
+
+/// Signals reported to GDB, numbered as GDB expects them
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Signal
+{
+	Interrupt          = 2,
+	IllegalInstruction = 4,
+	Trap               = 5,
+	Abort              = 6,
+	Bus                = 7,
+	FloatingPoint      = 8,
+	SegmentationFault  = 11
+}
+
+impl Signal
+{
+	/// Signal matching an exception vector
+	pub fn for_vector(vector: u8) -> Self
+	{
+		match vector
+		{
+			// #DE, #MF and #XM
+			0 | 16 | 19 => Self::FloatingPoint,
+			// #DB and #BP
+			1 | 3 => Self::Trap,
+			// #UD
+			6 => Self::IllegalInstruction,
+			// #NP, #SS, #GP and #PF
+			11..=14 => Self::SegmentationFault,
+			_ => Self::Bus
+		}
+	}
+}
+
+#[derive(Clone, Copy)]
+struct Breakpoint
+{
+	addr:     u64,
+	original: u8
+}
+
+/// A CPU stopped in the stub
+struct Stop
+{
+	regs:      GdbRegisters,
+	signal:    Signal,
+	/// Whether the stop was caused by one of our breakpoints
+	swbreak:   bool,
+	/// Hardware breakpoint which caused the stop
+	watch:     Option<Watchpoint>,
+	/// Whether `regs` is the context the CPU goes back to, i.e. whether it can
+	/// be modified and single-stepped
+	live:      bool,
+	/// Whether the CPU may be resumed at all
+	resumable: bool
+}
+
+/// What to do once GDB is done with a CPU
+enum Resume
+{
+	Continue,
+	Step,
+	Detach
+}
+
+/// Enable the stub on the serial port `COM<port>`
+pub fn init(port: u8)
+{
+	if SerialPortId::from_com_number(port)
+		.and_then(SerialPort::new)
+		.is_none()
+	{
+		error!("couldn't open com{port} for the GDB stub");
+		return;
+	}
+	if SerialPortId::from_com_number(port) == Some(SerialPortId::DEBUG)
+	{
+		warn!("the GDB stub shares com{port} with the kernel logs");
+	}
+	PORT.store(port, Ordering::Release);
+	info!("GDB stub listening on com{port}");
+}
+
+/// Whether the stub has been enabled with [`init`]
+pub fn enabled() -> bool
+{
+	PORT.load(Ordering::Acquire) != 0
+}
+
+fn port() -> Option<SerialPort>
+{
+	SerialPortId::from_com_number(PORT.load(Ordering::Acquire)).and_then(SerialPort::new)
+}
+
+/// Have the interrupt of the serial port of the stub enter it, once the I/O
+/// APICs have been initialized
+///
+/// If the interrupt can't be routed, GDB interrupt requests are only noticed
+/// where [`poll`] is called.
+pub fn route_interrupt()
+{
+	let number = PORT.load(Ordering::Acquire);
+	let Some((id, port)) = SerialPortId::from_com_number(number).zip(port())
+	else
+	{
+		return;
+	};
+	let Some(isa_irq) = id.isa_irq()
+	else
+	{
+		warn!("com{number} has no standard IRQ, GDB interrupt requests are only polled for");
+		return;
+	};
+	let (gsi, flags) = ioapic::isa_irq(isa_irq);
+	match request_irq(Irq::Gsi(gsi), handle_interrupt, flags, "GDB stub")
+	{
+		Ok(_) =>
+		{
+			port.enable_receive_interrupt();
+			info!("GDB interrupt requests are received on GSI {gsi}");
+		},
+		Err(err) =>
+		{
+			warn!(
+				"couldn't request the IRQ of com{number} ({err}), GDB interrupt requests are only \
+				 polled for"
+			);
+		}
+	}
+}
+
+fn handle_interrupt(context: &IrqContext) -> IrqReturn
+{
+	if !port().is_some_and(|port| port.serial_received())
+	{
+		return IrqReturn::NotMine;
+	}
+	// SAFETY: the frame is only used by the handler of the interrupt meanwhile
+	receive(Some(unsafe { &mut *context.frame }));
+	IrqReturn::Handled
+}
+
+/// Enter the stub if GDB asked to interrupt the kernel
+///
+/// This has to be called periodically (e.g. from idle loops) if
+/// [`route_interrupt`] couldn't route the interrupt of the serial port. GDB
+/// then sees the context of the caller, which it can't modify. Any other byte
+/// received meanwhile is dropped.
+pub fn poll()
+{
+	receive(None);
+}
+
+/// Enter the stub for every interrupt request received, with the interrupted
+/// context `frame` (which GDB may then modify and single-step), or a snapshot
+/// of the caller
+fn receive(mut frame: Option<&mut InterruptFrame>)
+{
+	let Some(port) = port()
+	else
+	{
+		return;
+	};
+	// the interrupt is only raised again once every received byte is read
+	while port.serial_received()
+	{
+		if port.serial_read_byte() != INTERRUPT
+		{
+			continue;
+		}
+
+		let (regs, live) = match frame.as_deref()
+		{
+			Some(frame) => (frame.gdb_registers(), true),
+			None =>
+			{
+				let snapshot = unwinding::read_registers!();
+				(GdbRegisters::from_register_set(&snapshot), false)
+			}
+		};
+		let mut stop = Stop {
+			regs,
+			signal: Signal::Interrupt,
+			swbreak: false,
+			watch: None,
+			live,
+			resumable: true
+		};
+		enter(&mut stop);
+		if let Some(frame) = frame.as_deref_mut()
+		{
+			frame.set_gdb_registers(&stop.regs);
+		}
+	}
+}
+
+/// Let GDB inspect the context of a panic, if the stub is enabled
+///
+/// This returns once GDB continues, detaches or kills the kernel, so that the
+/// next panic actions run.
+pub fn enter_on_panic(snapshot: &RegisterSet)
+{
+	if !enabled()
+	{
+		warn!("the GDB stub isn't enabled (use the `gdb=com<n>` command-line option)");
+		return;
+	}
+	let mut stop = Stop {
+		regs:      GdbRegisters::from_register_set(snapshot),
+		signal:    Signal::Abort,
+		swbreak:   false,
+		watch:     None,
+		live:      false,
+		resumable: false
+	};
+	enter(&mut stop);
+}
+
+/// Report an exception to GDB, returning whether it was handled
+///
+/// `regs` must be the interrupted context, which the exception handler
+/// restores once this returns `true`. Breakpoint (`#BP`) and debug (`#DB`)
+/// exceptions are expected to be forwarded here; others are only reported if
+/// the handler chooses to.
+pub fn handle_exception(vector: u8, regs: &mut GdbRegisters) -> bool
+{
+	if !enabled()
+	{
+		return false;
+	}
+
+	let cpu = cpu::current_cpu_index();
+	let mut swbreak = false;
+	let mut watch = None;
+	match vector
+	{
+		1 =>
+		{
+			let stepping = STEPPING[cpu].swap(false, Ordering::AcqRel);
+			if stepping
+			{
+				regs.regs[GdbRegisters::FLAGS] &= !TF;
+			}
+			let stale = clear_stale_hardware_breakpoints(cpu);
+			let triggered = dbgregs::take_triggered();
+			// a hardware breakpoint GDB already removed
+			if !stepping && triggered != 0 && triggered & !stale == 0
+			{
+				return true;
+			}
+			watch = (0..dbgregs::BREAKPOINT_COUNT)
+				.filter(|&slot| triggered & !stale & (1 << slot) != 0)
+				.find_map(dbgregs::watchpoint);
+			// instruction breakpoints are faults, which would trigger again
+			if watch.is_some_and(|watch| watch.kind == WatchKind::Execute)
+			{
+				regs.regs[GdbRegisters::FLAGS] |= RF;
+			}
+		},
+		3 =>
+		{
+			// `int3` traps after itself: make our breakpoints stop at their
+			// address, like GDB expects with `swbreak`
+			let addr = regs.pc().wrapping_sub(1);
+			// SAFETY: the breakpoints are only read here, and only modified
+			//         by the CPU owning the stub
+			if unsafe { &*BREAKPOINTS.get() }
+				.iter()
+				.flatten()
+				.any(|breakpoint| breakpoint.addr == addr)
+			{
+				regs.set_pc(addr);
+				swbreak = true;
+			}
+		},
+		_ =>
+		{}
+	}
+
+	let mut stop = Stop {
+		regs: regs.clone(),
+		signal: Signal::for_vector(vector),
+		swbreak,
+		watch,
+		live: true,
+		resumable: true
+	};
+	enter(&mut stop);
+	*regs = stop.regs;
+	true
+}
+
+/// Talk to GDB until it resumes the calling CPU
+fn enter(stop: &mut Stop)
+{
+	let Some(port) = port()
+	else
+	{
+		return;
+	};
+
+	let irqs = irq::enabled();
+	irq::disable();
+
+	let cpu = cpu::current_cpu_index();
+	STOPPED[cpu].store(ptr::from_mut(stop), Ordering::Release);
+	while OWNER
+		.compare_exchange_weak(NO_CPU, cpu, Ordering::AcqRel, Ordering::Acquire)
+		.is_err()
+	{
+		hint::spin_loop();
+	}
+
+	// before the debug registers of this CPU are reused
+	clear_stale_hardware_breakpoints(cpu);
+
+	// SAFETY: only the CPU owning the stub gets here
+	let [input, output] = unsafe { &mut *BUFFERS.get() };
+
+	if RESUMED.swap(false, Ordering::AcqRel)
+	{
+		let mut reply = Reply::new(output);
+		stop_reply(cpu, stop, &mut reply);
+		reply.send(&port);
+	}
+
+	let mut session = Session { cpu, selected: cpu };
+	let resume = loop
+	{
+		let packet = packet::receive(&port, input);
+		let mut reply = Reply::new(output);
+		let resume = session.handle(packet, stop, &mut reply);
+		if resume.is_none() || !reply.is_empty()
+		{
+			reply.send(&port);
+		}
+		if let Some(resume) = resume
+		{
+			break resume;
+		}
+	};
+
+	match resume
+	{
+		Resume::Continue => RESUMED.store(true, Ordering::Release),
+		Resume::Step =>
+		{
+			stop.regs.regs[GdbRegisters::FLAGS] |= TF;
+			STEPPING[cpu].store(true, Ordering::Release);
+			RESUMED.store(true, Ordering::Release);
+		},
+		Resume::Detach =>
+		{
+			remove_all_breakpoints();
+		}
+	}
+
+	STOPPED[cpu].store(ptr::null_mut(), Ordering::Release);
+	OWNER.store(NO_CPU, Ordering::Release);
+	if irqs
+	{
+		irq::enable();
+	}
+}
+
+/// Append the stop reply (`T` packet) describing `stop`
+fn stop_reply(cpu: usize, stop: &Stop, reply: &mut Reply)
+{
+	reply.push(b"T");
+	reply.push_hex(&[stop.signal as u8]);
+	reply.push(b"thread:");
+	reply.push_number(thread_id(cpu));
+	reply.push(b";");
+	if stop.swbreak
+	{
+		reply.push(b"swbreak:;");
+	}
+	if let Some(watch) = stop.watch
+	{
+		match watch.kind
+		{
+			WatchKind::Execute => reply.push(b"hwbreak:"),
+			WatchKind::Write => reply.push(b"watch:"),
+			WatchKind::Access => reply.push(b"awatch:")
+		}
+		if watch.kind != WatchKind::Execute
+		{
+			reply.push_number(watch.addr);
+		}
+		reply.push(b";");
+	}
+}
+
+const fn thread_id(cpu: usize) -> u64
+{
+	cpu as u64 + 1
+}
+
+/// CPU of a thread ID, where 0 and -1 (i.e. any or all threads) mean `current`
+fn thread_cpu(id: &[u8], current: usize) -> Option<usize>
+{
+	if id == b"-1" || id == b"0"
+	{
+		return Some(current);
+	}
+	let cpu = usize::try_from(parse_hex(id)?).ok()?.checked_sub(1)?;
+	(cpu < MAX_CPU_COUNT && !STOPPED[cpu].load(Ordering::Acquire).is_null()).then_some(cpu)
+}
+
+struct Session
+{
+	/// CPU owning the stub
+	cpu:      usize,
+	/// CPU targetted by register accesses (selected with `Hg`)
+	selected: usize
+}
+
+impl Session
+{
+	/// Handle a packet, returning how to resume if it asks for it
+	fn handle(&mut self, packet: &[u8], stop: &mut Stop, reply: &mut Reply) -> Option<Resume>
+	{
+		let Some((&command, args)) = packet.split_first()
+		else
+		{
+			return None;
+		};
+		match command
+		{
+			b'?' => stop_reply(self.cpu, stop, reply),
+			b'g' =>
+			{
+				self.with_selected(stop, reply, |target, reply| {
+					(0..target.regs.regs.len()).for_each(|n| target.regs.push(n, reply));
+				})
+			},
+			b'G' =>
+			{
+				self.with_selected(stop, reply, |target, reply| {
+					let mut regs = target.regs.clone();
+					let mut digits = args;
+					for n in 0..regs.regs.len()
+					{
+						match regs.set(n, digits)
+						{
+							Some(used) => digits = &digits[used..],
+							None => return reply.push(b"E01")
+						}
+					}
+					write_registers(target, regs, reply);
+				})
+			},
+			b'p' =>
+			{
+				self.with_selected(stop, reply, |target, reply| {
+					match parse_hex(args)
+						.and_then(|n| usize::try_from(n).ok())
+						.filter(|&n| n < target.regs.regs.len())
+					{
+						Some(n) => target.regs.push(n, reply),
+						None => reply.push(b"E01")
+					}
+				})
+			},
+			b'P' =>
+			{
+				self.with_selected(stop, reply, |target, reply| {
+					let mut regs = target.regs.clone();
+					let parsed = args
+						.iter()
+						.position(|&byte| byte == b'=')
+						.and_then(|equal| {
+							let n = usize::try_from(parse_hex(&args[..equal])?).ok()?;
+							regs.set(n, &args[equal + 1..])
+						});
+					match parsed
+					{
+						Some(_) => write_registers(target, regs, reply),
+						None => reply.push(b"E01")
+					}
+				})
+			},
+			b'm' => read_memory(args, reply),
+			b'M' => write_memory(args, reply),
+			b'Z' | b'z' => set_breakpoint(command == b'Z', args, reply),
+			b'c' | b's' =>
+			{
+				if !stop.resumable
+				{
+					// there is no way back from a panic: tell GDB the kernel is
+					// done, and let the next panic actions run
+					reply.push(b"X");
+					reply.push_hex(&[stop.signal as u8]);
+					return Some(Resume::Detach);
+				}
+				if let Some(addr) = parse_hex(args)
+				{
+					if !stop.live
+					{
+						reply.push(b"E01");
+						return None;
+					}
+					stop.regs.set_pc(addr);
+				}
+				if command == b'c'
+				{
+					return Some(Resume::Continue);
+				}
+				if !stop.live
+				{
+					reply.push(b"E01");
+					return None;
+				}
+				return Some(Resume::Step);
+			},
+			b'D' =>
+			{
+				reply.push(b"OK");
+				RESUMED.store(false, Ordering::Release);
+				return Some(Resume::Detach);
+			},
+			// no reply is expected
+			b'k' =>
+			{
+				RESUMED.store(false, Ordering::Release);
+				return Some(Resume::Detach);
+			},
+			b'H' =>
+			{
+				match args.split_first()
+				{
+					Some((b'g', id)) =>
+					{
+						match thread_cpu(id, self.cpu)
+						{
+							Some(cpu) =>
+							{
+								self.selected = cpu;
+								reply.push(b"OK");
+							},
+							None => reply.push(b"E01")
+						}
+					},
+					// only the CPU owning the stub can be resumed
+					Some((b'c', _)) => reply.push(b"OK"),
+					_ => reply.push(b"E01")
+				}
+			},
+			b'T' =>
+			{
+				match thread_cpu(args, self.cpu)
+				{
+					Some(_) => reply.push(b"OK"),
+					None => reply.push(b"E01")
+				}
+			},
+			b'q' => self.query(args, reply),
+			// unsupported packets get an empty reply
+			_ =>
+			{}
+		}
+		None
+	}
+
+	fn query(&self, query: &[u8], reply: &mut Reply)
+	{
+		if query.starts_with(b"Supported")
+		{
+			reply.push(b"PacketSize=");
+			reply.push_number(PACKET_SIZE as u64);
+			reply.push(b";swbreak+;hwbreak+");
+		}
+		else if query == b"fThreadInfo"
+		{
+			reply.push(b"m");
+			let mut first = true;
+			for cpu in
+				(0..MAX_CPU_COUNT).filter(|&cpu| !STOPPED[cpu].load(Ordering::Acquire).is_null())
+			{
+				if !first
+				{
+					reply.push(b",");
+				}
+				reply.push_number(thread_id(cpu));
+				first = false;
+			}
+		}
+		else if query == b"sThreadInfo"
+		{
+			reply.push(b"l");
+		}
+		else if query == b"C"
+		{
+			reply.push(b"QC");
+			reply.push_number(thread_id(self.cpu));
+		}
+		else if query == b"Attached"
+		{
+			reply.push(b"1");
+		}
+		else if let Some(id) = query.strip_prefix(b"ThreadExtraInfo,")
+		{
+			match thread_cpu(id, self.cpu)
+			{
+				Some(cpu) =>
+				{
+					let mut info = heapless::String::<32>::new();
+					let _ = write!(info, "CPU #{cpu}");
+					if cpu == self.cpu
+					{
+						let _ = write!(info, " (current)");
+					}
+					reply.push_hex(info.as_bytes());
+				},
+				None => reply.push(b"E01")
+			}
+		}
+	}
+
+	/// Run `f` on the stop of the selected CPU
+	fn with_selected(
+		&self,
+		stop: &mut Stop,
+		reply: &mut Reply,
+		f: impl FnOnce(&mut Stop, &mut Reply)
+	)
+	{
+		if self.selected == self.cpu
+		{
+			return f(stop, reply);
+		}
+		// SAFETY: the selected CPU waits for the stub (and keeps its stop
+		//         alive) until the calling CPU leaves it
+		match unsafe { STOPPED[self.selected].load(Ordering::Acquire).as_mut() }
+		{
+			Some(target) => f(target, reply),
+			None => reply.push(b"E01")
+		}
+	}
+}
+
+fn write_registers(target: &mut Stop, regs: GdbRegisters, reply: &mut Reply)
+{
+	if !target.live
+	{
+		reply.push(b"E01");
+		return;
+	}
+	target.regs = regs;
+	reply.push(b"OK");
+}
+
+/// Parse the `<addr>,<length>` arguments of memory packets
+fn parse_range(args: &[u8]) -> Option<(u64, usize)>
+{
+	let comma = args.iter().position(|&byte| byte == b',')?;
+	let addr = parse_hex(&args[..comma])?;
+	let length = usize::try_from(parse_hex(&args[comma + 1..])?).ok()?;
+	Some((addr, length))
+}
+
+/// Whether `length` bytes at `addr` are all mapped
+fn is_range_mapped(addr: u64, length: usize) -> bool
+{
+	const PAGE_SIZE: u64 = 4096;
+
+	let Some(end) = addr.checked_add(length as u64)
+	else
+	{
+		return false;
+	};
+	let mut page = addr & !(PAGE_SIZE - 1);
+	while page < end
+	{
+		if !target::is_mapped(page)
+		{
+			return false;
+		}
+		page += PAGE_SIZE;
+	}
+	true
+}
+
+fn read_memory(args: &[u8], reply: &mut Reply)
+{
+	let Some((addr, length)) = parse_range(args).filter(|&(_, length)| length <= PACKET_SIZE / 2)
+	else
+	{
+		return reply.push(b"E01");
+	};
+	if !is_range_mapped(addr, length)
+	{
+		return reply.push(b"E14");
+	}
+	for offset in 0..length as u64
+	{
+		// SAFETY: the range is mapped
+		let byte = unsafe { ((addr + offset) as *const u8).read_volatile() };
+		reply.push_hex(&[byte]);
+	}
+}
+
+fn write_memory(args: &[u8], reply: &mut Reply)
+{
+	let mut bytes = [0; PACKET_SIZE / 2];
+	let Some((range, data)) = args
+		.iter()
+		.position(|&byte| byte == b':')
+		.map(|colon| (&args[..colon], &args[colon + 1..]))
+	else
+	{
+		return reply.push(b"E01");
+	};
+	let Some((addr, length)) =
+		parse_range(range).filter(|&(_, length)| decode_hex(data, &mut bytes) == Some(length))
+	else
+	{
+		return reply.push(b"E01");
+	};
+	if !is_range_mapped(addr, length)
+	{
+		return reply.push(b"E14");
+	}
+	// SAFETY: the range is mapped
+	unsafe { poke(addr, &bytes[..length]) };
+	reply.push(b"OK");
+}
+
+/// Write `bytes` at `addr`, even if it is read-only (e.g. kernel code)
+///
+/// # Safety
+///
+/// The whole range must be mapped.
+unsafe fn poke(addr: u64, bytes: &[u8])
+{
+	let saved = cr0::read();
+	cr0::write(saved - Cr0::WP);
+	for (offset, &byte) in bytes.iter().enumerate()
+	{
+		unsafe { ((addr as usize + offset) as *mut u8).write_volatile(byte) };
+	}
+	cr0::write(saved);
+}
+
+/// Handle `Z`/`z` packets, for software breakpoints (type 0), hardware
+/// breakpoints (type 1), and write or access watchpoints (types 2 and 4)
+fn set_breakpoint(insert: bool, args: &[u8], reply: &mut Reply)
+{
+	let mut fields = args.split(|&byte| byte == b',');
+	let (Some(kind), Some(addr), Some(length)) = (
+		fields.next(),
+		fields.next().and_then(parse_hex),
+		fields.next().and_then(parse_hex)
+	)
+	else
+	{
+		return reply.push(b"E22");
+	};
+	let kind = match kind
+	{
+		b"0" => None,
+		b"1" => Some(WatchKind::Execute),
+		b"2" => Some(WatchKind::Write),
+		b"4" => Some(WatchKind::Access),
+		// unsupported breakpoint type (x86 has no read watchpoints), which an
+		// empty reply tells GDB
+		_ => return
+	};
+	if let Some(kind) = kind
+	{
+		return set_hardware_breakpoint(insert, addr, length, kind, reply);
+	}
+
+	// SAFETY: only the CPU owning the stub gets here
+	let breakpoints = unsafe { &mut *BREAKPOINTS.get() };
+	let existing = breakpoints
+		.iter()
+		.position(|slot| slot.is_some_and(|breakpoint| breakpoint.addr == addr));
+	match (insert, existing)
+	{
+		(true, Some(_)) | (false, None) => reply.push(b"OK"),
+		(true, None) =>
+		{
+			let Some(slot) = breakpoints.iter_mut().find(|slot| slot.is_none())
+			else
+			{
+				return reply.push(b"E12");
+			};
+			if !is_range_mapped(addr, 1)
+			{
+				return reply.push(b"E14");
+			}
+			// SAFETY: the address is mapped
+			let original = unsafe { (addr as *const u8).read_volatile() };
+			unsafe { poke(addr, &[INT3]) };
+			*slot = Some(Breakpoint { addr, original });
+			reply.push(b"OK");
+		},
+		(false, Some(index)) =>
+		{
+			if let Some(breakpoint) = breakpoints[index].take()
+			{
+				// SAFETY: the address was mapped when the breakpoint was set
+				unsafe { poke(breakpoint.addr, &[breakpoint.original]) };
+			}
+			reply.push(b"OK");
+		}
+	}
+}
+
+/// Set or remove a hardware breakpoint of the CPU owning the stub
+fn set_hardware_breakpoint(insert: bool, addr: u64, length: u64, kind: WatchKind, reply: &mut Reply)
+{
+	let cpu = cpu::current_cpu_index();
+	// SAFETY: only the CPU owning the stub gets here
+	let breakpoints = unsafe { &mut *HARDWARE_BREAKPOINTS.get() };
+	let existing = breakpoints.iter().enumerate().find_map(|(owner, slots)| {
+		slots
+			.iter()
+			.position(|slot| slot.is_some_and(|watch| watch.addr == addr && watch.kind == kind))
+			.map(|slot| (owner, slot))
+	});
+	match (insert, existing)
+	{
+		(true, Some(_)) | (false, None) => reply.push(b"OK"),
+		(true, None) =>
+		{
+			// the length of instruction breakpoints is the breakpoint kind
+			let length = if kind == WatchKind::Execute
+			{
+				Some(WatchLength::Byte)
+			}
+			else
+			{
+				WatchLength::from_bytes(length)
+			};
+			let Some(length) = length
+			else
+			{
+				return reply.push(b"E22");
+			};
+			match dbgregs::set_watchpoint(addr, length, kind)
+			{
+				Ok(slot) =>
+				{
+					breakpoints[cpu][slot] = Some(Watchpoint {
+						addr,
+						len: length,
+						kind
+					});
+					reply.push(b"OK");
+				},
+				Err(dbgregs::WatchpointError::NoFreeSlot) => reply.push(b"E28"),
+				Err(_) => reply.push(b"E22")
+			}
+		},
+		(false, Some((owner, slot))) =>
+		{
+			breakpoints[owner][slot] = None;
+			remove_hardware_breakpoint(cpu, owner, slot);
+			reply.push(b"OK");
+		}
+	}
+}
+
+/// Clear the hardware breakpoint `slot` of `owner`, from the CPU owning the
+/// stub
+fn remove_hardware_breakpoint(cpu: usize, owner: usize, slot: usize)
+{
+	if owner == cpu
+	{
+		let _ = dbgregs::clear_watchpoint(slot);
+	}
+	else
+	{
+		// the debug registers of another CPU can't be accessed from here
+		STALE_HARDWARE_BREAKPOINTS[owner].fetch_or(1 << slot, Ordering::AcqRel);
+	}
+}
+
+/// Clear the hardware breakpoints GDB removed from the calling CPU while
+/// another CPU owned the stub, returning their slots
+fn clear_stale_hardware_breakpoints(cpu: usize) -> u8
+{
+	let stale = STALE_HARDWARE_BREAKPOINTS[cpu].swap(0, Ordering::AcqRel);
+	for slot in (0..dbgregs::BREAKPOINT_COUNT).filter(|&slot| stale & (1 << slot) != 0)
+	{
+		let _ = dbgregs::clear_watchpoint(slot);
+	}
+	stale
+}
+
+fn remove_all_breakpoints()
+{
+	let cpu = cpu::current_cpu_index();
+	// SAFETY: only the CPU owning the stub gets here
+	for (owner, slots) in unsafe { &mut *HARDWARE_BREAKPOINTS.get() }
+		.iter_mut()
+		.enumerate()
+	{
+		for (slot, watch) in slots.iter_mut().enumerate()
+		{
+			if watch.take().is_some()
+			{
+				remove_hardware_breakpoint(cpu, owner, slot);
+			}
+		}
+	}
+
+	// SAFETY: only the CPU owning the stub gets here
+	for slot in unsafe { &mut *BREAKPOINTS.get() }
+	{
+		if let Some(breakpoint) = slot.take()
+		{
+			// SAFETY: the address was mapped when the breakpoint was set
+			unsafe { poke(breakpoint.addr, &[breakpoint.original]) };
+		}
+	}
+}
@@ -0,0 +1,176 @@
+//! Framing of the GDB Remote Serial Protocol packets
+//!
+//! A packet is sent as `$<data>#<checksum>`, the checksum being the sum of
+//! the data bytes modulo 256, as two hexadecimal digits. Each packet is
+//! acknowledged by the receiver with a `+`, or a `-` to ask for it again.
+
+use crate::{
+	arch::target::io::serial::SerialPort,
+	kernel::serial::{SerialInput, SerialOutput}
+};
+
+/// Size of the packet buffers (advertised to GDB as `PacketSize`)
+pub(super) const PACKET_SIZE: usize = 4096;
+
+/// The byte GDB sends to interrupt the target (i.e. on Ctrl-C)
+pub(super) const INTERRUPT: u8 = 0x03;
+
+/// Number of times a reply is sent again if GDB doesn't acknowledge it
+const MAX_RETRIES: usize = 8;
+
+const HEX_DIGITS: &[u8; 16] = b"0123456789abcdef";
+
+pub(super) fn hex_value(digit: u8) -> Option<u8>
+{
+	match digit
+	{
+		b'0'..=b'9' => Some(digit - b'0'),
+		b'a'..=b'f' => Some(digit - b'a' + 10),
+		b'A'..=b'F' => Some(digit - b'A' + 10),
+		_ => None
+	}
+}
+
+/// Parse a big-endian hexadecimal number (e.g. an address or a length)
+pub(super) fn parse_hex(digits: &[u8]) -> Option<u64>
+{
+	if digits.is_empty() || digits.len() > 16
+	{
+		return None;
+	}
+	digits.iter().try_fold(0_u64, |acc, &digit| {
+		Some((acc << 4) | u64::from(hex_value(digit)?))
+	})
+}
+
+/// Decode hex-encoded bytes into `out`, returning the number of bytes decoded
+pub(super) fn decode_hex(digits: &[u8], out: &mut [u8]) -> Option<usize>
+{
+	if digits.len() % 2 != 0 || digits.len() / 2 > out.len()
+	{
+		return None;
+	}
+	for (byte, pair) in out.iter_mut().zip(digits.chunks_exact(2))
+	{
+		*byte = (hex_value(pair[0])? << 4) | hex_value(pair[1])?;
+	}
+	Some(digits.len() / 2)
+}
+
+/// Read a packet into `buf`, returning its data
+///
+/// Bytes outside of a packet (e.g. stray acknowledgements, or interrupt
+/// requests, which are meaningless while stopped) are skipped, and packets
+/// with a bad checksum are asked again.
+pub(super) fn receive<'buf>(port: &SerialPort, buf: &'buf mut [u8; PACKET_SIZE]) -> &'buf [u8]
+{
+	'packet: loop
+	{
+		while port.serial_read_byte() != b'$'
+		{}
+
+		let mut len = 0;
+		let mut checksum = 0_u8;
+		loop
+		{
+			match port.serial_read_byte()
+			{
+				b'#' => break,
+				// GDB gave up on this packet, and is sending a new one
+				b'$' => continue 'packet,
+				byte =>
+				{
+					if len == PACKET_SIZE
+					{
+						port.serial_write_byte(b'-');
+						continue 'packet;
+					}
+					buf[len] = byte;
+					len += 1;
+					checksum = checksum.wrapping_add(byte);
+				}
+			}
+		}
+
+		let high = hex_value(port.serial_read_byte());
+		let low = hex_value(port.serial_read_byte());
+		if high.zip(low).map(|(high, low)| (high << 4) | low) == Some(checksum)
+		{
+			port.serial_write_byte(b'+');
+			return &buf[..len];
+		}
+		port.serial_write_byte(b'-');
+	}
+}
+
+/// A reply being built, which is silently truncated if it doesn't fit in a
+/// packet
+pub(super) struct Reply<'buf>
+{
+	buf: &'buf mut [u8; PACKET_SIZE],
+	len: usize
+}
+
+impl<'buf> Reply<'buf>
+{
+	pub(super) fn new(buf: &'buf mut [u8; PACKET_SIZE]) -> Self
+	{
+		Self { buf, len: 0 }
+	}
+
+	pub(super) fn is_empty(&self) -> bool
+	{
+		self.len == 0
+	}
+
+	pub(super) fn push(&mut self, bytes: &[u8])
+	{
+		let count = bytes.len().min(PACKET_SIZE - self.len);
+		self.buf[self.len..self.len + count].copy_from_slice(&bytes[..count]);
+		self.len += count;
+	}
+
+	/// Append `bytes`, hex-encoded
+	pub(super) fn push_hex(&mut self, bytes: &[u8])
+	{
+		for &byte in bytes
+		{
+			self.push(&[
+				HEX_DIGITS[usize::from(byte >> 4)],
+				HEX_DIGITS[usize::from(byte & 0xf)]
+			]);
+		}
+	}
+
+	/// Append `value` as a big-endian hexadecimal number, without leading
+	/// zeroes
+	pub(super) fn push_number(&mut self, value: u64)
+	{
+		let digits = (64 - value.leading_zeros()).div_ceil(4).max(1);
+		for i in (0..digits).rev()
+		{
+			self.push(&[HEX_DIGITS[((value >> (4 * i)) & 0xf) as usize]]);
+		}
+	}
+
+	/// Send the reply, until GDB acknowledges it
+	pub(super) fn send(self, port: &SerialPort)
+	{
+		let data = &self.buf[..self.len];
+		let checksum = data.iter().fold(0_u8, |sum, &byte| sum.wrapping_add(byte));
+		for _ in 0..MAX_RETRIES
+		{
+			port.serial_write_byte(b'$');
+			port.serial_write_bytes(data);
+			port.serial_write_byte(b'#');
+			port.serial_write_bytes(&[
+				HEX_DIGITS[usize::from(checksum >> 4)],
+				HEX_DIGITS[usize::from(checksum & 0xf)]
+			]);
+			if port.serial_read_byte() == b'+'
+			{
+				return;
+			}
+		}
+	}
+}
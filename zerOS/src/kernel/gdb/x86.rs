@@ -0,0 +1,132 @@
+use core::arch::asm;
+
+use crate::{
+	arch::target::cpu::ctlregs::{Cr4, cr3, cr4},
+	kernel::memory::hhdm_offset,
+	unwinding::RegisterSet
+};
+
+/// Number of registers in a `g` packet: the general purpose registers, `eip`,
+/// `eflags` and the segment registers (GDB treats the missing FPU and SSE
+/// registers as unavailable)
+pub(super) const REGISTER_COUNT: usize = 16;
+
+/// Size of each register in a `g` packet, indexed by GDB register number
+pub(super) const REGISTER_SIZES: [usize; REGISTER_COUNT] = [4; REGISTER_COUNT];
+
+/// GDB number of the instruction pointer
+pub(super) const PC: usize = 8;
+/// GDB number of the flags register
+pub(super) const FLAGS: usize = 9;
+
+const SEGMENTS: usize = 10;
+
+/// Fill `regs` from an unwinder snapshot
+///
+/// GDB numbers the general purpose registers like DWARF does. Registers the
+/// snapshot doesn't have (i.e. the flags and segment registers) are read from
+/// the calling CPU.
+pub(super) fn from_register_set(snapshot: &RegisterSet, regs: &mut [u64; REGISTER_COUNT])
+{
+	for (slot, &value) in regs
+		.iter_mut()
+		.zip(snapshot.dwarf_registers().iter().take(8))
+	{
+		*slot = value.unwrap_or(0);
+	}
+	regs[PC] = snapshot.get_pc().unwrap_or(0);
+
+	let flags: u32;
+	let segments: [u16; 6];
+	unsafe {
+		let (cs, ss, ds, es, fs, gs): (u16, u16, u16, u16, u16, u16);
+		asm! {
+			"pushfl",
+			"popl {flags}",
+			"movw %cs, {cs:x}",
+			"movw %ss, {ss:x}",
+			"movw %ds, {ds:x}",
+			"movw %es, {es:x}",
+			"movw %fs, {fs:x}",
+			"movw %gs, {gs:x}",
+			flags = out(reg) flags,
+			cs = out(reg) cs,
+			ss = out(reg) ss,
+			ds = out(reg) ds,
+			es = out(reg) es,
+			fs = out(reg) fs,
+			gs = out(reg) gs,
+			options(att_syntax, nomem, preserves_flags)
+		}
+		segments = [cs, ss, ds, es, fs, gs];
+	}
+	regs[FLAGS] = u64::from(flags);
+	for (slot, segment) in regs[SEGMENTS..].iter_mut().zip(segments)
+	{
+		*slot = u64::from(segment);
+	}
+}
+
+/// Whether the page containing `addr` is mapped in the current address space
+///
+/// The page tables are walked through the higher-half direct map, so this
+/// always answers `false` until it is known.
+pub(super) fn is_mapped(addr: u64) -> bool
+{
+	const PRESENT: u64 = 1 << 0;
+	const HUGE: u64 = 1 << 7;
+
+	let Some(hhdm) = hhdm_offset()
+	else
+	{
+		return false;
+	};
+
+	// SAFETY: page tables are mapped by the higher-half direct map
+	let read_entry = |table: u64, index: u64, wide: bool| unsafe {
+		if wide
+		{
+			((hhdm + table) as *const u64)
+				.add(index as usize)
+				.read_volatile()
+		}
+		else
+		{
+			u64::from(
+				((hhdm + table) as *const u32)
+					.add(index as usize)
+					.read_volatile()
+			)
+		}
+	};
+
+	let root = cr3::read().bits() as u64;
+	if cr4::read().contains(Cr4::PAE)
+	{
+		const ADDRESS_MASK: u64 = 0x000f_ffff_ffff_f000;
+
+		let pdpte = read_entry(root & 0xffff_ffe0, (addr >> 30) & 0x3, true);
+		if pdpte & PRESENT == 0
+		{
+			return false;
+		}
+		let pde = read_entry(pdpte & ADDRESS_MASK, (addr >> 21) & 0x1ff, true);
+		if pde & PRESENT == 0
+		{
+			return false;
+		}
+		pde & HUGE != 0 || read_entry(pde & ADDRESS_MASK, (addr >> 12) & 0x1ff, true) & PRESENT != 0
+	}
+	else
+	{
+		const ADDRESS_MASK: u64 = 0xffff_f000;
+
+		let pde = read_entry(root & ADDRESS_MASK, (addr >> 22) & 0x3ff, false);
+		if pde & PRESENT == 0
+		{
+			return false;
+		}
+		pde & HUGE != 0
+			|| read_entry(pde & ADDRESS_MASK, (addr >> 12) & 0x3ff, false) & PRESENT != 0
+	}
+}
@@ -1,73 +1,315 @@
-use core::result::Result;
+//! Hypervisor detection
+//!
+//! The hypervisor is identified by the signature of its CPUID leaves (at
+//! `0x40000000`, or `0x40000100` when it also emulates Hyper-V), which is
+//! available from the very beginning. [`init`] completes the detection with
+//! the DMI strings once physical memory can be mapped, for the emulators which
+//! don't advertise themselves through CPUID (e.g. Bochs).
+//!
+//! KVM doesn't tell which VMM runs the guest: QEMU is only assumed when its
+//! fw_cfg device or the DMI strings confirm it (see [`under_qemu`]).
 
-use overloadable::overloadable;
+use core::fmt;
 
-use crate::{arch::target::cpu::cpuid, kernel::sync::BasicMutex};
+use bitflags::bitflags;
 
-overloadable! {
-	pub under_qemu as
+use crate::{
+	arch::target::{cpu::cpuid, io::fw_cfg},
+	info,
+	kernel::{dmi, sync::BasicRwLock},
+	warn
+};
 
-	fn(cache_result: bool) -> Result<bool, core::array::TryFromSliceError>
+/// First CPUID leaf reserved for hypervisors
+const BASE_LEAF: u32 = 0x4000_0000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Hypervisor
+{
+	/// Bare metal
+	None,
+	Kvm,
+	/// QEMU's emulation (without KVM)
+	Tcg,
+	HyperV,
+	VMware,
+	Xen,
+	Bochs,
+	VirtualBox,
+	Parallels,
+	Bhyve,
+	/// A hypervisor this doesn't know about
+	Unknown
+}
+
+impl Hypervisor
+{
+	fn from_signature(signature: &[u8; 12]) -> Self
 	{
-		// SAFETY: the two following variables are only accessed
-		//         from this particular function, through a Mutex
-		//         (see `MTX` below)
-		static MTX: BasicMutex<(bool, bool)> = BasicMutex::new((false, false));
+		match signature
+		{
+			b"KVMKVMKVM\0\0\0" => Self::Kvm,
+			b"TCGTCGTCGTCG" => Self::Tcg,
+			b"Microsoft Hv" => Self::HyperV,
+			b"VMwareVMware" => Self::VMware,
+			b"XenVMMXenVMM" => Self::Xen,
+			b"VBoxVBoxVBox" => Self::VirtualBox,
+			b" lrpepyh  vr" => Self::Parallels,
+			b"bhyve bhyve " => Self::Bhyve,
+			_ => Self::Unknown
+		}
+	}
 
-		fn get_caching_status() -> bool
+	fn from_dmi(dmi: &dmi::DmiInfo) -> Self
+	{
+		let mentions = |needle: &str| {
+			[
+				&dmi.system_manufacturer,
+				&dmi.product_name,
+				&dmi.bios_vendor
+			]
+			.iter()
+			.any(|string| string.contains(needle))
+		};
+		if mentions("QEMU")
 		{
-			unsafe {
-				(*MTX.data_ptr()).0
-			}
+			Self::Tcg
 		}
-		fn get_cached_value() -> bool
+		else if mentions("Bochs")
 		{
-			unsafe {
-				(*MTX.data_ptr()).1
-			}
+			Self::Bochs
 		}
-		fn set_caching_status(value: bool)
+		else if mentions("VMware")
 		{
-			unsafe {
-				(*MTX.data_ptr()).0 = value;
-			}
+			Self::VMware
 		}
-		fn set_cached_value(value: bool)
+		else if mentions("innotek") || mentions("VirtualBox")
 		{
-			unsafe {
-				(*MTX.data_ptr()).1 = value;
-			}
+			Self::VirtualBox
+		}
+		else if mentions("Xen")
+		{
+			Self::Xen
+		}
+		else if mentions("Parallels")
+		{
+			Self::Parallels
+		}
+		else if dmi.system_manufacturer.contains("Microsoft") && mentions("Virtual Machine")
+		{
+			Self::HyperV
 		}
+		else
+		{
+			Self::None
+		}
+	}
+}
+
+impl fmt::Display for Hypervisor
+{
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+	{
+		let name = match self
+		{
+			Self::None => "none",
+			Self::Kvm => "KVM",
+			Self::Tcg => "QEMU TCG",
+			Self::HyperV => "Hyper-V",
+			Self::VMware => "VMware",
+			Self::Xen => "Xen",
+			Self::Bochs => "Bochs",
+			Self::VirtualBox => "VirtualBox",
+			Self::Parallels => "Parallels",
+			Self::Bhyve => "bhyve",
+			Self::Unknown => "unknown"
+		};
+		write!(f, "{name}")
+	}
+}
+
+bitflags! {
+	/// Paravirtual features of KVM (CPUID leaf `base + 1`, `EAX`)
+	#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+	pub struct ParavirtFeatures: u32
+	{
+		/// kvmclock, at the old MSRs
+		const KVM_CLOCK = 1 << 0;
+		/// `0x80` port writes aren't needed for I/O delays
+		const NOP_IO_DELAY = 1 << 1;
+		/// kvmclock, at the new MSRs
+		const KVM_CLOCK2 = 1 << 3;
+		const ASYNC_PF = 1 << 4;
+		const STEAL_TIME = 1 << 5;
+		const PV_EOI = 1 << 6;
+		const PV_UNHALT = 1 << 7;
+		const PV_TLB_FLUSH = 1 << 9;
+		const PV_SEND_IPI = 1 << 11;
+		const PV_SCHED_YIELD = 1 << 13;
+		/// The kvmclock is stable across CPUs
+		const CLOCK_STABLE = 1 << 24;
+	}
+}
+
+/// What is known about the hypervisor
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HypervisorInfo
+{
+	pub kind:      Hypervisor,
+	/// First CPUID leaf of the hypervisor, if it has some
+	pub base_leaf: u32,
+	/// Last CPUID leaf of the hypervisor
+	pub max_leaf:  u32,
+	pub features:  ParavirtFeatures,
+	/// Whether the VMM is known to be QEMU
+	pub qemu:      bool
+}
 
-		#[allow(unused_variables)]
-		let guard = MTX.lock();
+impl HypervisorInfo
+{
+	const BARE_METAL: Self = Self {
+		kind:      Hypervisor::None,
+		base_leaf: 0,
+		max_leaf:  0,
+		features:  ParavirtFeatures::empty(),
+		qemu:      false
+	};
+}
 
-		if cache_result && get_caching_status()
+static INFO: BasicRwLock<Option<HypervisorInfo>> = BasicRwLock::new(None);
+
+fn read_signature(leaf: u32) -> (u32, [u8; 12])
+{
+	let result = cpuid(leaf);
+	let mut signature = [0; 12];
+	signature[..4].copy_from_slice(&result.ebx.to_le_bytes());
+	signature[4..8].copy_from_slice(&result.ecx.to_le_bytes());
+	signature[8..].copy_from_slice(&result.edx.to_le_bytes());
+	(result.eax, signature)
+}
+
+/// Identify the hypervisor through CPUID
+fn detect() -> HypervisorInfo
+{
+	const HYPERVISOR_PRESENT: u32 = 1 << 31;
+
+	if cpuid(1_u32).ecx & HYPERVISOR_PRESENT == 0
+	{
+		return HypervisorInfo::BARE_METAL;
+	}
+
+	let (max_leaf, signature) = read_signature(BASE_LEAF);
+	let mut info = HypervisorInfo {
+		kind: Hypervisor::from_signature(&signature),
+		base_leaf: BASE_LEAF,
+		max_leaf,
+		features: ParavirtFeatures::empty(),
+		qemu: false
+	};
+	// KVM and Xen expose their own leaves after the Hyper-V ones when they
+	// emulate it
+	if info.kind == Hypervisor::HyperV
+	{
+		let (max_leaf, signature) = read_signature(BASE_LEAF + 0x100);
+		let kind = Hypervisor::from_signature(&signature);
+		if matches!(kind, Hypervisor::Kvm | Hypervisor::Xen)
 		{
-			return Ok(get_cached_value());
+			info = HypervisorInfo {
+				kind,
+				base_leaf: BASE_LEAF + 0x100,
+				max_leaf,
+				features: ParavirtFeatures::empty(),
+				qemu: false
+			};
 		}
-
-		const QEMU_HYPERV: [&[u8; 12]; 2] = [
-			b"TCGTCGTCGTCG", b"KVMKVMKVM\0\0\0"
-		];
-		let cpuid_res = cpuid(0x40000000_u32);
-		let hyperv: [u8; 12] = [
-			cpuid_res.ebx.to_ne_bytes(),
-			cpuid_res.ecx.to_ne_bytes(),
-			cpuid_res.edx.to_ne_bytes(),
-		].as_flattened()
-			.try_into()?;
-		let is_qemu = hyperv == *QEMU_HYPERV[0] || hyperv == *QEMU_HYPERV[1];
-		if cache_result
+	}
+	if info.kind == Hypervisor::Kvm
+	{
+		// old versions of KVM report 0 instead of their last leaf, which is
+		// then the features one
+		if info.max_leaf == 0
+		{
+			info.max_leaf = info.base_leaf + 1;
+		}
+		if info.max_leaf > info.base_leaf
 		{
-			set_cached_value(is_qemu);
-			set_caching_status(true);
+			info.features = ParavirtFeatures::from_bits_truncate(cpuid(info.base_leaf + 1).eax);
 		}
-		Ok(is_qemu)
-	},
+	}
+	info.qemu = info.kind == Hypervisor::Tcg
+		|| (matches!(info.kind, Hypervisor::Kvm | Hypervisor::Unknown) && fw_cfg::has_signature());
+	info
+}
+
+/// What is known about the hypervisor the kernel runs under
+pub fn hypervisor_info() -> HypervisorInfo
+{
+	if let Some(info) = *INFO.read()
+	{
+		return info;
+	}
+	let info = detect();
+	*INFO.write() = Some(info);
+	info
+}
+
+/// The hypervisor the kernel runs under
+pub fn hypervisor() -> Hypervisor
+{
+	hypervisor_info().kind
+}
+
+/// Complete the detection with the DMI strings, and log the result
+pub fn init()
+{
+	let mut info = hypervisor_info();
+	match dmi::read()
+	{
+		Ok(dmi) =>
+		{
+			info!(
+				"DMI: {} {}, BIOS {} {}",
+				dmi.system_manufacturer, dmi.product_name, dmi.bios_vendor, dmi.bios_version
+			);
+			let kind = Hypervisor::from_dmi(&dmi);
+			if matches!(info.kind, Hypervisor::None | Hypervisor::Unknown)
+				&& kind != Hypervisor::None
+			{
+				info.kind = kind;
+			}
+			// the DMI strings of QEMU mention it, with or without KVM
+			info.qemu |= kind == Hypervisor::Tcg;
+		},
+		Err(err) => warn!("couldn't read the DMI strings: {err}")
+	}
+	*INFO.write() = Some(info);
 
-	fn() -> Result<bool, core::array::TryFromSliceError>
+	if info.kind == Hypervisor::None
+	{
+		info!("running on bare metal");
+	}
+	else
 	{
-		under_qemu(true)
+		info!(
+			"running under {} (CPUID leaves {:#x}..={:#x}, paravirtual features {:?})",
+			info.kind, info.base_leaf, info.max_leaf, info.features
+		);
 	}
 }
+
+/// Whether the kernel runs in QEMU, with or without KVM, and may thus use its
+/// emulated debugging devices (e.g. the `0xe9` port)
+///
+/// Under KVM, this is only the case once the fw_cfg device or the DMI strings
+/// (after [`init`]) have confirmed it.
+pub fn under_qemu() -> bool
+{
+	hypervisor_info().qemu
+}
+
+/// Whether the kernel runs under KVM, and may thus use its paravirtual
+/// features (e.g. `kvmclock`)
+pub fn under_kvm() -> bool
+{
+	hypervisor() == Hypervisor::Kvm
+}
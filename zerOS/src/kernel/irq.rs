@@ -0,0 +1,643 @@
+//! Interrupt handler registration
+//!
+//! Subsystems register their handlers with [`request_irq`], either on an
+//! interrupt vector or on a global system interrupt (GSI), i.e. an input of an
+//! interrupt controller such as the I/O APIC. Vectors can also be allocated
+//! beforehand with [`allocate_vector`], in a range matching the priority
+//! [`IrqClass`] they need (the local APIC prioritizes interrupts by the upper
+//! nibble of their vector).
+//!
+//! A vector may be shared by several handlers (if they all ask for it with
+//! [`IrqFlags::SHARED`]): they are called in registration order, until one of
+//! them handles the interrupt. Handlers registered with [`IrqFlags::PER_CPU`]
+//! are only called on the CPU which registered them.
+//!
+//! The interrupt controllers plug themselves in with [`set_gsi_controller`]
+//! (to route GSIs to vectors) and [`set_end_of_interrupt`] (to acknowledge
+//! interrupts once handled).
+
+use core::fmt;
+
+use bitflags::bitflags;
+use portable_atomic::{AtomicU16, AtomicU64, Ordering};
+
+use crate::{
+	arch::target::cpu::{
+		self,
+		MAX_CPU_COUNT,
+		exceptions::{EXCEPTION_COUNT, InterruptFrame},
+		fpu,
+		idt::VECTOR_COUNT,
+		irq::without_interrupts,
+		misc::read_tsc
+	},
+	kernel::sync::BasicRwLock,
+	warn
+};
+
+/// Maximum number of handlers sharing a vector
+pub const MAX_SHARED_HANDLERS: usize = 8;
+
+/// Maximum number of GSIs which can be routed
+pub const MAX_GSI_COUNT: usize = 256;
+
+/// Vector of spurious interrupts, which never needs to be acknowledged
+pub const SPURIOUS_VECTOR: u8 = 0xff;
+
+/// Priority class of an interrupt vector
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrqClass
+{
+	/// Vectors of the remapped legacy 8259 PICs
+	Legacy,
+	/// Low priority device interrupts
+	Low,
+	/// Device interrupts
+	Normal,
+	/// High priority device interrupts
+	High,
+	/// Interrupts of the CPU itself (local timer, IPIs, APIC errors, ...)
+	System
+}
+
+impl IrqClass
+{
+	/// Vectors of the class
+	pub const fn vectors(self) -> core::ops::Range<u8>
+	{
+		match self
+		{
+			Self::Legacy => 0x20..0x30,
+			Self::Low => 0x30..0x80,
+			Self::Normal => 0x80..0xc0,
+			Self::High => 0xc0..0xe0,
+			Self::System => 0xe0..SPURIOUS_VECTOR
+		}
+	}
+}
+
+bitflags! {
+	/// Options of [`request_irq`]
+	#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+	pub struct IrqFlags: u32
+	{
+		/// The vector may be shared with other handlers
+		const SHARED = 1 << 0;
+		/// Only call the handler on the CPU registering it
+		const PER_CPU = 1 << 1;
+		/// The GSI is level-triggered (instead of edge-triggered)
+		const LEVEL_TRIGGERED = 1 << 2;
+		/// The GSI is active low (instead of active high)
+		const ACTIVE_LOW = 1 << 3;
+		/// Allocate the vector of the GSI in [`IrqClass::Low`]
+		const LOW_PRIORITY = 1 << 4;
+		/// Allocate the vector of the GSI in [`IrqClass::High`]
+		const HIGH_PRIORITY = 1 << 5;
+	}
+}
+
+/// An interrupt source a handler can be registered on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Irq
+{
+	/// An interrupt vector of the CPU (at least 32, the lower ones being
+	/// exceptions)
+	Vector(u8),
+	/// A global system interrupt, routed by the GSI controller
+	Gsi(u32)
+}
+
+impl fmt::Display for Irq
+{
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+	{
+		match self
+		{
+			Self::Vector(vector) => write!(f, "vector {vector:#04x}"),
+			Self::Gsi(gsi) => write!(f, "GSI {gsi}")
+		}
+	}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrqError
+{
+	/// The vector is reserved for CPU exceptions
+	ReservedVector,
+	/// No vector of the requested class is free
+	NoFreeVector,
+	/// The vector is already used without sharing
+	Busy,
+	/// Too many handlers share the vector
+	TooManyHandlers,
+	/// No GSI controller has been registered
+	NoGsiController,
+	/// The GSI doesn't exist, or can't be routed
+	InvalidGsi,
+	/// The interrupt can't be delivered to the requested CPU
+	UnreachableCpu,
+	/// The handle doesn't designate a registered handler
+	NotRegistered
+}
+
+impl fmt::Display for IrqError
+{
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+	{
+		match self
+		{
+			Self::ReservedVector => write!(f, "the vector is reserved for CPU exceptions"),
+			Self::NoFreeVector => write!(f, "no vector of the requested class is free"),
+			Self::Busy => write!(f, "the interrupt is already used, without sharing"),
+			Self::TooManyHandlers => write!(f, "too many handlers share the interrupt"),
+			Self::NoGsiController => write!(f, "no GSI controller has been registered"),
+			Self::InvalidGsi => write!(f, "the GSI can't be routed"),
+			Self::UnreachableCpu => write!(f, "the interrupt can't be delivered to the CPU"),
+			Self::NotRegistered => write!(f, "the handler isn't registered")
+		}
+	}
+}
+
+/// Whether a handler took care of an interrupt
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrqReturn
+{
+	/// The interrupt wasn't raised by the handler's device
+	NotMine,
+	Handled
+}
+
+/// What a handler is told about the interrupt it handles
+#[derive(Debug, Clone, Copy)]
+pub struct IrqContext
+{
+	/// The source the handler has been registered on
+	pub irq:    Irq,
+	pub vector: u8,
+	pub cpu:    usize,
+	/// The interrupted context, which the interrupt returns to with any change
+	/// a handler makes to it
+	pub frame:  *mut InterruptFrame
+}
+
+pub type IrqHandler = fn(&IrqContext) -> IrqReturn;
+
+/// A controller routing GSIs to vectors (e.g. the I/O APIC)
+pub trait GsiController: Sync
+{
+	fn name(&self) -> &'static str;
+
+	/// Deliver `gsi` to `vector` on `cpu`, masked, with the trigger mode and
+	/// polarity given by `flags`
+	fn route(&self, gsi: u32, vector: u8, flags: IrqFlags, cpu: usize) -> Result<(), IrqError>;
+
+	fn mask(&self, gsi: u32);
+
+	fn unmask(&self, gsi: u32);
+}
+
+/// A registered handler, to give back to [`free_irq`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IrqHandle
+{
+	irq:    Irq,
+	vector: u8,
+	slot:   usize
+}
+
+impl IrqHandle
+{
+	pub fn irq(&self) -> Irq
+	{
+		self.irq
+	}
+
+	pub fn vector(&self) -> u8
+	{
+		self.vector
+	}
+}
+
+#[derive(Clone, Copy)]
+struct Action
+{
+	handler: IrqHandler,
+	irq:     Irq,
+	flags:   IrqFlags,
+	name:    &'static str,
+	/// CPU the handler is restricted to, for per-CPU handlers
+	cpu:     Option<usize>
+}
+
+/// Statistics of a vector, as returned by [`stats`]
+#[derive(Debug, Clone)]
+pub struct IrqStats
+{
+	pub vector:       u8,
+	/// Names of the handlers registered on the vector
+	pub names:        heapless::Vec<&'static str, MAX_SHARED_HANDLERS>,
+	/// Number of interrupts received by each CPU
+	pub counts:       [u64; MAX_CPU_COUNT],
+	/// Number of interrupts no handler took care of
+	pub unhandled:    u64,
+	/// Time spent in the handlers by the last interrupt, in TSC cycles
+	pub last_latency: u64
+}
+
+impl IrqStats
+{
+	/// Number of interrupts received by all the CPUs
+	pub fn total(&self) -> u64
+	{
+		self.counts.iter().sum()
+	}
+}
+
+struct VectorStats
+{
+	counts:       [AtomicU64; MAX_CPU_COUNT],
+	unhandled:    AtomicU64,
+	last_latency: AtomicU64
+}
+
+impl VectorStats
+{
+	const fn new() -> Self
+	{
+		Self {
+			counts:       [const { AtomicU64::new(0) }; MAX_CPU_COUNT],
+			unhandled:    AtomicU64::new(0),
+			last_latency: AtomicU64::new(0)
+		}
+	}
+}
+
+static ACTIONS: [BasicRwLock<[Option<Action>; MAX_SHARED_HANDLERS]>; VECTOR_COUNT] =
+	[const { BasicRwLock::new([None; MAX_SHARED_HANDLERS]) }; VECTOR_COUNT];
+static STATS: [VectorStats; VECTOR_COUNT] = [const { VectorStats::new() }; VECTOR_COUNT];
+
+/// Allocated vectors, one bit per vector
+static ALLOCATED: [AtomicU64; VECTOR_COUNT / 64] = [
+	// exceptions
+	AtomicU64::new(u64::MAX >> (64 - EXCEPTION_COUNT)),
+	AtomicU64::new(0),
+	AtomicU64::new(0),
+	// spurious interrupts
+	AtomicU64::new(1 << 63)
+];
+
+/// Vectors [`request_irq`] claimed itself, to be released by [`free_irq`]
+static CLAIMED: [AtomicU64; VECTOR_COUNT / 64] = [const { AtomicU64::new(0) }; VECTOR_COUNT / 64];
+
+/// Vector each CPU is calling the handlers of (or [`NOT_DISPATCHING`])
+static DISPATCHING: [AtomicU16; MAX_CPU_COUNT] =
+	[const { AtomicU16::new(NOT_DISPATCHING) }; MAX_CPU_COUNT];
+const NOT_DISPATCHING: u16 = u16::MAX;
+
+/// Vector each GSI is routed to (0 if it isn't)
+static GSI_VECTORS: BasicRwLock<[u8; MAX_GSI_COUNT]> = BasicRwLock::new([0; MAX_GSI_COUNT]);
+
+static GSI_CONTROLLER: BasicRwLock<Option<&'static dyn GsiController>> = BasicRwLock::new(None);
+static END_OF_INTERRUPT: BasicRwLock<Option<fn(u8)>> = BasicRwLock::new(None);
+
+/// Register the controller GSIs are routed by
+pub fn set_gsi_controller(controller: &'static dyn GsiController)
+{
+	without_interrupts(|| *GSI_CONTROLLER.write() = Some(controller));
+}
+
+/// Register the function acknowledging interrupts (given their vector) once
+/// they have been handled
+pub fn set_end_of_interrupt(eoi: fn(u8))
+{
+	without_interrupts(|| *END_OF_INTERRUPT.write() = Some(eoi));
+}
+
+/// Try to allocate `vector`, returning whether it was free
+pub fn claim_vector(vector: u8) -> bool
+{
+	let (word, bit) = (usize::from(vector) / 64, vector % 64);
+	ALLOCATED[word].fetch_or(1 << bit, Ordering::AcqRel) & (1 << bit) == 0
+}
+
+/// Allocate a free vector of `class`
+pub fn allocate_vector(class: IrqClass) -> Result<u8, IrqError>
+{
+	class
+		.vectors()
+		.find(|&vector| claim_vector(vector))
+		.ok_or(IrqError::NoFreeVector)
+}
+
+/// Release a vector allocated with [`allocate_vector`] or [`claim_vector`]
+pub fn free_vector(vector: u8)
+{
+	if usize::from(vector) < EXCEPTION_COUNT || vector == SPURIOUS_VECTOR
+	{
+		return;
+	}
+	let (word, bit) = (usize::from(vector) / 64, vector % 64);
+	ALLOCATED[word].fetch_and(!(1 << bit), Ordering::AcqRel);
+}
+
+/// Whether a GSI is routed to `vector`
+fn routes_gsi(vector: u8) -> bool
+{
+	GSI_VECTORS.read().contains(&vector)
+}
+
+/// Vector `gsi` is routed to, routing it if needed
+///
+/// Returns whether it has been routed by this call as well.
+fn gsi_vector(gsi: u32, flags: IrqFlags) -> Result<(u8, bool), IrqError>
+{
+	let index = usize::try_from(gsi)
+		.ok()
+		.filter(|&index| index < MAX_GSI_COUNT)
+		.ok_or(IrqError::InvalidGsi)?;
+	let mut vectors = GSI_VECTORS.write();
+	if vectors[index] != 0
+	{
+		return Ok((vectors[index], false));
+	}
+
+	let controller = (*GSI_CONTROLLER.read()).ok_or(IrqError::NoGsiController)?;
+	let class = if flags.contains(IrqFlags::HIGH_PRIORITY)
+	{
+		IrqClass::High
+	}
+	else if flags.contains(IrqFlags::LOW_PRIORITY)
+	{
+		IrqClass::Low
+	}
+	else
+	{
+		IrqClass::Normal
+	};
+	let vector = allocate_vector(class)?;
+	if let Err(err) = controller.route(gsi, vector, flags, cpu::current_cpu_index())
+	{
+		free_vector(vector);
+		return Err(err);
+	}
+	vectors[index] = vector;
+	Ok((vector, true))
+}
+
+/// A vector claimed, or a GSI routed, by [`request_irq`], released unless the
+/// handler ends up registered (see [`Claim::keep`])
+struct Claim
+{
+	irq:    Irq,
+	vector: u8
+}
+
+impl Claim
+{
+	/// Leave the vector or the route to [`free_irq`]
+	fn keep(self)
+	{
+		if let Irq::Vector(vector) = self.irq
+		{
+			let (word, bit) = (usize::from(vector) / 64, vector % 64);
+			CLAIMED[word].fetch_or(1 << bit, Ordering::AcqRel);
+		}
+		core::mem::forget(self);
+	}
+}
+
+impl Drop for Claim
+{
+	fn drop(&mut self)
+	{
+		if let Irq::Gsi(gsi) = self.irq
+		{
+			// routed masked, and never unmasked
+			GSI_VECTORS.write()[gsi as usize] = 0;
+		}
+		free_vector(self.vector);
+	}
+}
+
+/// Register `handler` on `irq`
+///
+/// GSIs are routed to a newly allocated vector when their first handler is
+/// registered, and unmasked once it is. A vector is claimed by its first
+/// handler, unless it has been allocated beforehand (e.g. with
+/// [`allocate_vector`]); the vectors GSIs are routed to can only be requested
+/// through their GSI.
+pub fn request_irq(
+	irq: Irq,
+	handler: IrqHandler,
+	flags: IrqFlags,
+	name: &'static str
+) -> Result<IrqHandle, IrqError>
+{
+	without_interrupts(|| {
+		let (vector, claimed) = match irq
+		{
+			Irq::Vector(vector) if usize::from(vector) < EXCEPTION_COUNT =>
+			{
+				return Err(IrqError::ReservedVector);
+			},
+			Irq::Vector(vector) =>
+			{
+				let claimed = claim_vector(vector);
+				if !claimed && routes_gsi(vector)
+				{
+					return Err(IrqError::Busy);
+				}
+				(vector, claimed)
+			},
+			Irq::Gsi(gsi) => gsi_vector(gsi, flags)?
+		};
+		// released on the errors below
+		let claim = claimed.then_some(Claim { irq, vector });
+
+		let mut actions = ACTIONS[usize::from(vector)].write();
+		if actions
+			.iter()
+			.flatten()
+			.any(|action| !(action.flags & flags).contains(IrqFlags::SHARED))
+		{
+			return Err(IrqError::Busy);
+		}
+		let slot = actions
+			.iter()
+			.position(Option::is_none)
+			.ok_or(IrqError::TooManyHandlers)?;
+		let first = actions.iter().all(Option::is_none);
+		actions[slot] = Some(Action {
+			handler,
+			irq,
+			flags,
+			name,
+			cpu: flags
+				.contains(IrqFlags::PER_CPU)
+				.then(cpu::current_cpu_index)
+		});
+		drop(actions);
+		if let Some(claim) = claim
+		{
+			claim.keep();
+		}
+
+		if let Irq::Gsi(gsi) = irq
+			&& first && let Some(controller) = *GSI_CONTROLLER.read()
+		{
+			controller.unmask(gsi);
+		}
+		Ok(IrqHandle { irq, vector, slot })
+	})
+}
+
+/// Unregister a handler registered with [`request_irq`]
+///
+/// Once the last handler of a GSI is unregistered, the GSI is masked, and its
+/// vector released; the vectors claimed by [`request_irq`] are released as
+/// well. The handler may be unregistered from itself; otherwise, it isn't
+/// running anymore on any CPU once this returns.
+pub fn free_irq(handle: IrqHandle) -> Result<(), IrqError>
+{
+	without_interrupts(|| {
+		let mut actions = ACTIONS[usize::from(handle.vector)].write();
+		match actions[handle.slot]
+		{
+			Some(action) if action.irq == handle.irq => actions[handle.slot] = None,
+			_ => return Err(IrqError::NotRegistered)
+		}
+		let last = actions.iter().all(Option::is_none);
+		drop(actions);
+
+		let current = cpu::current_cpu_index();
+		for (_, dispatching) in DISPATCHING
+			.iter()
+			.enumerate()
+			.filter(|&(cpu, _)| cpu != current)
+		{
+			while dispatching.load(Ordering::Acquire) == u16::from(handle.vector)
+			{
+				core::hint::spin_loop();
+			}
+		}
+
+		if !last
+		{
+			return Ok(());
+		}
+		match handle.irq
+		{
+			Irq::Gsi(gsi) =>
+			{
+				if let Some(controller) = *GSI_CONTROLLER.read()
+				{
+					controller.mask(gsi);
+				}
+				GSI_VECTORS.write()[gsi as usize] = 0;
+				free_vector(handle.vector);
+			},
+			Irq::Vector(vector) =>
+			{
+				let (word, bit) = (usize::from(vector) / 64, vector % 64);
+				if CLAIMED[word].fetch_and(!(1 << bit), Ordering::AcqRel) & (1 << bit) != 0
+				{
+					free_vector(vector);
+				}
+			}
+		}
+		Ok(())
+	})
+}
+
+/// Statistics of `vector`
+pub fn stats(vector: u8) -> IrqStats
+{
+	let stats = &STATS[usize::from(vector)];
+	let mut names = heapless::Vec::new();
+	for action in ACTIONS[usize::from(vector)].read().iter().flatten()
+	{
+		let _ = names.push(action.name);
+	}
+	IrqStats {
+		vector,
+		names,
+		counts: core::array::from_fn(|cpu| stats.counts[cpu].load(Ordering::Relaxed)),
+		unhandled: stats.unhandled.load(Ordering::Relaxed),
+		last_latency: stats.last_latency.load(Ordering::Relaxed)
+	}
+}
+
+/// Call `f` with the statistics of every vector which has a handler, or
+/// received interrupts
+pub fn for_each_stats(mut f: impl FnMut(&IrqStats))
+{
+	for vector in EXCEPTION_COUNT..VECTOR_COUNT
+	{
+		let stats = stats(vector as u8);
+		if !stats.names.is_empty() || stats.total() != 0
+		{
+			f(&stats);
+		}
+	}
+}
+
+/// Call the handlers of `vector`, and acknowledge the interrupt
+///
+/// Called by the interrupt entry stubs, with interrupts disabled. The handlers
+/// are called without holding any lock, so that they can unregister
+/// themselves.
+pub fn dispatch(vector: u8, frame: &mut InterruptFrame)
+{
+	// the stubs only save the general purpose registers, while the handlers
+	// may use SIMD instructions
+	let _fpu = fpu::try_kernel_fpu_begin();
+
+	let start = read_tsc();
+	let cpu = cpu::current_cpu_index();
+	let stats = &STATS[usize::from(vector)];
+	stats.counts[cpu].fetch_add(1, Ordering::Relaxed);
+
+	DISPATCHING[cpu].store(u16::from(vector), Ordering::SeqCst);
+	let actions = *ACTIONS[usize::from(vector)].read();
+	let mut handled = false;
+	for action in actions
+		.iter()
+		.flatten()
+		.filter(|action| action.cpu.is_none_or(|owner| owner == cpu))
+	{
+		let context = IrqContext {
+			irq: action.irq,
+			vector,
+			cpu,
+			frame
+		};
+		if (action.handler)(&context) == IrqReturn::Handled
+		{
+			handled = true;
+			break;
+		}
+	}
+	DISPATCHING[cpu].store(NOT_DISPATCHING, Ordering::Release);
+
+	if !handled && vector != SPURIOUS_VECTOR
+	{
+		// only warn once, as an unhandled level-triggered interrupt fires
+		// again and again
+		if stats.unhandled.fetch_add(1, Ordering::Relaxed) == 0
+		{
+			warn!("unhandled interrupt on vector {vector:#04x} (cpu #{cpu})");
+		}
+	}
+	stats
+		.last_latency
+		.store(read_tsc().wrapping_sub(start), Ordering::Relaxed);
+
+	// the legacy PICs aren't acknowledged through the interrupt controller,
+	// but by their own handlers
+	if vector != SPURIOUS_VECTOR
+		&& !IrqClass::Legacy.vectors().contains(&vector)
+		&& let Some(eoi) = *END_OF_INTERRUPT.read()
+	{
+		eoi(vector);
+	}
+}
@@ -8,7 +8,7 @@ pub mod __linker_symbols
 	use lazy_static::lazy_static;
 
 	use super::super::LinkerSym;
-	pub const zerOS_section_count: usize = 8;
+	pub const zerOS_section_count: usize = 9;
 
 	unsafe extern "C" {
 		pub unsafe static zerOS_kernel_start: LinkerSym;
@@ -99,6 +99,20 @@ pub mod __linker_symbols
 		};
 	}
 
+	unsafe extern "C" {
+		pub unsafe static zerOS_troll_start: LinkerSym;
+		pub unsafe static zerOS_troll_end: LinkerSym;
+	}
+	lazy_static! {
+		#[allow(non_upper_case_globals)]
+		pub static ref zerOS_troll_size: usize = {
+			unsafe {
+				(zerOS_troll_end   as usize) -
+				(zerOS_troll_start as usize)
+			}
+		};
+	}
+
 	unsafe extern "C" {
 		pub unsafe static zerOS_data_start: LinkerSym;
 		pub unsafe static zerOS_data_end: LinkerSym;
@@ -25,5 +25,8 @@ pub use super::__generated::__linker_symbols::{
 	zerOS_section_count,
 	zerOS_text_end,
 	zerOS_text_size,
-	zerOS_text_start
+	zerOS_text_start,
+	zerOS_troll_end,
+	zerOS_troll_size,
+	zerOS_troll_start
 };
@@ -37,6 +37,11 @@ pub fn in_section(secname: &str, addr: *const c_void) -> Option<bool>
 				&raw const zerOS_eh_frame_hdr_start,
 				&raw const zerOS_eh_frame_hdr_end
 			),
+			(
+				"troll",
+				&raw const zerOS_troll_start,
+				&raw const zerOS_troll_end
+			),
 			// ("debug_line", unsafe { &raw const zerOS_debug_line_start }, unsafe { &raw const
 			// zerOS_debug_line_end }),
 			(
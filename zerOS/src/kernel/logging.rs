@@ -1,7 +1,10 @@
 /// ! TODO: implement key-value logging: https://docs.rs/log/0.4.27/log/kv/index.html
 /// ! TODO: implement per-subsystem logging using either kw logging or just
 /// `target`
-use core::sync::atomic::{self, AtomicBool};
+use core::{
+	fmt,
+	sync::atomic::{self, AtomicBool}
+};
 
 use anyhow::anyhow;
 use lazy_static::lazy_static;
@@ -265,7 +268,9 @@ pub enum LoggingBackend
 {
 	Serial = 0,
 	FrameBuffer,
-	DebugCon
+	DebugCon,
+	/// The in-memory [`LogRing`]
+	Ring
 }
 
 static mut ENABLED_LOGGING_BACKENDS: [AtomicBool; core::mem::variant_count::<LoggingBackend>()] =
@@ -505,3 +510,90 @@ impl log::Log for MultiLogger
 		}
 	}
 }
+
+/// Size of the in-memory log ring buffer
+pub const LOG_RING_SIZE: usize = 16 * 1024;
+
+/// A logging backend keeping the last [`LOG_RING_SIZE`] bytes of output in
+/// memory, so that they can be included in a crash dump
+pub struct LogRing
+{
+	buf:     [u8; LOG_RING_SIZE],
+	/// Index of the next byte to write
+	head:    usize,
+	wrapped: bool
+}
+
+impl LogRing
+{
+	const fn new() -> Self
+	{
+		Self {
+			buf:     [0; LOG_RING_SIZE],
+			head:    0,
+			wrapped: false
+		}
+	}
+
+	/// The content of the ring, oldest bytes first
+	///
+	/// The content is split in two parts when the ring has wrapped around. The
+	/// first part may start in the middle of a UTF-8 sequence.
+	pub fn contents(&self) -> (&[u8], &[u8])
+	{
+		if self.wrapped
+		{
+			(&self.buf[self.head..], &self.buf[..self.head])
+		}
+		else
+		{
+			(&self.buf[..self.head], &[])
+		}
+	}
+}
+
+impl fmt::Write for LogRing
+{
+	fn write_str(&mut self, s: &str) -> fmt::Result
+	{
+		for &byte in s.as_bytes()
+		{
+			self.buf[self.head] = byte;
+			self.head += 1;
+			if self.head == LOG_RING_SIZE
+			{
+				self.head = 0;
+				self.wrapped = true;
+			}
+		}
+		Ok(())
+	}
+}
+
+impl KernelOutput for LogRing
+{
+	fn flush(&mut self) -> fmt::Result
+	{
+		Ok(())
+	}
+
+	fn supports_ansi_escape_codes(&self) -> bool
+	{
+		false
+	}
+
+	fn write_byte(&self, _byte: u8)
+	{
+		// only reached through `fmt::Write`, which needs `&mut self`
+	}
+}
+
+pub static ZEROS_LOG_RING: BasicMutex<LogRing> = BasicMutex::new(LogRing::new());
+
+ctor! {
+	@name(zerOS_init_log_ring);
+	@priority(3);
+
+	ZEROS_GLOBAL_LOGGER.add_logger(&ZEROS_LOG_RING, None, LoggingBackend::Ring).unwrap();
+	set_global_backend_state(LoggingBackend::Ring, true);
+}
@@ -1,11 +1,14 @@
 use core::{
 	arch::asm,
-	mem::{align_of, size_of}
+	mem::{align_of, size_of},
+	ptr
 };
 
 use num::traits::AsPrimitive;
 use zerocopy::{FromBytes, IntoBytes};
 
+use super::tss::TaskStateSegment;
+
 #[repr(C, packed)]
 #[derive(Debug, Clone, Copy)]
 pub struct GDTDescriptor
@@ -319,6 +322,22 @@ pub macro entry_make
 
 impl GDT
 {
+	/// The default GDT, with a TSS descriptor for `tss`
+	pub fn with_tss(tss: &'static TaskStateSegment) -> Self
+	{
+		let mut this = Self::default();
+		this.entries[entry_index!(TSS) / 2].sys = Self::make_system_segment(
+			ptr::from_ref(tss).addr(),
+			size_of::<TaskStateSegment>() - 1,
+			9,
+			0,
+			true,
+			false,
+			false
+		);
+		this
+	}
+
 	fn make_null_segment_private<T: Default>() -> T
 	{
 		T::default()
@@ -428,6 +447,23 @@ impl GDT
 		}
 		crate::arch::target::cpu::irq::enable();
 	}
+
+	/// Load the TSS described by the GDT into the task register
+	///
+	/// # SAFETY
+	///
+	/// The GDT must be the one in use, and built with [`GDT::with_tss`]
+	pub unsafe fn load_tss(&self)
+	{
+		let selector: u16 = (entry_index!(TSS) as u16) << 3;
+		unsafe {
+			asm! {
+				"ltr {selector:x}",
+				selector = in(reg) selector,
+				options(att_syntax, nomem, nostack, preserves_flags)
+			};
+		}
+	}
 }
 
 macro_rules! entry_ids
@@ -5,6 +5,8 @@ use core::{
 	ptr::{self, NonNull}
 };
 
+use portable_atomic::{AtomicU64, Ordering};
+
 use super::allocators::{AllocationStrategy, RegionAllocator, RegionAllocatorReclaimHook};
 use crate::{arch::target, kernel::sync::BasicRwLock};
 
@@ -558,18 +560,88 @@ unsafe impl Allocator for KernelAllocator
 	}
 }
 
+/// Statistics about the allocations made through [`GlobalAlloc`]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+pub struct AllocatorStats
+{
+	pub allocations:        u64,
+	pub deallocations:      u64,
+	pub failed_allocations: u64,
+	/// Bytes currently allocated
+	pub allocated_bytes:    u64,
+	/// Highest value ever reached by `allocated_bytes`
+	pub peak_bytes:         u64
+}
+
+// Kept as plain atomics so that they can be read from the panic handler
+static ALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+static DEALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+static FAILED_ALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+static ALLOCATED_BYTES: AtomicU64 = AtomicU64::new(0);
+static PEAK_BYTES: AtomicU64 = AtomicU64::new(0);
+
+fn account_allocation(ptr: *mut u8, size: usize) -> *mut u8
+{
+	if ptr.is_null()
+	{
+		FAILED_ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+	}
+	else
+	{
+		ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+		let allocated = ALLOCATED_BYTES.fetch_add(size as u64, Ordering::Relaxed) + size as u64;
+		PEAK_BYTES.fetch_max(allocated, Ordering::Relaxed);
+	}
+	ptr
+}
+
+fn account_deallocation(size: usize)
+{
+	DEALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+	ALLOCATED_BYTES.fetch_sub(size as u64, Ordering::Relaxed);
+}
+
+/// Whether no allocation is in progress, so that the global allocator can be
+/// used right away
+///
+/// Code which may run while the heap is in an inconsistent state (e.g. the
+/// panic handler, if the panic happened inside the allocator) should rather
+/// skip what allocates than deadlock, or corrupt the heap further.
+pub fn is_usable() -> bool
+{
+	!ZEROS_GLOBAL_ALLOCATOR.regions.is_locked()
+}
+
+/// Statistics of the global allocator
+pub fn allocator_stats() -> AllocatorStats
+{
+	AllocatorStats {
+		allocations:        ALLOCATIONS.load(Ordering::Relaxed),
+		deallocations:      DEALLOCATIONS.load(Ordering::Relaxed),
+		failed_allocations: FAILED_ALLOCATIONS.load(Ordering::Relaxed),
+		allocated_bytes:    ALLOCATED_BYTES.load(Ordering::Relaxed),
+		peak_bytes:         PEAK_BYTES.load(Ordering::Relaxed)
+	}
+}
+
 unsafe impl GlobalAlloc for KernelAllocator
 {
 	unsafe fn alloc(&self, layout: Layout) -> *mut u8
 	{
-		self.allocate(layout)
-			.map_or(ptr::null_mut(), |ptr| ptr.as_mut_ptr())
+		account_allocation(
+			self.allocate(layout)
+				.map_or(ptr::null_mut(), |ptr| ptr.as_mut_ptr()),
+			layout.size()
+		)
 	}
 
 	unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8
 	{
-		self.allocate_zeroed(layout)
-			.map_or(ptr::null_mut(), |ptr| ptr.as_mut_ptr())
+		account_allocation(
+			self.allocate_zeroed(layout)
+				.map_or(ptr::null_mut(), |ptr| ptr.as_mut_ptr()),
+			layout.size()
+		)
 	}
 
 	unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout)
@@ -579,12 +651,13 @@ unsafe impl GlobalAlloc for KernelAllocator
 			unsafe {
 				self.deallocate(NonNull::new_unchecked(ptr), layout);
 			}
+			account_deallocation(layout.size());
 		}
 	}
 
 	unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8
 	{
-		if ptr.is_null()
+		let new_ptr = if ptr.is_null()
 		{
 			ptr::null_mut()
 		}
@@ -619,7 +692,23 @@ unsafe impl GlobalAlloc for KernelAllocator
 		else
 		{
 			unsafe { core::hint::unreachable_unchecked() }
+		};
+		if new_ptr.is_null()
+		{
+			FAILED_ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+		}
+		else
+		{
+			let allocated = ALLOCATED_BYTES
+				.fetch_add(
+					(new_size as u64).wrapping_sub(layout.size() as u64),
+					Ordering::Relaxed
+				)
+				.wrapping_add(new_size as u64)
+				.wrapping_sub(layout.size() as u64);
+			PEAK_BYTES.fetch_max(allocated, Ordering::Relaxed);
 		}
+		new_ptr
 	}
 }
 
@@ -1,3 +1,21 @@
-pub mod gdt;
 pub mod allocators;
-pub mod global_allocator;
\ No newline at end of file
+pub mod gdt;
+pub mod global_allocator;
+pub mod stack;
+pub mod tss;
+
+/// Offset of the higher-half direct map of the physical memory, if known
+#[cfg(bootloader = "limine")]
+pub fn hhdm_offset() -> Option<u64>
+{
+	crate::init::bootloaders::limine::HHDM_REQUEST
+		.get_response()
+		.map(|response| response.offset())
+}
+
+/// Offset of the higher-half direct map of the physical memory, if known
+#[cfg(not(bootloader = "limine"))]
+pub fn hhdm_offset() -> Option<u64>
+{
+	None
+}
@@ -0,0 +1,171 @@
+//! Kernel stacks protected by a guard page
+//!
+//! A [`GuardedStack`] is a static stack, right above a page that gets unmapped
+//! when the stack is armed: overflowing it faults instead of silently
+//! corrupting whatever lies below. As the CPU can't push an exception frame on
+//! an overflowed stack, such overflows end up as double faults, which run on
+//! their own stack (see [`tss`](super::tss)), and are recognized with
+//! [`overflowed_stack`].
+
+use core::{arch::asm, cell::SyncUnsafeCell, fmt, ptr};
+
+use portable_atomic::{AtomicPtr, AtomicUsize, Ordering};
+
+use crate::{
+	arch::target::cpu::{
+		MAX_CPU_COUNT,
+		paging::{self, PAGE_SIZE}
+	},
+	warn
+};
+
+/// Size of the stack `kmain` runs on
+pub const KERNEL_STACK_SIZE: usize = 1024 * 1024;
+
+/// Maximum number of armed stacks
+pub const MAX_GUARDED_STACKS: usize = 4 * MAX_CPU_COUNT;
+
+/// How far above the bottom of a stack the stack pointer may still be when
+/// overflowing it (e.g. when a `push` or a `call` faults, the stack pointer
+/// hasn't been decremented yet)
+const OVERFLOW_SLACK: u64 = 64;
+
+/// The stack of `kmain`
+pub static KERNEL_STACK: GuardedStack<KERNEL_STACK_SIZE> = GuardedStack::new();
+
+static REGISTRY: [AtomicPtr<StackInfo>; MAX_GUARDED_STACKS] =
+	[const { AtomicPtr::new(ptr::null_mut()) }; MAX_GUARDED_STACKS];
+static REGISTERED: AtomicUsize = AtomicUsize::new(0);
+
+/// Description of an armed [`GuardedStack`]
+#[derive(Debug, Clone, Copy)]
+pub struct StackInfo
+{
+	/// Name of the task using the stack
+	pub name:   &'static str,
+	/// CPU owning the stack, for per-CPU stacks
+	pub cpu:    Option<usize>,
+	/// Address of the guard page
+	pub guard:  u64,
+	/// Lowest address of the stack
+	pub bottom: u64,
+	/// Initial stack pointer
+	pub top:    u64
+}
+
+impl fmt::Display for StackInfo
+{
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+	{
+		write!(f, "{}", self.name)?;
+		if let Some(cpu) = self.cpu
+		{
+			write!(f, " (cpu #{cpu})")?;
+		}
+		Ok(())
+	}
+}
+
+/// A stack of `SIZE` bytes (a multiple of the page size), with a guard page
+/// below it
+#[repr(C, align(4096))]
+pub struct GuardedStack<const SIZE: usize>
+{
+	guard: SyncUnsafeCell<[u8; PAGE_SIZE]>,
+	stack: SyncUnsafeCell<[u8; SIZE]>,
+	info:  SyncUnsafeCell<StackInfo>
+}
+
+impl<const SIZE: usize> GuardedStack<SIZE>
+{
+	pub const fn new() -> Self
+	{
+		assert!(
+			SIZE % PAGE_SIZE == 0,
+			"stack sizes must be a multiple of the page size"
+		);
+		Self {
+			guard: SyncUnsafeCell::new([0; PAGE_SIZE]),
+			stack: SyncUnsafeCell::new([0; SIZE]),
+			info:  SyncUnsafeCell::new(StackInfo {
+				name:   "",
+				cpu:    None,
+				guard:  0,
+				bottom: 0,
+				top:    0
+			})
+		}
+	}
+
+	/// Unmap the guard page, and register the stack as used by the task
+	/// `name`
+	///
+	/// If the guard page can't be unmapped, the stack is still usable, just
+	/// unprotected.
+	///
+	/// # Safety
+	///
+	/// Must be called once, before the stack is used.
+	pub unsafe fn arm(&'static self, name: &'static str, cpu: Option<usize>) -> &'static StackInfo
+	{
+		let guard = self.guard.get().addr() as u64;
+		let bottom = self.stack.get().addr() as u64;
+		// SAFETY: the stack isn't used yet
+		let info = unsafe { &mut *self.info.get() };
+		*info = StackInfo {
+			name,
+			cpu,
+			guard,
+			bottom,
+			top: bottom + SIZE as u64
+		};
+
+		// SAFETY: nothing uses the guard page
+		if let Err(err) = unsafe { paging::unmap_page(guard) }
+		{
+			warn!("the stack of {info} has no guard page: {err}");
+		}
+
+		let index = REGISTERED.fetch_add(1, Ordering::AcqRel);
+		match REGISTRY.get(index)
+		{
+			Some(slot) => slot.store(ptr::from_mut(info), Ordering::Release),
+			None => warn!("too many guarded stacks, overflows of {info} won't be reported")
+		}
+		info
+	}
+
+	/// Switch to this stack, and run `entry` on it
+	///
+	/// # Safety
+	///
+	/// The stack must have been armed, and must not be in use.
+	pub unsafe fn enter(&'static self, entry: fn() -> !) -> !
+	{
+		// SAFETY: the stack has been armed
+		let top = unsafe { (*self.info.get()).top };
+		unsafe {
+			asm! {
+				"movq {top}, %rsp",
+				"xorl %ebp, %ebp",
+				// null return address, to terminate backtraces
+				"pushq $0",
+				"jmpq *{entry}",
+				top = in(reg) top,
+				entry = in(reg) entry,
+				options(att_syntax, noreturn)
+			}
+		}
+	}
+}
+
+/// The armed stack overflowed by a stack pointer of `sp`, if any
+pub fn overflowed_stack(sp: u64) -> Option<&'static StackInfo>
+{
+	REGISTRY
+		.iter()
+		.take(REGISTERED.load(Ordering::Acquire))
+		// SAFETY: registered infos are never modified again
+		.filter_map(|slot| unsafe { slot.load(Ordering::Acquire).as_ref() })
+		.find(|info| info.guard <= sp && sp < info.bottom + OVERFLOW_SLACK)
+}
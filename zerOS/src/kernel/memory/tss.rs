@@ -0,0 +1,94 @@
+use core::{cell::SyncUnsafeCell, mem::size_of};
+
+use super::stack::GuardedStack;
+use crate::arch::target::cpu::MAX_CPU_COUNT;
+
+/// The 64-bit task state segment
+///
+/// In long mode, it only holds the stacks the CPU switches to on privilege
+/// level changes and on interrupts using the interrupt stack table (IST).
+#[repr(C, packed(4))]
+#[derive(Debug, Clone, Copy)]
+pub struct TaskStateSegment
+{
+	_reserved0:           u32,
+	/// Stacks loaded when switching to ring 0, 1 or 2
+	pub privilege_stacks: [u64; 3],
+	_reserved1:           u64,
+	/// Stacks of the IST, the first one being IST index 1
+	pub interrupt_stacks: [u64; 7],
+	_reserved2:           u64,
+	_reserved3:           u16,
+	pub iomap_base:       u16
+}
+static_assert!(size_of::<TaskStateSegment>() == 104);
+
+impl TaskStateSegment
+{
+	pub const fn new() -> Self
+	{
+		Self {
+			_reserved0:       0,
+			privilege_stacks: [0; 3],
+			_reserved1:       0,
+			interrupt_stacks: [0; 7],
+			_reserved2:       0,
+			_reserved3:       0,
+			// no I/O permission bitmap
+			iomap_base:       size_of::<Self>() as u16
+		}
+	}
+}
+
+/// IST index of the double fault (`#DF`) handler
+pub const DOUBLE_FAULT_IST: u8 = 1;
+/// IST index of the non-maskable interrupt handler
+pub const NMI_IST: u8 = 2;
+/// IST index of the machine check (`#MC`) handler
+pub const MACHINE_CHECK_IST: u8 = 3;
+
+/// Size of the `#DF` stacks, which must be large enough to report a panic
+pub const DOUBLE_FAULT_STACK_SIZE: usize = 64 * 1024;
+/// Size of the other IST stacks
+pub const IST_STACK_SIZE: usize = 16 * 1024;
+
+static TSS: [SyncUnsafeCell<TaskStateSegment>; MAX_CPU_COUNT] =
+	[const { SyncUnsafeCell::new(TaskStateSegment::new()) }; MAX_CPU_COUNT];
+
+static DOUBLE_FAULT_STACKS: [GuardedStack<DOUBLE_FAULT_STACK_SIZE>; MAX_CPU_COUNT] =
+	[const { GuardedStack::new() }; MAX_CPU_COUNT];
+static NMI_STACKS: [GuardedStack<IST_STACK_SIZE>; MAX_CPU_COUNT] =
+	[const { GuardedStack::new() }; MAX_CPU_COUNT];
+static MACHINE_CHECK_STACKS: [GuardedStack<IST_STACK_SIZE>; MAX_CPU_COUNT] =
+	[const { GuardedStack::new() }; MAX_CPU_COUNT];
+
+/// Set up the TSS of `cpu`, with guarded IST stacks
+///
+/// # Safety
+///
+/// Must only be called once per CPU, by the CPU itself.
+pub unsafe fn init(cpu: usize) -> &'static TaskStateSegment
+{
+	// SAFETY: each CPU only ever touches its own TSS and stacks, and this is
+	//         only done once
+	unsafe {
+		let tss = &mut *TSS[cpu].get();
+		tss.interrupt_stacks[usize::from(DOUBLE_FAULT_IST) - 1] =
+			DOUBLE_FAULT_STACKS[cpu].arm("#DF", Some(cpu)).top;
+		tss.interrupt_stacks[usize::from(NMI_IST) - 1] = NMI_STACKS[cpu].arm("NMI", Some(cpu)).top;
+		tss.interrupt_stacks[usize::from(MACHINE_CHECK_IST) - 1] =
+			MACHINE_CHECK_STACKS[cpu].arm("#MC", Some(cpu)).top;
+		tss
+	}
+}
+
+/// Where the CPU loads its stack pointer from when an interrupt or an
+/// exception switches `cpu` from user mode to ring 0
+///
+/// The kernel stack of the thread running in user mode must be stored there
+/// (see [`user::enter`](crate::arch::target::cpu::user::enter)).
+pub fn kernel_stack_slot(cpu: usize) -> *mut u64
+{
+	// SAFETY: no reference is formed, the slot being unaligned
+	unsafe { &raw mut (*TSS[cpu].get()).privilege_stacks[0] }
+}
@@ -1,8 +1,14 @@
+pub mod acpi;
+pub mod dmi;
 pub mod error;
+pub mod gdb;
 pub mod hypervisor;
 pub mod io;
+pub mod irq;
 pub mod linker;
 pub mod logging;
 pub mod memory;
+pub mod power;
 pub mod serial;
 pub mod sync;
+pub mod time;
@@ -0,0 +1,110 @@
+//! Ending the execution of the kernel
+//!
+//! Under QEMU, [`exit`] makes the emulator exit through its `isa-debug-exit`
+//! device, with a status `xtask run` turns back into its own. Elsewhere, the
+//! machine is halted or rebooted, as selected with the `on-exit` command-line
+//! option.
+
+use core::{fmt, str::FromStr};
+
+use crate::{arch::target::cpu::misc, info, init::cmdline::ZEROS_COMMAND_LINE, kernel::hypervisor};
+
+/// How the kernel ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitStatus
+{
+	Success,
+	/// Failure with the given code: `0` is reported as `1`, and the codes above
+	/// [`ExitStatus::MAX_FAILURE_CODE`] as that one
+	Failure(u8)
+}
+
+impl ExitStatus
+{
+	/// Largest code a failure is reported with
+	pub const MAX_FAILURE_CODE: u8 = Self::SUCCESS_CODE - 1;
+	/// Code written to the `isa-debug-exit` device on success
+	///
+	/// QEMU exits with status `(code << 1) | 1`, which is `1` when writing `0`,
+	/// like when QEMU itself fails: success is thus reported with the last
+	/// code whose status fits in 8 bits (255), which `xtask run` maps back to
+	/// `0`.
+	pub const SUCCESS_CODE: u8 = 0x7f;
+
+	/// Code written to the `isa-debug-exit` device, QEMU exiting with status
+	/// `(code << 1) | 1`
+	pub const fn code(self) -> u8
+	{
+		match self
+		{
+			Self::Success => Self::SUCCESS_CODE,
+			Self::Failure(0) => 1,
+			Self::Failure(code) if code > Self::MAX_FAILURE_CODE => Self::MAX_FAILURE_CODE,
+			Self::Failure(code) => code
+		}
+	}
+}
+
+impl fmt::Display for ExitStatus
+{
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+	{
+		match self
+		{
+			Self::Success => write!(f, "success"),
+			Self::Failure(_) => write!(f, "failure (code {})", self.code())
+		}
+	}
+}
+
+/// What [`exit`] does when not running under QEMU
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitFallback
+{
+	Halt,
+	Reboot
+}
+
+impl FromStr for ExitFallback
+{
+	type Err = ();
+
+	fn from_str(s: &str) -> Result<Self, Self::Err>
+	{
+		if s.eq_ignore_ascii_case("halt") || s.eq_ignore_ascii_case("hcf")
+		{
+			Ok(Self::Halt)
+		}
+		else if s.eq_ignore_ascii_case("reboot")
+		{
+			Ok(Self::Reboot)
+		}
+		else
+		{
+			Err(())
+		}
+	}
+}
+
+/// Make QEMU exit with `status`, through its `isa-debug-exit` device
+///
+/// Returns if the kernel doesn't run under QEMU, or if QEMU has no such device.
+pub fn qemu_exit(status: ExitStatus)
+{
+	if hypervisor::under_qemu()
+	{
+		misc::qemu_exit(u32::from(status.code()));
+	}
+}
+
+/// End the execution of the kernel with `status`
+pub fn exit(status: ExitStatus) -> !
+{
+	info!("exiting with status: {status}");
+	qemu_exit(status);
+	match ZEROS_COMMAND_LINE.read().exit_fallback
+	{
+		ExitFallback::Halt => misc::hcf(),
+		ExitFallback::Reboot => misc::reboot()
+	}
+}
@@ -0,0 +1,361 @@
+//! Clock sources and clock events
+//!
+//! A [`ClockSource`] is a free-running counter the kernel reads the time
+//! from, and a [`ClockEvent`] a device raising an interrupt after a given
+//! delay. The architecture registers every device it finds, each one being
+//! rated by its precision and reliability: the best rated ones are used,
+//! unless another clock source is selected on the command line (e.g.
+//! `clocksource=hpet`).
+//!
+//! [`now_ns`] accumulates the elapsed time across wraparounds of the selected
+//! clock source, which must thus be read at least once per wraparound period.
+//! Unless it never wraps around in practice (e.g. a 64-bit counter), a tick
+//! reading it periodically is run on the clock event device, and it can't be
+//! used without one.
+
+use core::fmt;
+
+use portable_atomic::{AtomicU64, AtomicUsize, Ordering};
+
+use crate::{
+	arch::target::cpu::{self, MAX_CPU_COUNT, irq::without_interrupts},
+	info,
+	kernel::sync::{BasicMutex, BasicRwLock},
+	warn
+};
+
+/// Maximum number of clock sources or clock events which can be registered
+pub const MAX_CLOCK_DEVICES: usize = 8;
+
+pub const NS_PER_SEC: u64 = 1_000_000_000;
+
+/// Clock sources wrapping around after at least this long are considered
+/// never to wrap around
+const NEVER_WRAPS_NS: u64 = 10 * 365 * 24 * 3600 * NS_PER_SEC;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimeError
+{
+	/// Too many devices have been registered
+	TooManyDevices,
+	/// No clock event device has been registered
+	NoClockEvent,
+	/// The device couldn't be programmed
+	Device(&'static str)
+}
+
+impl fmt::Display for TimeError
+{
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+	{
+		match self
+		{
+			Self::TooManyDevices => write!(f, "too many clock devices are registered"),
+			Self::NoClockEvent => write!(f, "no clock event device is registered"),
+			Self::Device(err) => write!(f, "{err}")
+		}
+	}
+}
+
+/// A free-running counter
+pub trait ClockSource: Sync
+{
+	/// Name of the clock source, as selected on the command line
+	fn name(&self) -> &'static str;
+
+	/// How good the clock source is (the higher the better)
+	fn rating(&self) -> u32;
+
+	/// Frequency of the counter, in Hz
+	fn frequency(&self) -> u64;
+
+	/// Mask of the valid bits of the counter, which wraps around past it
+	fn mask(&self) -> u64;
+
+	fn read(&self) -> u64;
+
+	/// Nanoseconds after which the counter wraps around
+	fn wrap_period_ns(&self) -> u64
+	{
+		let period = (u128::from(self.mask()) + 1) * u128::from(NS_PER_SEC)
+			/ u128::from(self.frequency().max(1));
+		u64::try_from(period).unwrap_or(u64::MAX)
+	}
+}
+
+/// A device raising an interrupt after a given delay
+///
+/// When it fires, its interrupt handler must call [`handle_clock_event`].
+pub trait ClockEvent: Sync
+{
+	fn name(&self) -> &'static str;
+
+	/// How good the device is (the higher the better)
+	fn rating(&self) -> u32;
+
+	/// Fire once on the calling CPU, in `ns` nanoseconds
+	fn program(&self, ns: u64) -> Result<(), TimeError>;
+
+	/// Cancel the programmed event of the calling CPU, if any
+	fn cancel(&self);
+}
+
+struct Clock
+{
+	source:    Option<&'static dyn ClockSource>,
+	/// Counter value when the clock was last updated
+	last:      u64,
+	/// Nanoseconds elapsed since boot when the clock was last updated
+	ns:        u64,
+	/// Fractions of nanoseconds not accounted for in `ns`, in units of
+	/// `1 / frequency` nanosecond
+	remainder: u128
+}
+
+impl Clock
+{
+	fn update(&mut self) -> u64
+	{
+		let Some(source) = self.source
+		else
+		{
+			return self.ns;
+		};
+		let counter = source.read();
+		let elapsed = counter.wrapping_sub(self.last) & source.mask();
+		// a counter read slightly behind the last one (e.g. on another CPU)
+		// doesn't mean it wrapped around
+		if elapsed > source.mask() / 2
+		{
+			return self.ns;
+		}
+		let scaled = u128::from(elapsed) * u128::from(NS_PER_SEC) + self.remainder;
+		let frequency = u128::from(source.frequency().max(1));
+		self.ns += (scaled / frequency) as u64;
+		self.remainder = scaled % frequency;
+		self.last = counter;
+		self.ns
+	}
+}
+
+static CLOCK_SOURCES: BasicRwLock<heapless::Vec<&'static dyn ClockSource, MAX_CLOCK_DEVICES>> =
+	BasicRwLock::new(heapless::Vec::new());
+static CLOCK_EVENTS: BasicRwLock<heapless::Vec<&'static dyn ClockEvent, MAX_CLOCK_DEVICES>> =
+	BasicRwLock::new(heapless::Vec::new());
+
+static CLOCK: BasicMutex<Clock> = BasicMutex::new(Clock {
+	source:    None,
+	last:      0,
+	ns:        0,
+	remainder: 0
+});
+static CLOCK_EVENT: BasicRwLock<Option<&'static dyn ClockEvent>> = BasicRwLock::new(None);
+
+/// Deadline and callback of the pending clock event of each CPU
+static CALLBACKS: [BasicMutex<Option<(u64, fn(u64))>>; MAX_CPU_COUNT] =
+	[const { BasicMutex::new(None) }; MAX_CPU_COUNT];
+
+/// Period of the tick reading the clock source (0 if it doesn't need one)
+static TICK_PERIOD: AtomicU64 = AtomicU64::new(0);
+/// CPU running the tick
+static TICK_CPU: AtomicUsize = AtomicUsize::new(0);
+
+pub fn register_clock_source(source: &'static dyn ClockSource) -> Result<(), TimeError>
+{
+	info!(
+		"clock source {}: {} kHz, rating {}",
+		source.name(),
+		source.frequency() / 1000,
+		source.rating()
+	);
+	without_interrupts(|| CLOCK_SOURCES.write().push(source)).map_err(|_| TimeError::TooManyDevices)
+}
+
+/// Register a clock event device, using it if it is the best rated one so
+/// far
+pub fn register_clock_event(event: &'static dyn ClockEvent) -> Result<(), TimeError>
+{
+	info!("clock event {}: rating {}", event.name(), event.rating());
+	without_interrupts(|| {
+		CLOCK_EVENTS
+			.write()
+			.push(event)
+			.map_err(|_| TimeError::TooManyDevices)?;
+		let mut current = CLOCK_EVENT.write();
+		if current.is_none_or(|current| current.rating() < event.rating())
+		{
+			*current = Some(event);
+		}
+		Ok(())
+	})
+}
+
+fn needs_tick(source: &dyn ClockSource) -> bool
+{
+	source.wrap_period_ns() < NEVER_WRAPS_NS
+}
+
+/// Use the clock source named `preferred` if it is registered, or the best
+/// rated one otherwise
+///
+/// The clock sources which need a tick are only used if a clock event device
+/// has been registered.
+pub fn select_clock_source(preferred: Option<&str>)
+{
+	let event = *CLOCK_EVENT.read();
+	let usable = |source: &&&'static dyn ClockSource| event.is_some() || !needs_tick(**source);
+	let sources = CLOCK_SOURCES.read();
+	let chosen = preferred
+		.and_then(|name| {
+			let found = sources
+				.iter()
+				.find(|source| source.name().eq_ignore_ascii_case(name));
+			match found
+			{
+				None => warn!("clock source {name} isn't available"),
+				Some(source) if !usable(&source) =>
+				{
+					warn!(
+						"clock source {name} wraps around every {} ms, and there is no clock \
+						 event device to read it periodically",
+						source.wrap_period_ns() / 1_000_000
+					);
+				},
+				Some(_) => ()
+			}
+			found.filter(usable)
+		})
+		.or_else(|| {
+			sources
+				.iter()
+				.filter(usable)
+				.max_by_key(|source| source.rating())
+		})
+		.copied();
+	drop(sources);
+
+	let Some(chosen) = chosen
+	else
+	{
+		warn!("no clock source is available");
+		return;
+	};
+	without_interrupts(|| {
+		let mut clock = CLOCK.lock();
+		// keep the time monotonic across the switch
+		clock.update();
+		clock.source = Some(chosen);
+		clock.last = chosen.read();
+		clock.remainder = 0;
+	});
+	info!("using clock source {}", chosen.name());
+
+	// read often enough for a wraparound not to be mistaken for a counter
+	// going backwards
+	let period = if needs_tick(chosen)
+	{
+		(chosen.wrap_period_ns() / 4).max(1)
+	}
+	else
+	{
+		0
+	};
+	TICK_PERIOD.store(period, Ordering::Release);
+	TICK_CPU.store(cpu::current_cpu_index(), Ordering::Release);
+	if let Some(event) = event
+		&& let Err(err) = without_interrupts(|| program_next(event, now_ns()))
+	{
+		warn!("couldn't start the clock source tick: {err}");
+	}
+}
+
+/// The clock source in use
+pub fn clock_source() -> Option<&'static dyn ClockSource>
+{
+	without_interrupts(|| CLOCK.lock().source)
+}
+
+/// Nanoseconds elapsed since the clock source has been selected
+pub fn now_ns() -> u64
+{
+	without_interrupts(|| CLOCK.lock().update())
+}
+
+/// Program `event` for the earliest of the deadline of the calling CPU and of
+/// its next tick, if any
+///
+/// Must be called with interrupts disabled.
+fn program_next(event: &dyn ClockEvent, now: u64) -> Result<(), TimeError>
+{
+	let cpu = cpu::current_cpu_index();
+	let deadline = CALLBACKS[cpu].lock().map(|(deadline, _)| deadline);
+	let tick = Some(TICK_PERIOD.load(Ordering::Acquire))
+		.filter(|&period| period != 0 && TICK_CPU.load(Ordering::Acquire) == cpu)
+		.map(|period| now.saturating_add(period));
+	match deadline.into_iter().chain(tick).min()
+	{
+		Some(next) => event.program(next.saturating_sub(now)),
+		None =>
+		{
+			event.cancel();
+			Ok(())
+		}
+	}
+}
+
+/// Call `callback` with the current time on the calling CPU, once `deadline`
+/// (in nanoseconds, as returned by [`now_ns`]) has been reached
+///
+/// This replaces the pending deadline of the CPU, if any.
+pub fn set_deadline(deadline: u64, callback: fn(u64)) -> Result<(), TimeError>
+{
+	let event = (*CLOCK_EVENT.read()).ok_or(TimeError::NoClockEvent)?;
+	without_interrupts(|| {
+		*CALLBACKS[cpu::current_cpu_index()].lock() = Some((deadline, callback));
+		program_next(event, now_ns())
+	})
+}
+
+/// Cancel the pending deadline of the calling CPU
+pub fn cancel_deadline()
+{
+	without_interrupts(|| {
+		*CALLBACKS[cpu::current_cpu_index()].lock() = None;
+		if let Some(event) = *CLOCK_EVENT.read()
+			&& let Err(err) = program_next(event, now_ns())
+		{
+			warn!("couldn't reprogram clock event {}: {err}", event.name());
+		}
+	});
+}
+
+/// Run the callback of the deadline which has been reached on the calling
+/// CPU, and program the next one
+///
+/// Called by the interrupt handlers of the clock event devices.
+pub fn handle_clock_event()
+{
+	// also the tick, if this CPU runs it
+	let now = now_ns();
+	let callback = {
+		let mut pending = CALLBACKS[cpu::current_cpu_index()].lock();
+		match *pending
+		{
+			Some((deadline, callback)) if deadline <= now =>
+			{
+				*pending = None;
+				Some(callback)
+			},
+			_ => None
+		}
+	};
+	if let Some(event) = *CLOCK_EVENT.read()
+		&& let Err(err) = program_next(event, now)
+	{
+		warn!("couldn't reprogram clock event {}: {err}", event.name());
+	}
+	if let Some(callback) = callback
+	{
+		callback(now);
+	}
+}
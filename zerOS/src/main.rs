@@ -38,6 +38,8 @@
 #![feature(set_ptr_value)]
 #![feature(stmt_expr_attributes)]
 #![feature(ptr_metadata)]
+#![feature(core_intrinsics)]
+#![cfg_attr(feature = "unwind", feature(lang_items))]
 
 #![allow(internal_features)]
 #![feature(link_llvm_intrinsics)]
@@ -47,6 +49,9 @@
 
 #![recursion_limit = "512"]
 
+#[cfg(all(panic = "unwind", not(feature = "unwind")))]
+compile_error!("the `unwind` feature is required to build zerOS with `panic = \"unwind\"`");
+
 extern crate alloc;
 
 #[macro_use]
@@ -66,7 +71,7 @@ pub mod panic;
 pub mod unwinding;
 pub mod utils;
 
-use crate::arch::target::cpu::misc::hcf;
+use crate::kernel::power::{self, ExitStatus};
 
 #[allow(dead_code)]
 static UNIFONT: &[u8] = include_bytes!("../assets/font/unifont-16.0.04.otf");
@@ -102,5 +107,16 @@ fn kmain() -> !
 		}
 	}
 
-	hcf()
+	// in case the serial port interrupt couldn't be routed, GDB interrupt
+	// requests have to be polled for
+	if kernel::gdb::enabled()
+	{
+		loop
+		{
+			kernel::gdb::poll();
+			core::hint::spin_loop();
+		}
+	}
+
+	power::exit(ExitStatus::Success)
 }
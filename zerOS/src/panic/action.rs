@@ -0,0 +1,178 @@
+use core::fmt;
+
+use portable_atomic::{AtomicU64, Ordering};
+
+use super::dump::{self, DumpTarget};
+use crate::{
+	arch::target::cpu::{self, misc},
+	kernel::{
+		gdb,
+		power::{self, ExitStatus}
+	},
+	unwinding
+};
+
+/// Maximum number of actions that can be chained after a panic
+pub const MAX_PANIC_ACTIONS: usize = 4;
+
+/// What to do once a panic has been reported
+///
+/// Actions are tried in order: the ones that may return (e.g.
+/// [`PanicAction::QemuExit`] when not running under QEMU) fall through to the
+/// next one. If all of them return, the CPU is halted.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PanicAction
+{
+	/// Halt and catch fire
+	Halt,
+	/// Reboot the machine
+	Reboot,
+	/// Exit QEMU with the given failure code (see [`ExitStatus::Failure`]),
+	/// through the `isa-debug-exit` device
+	QemuExit(u8),
+	/// Write a crash dump (see [`dump`](super::dump)) to the given target
+	CrashDump(DumpTarget),
+	/// Hand the panicking context over to the GDB stub (see [`gdb`]), until
+	/// GDB detaches
+	Gdb
+}
+
+impl PanicAction
+{
+	/// Exit code used by `qemu-exit` when none is specified
+	pub const DEFAULT_QEMU_EXIT_CODE: u8 = 1;
+
+	/// Parse a single action, i.e. `halt`, `reboot`, `qemu-exit[:<code>]`,
+	/// `crash-dump[:<target>]` or `gdb`
+	pub fn parse(action: &str) -> Option<Self>
+	{
+		let (name, arg) = match action.split_once(':')
+		{
+			Some((name, arg)) => (name.trim(), Some(arg.trim())),
+			None => (action.trim(), None)
+		};
+
+		let is = |names: &[&str]| names.iter().any(|n| name.eq_ignore_ascii_case(n));
+		match arg
+		{
+			None if is(&["halt", "hcf"]) => Some(Self::Halt),
+			None if is(&["reboot"]) => Some(Self::Reboot),
+			None if is(&["qemu-exit", "qemu_exit"]) =>
+			{
+				Some(Self::QemuExit(Self::DEFAULT_QEMU_EXIT_CODE))
+			},
+			Some(code) if is(&["qemu-exit", "qemu_exit"]) =>
+			{
+				code.parse()
+					.ok()
+					.filter(|&code| code <= ExitStatus::MAX_FAILURE_CODE)
+					.map(Self::QemuExit)
+			},
+			None if is(&["crash-dump", "crash_dump", "dump"]) =>
+			{
+				Some(Self::CrashDump(DumpTarget::DEFAULT))
+			},
+			Some(target) if is(&["crash-dump", "crash_dump", "dump"]) =>
+			{
+				DumpTarget::parse(target).map(Self::CrashDump)
+			},
+			None if is(&["gdb"]) => Some(Self::Gdb),
+			_ => None
+		}
+	}
+
+	const fn encode(self) -> u64
+	{
+		match self
+		{
+			Self::Halt => 1,
+			Self::Reboot => 2,
+			Self::QemuExit(code) => 3 | ((code as u64) << 32),
+			Self::CrashDump(target) => 4 | ((target.encode() as u64) << 32),
+			Self::Gdb => 5
+		}
+	}
+
+	const fn decode(raw: u64) -> Option<Self>
+	{
+		match raw & 0xff
+		{
+			1 => Some(Self::Halt),
+			2 => Some(Self::Reboot),
+			3 => Some(Self::QemuExit((raw >> 32) as u8)),
+			4 => Some(Self::CrashDump(DumpTarget::decode((raw >> 32) as u32))),
+			5 => Some(Self::Gdb),
+			_ => None
+		}
+	}
+
+	fn run(self)
+	{
+		match self
+		{
+			Self::Halt => misc::hcf(),
+			Self::Reboot => misc::reboot(),
+			// a panic is never reported as a success, whatever the code
+			Self::QemuExit(code) => power::qemu_exit(ExitStatus::Failure(code)),
+			Self::CrashDump(target) =>
+			{
+				// SAFETY: actions are only run by the CPU owning the panic
+				unsafe { dump::write(target) }
+			},
+			Self::Gdb =>
+			{
+				// the panic handler saves the registers before running the
+				// actions, unless it panicked again before that
+				let current = unwinding::read_registers!();
+				gdb::enter_on_panic(
+					dump::saved_cpu_state(cpu::current_cpu_index()).unwrap_or(&current)
+				);
+			}
+		}
+	}
+}
+
+impl fmt::Display for PanicAction
+{
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+	{
+		match self
+		{
+			Self::Halt => write!(f, "halt"),
+			Self::Reboot => write!(f, "reboot"),
+			Self::QemuExit(code) => write!(f, "qemu-exit:{code}"),
+			Self::CrashDump(target) => write!(f, "crash-dump:{target}"),
+			Self::Gdb => write!(f, "gdb")
+		}
+	}
+}
+
+// Kept as plain atomics so that the panic handler never has to take a lock
+static PANIC_ACTIONS: [AtomicU64; MAX_PANIC_ACTIONS] = [
+	AtomicU64::new(PanicAction::Halt.encode()),
+	AtomicU64::new(0),
+	AtomicU64::new(0),
+	AtomicU64::new(0)
+];
+
+/// Set the actions to perform after a panic (extra actions are ignored)
+pub fn set_actions(actions: &[PanicAction])
+{
+	for (i, slot) in PANIC_ACTIONS.iter().enumerate()
+	{
+		slot.store(
+			actions.get(i).copied().map_or(0, PanicAction::encode),
+			Ordering::Release
+		);
+	}
+}
+
+/// Perform the configured panic actions
+pub(super) fn run_actions() -> !
+{
+	PANIC_ACTIONS
+		.iter()
+		.filter_map(|slot| PanicAction::decode(slot.load(Ordering::Acquire)))
+		.for_each(PanicAction::run);
+	misc::hcf()
+}
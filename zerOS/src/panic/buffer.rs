@@ -0,0 +1,98 @@
+use core::{cell::SyncUnsafeCell, fmt};
+
+use crate::arch::target::cpu::MAX_CPU_COUNT;
+
+/// Size of the buffer each CPU formats its panic message into
+pub const PANIC_BUFFER_SIZE: usize = 4096;
+
+static PANIC_BUFFERS: [SyncUnsafeCell<[u8; PANIC_BUFFER_SIZE]>; MAX_CPU_COUNT] =
+	[const { SyncUnsafeCell::new([0; PANIC_BUFFER_SIZE]) }; MAX_CPU_COUNT];
+
+/// A [`fmt::Write`] implementation writing into a fixed buffer, which indents
+/// every continuation line as it goes
+///
+/// Output that does not fit is silently dropped (see
+/// [`IndentingWriter::truncated`]), and only whole characters are ever written,
+/// so that the buffer content is always valid UTF-8.
+pub struct IndentingWriter<'buf>
+{
+	buf:       &'buf mut [u8],
+	len:       usize,
+	indent:    &'static str,
+	truncated: bool
+}
+
+impl<'buf> IndentingWriter<'buf>
+{
+	pub const fn new(buf: &'buf mut [u8], indent: &'static str) -> Self
+	{
+		Self {
+			buf,
+			len: 0,
+			indent,
+			truncated: false
+		}
+	}
+
+	/// Get a writer into the panic buffer of CPU number `cpu`
+	///
+	/// # Safety
+	///
+	/// Only one writer per CPU may be alive at a time, and the panic buffer of
+	/// another CPU must never be used.
+	pub unsafe fn for_cpu(cpu: usize, indent: &'static str) -> IndentingWriter<'static>
+	{
+		let buf = unsafe { &mut *PANIC_BUFFERS[cpu % MAX_CPU_COUNT].get() };
+		IndentingWriter::new(buf, indent)
+	}
+
+	pub fn as_str(&self) -> &str
+	{
+		// SAFETY: `push_str` never splits a character
+		unsafe { str::from_utf8_unchecked(&self.buf[..self.len]) }
+	}
+
+	pub const fn truncated(&self) -> bool
+	{
+		self.truncated
+	}
+
+	fn push_str(&mut self, s: &str)
+	{
+		if self.truncated
+		{
+			return;
+		}
+
+		let available = self.buf.len() - self.len;
+		let mut end = s.len();
+		if end > available
+		{
+			end = available;
+			while !s.is_char_boundary(end)
+			{
+				end -= 1;
+			}
+			self.truncated = true;
+		}
+		self.buf[self.len..self.len + end].copy_from_slice(&s.as_bytes()[..end]);
+		self.len += end;
+	}
+}
+
+impl<'buf> fmt::Write for IndentingWriter<'buf>
+{
+	fn write_str(&mut self, s: &str) -> fmt::Result
+	{
+		for (i, line) in s.split('\n').enumerate()
+		{
+			if i != 0
+			{
+				self.push_str("\n");
+				self.push_str(self.indent);
+			}
+			self.push_str(line);
+		}
+		Ok(())
+	}
+}
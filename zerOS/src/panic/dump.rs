@@ -0,0 +1,340 @@
+//! Crash dumps, written by the [`PanicAction::CrashDump`] panic action
+//!
+//! A dump is a stream of [`DumpRecord`]s, each of them encoded with `postcard`
+//! and framed with COBS (i.e. terminated by a zero byte), so that a truncated
+//! or partially garbled dump can still be decoded up to the damaged records.
+//! The stream starts with a zero byte and a [`DumpRecord::Header`], and ends
+//! with a [`DumpRecord::End`].
+//!
+//! Dumps are decoded (and their backtraces symbolized) by `xtask crash-dump`:
+//! the records must be kept in sync with the ones defined there, and
+//! [`CRASH_DUMP_VERSION`] bumped whenever they change.
+//!
+//! [`PanicAction::CrashDump`]: super::PanicAction::CrashDump
+
+use core::{cell::SyncUnsafeCell, fmt};
+
+use serde::Serialize;
+
+use super::last_panic;
+use crate::{
+	arch::target::{
+		cpu::{self, MAX_CPU_COUNT, io::outb},
+		io::serial::{SerialPort, SerialPortId}
+	},
+	error,
+	info,
+	kernel::{
+		logging::ZEROS_LOG_RING,
+		memory::global_allocator::{AllocatorStats, allocator_stats},
+		serial::SerialOutput
+	},
+	unwinding::{self, MAX_BACKTRACE_DEPTH, RegisterSet}
+};
+
+/// Version of the crash dump format
+pub const CRASH_DUMP_VERSION: u16 = 2;
+
+/// Architecture name, as understood by `xtask`
+#[cfg(target_arch = "x86_64")]
+const ARCH: &str = "amd64";
+#[cfg(target_arch = "x86")]
+const ARCH: &str = "x86";
+
+/// Maximum size of the log chunks, each of them being a separate record
+const LOG_CHUNK_SIZE: usize = 1024;
+/// Size of the buffer each record is encoded into
+const SCRATCH_SIZE: usize = 2 * LOG_CHUNK_SIZE;
+
+/// Where to write a crash dump
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DumpTarget
+{
+	/// The serial port `COM<n>`
+	Serial(u8),
+	/// A raw I/O port, written to byte by byte (e.g. a QEMU `isa-debugcon`
+	/// device backed by a file)
+	Port(u16)
+}
+
+impl DumpTarget
+{
+	/// Target used when none is specified, i.e. `COM2` (`COM1` being used for
+	/// logging)
+	pub const DEFAULT: Self = Self::Serial(2);
+
+	/// Parse a target, i.e. `com<n>` or an I/O port number
+	pub fn parse(target: &str) -> Option<Self>
+	{
+		if let Some(number) = target
+			.get(..3)
+			.filter(|prefix| prefix.eq_ignore_ascii_case("com"))
+			.and_then(|_| target[3..].parse().ok())
+		{
+			return SerialPortId::from_com_number(number).map(|_| Self::Serial(number));
+		}
+		match target
+			.strip_prefix("0x")
+			.or_else(|| target.strip_prefix("0X"))
+		{
+			Some(hex) => u16::from_str_radix(hex, 16).ok(),
+			None => target.parse().ok()
+		}
+		.map(Self::Port)
+	}
+
+	pub(super) const fn encode(self) -> u32
+	{
+		match self
+		{
+			Self::Serial(number) => number as u32,
+			Self::Port(port) => (1 << 16) | port as u32
+		}
+	}
+
+	pub(super) const fn decode(raw: u32) -> Self
+	{
+		if raw >> 16 == 0
+		{
+			Self::Serial(raw as u8)
+		}
+		else
+		{
+			Self::Port(raw as u16)
+		}
+	}
+}
+
+impl fmt::Display for DumpTarget
+{
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+	{
+		match self
+		{
+			Self::Serial(number) => write!(f, "com{number}"),
+			Self::Port(port) => write!(f, "{port:#x}")
+		}
+	}
+}
+
+#[derive(Serialize)]
+enum DumpRecord<'a>
+{
+	Header
+	{
+		version:        u16,
+		kernel_version: &'a str,
+		arch:           &'a str,
+		panicking_cpu:  u32
+	},
+	Panic
+	{
+		cpu:       u32,
+		file:      &'a str,
+		line:      u32,
+		column:    u32,
+		message:   &'a str,
+		truncated: bool
+	},
+	/// The registers of a CPU, indexed by their DWARF register number
+	Registers
+	{
+		cpu:       u32,
+		pc:        Option<u64>,
+		registers: &'a [Option<u64>]
+	},
+	Backtrace
+	{
+		cpu:    u32,
+		frames: &'a [u64]
+	},
+	/// A CPU which didn't save its registers, e.g. because it was still
+	/// running when the dump was written
+	MissingRegisters
+	{
+		cpu: u32
+	},
+	/// An entry of the memory map given by the bootloader
+	MemoryRegion
+	{
+		base:   u64,
+		length: u64,
+		kind:   u64
+	},
+	Allocator(AllocatorStats),
+	/// A chunk of the log ring buffer (which may cut UTF-8 sequences)
+	Log(&'a [u8]),
+	End
+}
+
+enum DumpSink
+{
+	Serial(SerialPort),
+	Port(u16)
+}
+
+impl DumpSink
+{
+	fn open(target: DumpTarget) -> Option<Self>
+	{
+		match target
+		{
+			DumpTarget::Serial(number) =>
+			{
+				SerialPortId::from_com_number(number)
+					.and_then(SerialPort::new)
+					.map(Self::Serial)
+			},
+			DumpTarget::Port(port) => Some(Self::Port(port))
+		}
+	}
+
+	fn write(&self, bytes: &[u8])
+	{
+		match self
+		{
+			Self::Serial(port) => port.serial_write_bytes(bytes),
+			Self::Port(port) => bytes.iter().for_each(|&byte| outb(*port, byte))
+		}
+	}
+}
+
+/// Registers of each CPU, saved with [`save_cpu_state`]
+static CPU_STATES: [SyncUnsafeCell<Option<RegisterSet>>; MAX_CPU_COUNT] =
+	[const { SyncUnsafeCell::new(None) }; MAX_CPU_COUNT];
+
+static SCRATCH: SyncUnsafeCell<[u8; SCRATCH_SIZE]> = SyncUnsafeCell::new([0; SCRATCH_SIZE]);
+
+/// Save the registers of the calling CPU, so that they (and the matching
+/// backtrace) are part of the crash dump
+///
+/// The CPU owning the panic saves its own registers once it is sure nothing
+/// catches the panic, and the CPUs panicking concurrently save theirs before
+/// halting. The other ones should call this when they are stopped because of a
+/// panic, or are reported as missing in the dump.
+pub fn save_cpu_state(regs: &RegisterSet)
+{
+	// SAFETY: each CPU only ever writes to its own slot
+	unsafe {
+		*CPU_STATES[cpu::current_cpu_index()].get() = Some(regs.clone());
+	}
+}
+
+/// Registers saved by `cpu` with [`save_cpu_state`], if any
+pub(super) fn saved_cpu_state(cpu: usize) -> Option<&'static RegisterSet>
+{
+	// SAFETY: CPUs only save their state once they are stopped
+	unsafe { (*CPU_STATES.get(cpu)?.get()).as_ref() }
+}
+
+/// Write a crash dump to `target`
+///
+/// # Safety
+///
+/// Must only be called from the panic handler, by the CPU owning the panic.
+pub(super) unsafe fn write(target: DumpTarget)
+{
+	let Some(sink) = DumpSink::open(target)
+	else
+	{
+		error!("couldn't open {target} to write the crash dump");
+		return;
+	};
+
+	// SAFETY: only the CPU owning the panic gets here
+	let scratch = unsafe { &mut *SCRATCH.get() };
+	let mut failures = 0_usize;
+	let mut emit = |record: &DumpRecord| {
+		match postcard::to_slice_cobs(record, scratch)
+		{
+			Ok(frame) => sink.write(frame),
+			Err(_) => failures += 1
+		}
+	};
+
+	sink.write(&[0]);
+	emit(&DumpRecord::Header {
+		version:        CRASH_DUMP_VERSION,
+		kernel_version: env!("CARGO_PKG_VERSION"),
+		arch:           ARCH,
+		panicking_cpu:  cpu::current_cpu_index() as u32
+	});
+
+	if let Some(record) = last_panic()
+	{
+		emit(&DumpRecord::Panic {
+			cpu:       record.cpu as u32,
+			file:      record.file(),
+			line:      record.line,
+			column:    record.column,
+			message:   record.message(),
+			truncated: record.truncated
+		});
+	}
+
+	for (cpu, state) in CPU_STATES.iter().enumerate()
+	{
+		// SAFETY: the other CPUs don't touch their slot once they have been
+		//         stopped
+		let Some(regs) = (unsafe { &*state.get() })
+		else
+		{
+			if cpu::cpu_apic_id(cpu).is_some()
+			{
+				emit(&DumpRecord::MissingRegisters { cpu: cpu as u32 });
+			}
+			continue;
+		};
+		emit(&DumpRecord::Registers {
+			cpu:       cpu as u32,
+			pc:        regs.get_pc(),
+			registers: regs.dwarf_registers()
+		});
+		let mut frames = [0; MAX_BACKTRACE_DEPTH];
+		let count = unwinding::capture_backtrace(regs.clone(), &mut frames);
+		emit(&DumpRecord::Backtrace {
+			cpu:    cpu as u32,
+			frames: &frames[..count]
+		});
+	}
+
+	#[cfg(bootloader = "limine")]
+	if let Some(response) = crate::init::bootloaders::limine::MEMMAP_REQUEST.get_response()
+	{
+		for entry in response.entries()
+		{
+			emit(&DumpRecord::MemoryRegion {
+				base:   entry.base,
+				length: entry.length,
+				kind:   u64::from(entry.entry_type)
+			});
+		}
+	}
+
+	emit(&DumpRecord::Allocator(allocator_stats()));
+
+	{
+		// the panic may have happened while logging: nothing is logged until
+		// the guard is dropped, and the lock is bypassed if it is already held
+		let guard = ZEROS_LOG_RING.try_lock();
+		// SAFETY: see above; at worst, the end of the log is garbled
+		let ring = guard
+			.as_deref()
+			.unwrap_or_else(|| unsafe { &*ZEROS_LOG_RING.data_ptr() });
+		let (first, second) = ring.contents();
+		for chunk in first
+			.chunks(LOG_CHUNK_SIZE)
+			.chain(second.chunks(LOG_CHUNK_SIZE))
+		{
+			emit(&DumpRecord::Log(chunk));
+		}
+	}
+
+	emit(&DumpRecord::End);
+
+	if failures != 0
+	{
+		error!("{failures} crash dump record(s) couldn't be encoded");
+	}
+	info!("crash dump written to {target}");
+}
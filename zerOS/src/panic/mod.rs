@@ -0,0 +1,139 @@
+//! The kernel panic handler
+//!
+//! Panicking must keep working when the kernel is in a bad state (e.g. out of
+//! memory, or with a corrupted heap), so nothing in here allocates: the panic
+//! message is formatted into a fixed per-CPU buffer, and the first panic is
+//! kept in a static [`PanicRecord`], which can then be written out as part of
+//! a crash dump (see [`PanicAction::CrashDump`]). The only exception is the
+//! symbolization of the backtrace, which is skipped if the panic happened while
+//! the heap was in use.
+
+use core::fmt::Write;
+
+use portable_atomic::{AtomicUsize, Ordering};
+
+use crate::{
+	arch::target::cpu::{self, irq, misc},
+	error,
+	unwinding
+};
+
+mod action;
+mod buffer;
+mod dump;
+mod record;
+mod unwind;
+
+pub use action::{MAX_PANIC_ACTIONS, PanicAction, set_actions};
+pub use buffer::{IndentingWriter, PANIC_BUFFER_SIZE};
+pub use dump::{CRASH_DUMP_VERSION, DumpTarget, save_cpu_state};
+pub use record::{PanicRecord, last_panic};
+pub use unwind::catch_unwind;
+#[cfg(feature = "unwind")]
+pub use unwind::init as init_unwinding;
+
+const NO_CPU: usize = usize::MAX;
+
+/// CPU currently handling a panic, if any
+static PANICKING_CPU: AtomicUsize = AtomicUsize::new(NO_CPU);
+/// Panic nesting depth on the CPU owning the panic
+static PANIC_DEPTH: AtomicUsize = AtomicUsize::new(0);
+
+/// Forget about the current panic, once it has been caught
+fn recover()
+{
+	PANIC_DEPTH.store(0, Ordering::Release);
+	PANICKING_CPU.store(NO_CPU, Ordering::Release);
+}
+
+#[panic_handler]
+fn rust_panic_impl(info: &core::panic::PanicInfo) -> !
+{
+	irq::disable();
+	let regs = unwinding::read_registers!();
+
+	let cpu = cpu::current_cpu_index();
+	if let Err(owner) =
+		PANICKING_CPU.compare_exchange(NO_CPU, cpu, Ordering::AcqRel, Ordering::Acquire)
+		&& owner != cpu
+	{
+		// another CPU is already reporting its panic, don't garble its output,
+		// but leave this CPU's state for the crash dump
+		dump::save_cpu_state(&regs);
+		misc::hcf();
+	}
+
+	match PANIC_DEPTH.fetch_add(1, Ordering::AcqRel)
+	{
+		0 =>
+		{},
+		1 =>
+		{
+			error!("attempted to `panic!` while panicking. aborting kernel...");
+			dump::save_cpu_state(&regs);
+			action::run_actions()
+		},
+		_ => misc::hcf()
+	}
+
+	// SAFETY: this is the only place using the panic buffers, and the checks
+	//         above ensure it is only reached once, by a single CPU
+	let mut message = unsafe { IndentingWriter::for_cpu(cpu, "\t") };
+	let _ = write!(message, "{}", info.message());
+
+	let (file, line, column) = info.location().map_or(("<unknown-file>", 0, 0), |loc| {
+		(loc.file(), loc.line(), loc.column())
+	});
+	// before anything else may fault (e.g. the unwinder on a corrupted stack)
+	// SAFETY: this CPU owns the panic
+	unsafe {
+		record::store(
+			cpu,
+			file,
+			line,
+			column,
+			message.as_str(),
+			message.truncated()
+		);
+	}
+	let mut line_buf = itoa::Buffer::new();
+	let mut column_buf = itoa::Buffer::new();
+	error!(
+		r#"
+PANIC at {}:{}:{} (cpu #{}):
+	{}
+"#,
+		file,
+		info.location()
+			.map_or("<unknown-line>", |_| line_buf.format(line)),
+		info.location()
+			.map_or("<unknown-column>", |_| column_buf.format(column)),
+		cpu,
+		message.as_str()
+	);
+	if message.truncated()
+	{
+		error!("(panic message truncated to {PANIC_BUFFER_SIZE} bytes)");
+	}
+
+	unwinding::print_backtrace(regs.clone(), log::Level::Error);
+
+	// only returns if nothing catches the panic
+	#[cfg(feature = "unwind")]
+	unsafe {
+		unwind::begin_unwind(
+			cpu,
+			file,
+			line,
+			column,
+			message.as_str(),
+			message.truncated()
+		);
+	}
+
+	// only saved once nothing caught the panic, not to leave a stale state
+	// behind
+	dump::save_cpu_state(&regs);
+
+	action::run_actions()
+}
@@ -0,0 +1,129 @@
+use core::cell::SyncUnsafeCell;
+
+use portable_atomic::{AtomicBool, Ordering};
+
+/// Maximum length of the file path kept in a [`PanicRecord`]
+pub const PANIC_RECORD_FILE_SIZE: usize = 256;
+/// Maximum length of the message kept in a [`PanicRecord`]
+pub const PANIC_RECORD_MESSAGE_SIZE: usize = 1024;
+
+/// Everything worth remembering about the first panic the kernel went through
+///
+/// It lives in a static so that it can be retrieved later on (e.g. by a crash
+/// dump or a debugger) without having to allocate anything. It is also what
+/// [`catch_unwind`](super::catch_unwind) hands back when a panic is caught.
+#[derive(Clone)]
+pub struct PanicRecord
+{
+	pub cpu:       usize,
+	pub line:      u32,
+	pub column:    u32,
+	pub truncated: bool,
+	file:          [u8; PANIC_RECORD_FILE_SIZE],
+	file_len:      usize,
+	message:       [u8; PANIC_RECORD_MESSAGE_SIZE],
+	message_len:   usize
+}
+
+impl PanicRecord
+{
+	const fn new() -> Self
+	{
+		Self {
+			cpu:         0,
+			line:        0,
+			column:      0,
+			truncated:   false,
+			file:        [0; PANIC_RECORD_FILE_SIZE],
+			file_len:    0,
+			message:     [0; PANIC_RECORD_MESSAGE_SIZE],
+			message_len: 0
+		}
+	}
+
+	pub(super) fn fill(
+		&mut self,
+		cpu: usize,
+		file: &str,
+		line: u32,
+		column: u32,
+		message: &str,
+		truncated: bool
+	)
+	{
+		self.cpu = cpu;
+		self.line = line;
+		self.column = column;
+		self.file_len = copy_truncated(&mut self.file, file).0;
+		let (message_len, message_truncated) = copy_truncated(&mut self.message, message);
+		self.message_len = message_len;
+		self.truncated = truncated || message_truncated;
+	}
+
+	pub fn file(&self) -> &str
+	{
+		// SAFETY: `fill` only copies whole characters of a `str`
+		unsafe { str::from_utf8_unchecked(&self.file[..self.file_len]) }
+	}
+
+	pub fn message(&self) -> &str
+	{
+		// SAFETY: see `file`
+		unsafe { str::from_utf8_unchecked(&self.message[..self.message_len]) }
+	}
+}
+
+/// Copy as many whole characters of `src` as possible into `dst`
+///
+/// Returns the number of bytes copied, and whether `src` had to be cut.
+fn copy_truncated(dst: &mut [u8], src: &str) -> (usize, bool)
+{
+	let mut end = src.len().min(dst.len());
+	while !src.is_char_boundary(end)
+	{
+		end -= 1;
+	}
+	dst[..end].copy_from_slice(&src.as_bytes()[..end]);
+	(end, end != src.len())
+}
+
+static PANIC_RECORD: SyncUnsafeCell<PanicRecord> = SyncUnsafeCell::new(PanicRecord::new());
+static PANIC_RECORDED: AtomicBool = AtomicBool::new(false);
+
+/// Record a panic, unless one has already been recorded
+///
+/// # Safety
+///
+/// Must only be called from the panic handler, by the CPU owning the panic.
+pub(super) unsafe fn store(
+	cpu: usize,
+	file: &str,
+	line: u32,
+	column: u32,
+	message: &str,
+	truncated: bool
+)
+{
+	if PANIC_RECORDED.load(Ordering::Acquire)
+	{
+		return;
+	}
+
+	let record = unsafe { &mut *PANIC_RECORD.get() };
+	record.fill(cpu, file, line, column, message, truncated);
+
+	PANIC_RECORDED.store(true, Ordering::Release);
+}
+
+/// The record of the first panic that happened, if any
+pub fn last_panic() -> Option<&'static PanicRecord>
+{
+	if PANIC_RECORDED.load(Ordering::Acquire)
+	{
+		Some(unsafe { &*PANIC_RECORD.get() })
+	}
+	else
+	{
+		None
+	}
+}
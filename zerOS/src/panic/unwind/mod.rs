@@ -0,0 +1,214 @@
+//! Panic recovery, for the `*-unwind` profiles
+//!
+//! With `panic = "unwind"` (and the `unwind` feature), the panic handler raises
+//! an exception once the panic has been reported, and the frames of the
+//! panicking CPU are unwound: landing pads run, so destructors are called and
+//! lock guards are released. If a [`catch_unwind`] frame is found, execution
+//! resumes there, with the [`PanicRecord`] of the panic; otherwise the panic
+//! actions are run as usual.
+//!
+//! With the other profiles, [`catch_unwind`] just calls its closure.
+
+#[cfg(feature = "unwind")]
+use core::{cell::SyncUnsafeCell, ptr};
+use core::{
+	intrinsics,
+	mem::{ManuallyDrop, MaybeUninit}
+};
+
+#[cfg(feature = "unwind")]
+use ::unwinding::{
+	abi::{_Unwind_RaiseException, UnwindException},
+	custom_eh_frame_finder::{EhFrameFinder, FrameInfo, FrameInfoKind, set_custom_eh_frame_finder}
+};
+
+use super::PanicRecord;
+use crate::arch::target::cpu::irq;
+#[cfg(feature = "unwind")]
+use crate::{
+	arch::target::cpu::MAX_CPU_COUNT,
+	error,
+	kernel::linker::map::{zerOS_eh_frame_hdr_start, zerOS_text_start}
+};
+
+#[cfg(feature = "unwind")]
+mod personality;
+
+/// Exception class of the panics raised by the kernel (i.e. `zerOS\0RS`)
+#[cfg(feature = "unwind")]
+const ZEROS_EXCEPTION_CLASS: u64 = u64::from_be_bytes(*b"zerOS\0RS");
+
+/// The exception object of a panic, as seen by the unwinder
+#[cfg(feature = "unwind")]
+#[repr(C)]
+struct KernelException
+{
+	header: UnwindException,
+	record: PanicRecord
+}
+
+/// Exception object of each CPU
+///
+/// A CPU never has more than one panic in flight (see `PANIC_DEPTH`), so the
+/// exception objects don't need to be allocated.
+#[cfg(feature = "unwind")]
+static EXCEPTIONS: [SyncUnsafeCell<MaybeUninit<KernelException>>; MAX_CPU_COUNT] =
+	[const { SyncUnsafeCell::new(MaybeUninit::zeroed()) }; MAX_CPU_COUNT];
+
+#[cfg(feature = "unwind")]
+struct KernelEhFrameFinder;
+
+// SAFETY: the returned addresses are those of the kernel's own `.text` and
+//         `.eh_frame_hdr` sections
+#[cfg(feature = "unwind")]
+unsafe impl EhFrameFinder for KernelEhFrameFinder
+{
+	fn find(&self, _pc: usize) -> Option<FrameInfo>
+	{
+		Some(FrameInfo {
+			text_base: Some((&raw const zerOS_text_start).addr()),
+			kind:      FrameInfoKind::EhFrameHdr((&raw const zerOS_eh_frame_hdr_start).addr())
+		})
+	}
+}
+
+/// Tell the unwinder where to find the kernel's call frame information
+#[cfg(feature = "unwind")]
+pub fn init()
+{
+	static FINDER: KernelEhFrameFinder = KernelEhFrameFinder;
+
+	if set_custom_eh_frame_finder(&FINDER).is_err()
+	{
+		error!("the unwinder has already been initialized");
+	}
+}
+
+/// Unwind the stack of CPU number `cpu` up to the innermost [`catch_unwind`]
+/// frame
+///
+/// Only returns if the exception couldn't be raised (most likely because
+/// there is no [`catch_unwind`] frame to resume to).
+///
+/// # Safety
+///
+/// Must only be called from the panic handler, by the CPU owning the panic.
+#[cfg(feature = "unwind")]
+pub(super) unsafe fn begin_unwind(
+	cpu: usize,
+	file: &str,
+	line: u32,
+	column: u32,
+	message: &str,
+	truncated: bool
+)
+{
+	let exception = EXCEPTIONS[cpu].get().cast::<KernelException>();
+	// SAFETY: the exception object of `cpu` is not in use, since `cpu` isn't
+	//         already unwinding; the private fields of the header are left
+	//         zeroed, as the unwinder expects
+	let reason = unsafe {
+		(&raw mut (*exception).header.exception_class).write(ZEROS_EXCEPTION_CLASS);
+		(&raw mut (*exception).header.exception_cleanup).write(None);
+		(*exception)
+			.record
+			.fill(cpu, file, line, column, message, truncated);
+		_Unwind_RaiseException(ptr::addr_of_mut!((*exception).header))
+	};
+	error!(
+		"couldn't unwind the stack (reason code {}): no `catch_unwind` frame to resume to",
+		reason.0
+	);
+}
+
+/// Call `f`, catching the panics it may raise
+///
+/// If `f` panics, its frames are unwound and the [`PanicRecord`] of the panic
+/// is returned, after the panic state of the kernel and the interrupt flag have
+/// been restored. This is meant for the test harness and for code probing
+/// drivers, which can then go on after a failure.
+///
+/// Panics can only be caught with the `*-unwind` profiles: with the other ones,
+/// a panic in `f` runs the panic actions as usual.
+pub fn catch_unwind<R, F: FnOnce() -> R>(f: F) -> Result<R, PanicRecord>
+{
+	union Data<F, R>
+	{
+		f: ManuallyDrop<F>,
+		r: ManuallyDrop<R>,
+		p: ManuallyDrop<MaybeUninit<PanicRecord>>
+	}
+
+	#[inline]
+	fn do_call<F: FnOnce() -> R, R>(data: *mut u8)
+	{
+		// SAFETY: `data` points to the `Data` of `catch_unwind`, whose `f` field
+		//         is initialized
+		unsafe {
+			let data = &mut *data.cast::<Data<F, R>>();
+			let f = ManuallyDrop::take(&mut data.f);
+			data.r = ManuallyDrop::new(f());
+		}
+	}
+
+	#[inline]
+	fn do_catch<F: FnOnce() -> R, R>(data: *mut u8, payload: *mut u8)
+	{
+		// SAFETY: `data` points to the `Data` of `catch_unwind`, and `payload` is
+		//         the exception object that has been caught
+		unsafe {
+			let data = &mut *data.cast::<Data<F, R>>();
+			data.p = ManuallyDrop::new(MaybeUninit::new(take_payload(payload)));
+		}
+	}
+
+	let interrupts = irq::enabled();
+	let mut data = Data {
+		f: ManuallyDrop::new(f)
+	};
+	let data_ptr = (&raw mut data).cast::<u8>();
+
+	// SAFETY: `do_call` and `do_catch` access `data` as documented above
+	unsafe {
+		if intrinsics::catch_unwind(do_call::<F, R>, data_ptr, do_catch::<F, R>) == 0
+		{
+			Ok(ManuallyDrop::into_inner(data.r))
+		}
+		else
+		{
+			super::recover();
+			if interrupts
+			{
+				irq::enable();
+			}
+			Err(ManuallyDrop::into_inner(data.p).assume_init())
+		}
+	}
+}
+
+/// Retrieve the panic record from the exception object that has been caught
+///
+/// # Safety
+///
+/// `payload` must be the exception object passed to the landing pad.
+#[cfg(feature = "unwind")]
+unsafe fn take_payload(payload: *mut u8) -> PanicRecord
+{
+	let exception = payload.cast::<KernelException>();
+	// SAFETY: see above
+	unsafe {
+		if (*exception).header.exception_class != ZEROS_EXCEPTION_CLASS
+		{
+			// only the kernel raises exceptions
+			error!("caught a foreign exception. aborting kernel...");
+			crate::arch::target::cpu::misc::hcf();
+		}
+		(*exception).record.clone()
+	}
+}
+
+#[cfg(not(feature = "unwind"))]
+unsafe fn take_payload(_payload: *mut u8) -> PanicRecord
+{
+	unreachable!("panics can't be caught without the `unwind` feature")
+}
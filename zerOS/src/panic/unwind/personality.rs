@@ -0,0 +1,316 @@
+//! The personality routine, telling the unwinder what to do in each frame
+//!
+//! This is a port of the GCC-style personality routine of `std`: the LSDA
+//! emitted by `rustc` for a frame lists its call sites, along with the landing
+//! pad to jump to when unwinding through them, and whether it is a cleanup or
+//! a handler (i.e. a `catch_unwind`).
+
+use core::ffi::c_int;
+
+use ::unwinding::abi::{
+	_Unwind_GetDataRelBase,
+	_Unwind_GetIPInfo,
+	_Unwind_GetLanguageSpecificData,
+	_Unwind_GetRegionStart,
+	_Unwind_GetTextRelBase,
+	_Unwind_SetGR,
+	_Unwind_SetIP,
+	UnwindAction,
+	UnwindContext,
+	UnwindException,
+	UnwindReasonCode
+};
+
+const DW_EH_PE_OMIT: u8 = 0xff;
+const DW_EH_PE_ABSPTR: u8 = 0x00;
+
+const DW_EH_PE_ULEB128: u8 = 0x01;
+const DW_EH_PE_UDATA2: u8 = 0x02;
+const DW_EH_PE_UDATA4: u8 = 0x03;
+const DW_EH_PE_UDATA8: u8 = 0x04;
+const DW_EH_PE_SLEB128: u8 = 0x09;
+const DW_EH_PE_SDATA2: u8 = 0x0a;
+const DW_EH_PE_SDATA4: u8 = 0x0b;
+const DW_EH_PE_SDATA8: u8 = 0x0c;
+
+const DW_EH_PE_PCREL: u8 = 0x10;
+const DW_EH_PE_TEXTREL: u8 = 0x20;
+const DW_EH_PE_DATAREL: u8 = 0x30;
+const DW_EH_PE_FUNCREL: u8 = 0x40;
+const DW_EH_PE_ALIGNED: u8 = 0x50;
+
+const DW_EH_PE_INDIRECT: u8 = 0x80;
+
+/// Registers used to pass the exception object (and a selector) to the landing
+/// pads
+#[cfg(target_arch = "x86_64")]
+const UNWIND_DATA_REG: (c_int, c_int) = (0, 1); // RAX, RDX
+#[cfg(target_arch = "x86")]
+const UNWIND_DATA_REG: (c_int, c_int) = (0, 2); // EAX, EDX
+
+/// What has to be done when unwinding through a frame
+enum EhAction
+{
+	None,
+	Cleanup(usize),
+	Catch(usize),
+	Filter(usize),
+	Terminate
+}
+
+/// Malformed LSDA
+struct InvalidLsda;
+
+struct LsdaReader
+{
+	ptr: *const u8
+}
+
+impl LsdaReader
+{
+	unsafe fn read<T: Copy>(&mut self) -> T
+	{
+		unsafe {
+			let value = self.ptr.cast::<T>().read_unaligned();
+			self.ptr = self.ptr.add(size_of::<T>());
+			value
+		}
+	}
+
+	unsafe fn read_uleb128(&mut self) -> u64
+	{
+		let mut result = 0;
+		let mut shift = 0;
+		loop
+		{
+			let byte = unsafe { self.read::<u8>() };
+			result |= u64::from(byte & 0x7f) << shift;
+			shift += 7;
+			if byte & 0x80 == 0
+			{
+				return result;
+			}
+		}
+	}
+
+	unsafe fn read_sleb128(&mut self) -> i64
+	{
+		let mut result = 0;
+		let mut shift = 0;
+		let mut byte;
+		loop
+		{
+			byte = unsafe { self.read::<u8>() };
+			result |= i64::from(byte & 0x7f) << shift;
+			shift += 7;
+			if byte & 0x80 == 0
+			{
+				break;
+			}
+		}
+		if shift < 64 && byte & 0x40 != 0
+		{
+			// sign-extend
+			result |= -1 << shift;
+		}
+		result
+	}
+}
+
+/// Bases of the relative pointer encodings, for the current frame
+struct EhContext<'ctx, 'a>
+{
+	ip:         usize,
+	func_start: usize,
+	context:    &'ctx UnwindContext<'a>
+}
+
+unsafe fn read_encoded_pointer(
+	reader: &mut LsdaReader,
+	ctx: &EhContext,
+	encoding: u8
+) -> Result<usize, InvalidLsda>
+{
+	if encoding == DW_EH_PE_OMIT
+	{
+		return Err(InvalidLsda);
+	}
+
+	let base = match encoding & 0x70
+	{
+		DW_EH_PE_ABSPTR => 0,
+		DW_EH_PE_PCREL => reader.ptr.addr(),
+		DW_EH_PE_FUNCREL if ctx.func_start != 0 => ctx.func_start,
+		DW_EH_PE_TEXTREL => _Unwind_GetTextRelBase(ctx.context),
+		DW_EH_PE_DATAREL => _Unwind_GetDataRelBase(ctx.context),
+		DW_EH_PE_ALIGNED =>
+		{
+			reader.ptr = reader
+				.ptr
+				.wrapping_add(reader.ptr.align_offset(align_of::<usize>()));
+			return Ok(unsafe { reader.read::<usize>() });
+		},
+		_ => return Err(InvalidLsda)
+	};
+
+	let mut result = unsafe {
+		match encoding & 0x0f
+		{
+			DW_EH_PE_ABSPTR => reader.read::<usize>(),
+			DW_EH_PE_ULEB128 => reader.read_uleb128() as usize,
+			DW_EH_PE_UDATA2 => reader.read::<u16>() as usize,
+			DW_EH_PE_UDATA4 => reader.read::<u32>() as usize,
+			DW_EH_PE_UDATA8 => reader.read::<u64>() as usize,
+			DW_EH_PE_SLEB128 => reader.read_sleb128() as usize,
+			DW_EH_PE_SDATA2 => reader.read::<i16>() as usize,
+			DW_EH_PE_SDATA4 => reader.read::<i32>() as usize,
+			DW_EH_PE_SDATA8 => reader.read::<i64>() as usize,
+			_ => return Err(InvalidLsda)
+		}
+	}
+	.wrapping_add(base);
+	if encoding & DW_EH_PE_INDIRECT != 0
+	{
+		result = unsafe { (result as *const usize).read_unaligned() };
+	}
+	Ok(result)
+}
+
+/// Find the call site of `ctx.ip` in `lsda`, and what to do when unwinding
+/// through it
+unsafe fn find_eh_action(lsda: *const u8, ctx: &EhContext) -> Result<EhAction, InvalidLsda>
+{
+	if lsda.is_null()
+	{
+		return Ok(EhAction::None);
+	}
+
+	let mut reader = LsdaReader { ptr: lsda };
+	unsafe {
+		let start_encoding = reader.read::<u8>();
+		let lpad_base = if start_encoding != DW_EH_PE_OMIT
+		{
+			read_encoded_pointer(&mut reader, ctx, start_encoding)?
+		}
+		else
+		{
+			ctx.func_start
+		};
+
+		let ttype_encoding = reader.read::<u8>();
+		if ttype_encoding != DW_EH_PE_OMIT
+		{
+			// the offset of the type table, which `rustc` doesn't use
+			reader.read_uleb128();
+		}
+
+		let call_site_encoding = reader.read::<u8>();
+		let call_site_table_length = reader.read_uleb128();
+		let action_table = reader.ptr.add(call_site_table_length as usize);
+
+		while reader.ptr < action_table
+		{
+			let cs_start = read_encoded_pointer(&mut reader, ctx, call_site_encoding)?;
+			let cs_len = read_encoded_pointer(&mut reader, ctx, call_site_encoding)?;
+			let cs_lpad = read_encoded_pointer(&mut reader, ctx, call_site_encoding)?;
+			let cs_action_entry = reader.read_uleb128();
+
+			// the call sites are sorted
+			if ctx.ip < ctx.func_start.wrapping_add(cs_start)
+			{
+				break;
+			}
+			if ctx.ip < ctx.func_start.wrapping_add(cs_start + cs_len)
+			{
+				if cs_lpad == 0
+				{
+					return Ok(EhAction::None);
+				}
+				let lpad = lpad_base.wrapping_add(cs_lpad);
+				if cs_action_entry == 0
+				{
+					return Ok(EhAction::Cleanup(lpad));
+				}
+				let mut action_reader = LsdaReader {
+					ptr: action_table.add(cs_action_entry as usize - 1)
+				};
+				return Ok(match action_reader.read_sleb128()
+				{
+					0 => EhAction::Cleanup(lpad),
+					index if index > 0 => EhAction::Catch(lpad),
+					_ => EhAction::Filter(lpad)
+				});
+			}
+		}
+	}
+	// the IP isn't part of any call site: unwinding through it is a bug
+	Ok(EhAction::Terminate)
+}
+
+#[lang = "eh_personality"]
+unsafe extern "C" fn rust_eh_personality(
+	version: c_int,
+	actions: UnwindAction,
+	_exception_class: u64,
+	exception: *mut UnwindException,
+	context: &mut UnwindContext<'_>
+) -> UnwindReasonCode
+{
+	if version != 1
+	{
+		return UnwindReasonCode::FATAL_PHASE1_ERROR;
+	}
+
+	let lsda = _Unwind_GetLanguageSpecificData(context)
+		.cast::<u8>()
+		.cast_const();
+	let mut ip_before_instruction = 0;
+	let ip = _Unwind_GetIPInfo(context, &mut ip_before_instruction);
+	let ctx = EhContext {
+		// the IP is the return address, which may not belong to the call site
+		ip: if ip_before_instruction != 0
+		{
+			ip
+		}
+		else
+		{
+			ip - 1
+		},
+		func_start: _Unwind_GetRegionStart(context),
+		context
+	};
+	let Ok(action) = (unsafe { find_eh_action(lsda, &ctx) })
+	else
+	{
+		return UnwindReasonCode::FATAL_PHASE1_ERROR;
+	};
+
+	if actions.0 & UnwindAction::SEARCH_PHASE.0 != 0
+	{
+		match action
+		{
+			EhAction::None | EhAction::Cleanup(_) => UnwindReasonCode::CONTINUE_UNWIND,
+			EhAction::Catch(_) | EhAction::Filter(_) => UnwindReasonCode::HANDLER_FOUND,
+			EhAction::Terminate => UnwindReasonCode::FATAL_PHASE1_ERROR
+		}
+	}
+	else
+	{
+		match action
+		{
+			EhAction::None => UnwindReasonCode::CONTINUE_UNWIND,
+			EhAction::Filter(_) if actions.0 & UnwindAction::FORCE_UNWIND.0 != 0 =>
+			{
+				UnwindReasonCode::CONTINUE_UNWIND
+			},
+			EhAction::Cleanup(lpad) | EhAction::Catch(lpad) | EhAction::Filter(lpad) =>
+			{
+				_Unwind_SetGR(context, UNWIND_DATA_REG.0, exception as usize);
+				_Unwind_SetGR(context, UNWIND_DATA_REG.1, 0);
+				_Unwind_SetIP(context, lpad);
+				UnwindReasonCode::INSTALL_CONTEXT
+			},
+			EhAction::Terminate => UnwindReasonCode::FATAL_PHASE2_ERROR
+		}
+	}
+}
@@ -0,0 +1,226 @@
+use core::slice;
+
+use gimli::{
+	BaseAddresses,
+	EhFrame,
+	EhFrameHdr,
+	EndianSlice,
+	NativeEndian,
+	ParsedEhFrameHdr,
+	Register,
+	X86_64
+};
+
+use super::UnwinderError;
+use crate::kernel::linker::map::{
+	zerOS_eh_frame_end,
+	zerOS_eh_frame_hdr_end,
+	zerOS_eh_frame_hdr_start,
+	zerOS_eh_frame_start,
+	zerOS_text_start
+};
+
+pub struct EhInfo
+{
+	/// A set of base addresses used for relative addressing.
+	pub(super) base_addrs: BaseAddresses,
+
+	/// The parsed `.eh_frame_hdr` section.
+	pub(super) hdr: ParsedEhFrameHdr<EndianSlice<'static, NativeEndian>>,
+
+	/// The parsed `.eh_frame` containing the call frame information.
+	pub(super) eh_frame: EhFrame<EndianSlice<'static, NativeEndian>>
+}
+
+impl EhInfo
+{
+	pub fn new() -> Result<Self, UnwinderError>
+	{
+		let mut base_addrs = BaseAddresses::default();
+		// We set the `.eh_frame_hdr`’s address in the set of base addresses,
+		// this will typically be used to compute the `.eh_frame` pointer.
+		base_addrs =
+			base_addrs.set_eh_frame_hdr((&raw const zerOS_eh_frame_hdr_start).addr() as u64);
+
+		// The `.eh_frame_hdr` is parsed by Gimli.
+		let hdr = EhFrameHdr::new(
+			unsafe {
+				slice::from_raw_parts(
+					(&raw const zerOS_eh_frame_hdr_start).cast(),
+					(&raw const zerOS_eh_frame_hdr_end).addr()
+						- (&raw const zerOS_eh_frame_hdr_start).addr()
+				)
+			},
+			NativeEndian
+		)
+		.parse(&base_addrs, 8)
+		.map_err(|_| UnwinderError::InvalidEhFrameHdr)?;
+
+		// We then add the `.eh_frame` address for addresses relative to that
+		// section.
+		base_addrs = base_addrs.set_eh_frame((&raw const zerOS_eh_frame_start).addr() as u64);
+
+		// The `.eh_frame` section is then parsed.
+		let eh_frame = EhFrame::new(
+			unsafe {
+				slice::from_raw_parts(
+					(&raw const zerOS_eh_frame_start).cast(),
+					(&raw const zerOS_eh_frame_end).addr()
+						- (&raw const zerOS_eh_frame_start).addr()
+				)
+			},
+			NativeEndian
+		);
+
+		base_addrs = base_addrs.set_text((&raw const zerOS_text_start).addr() as u64);
+
+		Ok(Self {
+			base_addrs,
+			hdr,
+			eh_frame
+		})
+	}
+}
+
+/// Number of registers tracked while unwinding: all the general purpose
+/// registers, plus the return address (in DWARF register number order)
+const REGISTER_COUNT: usize = X86_64::RA.0 as usize + 1;
+
+/// A snapshot of the registers of a frame
+///
+/// Registers are indexed by their DWARF register number. The value of a
+/// register is `None` if it is unknown (e.g. not restored by the CFI of a
+/// callee).
+#[derive(Debug, Default, Clone)]
+pub struct RegisterSet
+{
+	pub rip:         Option<u64>,
+	pub(super) regs: [Option<u64>; REGISTER_COUNT]
+}
+
+/// Take a snapshot of the registers at the point of use
+///
+/// The resulting [`RegisterSet`] describes the frame of the calling function,
+/// with `rip` pointing just after the snapshot code, so that it can be fed
+/// directly to an [`Unwinder`](super::Unwinder).
+pub macro unwind_get_registers() {{
+	let mut regs = [0_u64; 17];
+	unsafe {
+		::core::arch::asm! {
+			"movq %rax, 0x00({base})",
+			"movq %rdx, 0x08({base})",
+			"movq %rcx, 0x10({base})",
+			"movq %rbx, 0x18({base})",
+			"movq %rsi, 0x20({base})",
+			"movq %rdi, 0x28({base})",
+			"movq %rbp, 0x30({base})",
+			"movq %rsp, 0x38({base})",
+			"movq %r8,  0x40({base})",
+			"movq %r9,  0x48({base})",
+			"movq %r10, 0x50({base})",
+			"movq %r11, 0x58({base})",
+			"movq %r12, 0x60({base})",
+			"movq %r13, 0x68({base})",
+			"movq %r14, 0x70({base})",
+			"movq %r15, 0x78({base})",
+			"leaq 2f(%rip), {tmp}",
+			"movq {tmp}, 0x80({base})",
+			"2:",
+			base = in(reg) regs.as_mut_ptr(),
+			tmp = out(reg) _,
+			options(att_syntax, nostack, preserves_flags)
+		};
+	}
+	$crate::unwinding::RegisterSet::from_snapshot(regs)
+}}
+
+impl RegisterSet
+{
+	pub(super) const FRAME_POINTER: Register = X86_64::RBP;
+	pub(super) const RETURN_ADDRESS: Register = X86_64::RA;
+
+	/// Build a register set from the 16 general purpose registers (in DWARF
+	/// register number order) followed by `rip`
+	pub fn from_snapshot(snapshot: [u64; 17]) -> Self
+	{
+		let mut regs = [None; REGISTER_COUNT];
+		for (reg, value) in regs.iter_mut().zip(&snapshot[..16])
+		{
+			*reg = Some(*value);
+		}
+		Self {
+			rip: Some(snapshot[16]),
+			regs
+		}
+	}
+
+	/// Build a minimal register set, e.g. from an interrupt stack frame
+	pub fn from_frame(rip: u64, rsp: u64, rbp: u64) -> Self
+	{
+		let mut this = Self::default();
+		this.rip = Some(rip);
+		this.regs[X86_64::RSP.0 as usize] = Some(rsp);
+		this.regs[X86_64::RBP.0 as usize] = Some(rbp);
+		this
+	}
+
+	pub(super) fn get(&self, reg: Register) -> Option<u64>
+	{
+		self.regs.get(reg.0 as usize).copied().flatten()
+	}
+
+	pub(super) fn set(&mut self, reg: Register, val: u64) -> Result<(), UnwinderError>
+	{
+		*self
+			.regs
+			.get_mut(reg.0 as usize)
+			.ok_or(UnwinderError::UnexpectedRegister(reg))? = Some(val);
+
+		Ok(())
+	}
+
+	pub(super) fn undef(&mut self, reg: Register)
+	{
+		if let Some(slot) = self.regs.get_mut(reg.0 as usize)
+		{
+			*slot = None;
+		}
+	}
+
+	/// Values of the registers, indexed by their DWARF register number
+	pub fn dwarf_registers(&self) -> &[Option<u64>]
+	{
+		&self.regs
+	}
+
+	pub fn get_pc(&self) -> Option<u64>
+	{
+		self.rip
+	}
+
+	pub(super) fn set_pc(&mut self, val: u64)
+	{
+		self.rip = Some(val);
+	}
+
+	pub(super) fn get_ret(&self) -> Option<u64>
+	{
+		self.get(X86_64::RA)
+	}
+
+	pub fn get_stack_ptr(&self) -> Option<u64>
+	{
+		self.get(X86_64::RSP)
+	}
+
+	pub(super) fn set_stack_ptr(&mut self, val: u64)
+	{
+		self.regs[X86_64::RSP.0 as usize] = Some(val);
+	}
+}
+
+/// Whether `addr` may be dereferenced while unwinding the kernel stack
+pub(super) fn is_kernel_address(addr: u64) -> bool
+{
+	addr >= 0xffff_8000_0000_0000
+}
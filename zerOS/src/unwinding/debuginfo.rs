@@ -0,0 +1,169 @@
+//! Source-level symbolization, from the `debug-info.zko` kernel module
+//!
+//! The module is the file produced by splitting the debug information out of
+//! the kernel executable at build time. It is parsed into an
+//! [`addr2line::Context`], which only parses the compilation units that are
+//! actually looked up (their line programs, functions and inlined functions are
+//! then cached for subsequent lookups). As parsing allocates, nothing is
+//! symbolized while the global allocator is in use.
+
+use alloc::borrow::Cow;
+use core::fmt;
+
+use gimli::{EndianSlice, NativeEndian, SectionId};
+use object::{Object, ObjectSection};
+
+use crate::kernel::{memory::global_allocator, sync::BasicMutex};
+
+type DebugInfoReader = EndianSlice<'static, NativeEndian>;
+
+struct DebugInfo
+{
+	ctx: addr2line::Context<DebugInfoReader>
+}
+
+// SAFETY: the context is only ever accessed through `DEBUG_INFO`'s mutex
+unsafe impl Send for DebugInfo {}
+
+static DEBUG_INFO: BasicMutex<Option<DebugInfo>> = BasicMutex::new(None);
+
+#[derive(Debug, strum::AsRefStr)]
+#[strum(serialize_all = "kebab-case")]
+pub enum DebugInfoError
+{
+	InvalidObjectFile,
+	InvalidSection(SectionId),
+	InvalidDwarf
+}
+
+/// Parse the content of the `debug-info.zko` module, and use it for all
+/// subsequent symbolization requests
+pub fn load(module: &'static [u8]) -> Result<(), DebugInfoError>
+{
+	let file = object::File::parse(module).map_err(|_| DebugInfoError::InvalidObjectFile)?;
+	let dwarf = gimli::Dwarf::load(|id| {
+		let data = match file.section_by_name(id.name())
+		{
+			Some(section) =>
+			{
+				section
+					.data()
+					.map_err(|_| DebugInfoError::InvalidSection(id))?
+			},
+			None => &[]
+		};
+		Ok::<_, DebugInfoError>(EndianSlice::new(data, NativeEndian))
+	})?;
+	let ctx = addr2line::Context::from_dwarf(dwarf).map_err(|_| DebugInfoError::InvalidDwarf)?;
+
+	*DEBUG_INFO.lock() = Some(DebugInfo { ctx });
+	Ok(())
+}
+
+/// Whether debug information has been loaded
+pub fn is_loaded() -> bool
+{
+	DEBUG_INFO.lock().is_some()
+}
+
+/// A source location, as resolved from debug information
+pub struct SourceFrame<'a>
+{
+	/// Raw (possibly mangled) name of the function
+	pub function: Option<Cow<'a, str>>,
+	pub file:     Option<&'a str>,
+	pub line:     Option<u32>,
+	pub column:   Option<u32>,
+	/// Whether this function has been inlined into the next frame
+	pub inlined:  bool
+}
+
+impl fmt::Display for SourceFrame<'_>
+{
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+	{
+		match &self.function
+		{
+			Some(function) => write!(f, "at {:#}", rustc_demangle::demangle(function))?,
+			None => write!(f, "at <unknown-function>")?
+		}
+		match (self.file, self.line, self.column)
+		{
+			(Some(file), Some(line), Some(column)) if column != 0 =>
+			{
+				write!(f, " ({file}:{line}:{column})")
+			},
+			(Some(file), Some(line), _) => write!(f, " ({file}:{line})"),
+			(Some(file), None, _) => write!(f, " ({file})"),
+			_ => write!(f, " (<unknown-location>)")
+		}?;
+		if self.inlined
+		{
+			write!(f, " [inlined]")?;
+		}
+		Ok(())
+	}
+}
+
+/// Resolve `pc` to source locations, innermost inlined function first
+///
+/// Returns `false` if no debug information is available, if it is already
+/// being used (e.g. when panicking while symbolizing another address), or if
+/// the global allocator is (e.g. when panicking inside it).
+pub fn for_each_frame(pc: u64, mut f: impl FnMut(SourceFrame<'_>)) -> bool
+{
+	if !global_allocator::is_usable()
+	{
+		return false;
+	}
+	let Some(guard) = DEBUG_INFO.try_lock()
+	else
+	{
+		return false;
+	};
+	let Some(debug_info) = guard.as_ref()
+	else
+	{
+		return false;
+	};
+
+	let Ok(mut frames) = debug_info.ctx.find_frames(pc).skip_all_loads()
+	else
+	{
+		return false;
+	};
+
+	let mut previous = None;
+	while let Ok(Some(frame)) = frames.next()
+	{
+		if let Some(previous) = previous.take()
+		{
+			f(source_frame(&previous, true));
+		}
+		previous = Some(frame);
+	}
+	if let Some(previous) = previous
+	{
+		f(source_frame(&previous, false));
+	}
+	true
+}
+
+fn source_frame<'a>(
+	frame: &'a addr2line::Frame<'_, DebugInfoReader>,
+	inlined: bool
+) -> SourceFrame<'a>
+{
+	let function = frame
+		.function
+		.as_ref()
+		.and_then(|name| name.raw_name().ok());
+	let location = frame.location.as_ref();
+	SourceFrame {
+		function,
+		file: location.and_then(|loc| loc.file),
+		line: location.and_then(|loc| loc.line),
+		column: location.and_then(|loc| loc.column),
+		inlined
+	}
+}
@@ -1,9 +1,447 @@
-use addr2line::Context;
-
-// PERSONAL NOTES/TODOs:
-//
-//      For only unwinding, `.eh_frame` and `.eh_frame_hdr` are enough.
-//
-//      For debug builds, either manually split debug information in a custom
-//      kernel object (/ module ? namely `debug-info.zko`), or ask cargo to
-//      do it with `split-debuginfo=packed` and `debuginfo=2` or more
+//! # Acknoledgments
+//! Code initially copy-pasted/adapted from https://lesenechal.fr/en/linux/unwinding-the-stack-the-hard-way
+//!
+//! # Future direction
+//! As an idea for future work, try to *REALLY* unwind the stack, akin to what [Theseus OS](https://github.com/theseus-os/Theseus/blob/1fbfe567075a65ed749b6680db1aeb538819c70c/kernel/unwind/src/lib.rs#L626) does.
+
+use core::{
+	fmt,
+	sync::atomic::{AtomicU8, Ordering}
+};
+
+use cfg_if::cfg_if;
+use gimli::{
+	CfaRule,
+	Register,
+	RegisterRule,
+	UnwindContext,
+	UnwindContextStorage,
+	UnwindSection,
+	UnwindTableRow,
+	read::ReaderOffset
+};
+
+use crate::log;
+
+pub mod debuginfo;
+pub mod symbols;
+pub mod troll;
+
+cfg_if! {
+	if #[cfg(target_arch = "x86_64")]
+	{
+		mod amd64;
+		use self::amd64::is_kernel_address;
+		pub use self::amd64::{
+			EhInfo,
+			unwind_get_registers as read_registers,
+			RegisterSet
+		};
+	}
+	else if #[cfg(target_arch = "x86")]
+	{
+		mod x86;
+		use self::x86::is_kernel_address;
+		pub use self::x86::{
+			EhInfo,
+			unwind_get_registers as read_registers,
+			RegisterSet
+		};
+	}
+	else
+	{
+		compile_error!("TODO: implement stack unwinding/backtraces for this target !");
+	}
+}
+
+/// Maximum number of frames printed by [`print_backtrace`]
+pub const MAX_BACKTRACE_DEPTH: usize = 64;
+
+/// The unwinders [`print_backtrace`] may use
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, strum::AsRefStr, strum::EnumString)]
+#[strum(serialize_all = "kebab-case", ascii_case_insensitive)]
+#[repr(u8)]
+pub enum UnwinderKind
+{
+	/// Interpret the DWARF call frame information from `.eh_frame`
+	#[default]
+	Dwarf,
+	/// Use the precomputed TROLL table (see [`troll`]), falling back to
+	/// [`UnwinderKind::Dwarf`] if it is unavailable
+	Troll
+}
+
+static SELECTED_UNWINDER: AtomicU8 = AtomicU8::new(UnwinderKind::Dwarf as u8);
+
+/// Select the unwinder used by [`print_backtrace`]
+pub fn set_unwinder(kind: UnwinderKind)
+{
+	SELECTED_UNWINDER.store(kind as u8, Ordering::Relaxed);
+}
+
+/// The unwinder currently used by [`print_backtrace`]
+pub fn selected_unwinder() -> UnwinderKind
+{
+	match SELECTED_UNWINDER.load(Ordering::Relaxed)
+	{
+		x if x == UnwinderKind::Troll as u8 => UnwinderKind::Troll,
+		_ => UnwinderKind::Dwarf
+	}
+}
+
+/// Common interface of the unwinders, used to print backtraces
+pub trait FrameUnwinder
+{
+	/// Return the next (outer) frame, or `None` once the outermost frame has
+	/// been reached
+	fn next_frame(&mut self) -> Result<Option<CallFrame>, UnwinderError>;
+}
+
+const MAX_RULES: usize = 32;
+const MAX_UNWIND_STACK_DEPTH: usize = 4;
+
+/// In-line storage for gimli's [`UnwindContext`], so that unwinding never
+/// allocates
+pub struct InlineStorage;
+
+impl<T: ReaderOffset> UnwindContextStorage<T> for InlineStorage
+{
+	type Rules = [(Register, RegisterRule<T>); MAX_RULES];
+	type Stack = [UnwindTableRow<T, Self>; MAX_UNWIND_STACK_DEPTH];
+}
+
+pub struct Unwinder
+{
+	/// The call frame information.
+	eh_info: EhInfo,
+
+	/// A `UnwindContext` needed by Gimli for optimizations.
+	unwind_ctx: UnwindContext<usize, InlineStorage>,
+
+	/// The current values of registers. These values are updated as we restore
+	/// register values.
+	regs: RegisterSet,
+
+	/// The current CFA address.
+	cfa: u64,
+
+	/// Is it the first iteration?
+	is_first: bool
+}
+
+impl Unwinder
+{
+	pub fn new(eh_info: EhInfo, register_set: RegisterSet) -> Self
+	{
+		Self {
+			eh_info,
+			unwind_ctx: UnwindContext::new_in(),
+			regs: register_set,
+			cfa: 0,
+			is_first: true
+		}
+	}
+
+	/// Registers of the last frame returned by [`Unwinder::next`]
+	pub fn registers(&self) -> &RegisterSet
+	{
+		&self.regs
+	}
+
+	pub fn next(&mut self) -> Result<Option<CallFrame>, UnwinderError>
+	{
+		let pc = self.regs.get_pc().ok_or(UnwinderError::NoPcRegister)?;
+
+		if self.is_first
+		{
+			self.is_first = false;
+			return Ok(Some(CallFrame {
+				pc:        pc as usize,
+				lookup_pc: pc as usize
+			}));
+		}
+
+		// The program counter of the first frame points at the instruction that
+		// is being executed, but the one of the other frames is a return
+		// address, which may already be out of the calling function (e.g. after
+		// a call to a `noreturn` function)
+		let lookup_pc = if self.cfa == 0 { pc } else { pc - 1 };
+		let row = self
+			.eh_info
+			.hdr
+			.table()
+			.ok_or(UnwinderError::NoUnwindInfo)?
+			.unwind_info_for_address(
+				&self.eh_info.eh_frame,
+				&self.eh_info.base_addrs,
+				&mut self.unwind_ctx,
+				lookup_pc,
+				|section, bases, offset| section.cie_from_offset(bases, offset)
+			)
+			.map_err(|_| UnwinderError::NoUnwindInfo)?;
+
+		let cfa = match row.cfa()
+		{
+			CfaRule::RegisterAndOffset { register, offset } =>
+			{
+				let reg_val = self
+					.regs
+					.get(*register)
+					.ok_or(UnwinderError::CfaRuleUnknownRegister(*register))?;
+				reg_val.wrapping_add_signed(*offset)
+			},
+			_ => return Err(UnwinderError::UnsupportedCfaRule)
+		};
+		if cfa <= self.cfa
+		{
+			return Err(UnwinderError::CfaNotIncreasing);
+		}
+
+		// registers without a rule keep their value, except for the return
+		// address which must be explicitly restored
+		let mut caller_regs = self.regs.clone();
+		caller_regs.undef(RegisterSet::RETURN_ADDRESS);
+		for &(reg, ref rule) in row.registers()
+		{
+			match rule
+			{
+				RegisterRule::Undefined => caller_regs.undef(reg),
+				RegisterRule::SameValue => (),
+				RegisterRule::Offset(offset) =>
+				{
+					caller_regs.set(reg, read_stack(cfa.wrapping_add_signed(*offset))?)?;
+				},
+				RegisterRule::ValOffset(offset) =>
+				{
+					caller_regs.set(reg, cfa.wrapping_add_signed(*offset))?;
+				},
+				RegisterRule::Register(other) =>
+				{
+					match self.regs.get(*other)
+					{
+						Some(val) => caller_regs.set(reg, val)?,
+						None => caller_regs.undef(reg)
+					}
+				},
+				_ => return Err(UnwinderError::UnimplementedRegisterRule)
+			}
+		}
+
+		let Some(ret) = caller_regs.get_ret().filter(|&ret| ret != 0)
+		else
+		{
+			// we reached the outermost frame
+			return Ok(None);
+		};
+		caller_regs.set_pc(ret);
+		caller_regs.set_stack_ptr(cfa);
+		self.regs = caller_regs;
+		self.cfa = cfa;
+
+		Ok(Some(CallFrame {
+			pc:        ret as usize,
+			lookup_pc: ret as usize - 1
+		}))
+	}
+}
+
+impl FrameUnwinder for Unwinder
+{
+	fn next_frame(&mut self) -> Result<Option<CallFrame>, UnwinderError>
+	{
+		self.next()
+	}
+}
+
+fn read_stack(addr: u64) -> Result<u64, UnwinderError>
+{
+	if !is_kernel_address(addr) || !addr.is_multiple_of(align_of::<usize>() as u64)
+	{
+		return Err(UnwinderError::InvalidStackAddress(addr));
+	}
+	Ok(unsafe { (addr as *const usize).read() } as u64)
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct CallFrame
+{
+	pub pc:        usize,
+	/// Address to use when looking up the frame's function or source location
+	///
+	/// Except for the innermost frame, this points inside the call instruction
+	/// rather than after it.
+	pub lookup_pc: usize
+}
+
+impl fmt::Display for CallFrame
+{
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+	{
+		write!(f, "{:#018x}", self.pc)?;
+		match symbols::nearest_symbol(self.lookup_pc as u64)
+		{
+			Some(sym) =>
+			{
+				write!(
+					f,
+					" in {}",
+					symbols::SymbolInfo {
+						offset: self.pc as u64 - sym.address,
+						..sym
+					}
+				)
+			},
+			None => write!(f, " in <unknown>")
+		}
+	}
+}
+
+#[derive(Debug, strum::AsRefStr)]
+#[strum(serialize_all = "kebab-case")]
+pub enum UnwinderError
+{
+	UnexpectedRegister(Register),
+	UnsupportedCfaRule,
+	UnimplementedRegisterRule,
+	CfaRuleUnknownRegister(Register),
+	CfaNotIncreasing,
+	InvalidStackAddress(u64),
+	InvalidEhFrameHdr,
+	NoUnwindInfo,
+	NoPcRegister,
+	NoCfaBaseRegister,
+	InvalidTrollTable
+}
+
+/// Print a backtrace, starting from the frame described by `regs`
+///
+/// The frames are found with the unwinder selected by [`set_unwinder`], which
+/// never allocates, so this may be used from the panic handler or from
+/// exception handlers. Only their symbolization from debug information
+/// allocates, and it is skipped while the global allocator is in use (see
+/// [`debuginfo::for_each_frame`]).
+pub fn print_backtrace(regs: RegisterSet, level: log::Level)
+{
+	log!(event: "backtrace", level, "backtrace:");
+	if selected_unwinder() == UnwinderKind::Troll
+	{
+		match troll::TrollTable::get()
+		{
+			Ok(table) =>
+			{
+				print_frames(&mut troll::TrollUnwinder::new(table, regs), level);
+				return;
+			},
+			Err(err) =>
+			{
+				log!(
+					event: "backtrace",
+					level,
+					"\t<TROLL table unavailable ({}), falling back to DWARF>",
+					err.as_ref()
+				);
+			}
+		}
+	}
+
+	match EhInfo::new()
+	{
+		Ok(eh_info) => print_frames(&mut Unwinder::new(eh_info, regs), level),
+		Err(err) => log!(event: "backtrace", level, "\t<unavailable: {}>", err.as_ref())
+	}
+}
+
+/// Fill `pcs` with the program counters of the frames starting from the one
+/// described by `regs`, innermost first, and return how many of them have been
+/// written
+///
+/// Like [`print_backtrace`], this uses the selected unwinder and never
+/// allocates, but it does not do any symbolization, and stops silently on the
+/// first error.
+pub fn capture_backtrace(regs: RegisterSet, pcs: &mut [u64]) -> usize
+{
+	if selected_unwinder() == UnwinderKind::Troll
+		&& let Ok(table) = troll::TrollTable::get()
+	{
+		return capture_frames(&mut troll::TrollUnwinder::new(table, regs), pcs);
+	}
+	EhInfo::new().map_or(0, |eh_info| {
+		capture_frames(&mut Unwinder::new(eh_info, regs), pcs)
+	})
+}
+
+fn capture_frames(unwinder: &mut impl FrameUnwinder, pcs: &mut [u64]) -> usize
+{
+	let mut count = 0;
+	while count < pcs.len()
+	{
+		let Ok(Some(frame)) = unwinder.next_frame()
+		else
+		{
+			break;
+		};
+		pcs[count] = frame.pc as u64;
+		count += 1;
+	}
+	count
+}
+
+fn print_frames(unwinder: &mut impl FrameUnwinder, level: log::Level)
+{
+	for depth in 0..MAX_BACKTRACE_DEPTH
+	{
+		match unwinder.next_frame()
+		{
+			Ok(Some(frame)) =>
+			{
+				log!(event: "backtrace", level, "\t#{depth:<2} {frame}");
+				debuginfo::for_each_frame(frame.lookup_pc as u64, |source| {
+					log!(event: "backtrace", level, "\t    {source}");
+				});
+			},
+			Ok(None) => return,
+			Err(err) =>
+			{
+				log!(event: "backtrace", level, "\t(unwinding stopped: {})", err.as_ref());
+				return;
+			}
+		}
+	}
+	log!(
+		event: "backtrace",
+		level,
+		"\t(backtrace truncated to {MAX_BACKTRACE_DEPTH} frames)"
+	);
+}
+
+/// Print a backtrace of the current call stack
+///
+/// The log level defaults to [`log::Level::Info`].
+pub macro backtrace
+{
+	() => {
+		$crate::unwinding::backtrace!(::log::Level::Info)
+	},
+	($lvl:expr) => {{
+		let regs = $crate::unwinding::read_registers!();
+		$crate::unwinding::print_backtrace(regs, $lvl)
+	}}
+}
+
+/// Log a warning along with a (symbolized) backtrace if `cond` holds, and
+/// evaluate to `cond`
+pub macro warn_on
+{
+	($cond:expr) => {
+		$crate::unwinding::warn_on!($cond, "{}", ::core::stringify!($cond))
+	},
+	($cond:expr, $($arg:tt)+) => {{
+		let cond: bool = $cond;
+		if cond
+		{
+			$crate::warn!($($arg)+);
+			$crate::unwinding::backtrace!(::log::Level::Warn);
+		}
+		cond
+	}}
+}
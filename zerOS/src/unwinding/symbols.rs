@@ -0,0 +1,121 @@
+//! Nearest-symbol lookup in the kernel executable
+//!
+//! The symbol table is read straight from the ELF file the bootloader loaded
+//! us from, without building any index, so that lookups never allocate and
+//! can safely be performed while panicking.
+
+use core::{fmt, ptr, slice};
+
+use object::{
+	LittleEndian,
+	elf,
+	read::elf::{FileHeader, Sym}
+};
+use portable_atomic::{AtomicPtr, AtomicUsize, Ordering};
+
+type ElfHeader = elf::FileHeader64<LittleEndian>;
+
+static KERNEL_IMAGE: AtomicPtr<u8> = AtomicPtr::new(ptr::null_mut());
+static KERNEL_IMAGE_SIZE: AtomicUsize = AtomicUsize::new(0);
+
+/// Register the in-memory copy of the kernel ELF file
+pub fn set_kernel_image(image: &'static [u8])
+{
+	KERNEL_IMAGE_SIZE.store(image.len(), Ordering::Release);
+	KERNEL_IMAGE.store(image.as_ptr().cast_mut(), Ordering::Release);
+}
+
+fn kernel_image() -> Option<&'static [u8]>
+{
+	let image = KERNEL_IMAGE.load(Ordering::Acquire);
+	if image.is_null()
+	{
+		return None;
+	}
+	Some(unsafe { slice::from_raw_parts(image, KERNEL_IMAGE_SIZE.load(Ordering::Acquire)) })
+}
+
+/// The symbol an address belongs to
+#[derive(Debug, Clone, Copy)]
+pub struct SymbolInfo
+{
+	/// Raw (mangled) symbol name
+	pub name:    &'static str,
+	/// Start address of the symbol
+	pub address: u64,
+	/// Offset of the looked up address from the start of the symbol
+	pub offset:  u64
+}
+
+impl fmt::Display for SymbolInfo
+{
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+	{
+		write!(
+			f,
+			"{:#}+{:#x}",
+			rustc_demangle::demangle(self.name),
+			self.offset
+		)
+	}
+}
+
+/// Find the function or object symbol that is the closest below `addr`
+///
+/// Symbols with a size must contain `addr`; size-less ones (e.g. assembly
+/// labels) are only used when nothing better is available.
+pub fn nearest_symbol(addr: u64) -> Option<SymbolInfo>
+{
+	let image = kernel_image()?;
+	let header = ElfHeader::parse(image).ok()?;
+	let endian = header.endian().ok()?;
+	let symtab = header
+		.sections(endian, image)
+		.ok()?
+		.symbols(endian, image, elf::SHT_SYMTAB)
+		.ok()?;
+
+	let mut best: Option<(&elf::Sym64<LittleEndian>, u64)> = None;
+	for sym in symtab.symbols()
+	{
+		let kind = sym.st_type();
+		if (kind != elf::STT_FUNC && kind != elf::STT_OBJECT && kind != elf::STT_NOTYPE)
+			|| sym.st_shndx(endian) == elf::SHN_UNDEF
+		{
+			continue;
+		}
+
+		let start = sym.st_value(endian);
+		let size = sym.st_size(endian);
+		if start > addr || (size != 0 && addr - start >= size)
+		{
+			continue;
+		}
+
+		let better = match best
+		{
+			None => true,
+			Some((prev, prev_start)) =>
+			{
+				match (prev.st_size(endian) != 0, size != 0)
+				{
+					(true, false) => false,
+					(false, true) => true,
+					_ => start > prev_start
+				}
+			},
+		};
+		if better
+		{
+			best = Some((sym, start));
+		}
+	}
+
+	let (sym, start) = best?;
+	let name = symtab.symbol_name(endian, sym).ok()?;
+	Some(SymbolInfo {
+		name:    str::from_utf8(name).ok()?,
+		address: start,
+		offset:  addr - start
+	})
+}
@@ -0,0 +1,257 @@
+//! Fast stack unwinding, from the TROLL table generated by `unwindtool`
+//!
+//! The table is a flattened version of the `.eh_frame` content: for each
+//! range of addresses, it stores how to compute the CFA, and where the frame
+//! pointer and the return address have been saved. Unwinding a frame is then
+//! a binary search in the table, followed by (at most) two stack reads, which
+//! makes it cheap enough to be used for profiling or lock debugging.
+//!
+//! The linker script reserves the `.troll` section (`ZEROS_TROLL_RESERVED_SIZE`
+//! bytes), which `unwindtool troll generate --in-place` fills in after the
+//! kernel has been linked. If it has not been filled in, [`TrollTable::get`]
+//! fails and the DWARF unwinder should be used instead.
+
+use core::slice;
+
+use super::{CallFrame, FrameUnwinder, RegisterSet, UnwinderError, read_stack};
+use crate::kernel::linker::map::{zerOS_troll_end, zerOS_troll_start};
+
+const TROLL_MAGIC: [u8; 4] = *b"TROL";
+const TROLL_VERSION: u16 = 1;
+const TROLL_HEADER_SIZE: usize = 16;
+const TROLL_ENTRY_SIZE: usize = size_of::<TrollEntry>();
+
+const CFA_BASE_UNDEFINED: u8 = 0;
+const CFA_BASE_STACK_POINTER: u8 = 1;
+const CFA_BASE_FRAME_POINTER: u8 = 2;
+
+const FLAG_FP_SAVED: u8 = 1 << 0;
+const FLAG_FP_UNDEFINED: u8 = 1 << 1;
+const FLAG_RA_UNDEFINED: u8 = 1 << 2;
+const FLAG_RA_IN_REGISTER: u8 = 1 << 3;
+
+/// An entry of the table, as laid out by `unwindtool`
+///
+/// The rules of an entry apply from its `pc` up to the `pc` of the next one.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct TrollEntry
+{
+	pc:         u64,
+	cfa_offset: i16,
+	fp_offset:  i16,
+	ra_offset:  i16,
+	cfa_base:   u8,
+	flags:      u8
+}
+
+const _: () = assert!(TROLL_ENTRY_SIZE == 16);
+
+#[derive(Debug, Clone, Copy)]
+pub struct TrollTable
+{
+	entries: &'static [TrollEntry]
+}
+
+impl TrollTable
+{
+	/// Parse the header of the kernel's TROLL table
+	pub fn get() -> Result<Self, UnwinderError>
+	{
+		let start = (&raw const zerOS_troll_start).cast::<u8>();
+		let size = (&raw const zerOS_troll_end).addr() - start.addr();
+		if size < TROLL_HEADER_SIZE
+		{
+			return Err(UnwinderError::InvalidTrollTable);
+		}
+
+		// SAFETY: the section is part of the kernel image, and is never written
+		// to at runtime
+		let section = unsafe { slice::from_raw_parts(start, size) };
+		let (header, rest) = section.split_at(TROLL_HEADER_SIZE);
+		let read_u16 = |at: usize| u16::from_ne_bytes([header[at], header[at + 1]]);
+		let read_u32 = |at: usize| {
+			u32::from_ne_bytes([header[at], header[at + 1], header[at + 2], header[at + 3]])
+		};
+		if header[0..4] != TROLL_MAGIC
+			|| read_u16(4) != TROLL_VERSION
+			|| read_u32(12) as usize != TROLL_ENTRY_SIZE
+		{
+			return Err(UnwinderError::InvalidTrollTable);
+		}
+		let count = read_u32(8) as usize;
+		if count == 0
+			|| count > rest.len() / TROLL_ENTRY_SIZE
+			|| !rest
+				.as_ptr()
+				.addr()
+				.is_multiple_of(align_of::<TrollEntry>())
+		{
+			return Err(UnwinderError::InvalidTrollTable);
+		}
+
+		// SAFETY: the entries are in bounds and suitably aligned, and any bit
+		// pattern is a valid `TrollEntry`
+		let entries = unsafe { slice::from_raw_parts(rest.as_ptr().cast(), count) };
+		Ok(Self { entries })
+	}
+
+	/// Number of entries in the table (including the terminators)
+	pub fn entry_count(&self) -> usize
+	{
+		self.entries.len()
+	}
+
+	/// Find the entry covering `pc`, if any
+	fn lookup(&self, pc: u64) -> Option<&'static TrollEntry>
+	{
+		let index = self
+			.entries
+			.partition_point(|entry| entry.pc <= pc)
+			.checked_sub(1)?;
+		self.entries
+			.get(index)
+			.filter(|entry| entry.cfa_base != CFA_BASE_UNDEFINED)
+	}
+}
+
+/// An unwinder using the TROLL table rather than the DWARF call frame
+/// information
+///
+/// Only the stack pointer, the frame pointer and the program counter are
+/// tracked: the values of the other registers of the caller frames are
+/// discarded.
+pub struct TrollUnwinder
+{
+	table:    TrollTable,
+	regs:     RegisterSet,
+	cfa:      u64,
+	is_first: bool
+}
+
+impl TrollUnwinder
+{
+	pub fn new(table: TrollTable, register_set: RegisterSet) -> Self
+	{
+		let mut regs = RegisterSet::default();
+		regs.rip = register_set.get_pc();
+		if let Some(sp) = register_set.get_stack_ptr()
+		{
+			regs.set_stack_ptr(sp);
+		}
+		if let Some(fp) = register_set.get(RegisterSet::FRAME_POINTER)
+		{
+			// the frame pointer is always part of the register set
+			let _ = regs.set(RegisterSet::FRAME_POINTER, fp);
+		}
+		Self {
+			table,
+			regs,
+			cfa: 0,
+			is_first: true
+		}
+	}
+
+	/// Registers of the last frame returned by [`TrollUnwinder::next`]
+	pub fn registers(&self) -> &RegisterSet
+	{
+		&self.regs
+	}
+
+	pub fn next(&mut self) -> Result<Option<CallFrame>, UnwinderError>
+	{
+		let pc = self.regs.get_pc().ok_or(UnwinderError::NoPcRegister)?;
+
+		if self.is_first
+		{
+			self.is_first = false;
+			return Ok(Some(CallFrame {
+				pc:        pc as usize,
+				lookup_pc: pc as usize
+			}));
+		}
+
+		// see `Unwinder::next`
+		let lookup_pc = if self.cfa == 0 { pc } else { pc - 1 };
+		let entry = self
+			.table
+			.lookup(lookup_pc)
+			.ok_or(UnwinderError::NoUnwindInfo)?;
+
+		let base = match entry.cfa_base
+		{
+			CFA_BASE_STACK_POINTER => self.regs.get_stack_ptr(),
+			CFA_BASE_FRAME_POINTER => self.regs.get(RegisterSet::FRAME_POINTER),
+			_ => return Err(UnwinderError::UnsupportedCfaRule)
+		}
+		.ok_or(UnwinderError::NoCfaBaseRegister)?;
+		let cfa = base.wrapping_add_signed(entry.cfa_offset as i64);
+		if cfa <= self.cfa
+		{
+			return Err(UnwinderError::CfaNotIncreasing);
+		}
+
+		if entry.flags & FLAG_RA_UNDEFINED != 0
+		{
+			// we reached the outermost frame
+			return Ok(None);
+		}
+		if entry.flags & FLAG_RA_IN_REGISTER != 0
+		{
+			// the return address is never kept in a register on x86
+			return Err(UnwinderError::UnimplementedRegisterRule);
+		}
+		let ret = read_stack(cfa.wrapping_add_signed(entry.ra_offset as i64))?;
+		if ret == 0
+		{
+			return Ok(None);
+		}
+
+		if entry.flags & FLAG_FP_SAVED != 0
+		{
+			let fp = read_stack(cfa.wrapping_add_signed(entry.fp_offset as i64))?;
+			self.regs.set(RegisterSet::FRAME_POINTER, fp)?;
+		}
+		else if entry.flags & FLAG_FP_UNDEFINED != 0
+		{
+			self.regs.undef(RegisterSet::FRAME_POINTER);
+		}
+		self.regs.set_pc(ret);
+		self.regs.set_stack_ptr(cfa);
+		self.cfa = cfa;
+
+		Ok(Some(CallFrame {
+			pc:        ret as usize,
+			lookup_pc: ret as usize - 1
+		}))
+	}
+
+	/// Fill `pcs` with the program counters of the frames, innermost first,
+	/// and return how many of them have been written
+	///
+	/// Unlike a printed backtrace, this does not do any symbolization, and
+	/// stops silently on the first error.
+	pub fn capture(mut self, pcs: &mut [usize]) -> usize
+	{
+		let mut count = 0;
+		while count < pcs.len()
+		{
+			let Ok(Some(frame)) = self.next()
+			else
+			{
+				break;
+			};
+			pcs[count] = frame.pc;
+			count += 1;
+		}
+		count
+	}
+}
+
+impl FrameUnwinder for TrollUnwinder
+{
+	fn next_frame(&mut self) -> Result<Option<CallFrame>, UnwinderError>
+	{
+		self.next()
+	}
+}